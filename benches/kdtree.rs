@@ -0,0 +1,137 @@
+// Criterion suite for the tree internals.
+//
+// The default grid (10k points) finishes in a few minutes. Set VODB_BENCH_FULL=1 to
+// add the 100k and 1M point sizes; the 1M x 768 case needs roughly 8GB of memory.
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use std::env;
+use std::time::Duration;
+
+use vodb::kdtree::{KDTree, Point};
+use vodb::projection::Projection;
+
+const DIMENSIONS: [usize; 3] = [16, 128, 768];
+const TOP_N: usize = 10;
+
+fn sizes() -> Vec<usize> {
+    if env::var("VODB_BENCH_FULL").is_ok() {
+        vec![10_000, 100_000, 1_000_000]
+    } else {
+        vec![10_000]
+    }
+}
+
+// Small deterministic generator so runs are comparable without pulling in rand
+struct XorShift(u64);
+
+impl XorShift {
+    fn next_f64(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn point(&mut self, dim: usize) -> Point {
+        Point {
+            embedding: (0..dim).map(|_| self.next_f64()).collect(),
+            data: Some("chunk".to_string()),
+            ..Default::default()
+        }
+    }
+}
+
+fn random_points(count: usize, dim: usize, seed: u64) -> Vec<Point> {
+    let mut rng = XorShift(seed);
+    (0..count).map(|_| rng.point(dim)).collect()
+}
+
+fn incremental_tree(points: Vec<Point>, dim: usize) -> KDTree {
+    let mut tree = KDTree::new(dim).unwrap();
+    for point in points {
+        tree.insert(point);
+    }
+    tree
+}
+
+fn bench_build(c: &mut Criterion) {
+    let mut group = c.benchmark_group("build");
+    group.sample_size(10).measurement_time(Duration::from_secs(10));
+    for &size in &sizes() {
+        for &dim in &DIMENSIONS {
+            let points = random_points(size, dim, 42);
+            let id = format!("{}d/{}", dim, size);
+            group.throughput(Throughput::Elements(size as u64));
+            group.bench_function(BenchmarkId::new("incremental", &id), |b| {
+                b.iter_batched(|| points.clone(), |points| incremental_tree(points, dim), BatchSize::LargeInput)
+            });
+            group.bench_function(BenchmarkId::new("balanced", &id), |b| {
+                b.iter_batched(|| points.clone(), |points| KDTree::build(dim, points).unwrap(), BatchSize::LargeInput)
+            });
+        }
+    }
+    group.finish();
+}
+
+fn bench_topn(c: &mut Criterion) {
+    let mut group = c.benchmark_group("topn");
+    group.sample_size(20);
+    for &size in &sizes() {
+        for &dim in &DIMENSIONS {
+            let tree = incremental_tree(random_points(size, dim, 42), dim);
+            let queries = random_points(64, dim, 7);
+            let mut next = queries.iter().cycle();
+            group.bench_function(BenchmarkId::new(format!("n{}", TOP_N), format!("{}d/{}", dim, size)), |b| {
+                b.iter(|| tree.nearest_neighbors_topn(next.next().unwrap(), TOP_N))
+            });
+        }
+    }
+    group.finish();
+}
+
+// Work a search does while holding the trees lock: before, the hits were projected and
+// serialized in place; now only the winners are cloned and the rest happens after release
+fn bench_lock_hold(c: &mut Criterion) {
+    const DIM: usize = 768;
+    const N: usize = 50;
+    let mut group = c.benchmark_group("lock_hold");
+    group.sample_size(20);
+    for &size in &sizes() {
+        let tree = incremental_tree(random_points(size, DIM, 42), DIM);
+        let queries = random_points(64, DIM, 7);
+        let mut next = queries.iter().cycle();
+        let id = format!("n{}/{}d/{}", N, DIM, size);
+        group.bench_function(BenchmarkId::new("serialize_in_place", &id), |b| {
+            b.iter(|| {
+                let hits = tree.nearest_neighbors_topn_scored(next.next().unwrap(), N);
+                serde_json::to_vec(&Projection::default().project_all(hits)).unwrap()
+            })
+        });
+        group.bench_function(BenchmarkId::new("owned", &id), |b| {
+            b.iter(|| tree.nearest_neighbors_topn_owned(next.next().unwrap(), N))
+        });
+    }
+    group.finish();
+}
+
+fn bench_serialization(c: &mut Criterion) {
+    let mut group = c.benchmark_group("serialization");
+    group.sample_size(10);
+    let path = env::temp_dir().join("vodb_bench_roundtrip.bin");
+    let path = path.to_str().unwrap();
+    for &size in &sizes() {
+        for &dim in &DIMENSIONS {
+            let tree = incremental_tree(random_points(size, dim, 42), dim);
+            group.bench_function(BenchmarkId::new("roundtrip", format!("{}d/{}", dim, size)), |b| {
+                b.iter(|| {
+                    tree.save_to_file(path).unwrap();
+                    KDTree::load_from_file(path).unwrap()
+                })
+            });
+        }
+    }
+    let _ = std::fs::remove_file(path);
+    group.finish();
+}
+
+criterion_group!(benches, bench_build, bench_topn, bench_lock_hold, bench_serialization);
+criterion_main!(benches);
@@ -0,0 +1,449 @@
+// Async client for the vector store HTTP API. Request and response types come from
+// the server's own library crate, so they can't drift from what the server speaks.
+use reqwest::{Method, RequestBuilder, StatusCode};
+use serde::de::DeserializeOwned;
+use std::fmt;
+use std::time::Duration;
+
+pub use vodb::api::{BatchSummary, CacheEntry, CollectionInfo, CreateTreeResponse, DeleteByFilterResponse, DropCacheResponse, DistributionStats, DriftResponse, EmptySearchResponse, ExistsWithinResponse, GcResponse, ImportResponse, InsertResponse, JobState, JobStatus, JobsResponse, MultiInsertOutcome, MultiInsertResponse, MultiInsertStatus, NormBucket, RebuildResponse, RejectedRow, RowGroupImport, SampleResponse, SchemaResponse, SearchHit, SearchResponse, StatsResponse, StatusResponse, StatusTotals, StructureNode, StructureRoot, TreeStatus, TreeStructure, TreeSummary, TreeUsageStatus, TreeVerification, TreesResponse, TruncateResponse, VerifyAllResponse};
+pub use vodb::archive::Tier;
+pub use vodb::durability::Durability;
+pub use vodb::encoding::{Dtype, PointInput};
+pub use vodb::jobs::JobKind;
+pub use vodb::kdtree::{InvariantError, Point};
+pub use vodb::metadata::{Metadata, MetadataValue};
+pub use vodb::query::{Combine, MultiInsertEntry};
+pub use vodb::schema::{FieldSpec, FieldType, Schema};
+pub use vodb::store::Persistence;
+
+#[derive(Debug)]
+pub enum ClientError {
+    Http(reqwest::Error),                      // Connection, timeout or decoding failure
+    Status { status: StatusCode, body: String }, // The server answered with an error status
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Http(e) => write!(f, "request failed: {}", e),
+            ClientError::Status { status, body } => write!(f, "server returned {}: {}", status, body),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(e: reqwest::Error) -> Self {
+        ClientError::Http(e)
+    }
+}
+
+// Optional parameters of a top-n search
+#[derive(Debug, Clone, Default)]
+pub struct SearchOptions {
+    pub offset: Option<usize>,      // Skips the first results, to page through a long top list
+    pub fields: Option<String>,     // Comma separated result fields, e.g. `data,distance`
+    pub float_precision: Option<u32>, // Significant digits of returned embeddings
+    pub partition: Option<String>,  // Only search this partition of a partitioned tree
+    pub if_in_memory: Option<bool>, // Fail instead of loading an offloaded tree
+    pub filter: Option<String>,     // Metadata conditions, e.g. `{"tier": {"$gte": 2}}`
+    pub packed_embeddings: Option<Dtype>, // Return embeddings in `embedding_b64` as values of this type
+}
+
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+    timeout: Duration,
+    max_retries: u32,
+    initial_backoff: Duration,
+}
+
+impl Client {
+    pub fn new(base_url: &str) -> Self {
+        Client {
+            http: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key: None,
+            timeout: Duration::from_secs(30),
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(100),
+        }
+    }
+
+    // Sent as `X-API-Key` on every request
+    pub fn with_api_key(mut self, api_key: &str) -> Self {
+        self.api_key = Some(api_key.to_string());
+        self
+    }
+
+    // Per-attempt timeout, retries get a fresh one
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    // Retries failures the request can safely be sent again after, doubling the wait each
+    // time: connection failures, 429 and the gateway statuses 502, 503 and 504. Timeouts and
+    // other 5xx statuses are only retried for reads, since a write may have been applied.
+    pub fn with_retries(mut self, max_retries: u32, initial_backoff: Duration) -> Self {
+        self.max_retries = max_retries;
+        self.initial_backoff = initial_backoff;
+        self
+    }
+
+    // A point the server applied but could not save comes back with `save_error` set
+    // rather than as an error, since sending it again would insert it twice
+    pub async fn insert(
+        &self,
+        tree_name: &str,
+        point: &Point,
+        durability: Option<Durability>,
+    ) -> Result<InsertResponse, ClientError> {
+        let durability = durability.map(|durability| match durability {
+            Durability::None => "none",
+            Durability::Buffered => "buffered",
+            Durability::Fsync => "fsync",
+        });
+        self.send(Method::POST, "/insert", |request| {
+            let request = request.query(&[("tree_name", tree_name)]).json(point);
+            match durability {
+                Some(durability) => request.query(&[("durability", durability)]),
+                None => request,
+            }
+        })
+        .await
+    }
+
+    // Inserts the points one after another, stopping at the first that fails. The points
+    // before it stay inserted; the error doesn't say how many there were.
+    pub async fn insert_batch(
+        &self,
+        tree_name: &str,
+        points: &[Point],
+        durability: Option<Durability>,
+    ) -> Result<Vec<InsertResponse>, ClientError> {
+        let mut responses = Vec::with_capacity(points.len());
+        for point in points {
+            responses.push(self.insert(tree_name, point, durability).await?);
+        }
+        Ok(responses)
+    }
+
+    // Inserts one point into each of several trees, all or nothing. A request the server
+    // rejects comes back as `ClientError::Status` with the per-entry outcomes as its body.
+    pub async fn insert_multi(&self, entries: &[MultiInsertEntry]) -> Result<MultiInsertResponse, ClientError> {
+        self.send(Method::POST, "/insert_multi", |request| request.json(entries)).await
+    }
+
+    // The single closest point, or `None` for an empty tree
+    pub async fn nearest(&self, tree_name: &str, embedding: &[f64], options: &SearchOptions) -> Result<Option<SearchHit>, ClientError> {
+        Ok(self.nearest_top_n(tree_name, embedding, 1, options).await?.into_iter().next())
+    }
+
+    pub async fn nearest_top_n(
+        &self,
+        tree_name: &str,
+        embedding: &[f64],
+        n: usize,
+        options: &SearchOptions,
+    ) -> Result<Vec<SearchHit>, ClientError> {
+        Ok(self.search(tree_name, serde_json::json!({ "embedding": embedding }), n, options).await?.results)
+    }
+
+    // Same search, also returning the `collection` summary of the tree (unless
+    // `options.fields` leaves it out) to check for an empty or mismatched tree
+    pub async fn nearest_top_n_with_collection(
+        &self,
+        tree_name: &str,
+        embedding: &[f64],
+        n: usize,
+        options: &SearchOptions,
+    ) -> Result<SearchResponse, ClientError> {
+        self.search(tree_name, serde_json::json!({ "embedding": embedding }), n, options).await
+    }
+
+    // Searches near the combination of several embeddings, e.g. the mean of a few liked items
+    pub async fn nearest_top_n_combined(
+        &self,
+        tree_name: &str,
+        embeddings: &[Vec<f64>],
+        combine: Combine,
+        n: usize,
+        options: &SearchOptions,
+    ) -> Result<Vec<SearchHit>, ClientError> {
+        let points: Vec<_> = embeddings.iter().map(|embedding| serde_json::json!({ "embedding": embedding })).collect();
+        Ok(self.search(tree_name, serde_json::json!({ "points": points, "combine": combine }), n, options).await?.results)
+    }
+
+    async fn search(
+        &self,
+        tree_name: &str,
+        body: serde_json::Value,
+        n: usize,
+        options: &SearchOptions,
+    ) -> Result<SearchResponse, ClientError> {
+        self.send(Method::POST, "/nearesttop", |request| {
+            let mut query = vec![("tree_name", tree_name.to_string()), ("n", n.to_string())];
+            if let Some(offset) = options.offset {
+                query.push(("offset", offset.to_string()));
+            }
+            if let Some(fields) = &options.fields {
+                query.push(("fields", fields.clone()));
+            }
+            if let Some(float_precision) = options.float_precision {
+                query.push(("float_precision", float_precision.to_string()));
+            }
+            if let Some(partition) = &options.partition {
+                query.push(("partition", partition.clone()));
+            }
+            if let Some(if_in_memory) = options.if_in_memory {
+                query.push(("if_in_memory", if_in_memory.to_string()));
+            }
+            if let Some(filter) = &options.filter {
+                query.push(("filter", filter.clone()));
+            }
+            if let Some(dtype) = options.packed_embeddings {
+                query.push(("encoding", "b64".to_string()));
+                query.push(("embedding_dtype", dtype.to_string()));
+            }
+            request.query(&query).json(&body)
+        })
+        .await
+    }
+
+    // Whether any point lies within `distance` of `embedding`; stops at the first hit
+    pub async fn exists_within(&self, tree_name: &str, embedding: &[f64], distance: f64) -> Result<ExistsWithinResponse, ClientError> {
+        let body = serde_json::json!({ "embedding": embedding });
+        self.send(Method::POST, "/exists_within", |request| {
+            request.query(&[("tree_name", tree_name.to_string()), ("distance", distance.to_string())]).json(&body)
+        })
+        .await
+    }
+
+    // The stored point with exactly this embedding; a 404 status when there is none
+    pub async fn get_by_embedding(&self, tree_name: &str, embedding: &[f64]) -> Result<SearchHit, ClientError> {
+        let body = serde_json::json!({ "embedding": embedding });
+        self.send(Method::POST, "/get_by_embedding", |request| request.query(&[("tree_name", tree_name)]).json(&body)).await
+    }
+
+    pub async fn create_tree(
+        &self,
+        tree_name: &str,
+        dimensions: usize,
+        partition_field: Option<&str>,
+    ) -> Result<CreateTreeResponse, ClientError> {
+        self.send(Method::POST, "/create_tree", |request| {
+            let request = request.query(&[("tree_name", tree_name.to_string()), ("dimensions", dimensions.to_string())]);
+            match partition_field {
+                Some(field) => request.query(&[("partition_field", field)]),
+                None => request,
+            }
+        })
+        .await
+    }
+
+    pub async fn rebuild(&self, tree_name: &str) -> Result<RebuildResponse, ClientError> {
+        self.send(Method::POST, "/rebuild", |request| request.query(&[("tree_name", tree_name)])).await
+    }
+
+    // Imports a Parquet file that is on the server, at `path` relative to its IMPORT_DIRECTORY
+    pub async fn import_parquet(
+        &self,
+        tree_name: &str,
+        path: &str,
+        embedding_column: &str,
+        data_column: Option<&str>,
+    ) -> Result<ImportResponse, ClientError> {
+        self.send(Method::POST, "/import_parquet", |request| {
+            let request = request.query(&[("tree_name", tree_name), ("path", path), ("embedding_column", embedding_column)]);
+            match data_column {
+                Some(column) => request.query(&[("data_column", column)]),
+                None => request,
+            }
+        })
+        .await
+    }
+
+    // Starts re-embedding the data of `tree_name` into the new tree `target`; poll the job
+    // with `job`. Starting it again resumes an interrupted run.
+    pub async fn reembed(&self, tree_name: &str, target: &str) -> Result<JobStatus, ClientError> {
+        self.send(Method::POST, "/reembed", |request| request.query(&[("tree_name", tree_name), ("target", target)])).await
+    }
+
+    // Starts a rebuild as a background job instead of waiting for it
+    pub async fn rebuild_async(&self, tree_name: &str) -> Result<JobStatus, ClientError> {
+        self.send(Method::POST, "/rebuild", |request| request.query(&[("tree_name", tree_name), ("async", "true")])).await
+    }
+
+    pub async fn jobs(&self) -> Result<JobsResponse, ClientError> {
+        self.send(Method::GET, "/jobs", |request| request).await
+    }
+
+    pub async fn job(&self, id: u64) -> Result<JobStatus, ClientError> {
+        self.send(Method::GET, &format!("/jobs/{}", id), |request| request).await
+    }
+
+    // Asks a running job to stop at its next safe point
+    pub async fn cancel_job(&self, id: u64) -> Result<JobStatus, ClientError> {
+        self.send(Method::DELETE, &format!("/jobs/{}", id), |request| request).await
+    }
+
+    // Deletes the points matching `filter`; an empty filter is refused by the server unless
+    // `confirm_delete_all` is set
+    pub async fn delete_by_filter(
+        &self,
+        tree_name: &str,
+        filter: &str,
+        dry_run: bool,
+        confirm_delete_all: bool,
+    ) -> Result<DeleteByFilterResponse, ClientError> {
+        self.send(Method::POST, "/delete_by_filter", |request| {
+            request.query(&[
+                ("tree_name", tree_name.to_string()),
+                ("filter", filter.to_string()),
+                ("dry_run", dry_run.to_string()),
+                ("confirm_delete_all", confirm_delete_all.to_string()),
+            ])
+        })
+        .await
+    }
+
+    // Deletes every point of the tree but keeps its configuration
+    pub async fn truncate(&self, tree_name: &str) -> Result<TruncateResponse, ClientError> {
+        self.send(Method::POST, "/truncate", |request| request.query(&[("tree_name", tree_name), ("confirm", "true")])).await
+    }
+
+    pub async fn status(&self, tree_name: Option<&str>) -> Result<StatusResponse, ClientError> {
+        self.send(Method::GET, "/v1/status", |request| match tree_name {
+            Some(tree_name) => request.query(&[("tree_name", tree_name)]),
+            None => request,
+        })
+        .await
+    }
+
+    pub async fn stats(&self, tree_name: &str) -> Result<StatsResponse, ClientError> {
+        self.send(Method::GET, "/stats", |request| request.query(&[("tree_name", tree_name)])).await
+    }
+
+    // The tree's shape down to `max_depth`, as JSON
+    pub async fn tree_structure(&self, tree_name: &str, max_depth: Option<usize>) -> Result<TreeStructure, ClientError> {
+        self.send(Method::GET, "/tree_structure", |request| {
+            let mut query = vec![("tree_name", tree_name.to_string())];
+            if let Some(max_depth) = max_depth {
+                query.push(("max_depth", max_depth.to_string()));
+            }
+            request.query(&query)
+        })
+        .await
+    }
+
+    pub async fn schema(&self, tree_name: &str) -> Result<SchemaResponse, ClientError> {
+        self.send(Method::GET, "/schema", |request| request.query(&[("tree_name", tree_name)])).await
+    }
+
+    // Declares the tree's metadata schema; one that stored points don't match is refused
+    // with a 409 status unless `force` is set
+    pub async fn set_schema(&self, tree_name: &str, schema: &Schema, force: bool) -> Result<SchemaResponse, ClientError> {
+        self.send(Method::PUT, "/schema", |request| {
+            request.query(&[("tree_name", tree_name), ("force", if force { "true" } else { "false" })]).json(schema)
+        })
+        .await
+    }
+
+    // Draws `count` random points; the same `seed` returns the same sample
+    pub async fn sample(&self, tree_name: &str, count: usize, seed: Option<u64>) -> Result<SampleResponse, ClientError> {
+        self.send(Method::GET, "/sample", |request| {
+            let request = request.query(&[("tree_name", tree_name.to_string()), ("count", count.to_string())]);
+            match seed {
+                Some(seed) => request.query(&[("seed", seed)]),
+                None => request,
+            }
+        })
+        .await
+    }
+
+    // Checks the KD-tree invariant of every hot tree on the server
+    pub async fn verify_all(&self) -> Result<VerifyAllResponse, ClientError> {
+        self.send(Method::GET, "/verify_all", |request| request).await
+    }
+
+    pub async fn gc(&self) -> Result<GcResponse, ClientError> {
+        self.send(Method::POST, "/gc", |request| request).await
+    }
+
+    pub async fn drift(&self, tree_name: &str, against: &str) -> Result<DriftResponse, ClientError> {
+        self.send(Method::GET, "/drift", |request| request.query(&[("tree_name", tree_name), ("against", against)])).await
+    }
+
+    pub async fn cache_entry(&self, tree_name: &str) -> Result<CacheEntry, ClientError> {
+        self.send(Method::GET, "/v1/cache", |request| request.query(&[("tree_name", tree_name)])).await
+    }
+
+    // Drops the server's in-memory copy of a tree; `discard_unsaved` allows losing changes not yet on disk
+    pub async fn drop_cache(&self, tree_name: &str, discard_unsaved: bool) -> Result<DropCacheResponse, ClientError> {
+        self.send(Method::DELETE, "/cache", |request| {
+            request.query(&[("tree_name", tree_name), ("discard_unsaved", if discard_unsaved { "true" } else { "false" })])
+        })
+        .await
+    }
+
+    pub async fn trees(&self) -> Result<TreesResponse, ClientError> {
+        self.send(Method::GET, "/v1/trees", |request| request).await
+    }
+
+    // Sends the request built by `build`, retrying retryable failures with backoff
+    async fn send<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        build: impl Fn(RequestBuilder) -> RequestBuilder,
+    ) -> Result<T, ClientError> {
+        let url = format!("{}{}", self.base_url, path);
+        let mut backoff = self.initial_backoff;
+        let mut attempt = 0;
+        loop {
+            let mut request = build(self.http.request(method.clone(), &url)).timeout(self.timeout);
+            if let Some(api_key) = &self.api_key {
+                request = request.header("X-API-Key", api_key);
+            }
+
+            let error = match request.send().await {
+                Ok(response) if response.status().is_success() => return Ok(response.json().await?),
+                Ok(response) => {
+                    let status = response.status();
+                    let body = response.text().await.unwrap_or_default();
+                    ClientError::Status { status, body }
+                }
+                Err(e) => ClientError::Http(e),
+            };
+
+            let retryable = match &error {
+                ClientError::Status { status, .. } => match *status {
+                    StatusCode::TOO_MANY_REQUESTS
+                    | StatusCode::BAD_GATEWAY
+                    | StatusCode::SERVICE_UNAVAILABLE
+                    | StatusCode::GATEWAY_TIMEOUT => true,
+                    // 501 and 507 won't change on a retry
+                    StatusCode::NOT_IMPLEMENTED | StatusCode::INSUFFICIENT_STORAGE => false,
+                    status => status.is_server_error() && replayable(&method, path),
+                },
+                ClientError::Http(e) => e.is_connect() || (e.is_timeout() && replayable(&method, path)),
+            };
+            if !retryable || attempt >= self.max_retries {
+                return Err(error);
+            }
+            attempt += 1;
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+}
+
+// Whether sending the request again can't apply it twice: GETs, and the POSTs that only search
+fn replayable(method: &Method, path: &str) -> bool {
+    const SEARCHES: &[&str] = &["/nearesttop", "/exists_within", "/get_by_embedding"];
+    *method == Method::GET || (*method == Method::POST && SEARCHES.contains(&path))
+}
@@ -0,0 +1,214 @@
+// Drives the client against the real server, served in-process on an ephemeral port
+// over a fresh bin directory.
+use actix_web::dev::ServerHandle;
+use actix_web::{web, App, HttpResponse, HttpServer};
+use reqwest::StatusCode;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use vector_store_client::{Client, ClientError, MultiInsertEntry, Point, PointInput, SearchOptions};
+use vodb::config::Settings;
+use vodb::server::{all_routes, app, build_state};
+
+struct TestServer {
+    client: Client,
+    handle: ServerHandle,
+    _bin_directory: TempDir,
+}
+
+impl TestServer {
+    async fn start() -> TestServer {
+        let bin_directory = tempfile::tempdir().unwrap();
+        let settings = Settings { bin_directory: bin_directory.path().to_path_buf(), ..Default::default() };
+        let state = build_state(settings, false).unwrap();
+        let server = HttpServer::new(move || app(state.clone(), all_routes))
+            .workers(1)
+            .bind(("127.0.0.1", 0))
+            .unwrap();
+        let address = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+        TestServer {
+            client: Client::new(&format!("http://{}", address)),
+            handle,
+            _bin_directory: bin_directory,
+        }
+    }
+
+    async fn stop(self) {
+        self.handle.stop(true).await;
+    }
+}
+
+fn point(embedding: &[f64], data: &str) -> Point {
+    Point { embedding: embedding.to_vec(), data: Some(data.to_string()), ..Default::default() }
+}
+
+fn with_distance() -> SearchOptions {
+    SearchOptions { fields: Some("data,distance".to_string()), ..Default::default() }
+}
+
+#[actix_web::test]
+async fn insert_then_search() {
+    let server = TestServer::start().await;
+    let client = &server.client;
+
+    let first = client.insert("docs", &point(&[0.0, 0.0], "origin"), None).await.unwrap();
+    assert!(first.implicitly_created);
+    let rest = [point(&[1.0, 1.0], "near"), point(&[10.0, 10.0], "far")];
+    let inserted = client.insert_batch("docs", &rest, None).await.unwrap();
+    assert_eq!(inserted.len(), 2);
+    assert!(inserted.iter().all(|response| !response.implicitly_created));
+
+    let nearest = client.nearest("docs", &[9.0, 9.0], &SearchOptions::default()).await.unwrap();
+    assert_eq!(nearest.unwrap().data.as_deref(), Some("far"));
+
+    let hits = client.nearest_top_n("docs", &[0.9, 0.9], 2, &with_distance()).await.unwrap();
+    let data: Vec<_> = hits.iter().map(|hit| hit.data.as_deref().unwrap()).collect();
+    assert_eq!(data, ["near", "origin"]);
+    assert!(hits[0].distance.unwrap() < hits[1].distance.unwrap());
+
+    let exact = client.get_by_embedding("docs", &[10.0, 10.0]).await.unwrap();
+    assert_eq!(exact.data.as_deref(), Some("far"));
+    let within = client.exists_within("docs", &[0.1, 0.0], 0.5).await.unwrap();
+    assert!(within.found);
+    assert_eq!(within.data.as_deref(), Some("origin"));
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn insert_multi_reaches_every_tree() {
+    let server = TestServer::start().await;
+    let client = &server.client;
+
+    let entries: Vec<MultiInsertEntry> = ["left", "right"]
+        .iter()
+        .map(|tree_name| MultiInsertEntry {
+            tree_name: tree_name.to_string(),
+            point: PointInput::from(point(&[1.0, 2.0, 3.0], tree_name)),
+            reference: Some(serde_json::json!(tree_name)),
+        })
+        .collect();
+    let response = client.insert_multi(&entries).await.unwrap();
+    assert!(response.committed);
+    assert_eq!(response.entries.len(), 2);
+    assert_eq!(response.entries[1].reference, Some(serde_json::json!("right")));
+    assert_eq!((response.summary.succeeded, response.summary.failed, response.summary.skipped), (2, 0, 0));
+
+    let status = client.status(None).await.unwrap();
+    for tree_name in ["left", "right"] {
+        let tree = status.trees.iter().find(|tree| tree.tree_name == tree_name).unwrap();
+        assert_eq!(tree.num_records, 1);
+        assert_eq!(tree.dimensions, Some(3));
+    }
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn truncate_keeps_the_tree() {
+    let server = TestServer::start().await;
+    let client = &server.client;
+
+    client.create_tree("notes", 2, None).await.unwrap();
+    for i in 0..5 {
+        client.insert("notes", &point(&[i as f64, 0.0], &i.to_string()), None).await.unwrap();
+    }
+    let truncated = client.truncate("notes").await.unwrap();
+    assert_eq!(truncated.removed, 5);
+    assert_eq!(truncated.dimensions, 2);
+
+    let status = client.status(Some("notes")).await.unwrap();
+    assert_eq!(status.trees.len(), 1);
+    assert_eq!(status.trees[0].num_records, 0);
+    assert!(client.nearest_top_n("notes", &[0.0, 0.0], 3, &SearchOptions::default()).await.unwrap().is_empty());
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn client_errors_are_not_retried() {
+    let server = TestServer::start().await;
+    let client = server.client.clone().with_retries(3, Duration::from_secs(2));
+
+    client.create_tree("fixed", 3, None).await.unwrap();
+    let started = Instant::now();
+    match client.insert("fixed", &point(&[1.0, 2.0], "short"), None).await {
+        Err(ClientError::Status { status, .. }) => assert_eq!(status, StatusCode::BAD_REQUEST),
+        other => panic!("expected a 400, got {:?}", other),
+    }
+    // A retry would have waited out the two second backoff first
+    assert!(started.elapsed() < Duration::from_secs(2));
+
+    match client.nearest_top_n("missing", &[1.0, 2.0, 3.0], 1, &SearchOptions::default()).await {
+        Err(ClientError::Status { status, .. }) => assert_eq!(status, StatusCode::NOT_FOUND),
+        other => panic!("expected a 404, got {:?}", other),
+    }
+
+    server.stop().await;
+}
+
+// Serves every path with `status` after `delay`, counting the requests it gets
+async fn stub_server(status: u16, delay: Duration, requests: Arc<AtomicUsize>) -> (Client, ServerHandle) {
+    let server = HttpServer::new(move || {
+        let requests = requests.clone();
+        App::new().default_service(web::to(move || {
+            let requests = requests.clone();
+            async move {
+                requests.fetch_add(1, Ordering::SeqCst);
+                actix_web::rt::time::sleep(delay).await;
+                HttpResponse::build(actix_web::http::StatusCode::from_u16(status).unwrap()).body("stub")
+            }
+        }))
+    })
+    .workers(1)
+    .bind(("127.0.0.1", 0))
+    .unwrap();
+    let address = server.addrs()[0];
+    let server = server.run();
+    let handle = server.handle();
+    actix_web::rt::spawn(server);
+    (Client::new(&format!("http://{}", address)), handle)
+}
+
+#[actix_web::test]
+async fn writes_are_only_retried_when_they_cannot_have_been_applied() {
+    let backoff = Duration::from_millis(10);
+
+    // An insert that timed out may have been applied, a search may be sent again
+    let requests = Arc::new(AtomicUsize::new(0));
+    let (client, handle) = stub_server(200, Duration::from_millis(500), requests.clone()).await;
+    let client = client.with_timeout(Duration::from_millis(50)).with_retries(2, backoff);
+    assert!(matches!(client.insert("docs", &point(&[1.0], "a"), None).await, Err(ClientError::Http(e)) if e.is_timeout()));
+    assert_eq!(requests.swap(0, Ordering::SeqCst), 1);
+    assert!(client.nearest("docs", &[1.0], &SearchOptions::default()).await.is_err());
+    assert_eq!(requests.load(Ordering::SeqCst), 3);
+    handle.stop(false).await;
+
+    // So may an insert answered with a 500
+    let requests = Arc::new(AtomicUsize::new(0));
+    let (client, handle) = stub_server(500, Duration::ZERO, requests.clone()).await;
+    let client = client.with_retries(2, backoff);
+    assert!(client.insert("docs", &point(&[1.0], "a"), None).await.is_err());
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+    handle.stop(false).await;
+
+    // A 507 is not worth another try, whatever the request
+    let requests = Arc::new(AtomicUsize::new(0));
+    let (client, handle) = stub_server(507, Duration::ZERO, requests.clone()).await;
+    let client = client.with_retries(2, backoff);
+    assert!(client.nearest("docs", &[1.0], &SearchOptions::default()).await.is_err());
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+    handle.stop(false).await;
+
+    // An overloaded server never took the insert in
+    let requests = Arc::new(AtomicUsize::new(0));
+    let (client, handle) = stub_server(503, Duration::ZERO, requests.clone()).await;
+    let client = client.with_retries(2, backoff);
+    assert!(client.insert("docs", &point(&[1.0], "a"), None).await.is_err());
+    assert_eq!(requests.load(Ordering::SeqCst), 3);
+    handle.stop(false).await;
+}
@@ -0,0 +1,166 @@
+// Advice on how trees are written, from their insert traffic over the last hour. Every
+// save rewrites the whole tree file, so a large tree saved after each small insert
+// writes its size again for every few bytes inserted. The advice depends only on the
+// traffic handed in, so the same traffic always gets the same advice.
+use crate::api::TreeWriteTraffic;
+use crate::usage::{write_amplification, RecentWrites};
+
+// Inserts a tree needs in the window before its traffic is judged
+const MIN_INSERTS: u64 = 100;
+
+// Amplification that calls for write-behind, and what batching should bring it under
+const HIGH_AMPLIFICATION: f64 = 100.0;
+const TARGET_AMPLIFICATION: f64 = 10.0;
+
+// Saves a minute worth cutting down even when each one carries enough points
+const FREQUENT_SAVES_PER_MINUTE: f64 = 60.0;
+
+pub fn traffic(tree_name: &str, recent: &RecentWrites) -> TreeWriteTraffic {
+    let window_secs = recent.window_secs.max(1) as f64;
+    TreeWriteTraffic {
+        tree_name: tree_name.to_string(),
+        inserts: recent.inserts,
+        inserts_per_sec: recent.inserts as f64 / window_secs,
+        saves: recent.saves,
+        saves_per_minute: recent.saves as f64 * 60.0 / window_secs,
+        points_per_save: (recent.saves > 0).then(|| recent.inserts as f64 / recent.saves as f64),
+        bytes_inserted: recent.bytes_inserted,
+        bytes_written: recent.bytes_written,
+        write_amplification: write_amplification(recent.bytes_written, recent.bytes_inserted),
+    }
+}
+
+// What to change about one tree's writes, or None when it is quiet or already batched well
+pub fn advise(traffic: &TreeWriteTraffic) -> Option<String> {
+    let (Some(amplification), Some(points_per_save)) = (traffic.write_amplification, traffic.points_per_save) else {
+        return None;
+    };
+    if traffic.inserts < MIN_INSERTS {
+        return None;
+    }
+    let tree_name = &traffic.tree_name;
+    if amplification >= TARGET_AMPLIFICATION {
+        // Amplification falls in proportion to the points each save carries
+        let batch = round_up(points_per_save * amplification / TARGET_AMPLIFICATION);
+        return Some(match amplification >= HIGH_AMPLIFICATION {
+            true => format!(
+                "tree {}: {:.0}x write amplification; enable write-behind or batch inserts ≥{}",
+                tree_name, amplification, batch
+            ),
+            false => format!(
+                "tree {}: {:.0}x write amplification; batch inserts ≥{} to bring it under {:.0}x",
+                tree_name, amplification, batch, TARGET_AMPLIFICATION
+            ),
+        });
+    }
+    (traffic.saves_per_minute > FREQUENT_SAVES_PER_MINUTE).then(|| format!(
+        "tree {}: saved {:.0} times a minute; enable write-behind to save it once per flush interval",
+        tree_name, traffic.saves_per_minute
+    ))
+}
+
+// The smallest of 1, 2 or 5 times a power of ten that is at least `value`
+fn round_up(value: f64) -> u64 {
+    let mut magnitude = 1;
+    loop {
+        for step in [1, 2, 5] {
+            if (step * magnitude) as f64 >= value {
+                return step * magnitude;
+            }
+        }
+        magnitude *= 10;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: u64 = 3600;
+
+    // An hour of `inserts` points of `point_bytes` each, saved `saves` times as a tree
+    // file of `file_bytes`
+    fn profile(window_secs: u64, inserts: u64, point_bytes: u64, saves: u64, file_bytes: u64) -> TreeWriteTraffic {
+        let recent = RecentWrites {
+            window_secs,
+            inserts,
+            bytes_inserted: inserts * point_bytes,
+            saves,
+            bytes_written: saves * file_bytes,
+        };
+        traffic("docs", &recent)
+    }
+
+    #[test]
+    fn traffic_is_summarized_per_second_and_per_save() {
+        let traffic = profile(HOUR, 1800, 100, 600, 10_000);
+        assert_eq!(traffic.inserts_per_sec, 0.5);
+        assert_eq!(traffic.saves_per_minute, 10.0);
+        assert_eq!(traffic.points_per_save, Some(3.0));
+        assert_eq!(traffic.write_amplification, Some(100.0 / 3.0));
+        // An empty window doesn't divide by zero
+        assert_eq!(profile(0, 10, 100, 1, 1000).inserts_per_sec, 10.0);
+    }
+
+    #[test]
+    fn saving_a_large_tree_per_insert_calls_for_write_behind() {
+        // 8MB rewritten for every 200 byte point
+        let traffic = profile(HOUR, 1000, 200, 1000, 8_000_000);
+        assert_eq!(
+            advise(&traffic).as_deref(),
+            Some("tree docs: 40000x write amplification; enable write-behind or batch inserts ≥5000")
+        );
+    }
+
+    #[test]
+    fn moderate_amplification_asks_for_bigger_batches() {
+        let traffic = profile(HOUR, 1000, 200, 500, 20_000);
+        assert_eq!(traffic.write_amplification, Some(50.0));
+        assert_eq!(
+            advise(&traffic).as_deref(),
+            Some("tree docs: 50x write amplification; batch inserts ≥10 to bring it under 10x")
+        );
+    }
+
+    #[test]
+    fn frequent_saves_of_a_well_batched_tree_call_for_write_behind() {
+        // Two saves a second, each carrying a hundred points
+        let traffic = profile(60, 12_000, 200, 120, 100_000);
+        assert_eq!(traffic.write_amplification, Some(5.0));
+        assert_eq!(
+            advise(&traffic).as_deref(),
+            Some("tree docs: saved 120 times a minute; enable write-behind to save it once per flush interval")
+        );
+    }
+
+    #[test]
+    fn quiet_or_well_batched_trees_get_no_advice() {
+        // Too few inserts to judge
+        assert_eq!(advise(&profile(HOUR, MIN_INSERTS - 1, 200, MIN_INSERTS - 1, 8_000_000)), None);
+        // Inserted but never saved, and never inserted at all
+        assert_eq!(advise(&profile(HOUR, 1000, 200, 0, 0)), None);
+        assert_eq!(advise(&profile(HOUR, 0, 200, 10, 8_000_000)), None);
+        // Big batches, a save every few minutes
+        assert_eq!(advise(&profile(HOUR, 10_000, 200, 20, 500_000)), None);
+    }
+
+    #[test]
+    fn the_same_traffic_always_gets_the_same_advice() {
+        let profiles = [
+            profile(HOUR, 1000, 200, 1000, 8_000_000),
+            profile(HOUR, 1000, 200, 500, 20_000),
+            profile(60, 12_000, 200, 120, 100_000),
+        ];
+        for traffic in &profiles {
+            let advice = advise(traffic);
+            assert!(advice.is_some());
+            assert!((0..10).all(|_| advise(traffic) == advice));
+        }
+    }
+
+    #[test]
+    fn batch_sizes_round_up_to_one_two_or_five() {
+        let rounded: Vec<u64> = [0.5, 1.0, 1.5, 3.0, 7.0, 10.0, 11.0, 4000.0, 40_000.0].iter().map(|value| round_up(*value)).collect();
+        assert_eq!(rounded, [1, 1, 2, 5, 10, 10, 20, 5000, 50_000]);
+    }
+}
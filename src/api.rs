@@ -0,0 +1,697 @@
+// Response bodies of the HTTP API. The server builds its answers from these and the
+// client crate decodes them, so both sides always agree on the shape.
+//
+// Every JSON name the API uses, in requests and responses alike, is snake_case. Fields
+// get it from their Rust names, which the `non_snake_case` lint keeps that way, and
+// enums by `#[serde(rename_all = "snake_case")]`; a field renamed for the wire keeps to
+// it too. Request bodies deny unknown fields, so a misspelt one is a 400, not ignored.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+use crate::archive::Tier;
+use crate::durability::Durability;
+use crate::gc::GarbageKind;
+use crate::jobs::JobKind;
+use crate::kdtree::InvariantError;
+use crate::metadata::Metadata;
+use crate::operation::OperationKind;
+use crate::routes::KeyScope;
+use crate::scan::OffloadedStrategy;
+use crate::schema::Schema;
+use crate::store::Persistence;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InsertResponse {
+    pub message: String,
+    pub durability: Durability, // Level actually reached, which may be below the requested one
+    pub implicitly_created: bool, // The tree did not exist and took this point's dimension
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub save_error: Option<String>, // Why the tree could not be written; the point is in memory only, and the response is a 202
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model_warning: Option<String>, // The tree was created for another model; only sent with strict_model=false
+}
+
+// What happened to one entry of an /insert_multi request
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MultiInsertStatus {
+    Inserted,   // The tree gained the point
+    Duplicate,  // The tree already held the point, which counts as success
+    Failed,     // This entry stopped the request; see `error`
+    NotApplied, // Another entry failed before this one was applied
+    RolledBack, // Applied, then undone because another entry failed
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MultiInsertOutcome {
+    pub tree_name: String,
+    pub status: MultiInsertStatus,
+    pub implicitly_created: bool, // The tree did not exist and took this point's dimension
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, rename = "ref", skip_serializing_if = "Option::is_none")]
+    pub reference: Option<serde_json::Value>, // The entry's `ref`, passed back as it came
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MultiInsertResponse {
+    pub committed: bool, // Every tree gained (or already held) its point and was saved
+    pub entries: Vec<MultiInsertOutcome>, // In request order
+    #[serde(default)]
+    pub summary: BatchSummary,
+}
+
+impl MultiInsertResponse {
+    // Duplicates count as success, entries another entry's failure kept out as skipped
+    pub fn new(committed: bool, entries: Vec<MultiInsertOutcome>, started: Instant) -> Self {
+        let count = |wanted: &[MultiInsertStatus]| entries.iter().filter(|entry| wanted.contains(&entry.status)).count();
+        let summary = BatchSummary::finished(
+            started,
+            count(&[MultiInsertStatus::Inserted, MultiInsertStatus::Duplicate]),
+            count(&[MultiInsertStatus::Failed]),
+            count(&[MultiInsertStatus::NotApplied, MultiInsertStatus::RolledBack]),
+        );
+        MultiInsertResponse { committed, entries, summary }
+    }
+}
+
+// One search result. Which fields are present depends on the `fields` parameter.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SearchHit {
+    pub embedding: Option<Vec<f64>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub embedding_b64: Option<String>, // In place of `embedding` with `encoding=b64`
+    pub data: Option<String>,
+    pub metadata: Option<Metadata>,
+    pub distance: Option<f64>,
+    pub mmr_score: Option<f64>, // Only on searches re-ranked with `diversity`
+}
+
+// The searched tree as the search found it, taken from counters kept in memory. Lets a
+// client notice an empty tree, or one of another model's dimension, without a /status call.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CollectionInfo {
+    pub dimensions: usize, // As inserted, before any projection
+    pub num_records: usize,
+    pub metric: String,
+    pub last_write_at: u64, // Unix seconds of the last insert, delete or truncation, 0 if never
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>, // Embedding model the tree was created for
+}
+
+// A search answer. `tree_size` is only set when nothing was found, `collection` unless
+// `fields` left it out.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SearchResponse {
+    pub results: Vec<SearchHit>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tree_size: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub collection: Option<CollectionInfo>,
+}
+
+// Answer of a plain search that found nothing. `tree_size` is `0` for an empty tree;
+// otherwise the filter or partition excluded every point.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EmptySearchResponse {
+    pub results: Vec<SearchHit>,
+    pub tree_size: usize,
+}
+
+// Answer of /exists_within; `distance` and `data` describe the first point found
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExistsWithinResponse {
+    pub found: bool,
+    pub distance: Option<f64>,
+    pub data: Option<String>,
+}
+
+// Points drawn by /sample; each hit also carries `seq` on the wire
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SampleResponse {
+    pub tree_name: String,
+    pub seed: u64,      // Pass back as `seed` to draw the same sample again
+    pub matched: usize, // Points the sample was drawn from
+    pub points: Vec<SearchHit>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OperationStatus {
+    pub operation: OperationKind,
+    pub started_at: u64, // Unix seconds
+    pub elapsed_secs: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TreeStatus {
+    pub tree_name: String,
+    pub tier: Tier,
+    pub num_records: usize,
+    pub dimensions: Option<usize>,
+    #[serde(default)]
+    pub model: Option<String>, // Embedding model the tree was created for, null if it was not named
+    pub in_memory: bool,
+    pub estimated_bytes: usize, // Tree plus duplicate filter, 0 when offloaded
+    pub dirty: bool,            // Changes not yet written to disk
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_accessed: Option<u64>, // Deprecated: only sent by the unversioned routes, use `seconds_since_access`
+    pub last_accessed_at: Option<String>, // RFC 3339 time of the last data-path access, null if never
+    pub seconds_since_access: Option<u64>,
+    pub created_at: Option<String>, // RFC 3339, null for trees created before it was recorded
+    pub updated_at: Option<String>, // RFC 3339 time of the last applied write, null if never
+    pub warmed_at: Option<String>, // RFC 3339 time WARM_FILES read the tree file at startup, null if it didn't
+    pub access_count: u64,
+    pub operation: Option<OperationStatus>,
+    pub suspect: bool,
+    pub state: TreeState,
+    pub load_failure: Option<LoadFailureStatus>, // Set while `state` is `load_failed`
+    pub save_failures: u64, // Failed saves of the tree file since startup, each counted once however often it was retried
+    pub save_failure: Option<SaveFailureStatus>, // Set while saves of the tree keep failing
+    pub usage: TreeUsageStatus,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TreeState {
+    Loaded,     // Resident in memory
+    Offloaded,  // Read from disk by the next request
+    LoadFailed, // The last attempt to read the file failed
+}
+
+// The latest of one or more failed loads in a row
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LoadFailureStatus {
+    pub error: String,
+    pub attempted_at: u64, // Unix seconds
+    pub consecutive_failures: u32,
+    pub retry_in_secs: u64, // Until then loads fail right away with `error`; 0 when the next one reads the file
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SaveFailureStatus {
+    pub error: String,
+    pub failed_at: u64, // Unix seconds
+    pub consecutive_failures: u32,
+    pub failing_secs: u64, // Since the first failure in a row; the tree's changes are unsaved at least this long
+    pub degraded: bool,    // Past PERSISTENCE_DEADLINE_SECS, so writes to the tree get a 503
+}
+
+// Read and write activity of a tree. Totals survive restarts, the rates start over.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TreeUsageStatus {
+    pub searches: u64,
+    pub inserts: u64,
+    pub bytes_served: u64,  // Response bytes of searches
+    pub bytes_inserted: u64, // Content of inserted points: components, data and metadata
+    pub bytes_written: u64, // Tree file bytes written to disk
+    pub saves: u64,         // Tree file writes
+    pub write_amplification: Option<f64>, // bytes_written / bytes_inserted, None before anything was inserted
+    pub last_write_at: u64, // Unix seconds of the last applied insert, delete or truncation, 0 if never
+    pub search_qps_1m: f64, // Searches per second over the last minute
+    pub search_qps_1h: f64, // Searches per second over the last hour
+}
+
+// Sums over the trees of the same report, taken in the same pass
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct StatusTotals {
+    pub trees: usize,
+    pub in_memory: usize,
+    pub num_records: usize,
+    pub estimated_bytes: usize,
+    pub unhealthy: usize, // Trees whose file failed to load or that the self-test marked suspect
+    #[serde(default)]
+    pub persistence_degraded: usize, // Trees refusing writes because their saves keep failing
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StatusResponse {
+    pub persistence: Persistence, // `disabled` means nothing survives an eviction or restart
+    pub disk: DiskStatus,
+    pub active_trees: usize,
+    pub unhealthy_trees: usize, // Same as `totals.unhealthy`, for alerting
+    pub trees: Vec<TreeStatus>,
+    pub totals: StatusTotals,
+    pub maintenance: MaintenanceStatus,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deprecations: Vec<String>, // Fields of this response that are going away, sent by the unversioned route
+}
+
+// Free space where tree files are written. Writes are refused with 507 below the reserve.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DiskStatus {
+    pub available_bytes: Option<u64>, // None when persistence is disabled or it can't be read
+    pub reserve_bytes: u64,           // MIN_FREE_DISK_MB
+    pub low: bool,                    // Below twice the reserve
+}
+
+// Background maintenance and when each task last ran and runs next, in Unix seconds
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MaintenanceStatus {
+    pub window: String, // `always` or e.g. `02:00-04:00 UTC`
+    pub in_window: bool,
+    pub tasks: Vec<MaintenanceTaskStatus>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MaintenanceTaskStatus {
+    pub task: String,
+    pub running: bool,
+    pub last_started: Option<u64>,
+    pub last_finished: Option<u64>,
+    pub next_run: Option<u64>, // None if no tick of the task's interval lands in the window
+    pub skipped: u64,          // Ticks skipped because the previous run was still going
+}
+
+// The cache entry of one tree as reported by GET /cache
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CacheEntry {
+    pub tree_name: String,
+    pub tier: Tier,
+    pub in_memory: bool,
+    pub estimated_bytes: usize, // Tree plus duplicate filter, 0 when offloaded
+    pub dirty: bool,            // Changes not yet written to disk
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_accessed: Option<u64>, // Deprecated: only sent by the unversioned route, use `seconds_since_access`
+    pub last_accessed_at: Option<String>, // RFC 3339 time of the last data-path access, null if never
+    pub seconds_since_access: Option<u64>,
+    pub access_count: u64,
+    pub operation: Option<OperationStatus>,
+    pub suspect: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deprecations: Vec<String>, // As in StatusResponse
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DropCacheResponse {
+    pub tree_name: String,
+    pub dropped: bool,           // False when the tree was not in memory
+    pub discarded_unsaved: bool, // Changes that never reached disk were thrown away
+}
+
+// One entry of /trees
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TreeSummary {
+    pub tree_name: String,
+    pub tier: Tier,
+    pub num_records: usize,
+    pub dimensions: Option<usize>,
+    #[serde(default)]
+    pub model: Option<String>,
+    pub last_accessed_at: Option<String>, // RFC 3339 time of the last data-path access, null if never
+    pub seconds_since_access: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TreesResponse {
+    pub trees: Vec<TreeSummary>,
+    pub totals: StatusTotals,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CreateTreeResponse {
+    pub tree_name: String,
+    pub dimensions: usize,
+    pub partition_field: Option<String>,
+    pub project_to: Option<usize>,
+    pub seed: Option<u64>, // Seed the projection matrix was generated from
+    #[serde(default)]
+    pub schema: Option<Schema>,
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offloaded_strategy: Option<OffloadedStrategy>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SchemaResponse {
+    pub tree_name: String,
+    pub schema: Option<Schema>, // None when the tree takes any metadata
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonconforming: Option<usize>, // Stored points the new schema doesn't match, when it was set
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RebuildResponse {
+    pub tree_name: String,
+    pub num_records: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TruncateResponse {
+    pub tree_name: String,
+    pub removed: usize,
+    pub dimensions: usize, // Input dimensions, unchanged by the truncation
+}
+
+// Progress of a chunked upload, so a client can resume where it left off
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UploadResponse {
+    pub tree_name: String,
+    pub staged: u64, // Bytes received so far
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RestoreResponse {
+    pub tree_name: String,
+    pub num_records: usize,
+    pub dimensions: usize,
+    pub sha256: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeleteByFilterResponse {
+    pub tree_name: String,
+    pub dry_run: bool,
+    pub matched: usize,     // Points the filter matched
+    pub removed: usize,     // 0 on a dry run
+    pub num_records: usize, // Points left in the tree
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StatsResponse {
+    pub tree_name: String,
+    pub num_records: usize,
+    pub dimensions: usize,        // Of the embeddings inserted and queried
+    pub stored_dimensions: usize, // Below `dimensions` when the tree projects embeddings
+    pub projection_seed: Option<u64>,
+    pub depth: usize,
+    pub partition_field: Option<String>,
+    #[serde(default)]
+    pub schema: Option<Schema>,
+    #[serde(default)]
+    pub model: Option<String>,
+    pub partitions: BTreeMap<String, usize>,
+    pub unpartitioned: usize,
+    pub distribution: DistributionStats,
+    pub validated: bool,                    // Whether the invariant was checked, see `validate=true`
+    pub violation: Option<InvariantError>, // First violation found by the check
+}
+
+// Shape of a tree down to a depth, for visualization; see /tree_structure
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TreeStructure {
+    pub tree_name: String,
+    pub num_records: usize,
+    pub max_depth: usize,
+    pub truncated: bool, // The node budget ran out first, so some subtrees above `max_depth` are summarized too
+    pub roots: Vec<StructureRoot>,
+}
+
+// The shared tree (`partition` null) or one partition subtree
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StructureRoot {
+    pub partition: Option<String>,
+    pub root: StructureNode,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum StructureNode {
+    Node {
+        node: String, // Hex hash of the node's embedding, as in explain traces
+        depth: usize,
+        axis: usize,
+        split: f64,
+        size: usize, // Points in the subtree rooted here, this one included
+        preview: Option<String>, // Start of the point's data
+        left: Option<Box<StructureNode>>,
+        right: Option<Box<StructureNode>>,
+    },
+    // A subtree below the cutoff, only counted
+    Subtree { depth: usize, size: usize },
+}
+
+// The invariant check of one tree by /verify_all
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TreeVerification {
+    pub tree_name: String,
+    pub valid: bool,
+    pub violation: Option<InvariantError>,
+    pub error: Option<String>, // The tree could not be loaded to be checked
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VerifyAllResponse {
+    pub checked: usize,
+    pub invalid: usize,
+    pub skipped: usize, // Archived trees, which are not restored to be checked
+    pub trees: Vec<TreeVerification>,
+}
+
+// A query whose tree search disagreed with the brute-force scan, with the distances
+// each returned, closest first
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditDivergence {
+    pub query: usize,        // Position among the queries run
+    pub seq: Option<u64>,    // Stored point queried with, for sampled queries
+    pub recall: f64,
+    pub tree: Vec<f64>,
+    pub brute_force: Vec<f64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditSearchResponse {
+    pub tree_name: String,
+    pub n: usize,
+    pub queries: usize,      // Queries compared before the budget ran out
+    pub requested: usize,
+    pub recall: f64,         // Mean recall@n over the queries compared
+    pub mismatched: usize,   // Queries whose result distances differ from the scan's
+    pub elapsed_ms: u64,
+    pub seed: Option<u64>,   // For sampled queries
+    pub divergences: Vec<AuditDivergence>, // The first few mismatches
+}
+
+// Outcome of one row group of a Parquet import
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RowGroupImport {
+    pub row_group: usize,
+    pub rows: usize,
+    pub imported: usize,
+    pub rejected: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RejectedRow {
+    pub row: usize, // Counted from 0 across the whole file
+    pub reason: String,
+    #[serde(default, rename = "ref", skip_serializing_if = "Option::is_none")]
+    pub reference: Option<serde_json::Value>, // The row's value in the `echo` column
+}
+
+// Outcome of one chunk of lines of a JSONL import
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChunkImport {
+    pub chunk: usize,      // Counted from 0 across the whole file, so a resumed import goes on numbering
+    pub first_line: usize, // Counted from 1
+    pub lines: usize,
+    pub imported: usize,
+    pub rejected: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RejectedLine {
+    pub line: usize, // Counted from 1
+    pub reason: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JsonlImportResponse {
+    pub tree_name: String,
+    pub imported: usize, // By this run; a resumed import's earlier runs are not counted
+    pub rejected: usize,
+    pub num_records: usize, // Size of the tree after the import
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resumed_from_line: Option<usize>, // Set when the import picked up from an earlier run's checkpoint
+    pub chunks: Vec<ChunkImport>,
+    pub rejected_lines: Vec<RejectedLine>, // The first 100 rejections
+    #[serde(default)]
+    pub summary: BatchSummary,
+}
+
+// Usage of every tree over a window of whole hours, for charging it back to its owner
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UsageResponse {
+    pub from: String, // Start of the first hour covered
+    pub to: String,   // End of the window, exclusive
+    pub retention_days: u64,
+    pub trees: Vec<TreeUsageReport>, // Trees with recorded hours in the window, by name
+    pub totals: UsageTotals,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TreeUsageReport {
+    pub tree_name: String,
+    pub hours: usize, // Recorded hours in the window
+    pub inserts: u64,
+    pub searches: u64,
+    pub bytes_served: u64,    // Response bytes of searches
+    pub points: u64,          // Size as of the window's last recorded hour
+    pub peak_points: u64,
+    pub bytes_stored: u64,    // Size of the tree file as of the window's last recorded hour
+    pub peak_bytes_stored: u64,
+    pub point_hours: u64,     // Sum of each hour's size, for charging storage by time held
+    pub byte_hours: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct UsageTotals {
+    pub trees: usize,
+    pub inserts: u64,
+    pub searches: u64,
+    pub bytes_served: u64,
+    pub points: u64,
+    pub bytes_stored: u64,
+    pub point_hours: u64,
+    pub byte_hours: u64,
+}
+
+// How trees were written over the last hour and what to change about it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AdvisorResponse {
+    pub window_secs: u64,
+    pub trees: Vec<TreeWriteTraffic>, // Trees inserted into or saved in the window, by name
+    pub recommendations: Vec<String>, // Worst write amplification first
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TreeWriteTraffic {
+    pub tree_name: String,
+    pub inserts: u64,
+    pub inserts_per_sec: f64,
+    pub saves: u64,                        // Writes of the tree file
+    pub saves_per_minute: f64,
+    pub points_per_save: Option<f64>,      // Average batch a save carried, None without saves
+    pub bytes_inserted: u64,               // Content of the inserted points
+    pub bytes_written: u64,
+    pub write_amplification: Option<f64>,  // None without inserts
+}
+
+// Every route the server registers, for gateways and other tooling
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoutesResponse {
+    pub version_prefix: String, // Versioned routes are listed with it, e.g. `/v1/status`
+    pub routes: Vec<RouteInfo>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RouteInfo {
+    pub method: String,
+    pub path: String,                 // Actix pattern, `{id}` for path parameters
+    pub mutates: bool,
+    pub admin: bool,                  // Served on ADMIN_PORT instead of PORT when it is set
+    pub required_params: Vec<String>, // Query parameters the route answers 400 without
+    pub scope: KeyScope,
+    pub version: Option<String>,      // `v1` for routes under the version prefix, None for unversioned ones
+}
+
+// Totals of a request that handles many items, so clients needn't count result entries
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct BatchSummary {
+    pub succeeded: usize,
+    pub failed: usize,
+    pub skipped: usize,
+    pub duration_ms: u64,
+}
+
+impl BatchSummary {
+    // Every batch endpoint builds its summary here, timed from when it started
+    pub fn finished(started: Instant, succeeded: usize, failed: usize, skipped: usize) -> Self {
+        BatchSummary { succeeded, failed, skipped, duration_ms: started.elapsed().as_millis() as u64 }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImportResponse {
+    pub tree_name: String,
+    pub imported: usize,
+    pub rejected: usize,
+    pub num_records: usize, // Size of the tree after the import
+    pub row_groups: Vec<RowGroupImport>,
+    pub rejected_rows: Vec<RejectedRow>, // The first 100 rejections
+    #[serde(default)]
+    pub summary: BatchSummary,
+}
+
+// A file removed by /gc or the maintenance loop
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RemovedFile {
+    pub file_name: String,
+    pub kind: GarbageKind,
+    pub bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GcResponse {
+    pub files_removed: usize,
+    pub bytes_reclaimed: u64,
+    pub skipped: usize, // Candidates left alone because their tree was busy
+    pub removed: Vec<RemovedFile>,
+}
+
+// Embedding statistics maintained on insert
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DistributionStats {
+    pub count: u64,
+    pub mean: Vec<f64>,
+    pub variance: Vec<f64>, // Population variance per dimension
+    pub norm_histogram: Vec<NormBucket>,
+}
+
+// Points whose L2 norm is at most `le` and above the previous bucket's bound
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NormBucket {
+    pub le: Option<f64>, // None for the last, unbounded bucket
+    pub count: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DriftResponse {
+    pub tree_name: String,
+    pub against: String,
+    pub score: f64,                      // Mean absolute standardized mean difference
+    pub max_abs_smd: f64,
+    pub max_dimension: Option<usize>,
+    pub shifted_dimensions: usize,
+    pub per_dimension: Vec<Option<f64>>, // Null where both trees are constant at different values
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Running,
+    Succeeded,
+    Failed,      // See `error`
+    Cancelled,   // Stopped by DELETE /jobs/{id}
+    Interrupted, // The server restarted while it ran
+}
+
+// A background job as polled through /jobs/{id}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JobStatus {
+    pub id: u64,
+    pub kind: JobKind,
+    pub tree_name: Option<String>, // None for jobs about every tree
+    pub target: Option<String>, // Tree the job writes to, when not `tree_name` itself
+    pub state: JobState,
+    pub started_at: u64, // Unix seconds
+    pub elapsed_secs: u64,
+    pub processed: u64,  // Items handled so far, failures included
+    pub total: u64,      // Items this run has to handle
+    pub failures: u64,   // Items given up on
+    pub retries: u64,    // Retried calls to outside services
+    pub resumed_after_seq: Option<u64>, // Set when the job picked up from an earlier run's checkpoint
+    #[serde(default)]
+    pub cancel_requested: bool,
+    #[serde(default)]
+    pub http_status: Option<u16>,  // Status the request would have been answered with, once finished
+    #[serde(default)]
+    pub result: Option<serde_json::Value>, // JSON body the request would have been answered with
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JobsResponse {
+    pub jobs: Vec<JobStatus>, // Newest first
+}
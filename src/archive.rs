@@ -0,0 +1,58 @@
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+
+// Where a tree's file currently lives
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Tier {
+    #[default]
+    Hot,       // Tree file in the bin directory, loadable on demand
+    Archiving, // Being compressed into the archive directory
+    Archived,  // Only the compressed copy exists; the first request restores it
+    Restoring, // Being decompressed back into the bin directory
+}
+
+impl Tier {
+    // What survives a restart: a move that never finished is redone from the start
+    pub fn persisted(self) -> Tier {
+        match self {
+            Tier::Hot | Tier::Archiving => Tier::Hot,
+            Tier::Archived | Tier::Restoring => Tier::Archived,
+        }
+    }
+}
+
+// Gzips `from` into `to` and removes `from`. The copy is synced and renamed into place
+// first, so a crash at any point leaves at least one complete file.
+pub fn compress_file(from: &Path, to: &Path) -> io::Result<u64> {
+    let tmp_path = to.with_extension("tmp");
+    let mut reader = BufReader::new(File::open(from)?);
+    let mut encoder = GzEncoder::new(BufWriter::new(File::create(&tmp_path)?), Compression::default());
+    io::copy(&mut reader, &mut encoder)?;
+    let mut writer = encoder.finish()?;
+    writer.flush()?;
+    let file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    let compressed = file.metadata()?.len();
+    fs::rename(&tmp_path, to)?;
+    fs::remove_file(from)?;
+    Ok(compressed)
+}
+
+// Reverse of `compress_file`: restores `to` from the gzipped `from`, then removes `from`
+pub fn decompress_file(from: &Path, to: &Path) -> io::Result<u64> {
+    let tmp_path = to.with_extension("tmp");
+    let mut decoder = GzDecoder::new(BufReader::new(File::open(from)?));
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    let restored = io::copy(&mut decoder, &mut writer)?;
+    let file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, to)?;
+    fs::remove_file(from)?;
+    Ok(restored)
+}
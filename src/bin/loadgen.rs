@@ -0,0 +1,175 @@
+// Drives a running server over HTTP with a configurable read/write mix and
+// prints latency percentiles per operation, and how long the requests spent
+// waiting for the trees lock.
+use clap::Parser;
+use serde_json::json;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Parser, Debug)]
+#[command(about = "Load generator for a running vodb server")]
+struct Args {
+    /// Base URL of the server
+    #[arg(long, default_value = "http://127.0.0.1:8080")]
+    url: String,
+    /// Tree to read from and write to
+    #[arg(long, default_value = "loadgen")]
+    tree_name: String,
+    /// Number of concurrent workers
+    #[arg(long, default_value_t = 8)]
+    concurrency: usize,
+    /// Dimension of generated points
+    #[arg(long, default_value_t = 128)]
+    dimensions: usize,
+    /// Total number of measured requests
+    #[arg(long, default_value_t = 10_000)]
+    requests: usize,
+    /// Fraction of requests that are inserts, the rest are top-n searches
+    #[arg(long, default_value_t = 0.1)]
+    write_ratio: f64,
+    /// Neighbors requested per search
+    #[arg(long, default_value_t = 10)]
+    n: usize,
+    /// Points inserted before measuring so searches hit a populated tree
+    #[arg(long, default_value_t = 1_000)]
+    seed_points: usize,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Op {
+    Insert,
+    Search,
+}
+
+struct Sample {
+    op: Op,
+    latency: Duration,
+    ok: bool,
+}
+
+struct XorShift(u64);
+
+impl XorShift {
+    fn next_f64(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn embedding(&mut self, dim: usize) -> Vec<f64> {
+        (0..dim).map(|_| self.next_f64()).collect()
+    }
+}
+
+fn run_op(agent: &ureq::Agent, args: &Args, op: Op, rng: &mut XorShift) -> bool {
+    let body = json!({ "embedding": rng.embedding(args.dimensions), "data": "loadgen" }).to_string();
+    let url = match op {
+        Op::Insert => format!("{}/insert?tree_name={}", args.url, args.tree_name),
+        Op::Search => format!("{}/nearesttop?tree_name={}&n={}", args.url, args.tree_name, args.n),
+    };
+    agent
+        .post(&url)
+        .set("Content-Type", "application/json")
+        .send_string(&body)
+        .is_ok()
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let index = ((sorted.len() as f64 * p).ceil() as usize).clamp(1, sorted.len()) - 1;
+    sorted[index]
+}
+
+fn report(name: &str, samples: &[Sample], op: Op) {
+    let mut latencies: Vec<Duration> = samples.iter().filter(|s| s.op == op).map(|s| s.latency).collect();
+    let errors = samples.iter().filter(|s| s.op == op && !s.ok).count();
+    latencies.sort();
+    println!(
+        "{:<8} count={:<7} errors={:<5} p50={:>9.2?} p90={:>9.2?} p99={:>9.2?} max={:>9.2?}",
+        name,
+        latencies.len(),
+        errors,
+        percentile(&latencies, 0.50),
+        percentile(&latencies, 0.90),
+        percentile(&latencies, 0.99),
+        latencies.last().copied().unwrap_or_default(),
+    );
+}
+
+// Time requests spent waiting for the server's trees lock during the run, per endpoint,
+// from the wait histograms on /metrics
+fn report_lock_waits(agent: &ureq::Agent, args: &Args) {
+    let response = agent.get(&format!("{}/metrics", args.url)).call().ok();
+    let Some(metrics) = response.and_then(|response| response.into_string().ok()) else {
+        println!("lock waits unavailable: /metrics could not be read");
+        return;
+    };
+    let tree_label = format!("tree=\"{}\"", args.tree_name);
+    let value = |line: &str| line.rsplit(' ').next().and_then(|value| value.parse::<f64>().ok()).unwrap_or(0.0);
+    for line in metrics.lines().filter(|line| line.starts_with("vodb_lock_wait_seconds_sum{") && line.contains(&tree_label)) {
+        let labels = &line[line.find('{').unwrap()..line.find('}').unwrap() + 1];
+        let count_line = format!("vodb_lock_wait_seconds_count{}", labels);
+        let count = metrics.lines().find(|line| line.starts_with(&count_line)).map_or(0.0, value);
+        let endpoint = labels.split("endpoint=\"").nth(1).and_then(|rest| rest.split('"').next()).unwrap_or_default();
+        println!(
+            "lock wait {:<12} acquisitions={:<7} total={:>9.2?} mean={:>9.2?}",
+            endpoint,
+            count,
+            Duration::from_secs_f64(value(line)),
+            Duration::from_secs_f64(if count > 0.0 { value(line) / count } else { 0.0 }),
+        );
+    }
+}
+
+fn main() {
+    let args = Arc::new(Args::parse());
+    let agent = ureq::AgentBuilder::new().timeout(Duration::from_secs(30)).build();
+
+    println!("Seeding {} points of dimension {} into {}", args.seed_points, args.dimensions, args.tree_name);
+    let mut rng = XorShift(0x9e3779b97f4a7c15);
+    for _ in 0..args.seed_points {
+        if !run_op(&agent, &args, Op::Insert, &mut rng) {
+            eprintln!("Seeding insert failed, is the server running at {}?", args.url);
+            std::process::exit(1);
+        }
+    }
+
+    let concurrency = args.concurrency.max(1);
+    let started = Instant::now();
+    let workers: Vec<_> = (0..concurrency)
+        .map(|worker| {
+            let args = Arc::clone(&args);
+            let agent = agent.clone();
+            thread::spawn(move || {
+                let mut rng = XorShift(0x2545f4914f6cdd1d ^ (worker as u64 + 1));
+                let count = args.requests / concurrency + usize::from(worker < args.requests % concurrency);
+                (0..count)
+                    .map(|_| {
+                        let op = if rng.next_f64() < args.write_ratio { Op::Insert } else { Op::Search };
+                        let start = Instant::now();
+                        let ok = run_op(&agent, &args, op, &mut rng);
+                        Sample { op, latency: start.elapsed(), ok }
+                    })
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect();
+
+    let samples: Vec<Sample> = workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect();
+    let elapsed = started.elapsed();
+
+    println!(
+        "{} requests with concurrency {} in {:.2?} ({:.1} req/s)",
+        samples.len(),
+        concurrency,
+        elapsed,
+        samples.len() as f64 / elapsed.as_secs_f64()
+    );
+    report("insert", &samples, Op::Insert);
+    report("search", &samples, Op::Search);
+    report_lock_waits(&agent, &args);
+}
@@ -0,0 +1,97 @@
+use serde::{Serialize, Deserialize};
+use std::fs::File;
+use std::io::{self};
+
+use crate::kdtree::KDTree;
+
+// Sizing for per-tree duplicate filters, taken from the environment
+#[derive(Debug, Clone, Copy)]
+pub struct BloomSettings {
+    pub capacity: usize, // Expected number of points per tree
+    pub fp_rate: f64,    // Target false-positive rate at that capacity
+}
+
+// Bloom filter over the raw bytes of embeddings, used to skip the exact
+// duplicate search for inserts that are certainly new
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_hashes: u32,
+    pub items: usize, // Number of embeddings added, used to detect a stale filter
+}
+
+impl BloomFilter {
+    pub fn new(settings: BloomSettings) -> Self {
+        let capacity = settings.capacity.max(1) as f64;
+        let fp_rate = settings.fp_rate.clamp(1e-9, 0.5);
+        let ln2 = std::f64::consts::LN_2;
+
+        // Standard optimal sizing: m = -n ln(p) / ln(2)^2, k = m/n ln(2)
+        let num_bits = ((-capacity * fp_rate.ln()) / (ln2 * ln2)).ceil().max(64.0) as u64;
+        let num_hashes = ((num_bits as f64 / capacity) * ln2).round().clamp(1.0, 16.0) as u32;
+
+        BloomFilter {
+            bits: vec![0; num_bits.div_ceil(64) as usize],
+            num_bits,
+            num_hashes,
+            items: 0,
+        }
+    }
+
+    // Builds a filter containing every point currently stored in `tree`
+    pub fn from_tree(tree: &KDTree, settings: BloomSettings) -> Self {
+        let mut filter = BloomFilter::new(settings);
+        tree.for_each_point(|point| filter.insert(&point.embedding));
+        filter
+    }
+
+    pub fn insert(&mut self, embedding: &[f64]) {
+        let (h1, h2) = hash_pair(embedding);
+        for i in 0..self.num_hashes as u64 {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % self.num_bits;
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+        self.items += 1;
+    }
+
+    pub fn contains(&self, embedding: &[f64]) -> bool {
+        let (h1, h2) = hash_pair(embedding);
+        (0..self.num_hashes as u64).all(|i| {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % self.num_bits;
+            self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0
+        })
+    }
+
+    pub fn size_in_bytes(&self) -> usize {
+        self.bits.len() * std::mem::size_of::<u64>()
+    }
+
+    pub fn save_to_file(&self, filename: &str) -> Result<(), io::Error> {
+        let file = File::create(filename)?;
+        bincode::serialize_into(file, self).map_err(io::Error::other)?;
+        Ok(())
+    }
+
+    pub fn load_from_file(filename: &str) -> Result<Self, io::Error> {
+        let file = File::open(filename)?;
+        let filter: BloomFilter = bincode::deserialize_from(file).map_err(io::Error::other)?;
+        Ok(filter)
+    }
+}
+
+// Two independent 64-bit FNV-1a hashes of the embedding bytes for double hashing.
+// FNV is used instead of std's hasher because the filter is persisted and must
+// hash identically across builds.
+pub(crate) fn hash_pair(embedding: &[f64]) -> (u64, u64) {
+    const PRIME: u64 = 0x100000001b3;
+    let mut h1: u64 = 0xcbf29ce484222325;
+    let mut h2: u64 = 0x84222325cbf29ce4;
+    for value in embedding {
+        for byte in value.to_le_bytes() {
+            h1 = (h1 ^ byte as u64).wrapping_mul(PRIME);
+            h2 = (h2 ^ byte.rotate_left(3) as u64).wrapping_mul(PRIME);
+        }
+    }
+    (h1, h2 | 1)
+}
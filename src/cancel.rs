@@ -0,0 +1,61 @@
+// Stopping blocking work a request started once its client has gone. A handler keeps a
+// guard while the work runs; actix drops the handler's future when the connection
+// closes, which drops the guard and cancels the token, and the work checks the token
+// every so often and gives up.
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+// Points visited between checks of the token in traversals, so the check costs nothing
+// next to the visits
+pub const CHECK_INTERVAL: usize = 4096;
+
+// Returned by work that stopped because its token was cancelled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn check(&self) -> Result<(), Cancelled> {
+        match self.is_cancelled() {
+            true => Err(Cancelled),
+            false => Ok(()),
+        }
+    }
+
+    // A guard that cancels the token when dropped, unless it was disarmed first
+    pub fn guard(&self) -> CancelOnDrop {
+        CancelOnDrop(Some(self.clone()))
+    }
+}
+
+#[derive(Debug)]
+pub struct CancelOnDrop(Option<CancellationToken>);
+
+impl CancelOnDrop {
+    // The work finished, so dropping the guard no longer means anything
+    pub fn disarm(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if let Some(token) = self.0.take() {
+            token.cancel();
+        }
+    }
+}
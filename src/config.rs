@@ -0,0 +1,392 @@
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::durability::Durability;
+use crate::maintenance::MaintenanceWindow;
+use crate::warm::WarmFiles;
+use crate::projection::MAX_FLOAT_PRECISION;
+use crate::rebalance::Rebalance;
+use crate::store::Persistence;
+
+const REDACTED: &str = "<redacted>";
+const VARIABLE_PREFIX: &str = "VECTOR_STORE_";
+
+// Server configuration. Every key can be set in the file named by `CONFIG_PATH` (TOML)
+// and overridden by the environment variable of the same name in upper case; keys set
+// in neither keep the defaults below.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub host: String,
+    pub port: u16,
+    pub admin_port: Option<u16>,             // Serves the operational endpoints instead of PORT when set
+    pub admin_host: Option<String>,          // Interface ADMIN_PORT listens on, defaults to HOST
+    pub max_memory_mb: usize,
+    pub memory_soft_limit_percent: usize,    // Background eviction keeps memory below this share of the budget; 0 disables
+    pub eviction_idle_secs: u64,             // Trees unused this long are evicted clean and largest first
+    pub persistence: Persistence,            // `disabled` keeps trees in memory only and loses them on eviction or exit
+    pub bin_directory: PathBuf,
+    pub auto_migrate: bool,
+    pub preload: bool,
+    pub preload_concurrency: Option<usize>, // Number of CPUs when unset, also bounds startup rebalancing
+    pub warm_files: Option<WarmFiles>,       // Tree files read into the OS page cache at startup; none when unset
+    pub warm_files_mb_per_sec: u64,          // Read rate cap of that warming; 0 disables the cap
+    pub rebalance_on_startup: Rebalance,     // Trees rebuilt with median splits before serving
+    pub manifest_flush_secs: u64,
+    pub dedup_bloom_capacity: Option<usize>, // Duplicate filtering is off when unset
+    pub dedup_bloom_fp_rate: f64,
+    pub query_cache_entries: usize,          // 0 disables the query cache
+    pub query_cache_ttl_secs: u64,
+    pub insert_durability: Durability,
+    pub dirty_flush_secs: u64,
+    pub save_retry_attempts: u32,            // Tries of a failing background save, the first included
+    pub save_retry_base_ms: u64,             // Wait before its first retry, doubled for every further one
+    pub persistence_deadline_secs: u64,      // Writes to a tree whose saves fail this long get a 503; 0 disables
+    pub max_depth_factor: f64,               // Below 1 disables the depth bound
+    pub write_queue_capacity: usize,
+    pub max_heavy_concurrency: usize,        // Export and rebuild requests running at once
+    pub max_heavy_queue: usize,              // Heavy requests waiting before the rest get a 429
+    pub rate_limit_budget: u64,              // Cost each client may spend per minute on searches and inserts; 0 disables
+    pub lock_wait_warn_ms: u64,              // Waits for the trees lock this long are logged; 0 disables
+    pub metrics_tree_labels: usize,          // Most searched trees labelled by name in histograms, the rest as `other`
+    pub self_test_interval_minutes: u64,     // 0 disables the self-test
+    pub self_test_samples: usize,
+    pub self_test_budget_ms: u64,
+    pub self_test_webhook_url: Option<String>, // Secret: may carry a token
+    pub self_test_reload: bool,
+    pub archive_directory: PathBuf,
+    pub archive_after_days: u64,             // 0 disables archival
+    pub archive_restore_wait_ms: u64,
+    pub usage_retention_days: u64,           // Hourly usage history kept per tree for /usage; 0 disables it
+    pub load_wait_timeout_secs: u64,         // How long a search waits for another search's load of the same tree
+    pub max_dimensions: usize,               // Largest embedding a new tree accepts
+    pub strict_create: bool,                 // Trees must be made with /create_tree, not by their first insert
+    pub max_data_bytes: usize,               // Largest `data` payload of a single point
+    pub max_payload_mb: usize,               // Largest request body once decompressed
+    pub min_free_disk_mb: u64,               // Free space writes must leave on the bin directory's filesystem
+    pub max_response_mb: u64,                // Searches whose answer is estimated above this are refused; 0 disables
+    pub float_precision: Option<u32>,        // Significant digits of returned embedding components; full when unset
+    pub gc_interval_minutes: u64,            // 0 disables background garbage collection
+    pub gc_temp_max_age_secs: u64,           // Temp files younger than this are left alone
+    pub gc_backup_retention: usize,          // Migration backups kept per tree
+    pub maintenance_window: MaintenanceWindow, // When archival and garbage collection run in the background
+    pub import_directory: Option<PathBuf>,   // Imports may only read files below it; off when unset
+    pub audit_webhook_url: Option<String>,   // Secret: may carry a token
+    pub embedding_url: Option<String>,       // OpenAI-style embeddings endpoint used by /reembed; off when unset. Secret: may carry a token
+    pub embedding_model: Option<String>,     // Sent as `model` when set
+    pub embedding_api_key: Option<String>,   // Secret: sent as a bearer token when set
+    pub embedding_batch_size: usize,         // Inputs per provider request
+    pub embedding_concurrency: usize,        // Provider requests in flight per job
+    pub embedding_max_retries: u32,          // Retries of a request failing with a transport error, 429 or 5xx
+    pub embedding_timeout_secs: u64,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            host: "127.0.0.1".to_string(),
+            port: 8080,
+            admin_port: None,
+            admin_host: None,
+            max_memory_mb: 1024,
+            memory_soft_limit_percent: 80,
+            eviction_idle_secs: 60,
+            persistence: Persistence::Enabled,
+            bin_directory: PathBuf::from("bin"),
+            auto_migrate: false,
+            preload: false,
+            preload_concurrency: None,
+            warm_files: None,
+            warm_files_mb_per_sec: 50,
+            rebalance_on_startup: Rebalance::Off,
+            manifest_flush_secs: 30,
+            dedup_bloom_capacity: None,
+            dedup_bloom_fp_rate: 0.01,
+            query_cache_entries: 0,
+            query_cache_ttl_secs: 60,
+            insert_durability: Durability::Buffered,
+            dirty_flush_secs: 1,
+            save_retry_attempts: 3,
+            save_retry_base_ms: 100,
+            persistence_deadline_secs: 300,
+            max_depth_factor: 2.0,
+            write_queue_capacity: 1024,
+            max_heavy_concurrency: 2,
+            max_heavy_queue: 16,
+            rate_limit_budget: 0,
+            lock_wait_warn_ms: 100,
+            metrics_tree_labels: 20,
+            self_test_interval_minutes: 0,
+            self_test_samples: 3,
+            self_test_budget_ms: 50,
+            self_test_webhook_url: None,
+            self_test_reload: false,
+            archive_directory: PathBuf::from("archive"),
+            archive_after_days: 0,
+            archive_restore_wait_ms: 0,
+            usage_retention_days: 30,
+            load_wait_timeout_secs: 30,
+            max_dimensions: 4096,
+            strict_create: false,
+            max_data_bytes: 1024 * 1024,
+            max_payload_mb: 2,
+            min_free_disk_mb: 0,
+            max_response_mb: 0,
+            float_precision: None,
+            gc_interval_minutes: 0,
+            gc_temp_max_age_secs: 60 * 60,
+            gc_backup_retention: 1,
+            maintenance_window: MaintenanceWindow::Always,
+            import_directory: None,
+            audit_webhook_url: None,
+            embedding_url: None,
+            embedding_model: None,
+            embedding_api_key: None,
+            embedding_batch_size: 64,
+            embedding_concurrency: 4,
+            embedding_max_retries: 3,
+            embedding_timeout_secs: 60,
+        }
+    }
+}
+
+impl Settings {
+    // Reads the config file if `CONFIG_PATH` is set, applies environment overrides and
+    // validates the result. Every problem is reported at once, each naming the offending
+    // key and value; nothing falls back to a default. On success also returns warnings
+    // about `VECTOR_STORE_*` variables that are not settings, which are likely typos.
+    pub fn load() -> Result<(Settings, Vec<String>), Vec<String>> {
+        let mut settings = match env::var("CONFIG_PATH") {
+            Ok(path) => {
+                let text = fs::read_to_string(&path)
+                    .map_err(|e| vec![format!("CONFIG_PATH: cannot read {}: {}", path, e)])?;
+                toml::from_str(&text).map_err(|e| vec![describe_toml_error(&path, &text, &e)])?
+            }
+            Err(_) => Settings::default(),
+        };
+        let mut reader = EnvReader::default();
+        settings.apply_env(&mut reader);
+        let mut problems = reader.problems;
+        problems.extend(settings.validate());
+        if !problems.is_empty() {
+            return Err(problems);
+        }
+        Ok((settings, unknown_variables(&reader.known)))
+    }
+
+    fn apply_env(&mut self, reader: &mut EnvReader) {
+        reader.value(&mut self.host, "HOST");
+        reader.value(&mut self.port, "PORT");
+        reader.option(&mut self.admin_port, "ADMIN_PORT");
+        reader.option(&mut self.admin_host, "ADMIN_HOST");
+        reader.value(&mut self.max_memory_mb, "MAX_MEMORY_MB");
+        reader.value(&mut self.memory_soft_limit_percent, "MEMORY_SOFT_LIMIT_PERCENT");
+        reader.value(&mut self.eviction_idle_secs, "EVICTION_IDLE_SECS");
+        reader.value(&mut self.persistence, "PERSISTENCE");
+        reader.value(&mut self.bin_directory, "BIN_DIRECTORY");
+        reader.value(&mut self.auto_migrate, "AUTO_MIGRATE");
+        reader.value(&mut self.preload, "PRELOAD");
+        reader.option(&mut self.preload_concurrency, "PRELOAD_CONCURRENCY");
+        reader.option(&mut self.warm_files, "WARM_FILES");
+        reader.value(&mut self.warm_files_mb_per_sec, "WARM_FILES_MB_PER_SEC");
+        reader.value(&mut self.rebalance_on_startup, "REBALANCE_ON_STARTUP");
+        reader.value(&mut self.manifest_flush_secs, "MANIFEST_FLUSH_SECS");
+        reader.option(&mut self.dedup_bloom_capacity, "DEDUP_BLOOM_CAPACITY");
+        reader.value(&mut self.dedup_bloom_fp_rate, "DEDUP_BLOOM_FP_RATE");
+        reader.value(&mut self.query_cache_entries, "QUERY_CACHE_ENTRIES");
+        reader.value(&mut self.query_cache_ttl_secs, "QUERY_CACHE_TTL_SECS");
+        reader.value(&mut self.insert_durability, "INSERT_DURABILITY");
+        reader.value(&mut self.dirty_flush_secs, "DIRTY_FLUSH_SECS");
+        reader.value(&mut self.save_retry_attempts, "SAVE_RETRY_ATTEMPTS");
+        reader.value(&mut self.save_retry_base_ms, "SAVE_RETRY_BASE_MS");
+        reader.value(&mut self.persistence_deadline_secs, "PERSISTENCE_DEADLINE_SECS");
+        reader.value(&mut self.max_depth_factor, "MAX_DEPTH_FACTOR");
+        reader.value(&mut self.write_queue_capacity, "WRITE_QUEUE_CAPACITY");
+        reader.value(&mut self.max_heavy_concurrency, "MAX_HEAVY_CONCURRENCY");
+        reader.value(&mut self.max_heavy_queue, "MAX_HEAVY_QUEUE");
+        reader.value(&mut self.rate_limit_budget, "RATE_LIMIT_BUDGET");
+        reader.value(&mut self.lock_wait_warn_ms, "LOCK_WAIT_WARN_MS");
+        reader.value(&mut self.metrics_tree_labels, "METRICS_TREE_LABELS");
+        reader.value(&mut self.self_test_interval_minutes, "SELF_TEST_INTERVAL_MINUTES");
+        reader.value(&mut self.self_test_samples, "SELF_TEST_SAMPLES");
+        reader.value(&mut self.self_test_budget_ms, "SELF_TEST_BUDGET_MS");
+        reader.option(&mut self.self_test_webhook_url, "SELF_TEST_WEBHOOK_URL");
+        reader.value(&mut self.self_test_reload, "SELF_TEST_RELOAD");
+        reader.value(&mut self.archive_directory, "ARCHIVE_DIRECTORY");
+        reader.value(&mut self.archive_after_days, "ARCHIVE_AFTER_DAYS");
+        reader.value(&mut self.archive_restore_wait_ms, "ARCHIVE_RESTORE_WAIT_MS");
+        reader.value(&mut self.usage_retention_days, "USAGE_RETENTION_DAYS");
+        reader.value(&mut self.load_wait_timeout_secs, "LOAD_WAIT_TIMEOUT_SECS");
+        reader.value(&mut self.max_dimensions, "MAX_DIMENSIONS");
+        reader.value(&mut self.strict_create, "STRICT_CREATE");
+        reader.value(&mut self.max_data_bytes, "MAX_DATA_BYTES");
+        reader.value(&mut self.max_payload_mb, "MAX_PAYLOAD_MB");
+        reader.value(&mut self.min_free_disk_mb, "MIN_FREE_DISK_MB");
+        reader.value(&mut self.max_response_mb, "MAX_RESPONSE_MB");
+        reader.option(&mut self.float_precision, "FLOAT_PRECISION");
+        reader.value(&mut self.gc_interval_minutes, "GC_INTERVAL_MINUTES");
+        reader.value(&mut self.gc_temp_max_age_secs, "GC_TEMP_MAX_AGE_SECS");
+        reader.value(&mut self.gc_backup_retention, "GC_BACKUP_RETENTION");
+        reader.value(&mut self.maintenance_window, "MAINTENANCE_WINDOW");
+        reader.option(&mut self.import_directory, "IMPORT_DIRECTORY");
+        reader.option(&mut self.audit_webhook_url, "AUDIT_WEBHOOK_URL");
+        reader.option(&mut self.embedding_url, "EMBEDDING_URL");
+        reader.option(&mut self.embedding_model, "EMBEDDING_MODEL");
+        reader.option(&mut self.embedding_api_key, "EMBEDDING_API_KEY");
+        reader.value(&mut self.embedding_batch_size, "EMBEDDING_BATCH_SIZE");
+        reader.value(&mut self.embedding_concurrency, "EMBEDDING_CONCURRENCY");
+        reader.value(&mut self.embedding_max_retries, "EMBEDDING_MAX_RETRIES");
+        reader.value(&mut self.embedding_timeout_secs, "EMBEDDING_TIMEOUT_SECS");
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.admin_port == Some(self.port) && self.admin_host.as_ref().is_none_or(|host| *host == self.host) {
+            problems.push(format!("admin_port: must differ from port, got {}", self.port));
+        }
+        if self.admin_host.is_some() && self.admin_port.is_none() {
+            problems.push("admin_host: only applies with admin_port set".to_string());
+        }
+        if self.max_memory_mb == 0 {
+            problems.push("max_memory_mb: must be at least 1, got 0".to_string());
+        }
+        if self.memory_soft_limit_percent > 100 {
+            problems.push(format!("memory_soft_limit_percent: must be at most 100, got {}", self.memory_soft_limit_percent));
+        }
+        if !(self.dedup_bloom_fp_rate > 0.0 && self.dedup_bloom_fp_rate < 1.0) {
+            problems.push(format!("dedup_bloom_fp_rate: must be between 0 and 1, got {}", self.dedup_bloom_fp_rate));
+        }
+        if self.dedup_bloom_capacity == Some(0) {
+            problems.push("dedup_bloom_capacity: must be at least 1, got 0".to_string());
+        }
+        if self.write_queue_capacity == 0 {
+            problems.push("write_queue_capacity: must be at least 1, got 0".to_string());
+        }
+        if self.save_retry_attempts == 0 {
+            problems.push("save_retry_attempts: must be at least 1, got 0".to_string());
+        }
+        if self.max_heavy_concurrency == 0 {
+            problems.push("max_heavy_concurrency: must be at least 1, got 0".to_string());
+        }
+        if self.preload_concurrency == Some(0) {
+            problems.push("preload_concurrency: must be at least 1, got 0".to_string());
+        }
+        if self.load_wait_timeout_secs == 0 {
+            problems.push("load_wait_timeout_secs: must be at least 1, got 0".to_string());
+        }
+        if self.max_dimensions == 0 {
+            problems.push("max_dimensions: must be at least 1, got 0".to_string());
+        }
+        if self.max_payload_mb == 0 {
+            problems.push("max_payload_mb: must be at least 1, got 0".to_string());
+        }
+        if let Some(precision) = self.float_precision.filter(|precision| !(1..=MAX_FLOAT_PRECISION).contains(precision)) {
+            problems.push(format!("float_precision: must be between 1 and {}, got {}", MAX_FLOAT_PRECISION, precision));
+        }
+        if self.embedding_batch_size == 0 {
+            problems.push("embedding_batch_size: must be at least 1, got 0".to_string());
+        }
+        if self.embedding_concurrency == 0 {
+            problems.push("embedding_concurrency: must be at least 1, got 0".to_string());
+        }
+        if self.embedding_timeout_secs == 0 {
+            problems.push("embedding_timeout_secs: must be at least 1, got 0".to_string());
+        }
+        if !self.max_depth_factor.is_finite() {
+            problems.push(format!("max_depth_factor: must be a finite number, got {}", self.max_depth_factor));
+        }
+        if self.persistence == Persistence::Disabled {
+            if self.archive_after_days > 0 {
+                problems.push(format!("archive_after_days: archival needs tree files, but persistence is disabled, got {}", self.archive_after_days));
+            }
+            if self.gc_interval_minutes > 0 {
+                problems.push(format!(
+                    "gc_interval_minutes: garbage collection needs tree files, but persistence is disabled, got {}",
+                    self.gc_interval_minutes
+                ));
+            }
+        }
+        problems
+    }
+
+    // The effective configuration as served by /config, with secrets blanked out
+    pub fn redacted(&self) -> Settings {
+        Settings {
+            self_test_webhook_url: self.self_test_webhook_url.as_ref().map(|_| REDACTED.to_string()),
+            audit_webhook_url: self.audit_webhook_url.as_ref().map(|_| REDACTED.to_string()),
+            embedding_url: self.embedding_url.as_ref().map(|_| REDACTED.to_string()),
+            embedding_api_key: self.embedding_api_key.as_ref().map(|_| REDACTED.to_string()),
+            ..self.clone()
+        }
+    }
+}
+
+// Points at the offending line, since type errors alone don't name the key
+fn describe_toml_error(path: &str, text: &str, error: &toml::de::Error) -> String {
+    match error.span() {
+        Some(span) => {
+            let line_start = text[..span.start].rfind('\n').map_or(0, |index| index + 1);
+            let line = text[line_start..].lines().next().unwrap_or_default();
+            let line_number = text[..span.start].matches('\n').count() + 1;
+            format!("{}:{}: {} (in `{}`)", path, line_number, error.message(), line.trim())
+        }
+        None => format!("{}: {}", path, error.message()),
+    }
+}
+
+// Applies environment overrides, collecting every invalid value instead of stopping at
+// the first, and remembers which variables are settings
+#[derive(Default)]
+struct EnvReader {
+    problems: Vec<String>,
+    known: Vec<&'static str>,
+}
+
+impl EnvReader {
+    fn value<T: FromStr>(&mut self, field: &mut T, name: &'static str)
+    where
+        T::Err: Display,
+    {
+        self.known.push(name);
+        if let Ok(value) = env::var(name) {
+            match value.parse() {
+                Ok(parsed) => *field = parsed,
+                Err(e) => self.problems.push(format!("{}: invalid value {:?}: {}", name, value, e)),
+            }
+        }
+    }
+
+    fn option<T: FromStr>(&mut self, field: &mut Option<T>, name: &'static str)
+    where
+        T::Err: Display,
+    {
+        self.known.push(name);
+        if let Ok(value) = env::var(name) {
+            match value.parse() {
+                Ok(parsed) => *field = Some(parsed),
+                Err(e) => self.problems.push(format!("{}: invalid value {:?}: {}", name, value, e)),
+            }
+        }
+    }
+}
+
+// Settings are read without a prefix, so a `VECTOR_STORE_*` variable is most likely a
+// setting under the wrong name
+fn unknown_variables(known: &[&str]) -> Vec<String> {
+    let mut warnings: Vec<String> = env::vars_os()
+        .filter_map(|(name, _)| name.into_string().ok())
+        .filter_map(|name| {
+            let setting = name.strip_prefix(VARIABLE_PREFIX)?.to_string();
+            Some(match known.contains(&setting.as_str()) {
+                true => format!("{} is not a setting and is ignored; did you mean {}?", name, setting),
+                false => format!("{} is not a setting and is ignored", name),
+            })
+        })
+        .collect();
+    warnings.sort();
+    warnings
+}
@@ -0,0 +1,93 @@
+// Free space on the filesystem tree files are written to. Writes check it up front and
+// are refused while they would leave less than the MIN_FREE_DISK_MB reserve, instead of
+// failing half way through a file; reads never check, so a full disk only stops writes.
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::api::DiskStatus;
+
+// A write that does not fit: `required` is what it needs plus the reserve
+#[derive(Debug, Clone, Copy)]
+pub struct InsufficientSpace {
+    pub available: u64,
+    pub required: u64,
+}
+
+pub struct DiskSpace {
+    directory: Option<PathBuf>, // None when persistence is disabled: nothing is written
+    reserve: u64,
+    low: AtomicBool, // Below twice the reserve as of the last check, so the warning is logged once
+}
+
+impl DiskSpace {
+    pub fn new(directory: Option<&Path>, reserve: u64) -> Self {
+        DiskSpace { directory: directory.map(Path::to_path_buf), reserve, low: AtomicBool::new(false) }
+    }
+
+    // Bytes free for this process, None without a directory or where the platform can't
+    // tell. Logs a warning when free space first drops below twice the reserve.
+    pub fn available(&self) -> Option<u64> {
+        let directory = self.directory.as_deref()?;
+        let available = match available_bytes(directory) {
+            Ok(available) => available,
+            Err(e) => {
+                println!("Failed to read free disk space of {:?}: {}", directory, e);
+                return None;
+            }
+        };
+        let low = available < self.reserve.saturating_mul(2);
+        if low != self.low.swap(low, Ordering::Relaxed) {
+            match low {
+                true => println!(
+                    "WARNING: {} MB free on the filesystem of {:?}, below twice the MIN_FREE_DISK_MB reserve of {} MB; \
+                     writes are refused below the reserve",
+                    available / (1024 * 1024), directory, self.reserve / (1024 * 1024)
+                ),
+                false => println!("Free disk space of {:?} is back to {} MB", directory, available / (1024 * 1024)),
+            }
+        }
+        Some(available)
+    }
+
+    // Whether a write of `needed` bytes leaves the reserve free. Passes when free space
+    // can't be read, since refusing every write on an unknown platform helps nobody.
+    pub fn check(&self, needed: u64) -> Result<(), InsufficientSpace> {
+        let Some(available) = self.available() else { return Ok(()) };
+        let required = needed.saturating_add(self.reserve);
+        if available < required {
+            return Err(InsufficientSpace { available, required });
+        }
+        Ok(())
+    }
+
+    pub fn status(&self) -> DiskStatus {
+        let available_bytes = self.available();
+        DiskStatus {
+            available_bytes,
+            reserve_bytes: self.reserve,
+            low: available_bytes.is_some_and(|available| available < self.reserve.saturating_mul(2)),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn available_bytes(path: &Path) -> io::Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: statvfs only writes into the zeroed struct it is given
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // The field types differ between platforms
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn available_bytes(_: &Path) -> io::Result<u64> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "free disk space is only read on Unix"))
+}
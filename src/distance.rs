@@ -0,0 +1,248 @@
+// Distance functions between embeddings. Smaller is always closer, so every
+// metric can be used wherever the tree ranks points.
+
+pub trait Metric {
+    fn dist(&self, a: &[f64], b: &[f64]) -> f64;
+
+    // Same as `dist`, given both vectors' L2 norms, e.g. one stored with a point and one
+    // computed once per query. Only metrics that need the norms read them.
+    fn dist_with_norms(&self, a: &[f64], a_norm: f64, b: &[f64], b_norm: f64) -> f64 {
+        let _ = (a_norm, b_norm);
+        self.dist(a, b)
+    }
+
+    // The smallest distance any point on the far side of a splitting plane can have,
+    // given the target's distance to that plane along the split axis. `None` means the
+    // metric admits no such bound and the far side must always be searched.
+    fn lower_bound_on_axis(&self, plane_distance: f64) -> Option<f64> {
+        let _ = plane_distance;
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Euclidean;
+
+// Euclidean distance without the square root; ranks identically but is cheaper
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SquaredEuclidean;
+
+// 1 - cosine similarity, in [0, 2]. A zero vector has no direction and is treated
+// as orthogonal to everything.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Cosine;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Manhattan;
+
+// Negated dot product, so larger similarities sort first
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Dot;
+
+impl Metric for Euclidean {
+    fn dist(&self, a: &[f64], b: &[f64]) -> f64 {
+        SquaredEuclidean.dist(a, b).sqrt()
+    }
+
+    fn lower_bound_on_axis(&self, plane_distance: f64) -> Option<f64> {
+        Some(plane_distance.abs())
+    }
+}
+
+impl Metric for SquaredEuclidean {
+    fn dist(&self, a: &[f64], b: &[f64]) -> f64 {
+        a.iter()
+            .zip(b.iter())
+            .map(|(x, y)| (x - y).powi(2))
+            .sum::<f64>()
+    }
+
+    fn lower_bound_on_axis(&self, plane_distance: f64) -> Option<f64> {
+        Some(plane_distance * plane_distance)
+    }
+}
+
+impl Metric for Cosine {
+    fn dist(&self, a: &[f64], b: &[f64]) -> f64 {
+        self.dist_with_norms(a, norm(a), b, norm(b))
+    }
+
+    fn dist_with_norms(&self, a: &[f64], a_norm: f64, b: &[f64], b_norm: f64) -> f64 {
+        let norms = a_norm * b_norm;
+        if norms == 0.0 {
+            return 1.0;
+        }
+        // Rounding can push the ratio just outside [-1, 1]
+        1.0 - (dot(a, b) / norms).clamp(-1.0, 1.0)
+    }
+}
+
+impl Metric for Manhattan {
+    fn dist(&self, a: &[f64], b: &[f64]) -> f64 {
+        a.iter().zip(b.iter()).map(|(x, y)| (x - y).abs()).sum()
+    }
+
+    fn lower_bound_on_axis(&self, plane_distance: f64) -> Option<f64> {
+        Some(plane_distance.abs())
+    }
+}
+
+impl Metric for Dot {
+    fn dist(&self, a: &[f64], b: &[f64]) -> f64 {
+        -dot(a, b)
+    }
+}
+
+// L2 norm, as trees store it with each point
+pub fn norm(v: &[f64]) -> f64 {
+    dot(v, v).sqrt()
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b.iter()).map(|(x, y)| x * y).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::SplitMix64;
+
+    const METRICS: [(&str, &dyn Metric); 5] = [
+        ("euclidean", &Euclidean),
+        ("squared", &SquaredEuclidean),
+        ("cosine", &Cosine),
+        ("manhattan", &Manhattan),
+        ("dot", &Dot),
+    ];
+
+    fn random_vector(rng: &mut SplitMix64, k: usize) -> Vec<f64> {
+        (0..k).map(|_| rng.next_unit() * 20.0 - 10.0).collect()
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() <= 1e-12 * expected.abs().max(1.0), "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn metrics_give_known_distances() {
+        let (a, b) = ([1.0, 2.0, 3.0], [4.0, 6.0, 3.0]);
+        assert_eq!(Euclidean.dist(&a, &b), 5.0);
+        assert_eq!(SquaredEuclidean.dist(&a, &b), 25.0);
+        assert_eq!(Manhattan.dist(&a, &b), 7.0);
+        assert_eq!(Dot.dist(&a, &b), -25.0);
+        assert_close(Cosine.dist(&a, &b), 1.0 - 25.0 / (14f64.sqrt() * 61f64.sqrt()));
+        assert_eq!(Manhattan.dist(&[-1.0, 1.0], &[1.0, -1.0]), 4.0);
+    }
+
+    #[test]
+    fn metrics_are_symmetric_and_closest_to_themselves() {
+        let mut rng = SplitMix64::new(1);
+        for _ in 0..200 {
+            let k = 1 + rng.next_below(8) as usize;
+            let (a, b) = (random_vector(&mut rng, k), random_vector(&mut rng, k));
+            for (name, metric) in METRICS {
+                assert_eq!(metric.dist(&a, &b), metric.dist(&b, &a), "{}", name);
+            }
+            for metric in [&Euclidean as &dyn Metric, &SquaredEuclidean, &Manhattan] {
+                assert_eq!(metric.dist(&a, &a), 0.0);
+                assert!(metric.dist(&a, &b) >= 0.0);
+            }
+            assert!(Cosine.dist(&a, &a).abs() < 1e-12);
+            assert!((0.0..=2.0).contains(&Cosine.dist(&a, &b)));
+            // No vector scores better against `a` under dot product than `a` scaled up
+            let scaled: Vec<f64> = a.iter().map(|x| x * 2.0).collect();
+            assert!(Dot.dist(&a, &scaled) < Dot.dist(&a, &a));
+        }
+    }
+
+    #[test]
+    fn euclidean_is_the_root_of_squared_euclidean() {
+        let mut rng = SplitMix64::new(2);
+        for _ in 0..100 {
+            let (a, b) = (random_vector(&mut rng, 5), random_vector(&mut rng, 5));
+            assert_close(Euclidean.dist(&a, &b), SquaredEuclidean.dist(&a, &b).sqrt());
+        }
+    }
+
+    #[test]
+    fn euclidean_satisfies_the_triangle_inequality() {
+        let mut rng = SplitMix64::new(3);
+        for _ in 0..200 {
+            let (a, b, c) = (random_vector(&mut rng, 4), random_vector(&mut rng, 4), random_vector(&mut rng, 4));
+            for metric in [&Euclidean as &dyn Metric, &Manhattan] {
+                assert!(metric.dist(&a, &c) <= metric.dist(&a, &b) + metric.dist(&b, &c) + 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn cosine_depends_only_on_direction() {
+        assert_eq!(Cosine.dist(&[1.0, 0.0], &[0.0, 3.0]), 1.0);
+        assert_close(Cosine.dist(&[1.0, 1.0], &[-2.0, -2.0]), 2.0);
+        assert_close(Cosine.dist(&[2.0, 2.0], &[5.0, 5.0]), 0.0);
+        // Parallel vectors whose ratio rounds just above 1 still don't go negative
+        let a = [0.1, 0.2, 0.3];
+        let b = [0.30000000000000004, 0.6000000000000001, 0.8999999999999999];
+        assert!(Cosine.dist(&a, &b) >= 0.0);
+    }
+
+    #[test]
+    fn cosine_treats_zero_vectors_as_orthogonal() {
+        let zero = [0.0, 0.0, 0.0];
+        assert_eq!(Cosine.dist(&zero, &[1.0, 2.0, 3.0]), 1.0);
+        assert_eq!(Cosine.dist(&[1.0, 2.0, 3.0], &zero), 1.0);
+        assert_eq!(Cosine.dist(&zero, &zero), 1.0);
+        assert_eq!(Cosine.dist(&[], &[]), 1.0);
+        assert!(!Cosine.dist(&zero, &[f64::MIN_POSITIVE, 0.0, 0.0]).is_nan());
+    }
+
+    #[test]
+    fn empty_vectors_are_at_no_distance() {
+        for (name, metric) in METRICS {
+            if name != "cosine" {
+                assert_eq!(metric.dist(&[], &[]), 0.0, "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn given_norms_give_the_same_distances() {
+        let mut rng = SplitMix64::new(4);
+        for _ in 0..100 {
+            let (a, b) = (random_vector(&mut rng, 6), random_vector(&mut rng, 6));
+            for (name, metric) in METRICS {
+                assert_eq!(metric.dist_with_norms(&a, norm(&a), &b, norm(&b)), metric.dist(&a, &b), "{}", name);
+            }
+        }
+        assert_eq!(norm(&[3.0, 4.0]), 5.0);
+        assert_eq!(norm(&[]), 0.0);
+    }
+
+    #[test]
+    fn axis_bounds_never_exceed_the_distance_across_the_plane() {
+        let mut rng = SplitMix64::new(5);
+        for _ in 0..500 {
+            let (target, other) = (random_vector(&mut rng, 4), random_vector(&mut rng, 4));
+            let axis = rng.next_below(4) as usize;
+            // A plane between the two points along `axis`
+            let split = target[axis] + (other[axis] - target[axis]) * rng.next_unit();
+            let plane_distance = split - target[axis];
+            for (name, metric) in METRICS {
+                if let Some(bound) = metric.lower_bound_on_axis(plane_distance) {
+                    assert!(bound <= metric.dist(&target, &other) + 1e-12, "{}", name);
+                    assert!(bound >= 0.0, "{}", name);
+                }
+            }
+        }
+        // Neither admits a bound from one axis alone
+        assert_eq!(Cosine.lower_bound_on_axis(1.0), None);
+        assert_eq!(Dot.lower_bound_on_axis(1.0), None);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn the_deprecated_wrapper_is_euclidean() {
+        let (a, b) = (vec![0.0, 3.0], vec![4.0, 0.0]);
+        assert_eq!(crate::kdtree::euclidean_distance(&a, &b), 5.0);
+    }
+}
@@ -0,0 +1,374 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::payload::{self, TreeImage};
+use crate::rng::SplitMix64;
+
+// How far an insert has to get towards disk before it is acknowledged
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Durability {
+    None,     // In-memory tree updated; the file is written by the background flush
+    Buffered, // Tree file written and handed to the OS
+    Fsync,    // Tree file flushed to disk
+}
+
+impl FromStr for Durability {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, String> {
+        match value {
+            "none" => Ok(Durability::None),
+            "buffered" => Ok(Durability::Buffered),
+            "fsync" => Ok(Durability::Fsync),
+            _ => Err(format!("unknown durability level: {}", value)),
+        }
+    }
+}
+
+// A tree image waiting to be written once the trees lock has been released.
+// `persisted` holds the sequence number of the newest snapshot on disk and also
+// serializes writes to the file, so a slow older snapshot never overwrites a newer one.
+pub struct PendingWrite {
+    pub path: PathBuf,
+    pub image: TreeImage,
+    pub seq: u64,
+    pub persisted: Arc<Mutex<u64>>,
+    pub written: Arc<WriteTally>,
+    pub health: Arc<SaveHealth>,
+}
+
+impl PendingWrite {
+    // Writes the snapshot (unless a newer one already landed) and optionally fsyncs
+    // it, returning the durability reached. A failed fsync fails the write like any
+    // other error, and counts against the tree's save health.
+    pub fn write(self, fsync: bool) -> io::Result<Durability> {
+        let result = self.replace(fsync);
+        self.health.record(&result);
+        result
+    }
+
+    // Like `write`, but tries again on failure as `policy` allows, sleeping between the
+    // attempts; for the background flush, which nobody waits on
+    pub fn write_with(self, fsync: bool, policy: &RetryPolicy) -> io::Result<Durability> {
+        let mut rng = jitter_rng();
+        let result = policy.run(&mut rng, || self.replace(fsync), std::thread::sleep);
+        self.health.record(&result);
+        result
+    }
+
+    fn replace(&self, fsync: bool) -> io::Result<Durability> {
+        let mut persisted = self.persisted.lock().unwrap();
+        if self.seq > *persisted {
+            payload::replace_file(&self.path, fsync, |writer| self.image.write_to(writer))?;
+            *persisted = self.seq;
+            self.written.record(&self.path);
+        } else if fsync {
+            // The file already holds a newer snapshot, which may not have been synced.
+            // Syncing it covers this one too; the lock keeps it from being replaced meanwhile.
+            File::open(&self.path)?.sync_all()?;
+            payload::sync_directory(&self.path)?;
+        }
+        Ok(match fsync {
+            true => Durability::Fsync,
+            false => Durability::Buffered,
+        })
+    }
+}
+
+// Writes of a tree file not yet counted into the tree's usage, shared with writers
+// outside the trees lock. A write counts the whole file, since it is rewritten whole.
+#[derive(Debug, Default)]
+pub struct WriteTally {
+    bytes: AtomicU64,
+    saves: AtomicU64,
+}
+
+impl WriteTally {
+    pub fn record(&self, path: &Path) {
+        let bytes = fs::metadata(path).map_or(0, |metadata| metadata.len());
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.saves.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // The bytes and saves counted since the last call
+    pub fn take(&self) -> (u64, u64) {
+        (self.bytes.swap(0, Ordering::Relaxed), self.saves.swap(0, Ordering::Relaxed))
+    }
+}
+
+// How often a failed save is tried again before it is given up until the next flush
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub attempts: u32, // Tries in all, at least 1
+    pub base: Duration, // Wait before the first retry, doubled for every further one
+    pub max: Duration,  // Longest wait between two tries
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy { attempts: 3, base: Duration::from_millis(100), max: Duration::from_secs(5) }
+    }
+}
+
+impl RetryPolicy {
+    // Wait before retry number `retry` (1 for the first): the doubled base capped at
+    // `max`, of which a random half is taken off so failing trees don't retry in step
+    pub fn delay(&self, retry: u32, rng: &mut SplitMix64) -> Duration {
+        let backoff = self.base.saturating_mul(2u32.saturating_pow(retry.saturating_sub(1))).min(self.max);
+        backoff.mul_f64(0.5 + rng.next_unit() / 2.0)
+    }
+
+    // Calls `attempt` until it succeeds or the attempts run out, passing the waits in
+    // between to `sleep`. Returns the last result.
+    pub fn run<T>(
+        &self,
+        rng: &mut SplitMix64,
+        mut attempt: impl FnMut() -> io::Result<T>,
+        mut sleep: impl FnMut(Duration),
+    ) -> io::Result<T> {
+        let mut retry = 0;
+        loop {
+            match attempt() {
+                Err(e) if retry + 1 < self.attempts.max(1) => {
+                    retry += 1;
+                    println!("Save failed, retrying ({} of {}): {}", retry, self.attempts - 1, e);
+                    sleep(self.delay(retry, rng));
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+// Jitter only has to differ between trees and runs, so the clock seeds it
+fn jitter_rng() -> SplitMix64 {
+    SplitMix64::new(SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos() as u64))
+}
+
+// Wait before the background flush tries a tree again after its retries ran out,
+// doubled for every further failed flush in a row up to the maximum
+const FLUSH_RETRY_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_FLUSH_RETRY_BACKOFF: Duration = Duration::from_secs(60);
+
+// How saving a tree has been going, shared with writers outside the trees lock
+#[derive(Debug, Default)]
+pub struct SaveHealth {
+    failures: AtomicU64, // Failed saves since startup, not counting their retries
+    streak: Mutex<Option<SaveFailure>>, // Set from a failed save until the next one succeeds
+}
+
+// The latest of one or more failed saves in a row
+#[derive(Debug, Clone)]
+pub struct SaveFailure {
+    pub error: String,
+    pub failed_at: SystemTime,
+    pub consecutive: u32,
+    pub since: Instant, // First failure of the streak; the tree's changes are unsaved at least this long
+    retry_at: Instant,  // The background flush leaves the tree alone until then
+}
+
+impl SaveHealth {
+    pub fn record<T, E: fmt::Display>(&self, result: &Result<T, E>) {
+        let mut streak = self.streak.lock().unwrap();
+        let Err(e) = result else {
+            *streak = None;
+            return;
+        };
+        self.failures.fetch_add(1, Ordering::Relaxed);
+        let now = Instant::now();
+        let consecutive = streak.as_ref().map_or(1, |failure| failure.consecutive + 1);
+        let backoff = RetryPolicy { attempts: 1, base: FLUSH_RETRY_BACKOFF, max: MAX_FLUSH_RETRY_BACKOFF }
+            .delay(consecutive, &mut jitter_rng());
+        *streak = Some(SaveFailure {
+            error: e.to_string(),
+            failed_at: SystemTime::now(),
+            consecutive,
+            since: streak.as_ref().map_or(now, |failure| failure.since),
+            retry_at: now + backoff,
+        });
+    }
+
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    // None while saves succeed
+    pub fn failure(&self) -> Option<SaveFailure> {
+        self.streak.lock().unwrap().clone()
+    }
+
+    // Whether the background flush should skip the tree for now
+    pub fn backing_off(&self) -> bool {
+        self.streak.lock().unwrap().as_ref().is_some_and(|failure| failure.retry_at > Instant::now())
+    }
+
+    // How long saves of the tree have been failing, zero while they succeed
+    pub fn failing_for(&self) -> Duration {
+        self.streak.lock().unwrap().as_ref().map_or(Duration::ZERO, |failure| failure.since.elapsed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending(path: PathBuf, seq: u64, persisted: &Arc<Mutex<u64>>, health: &Arc<SaveHealth>) -> PendingWrite {
+        PendingWrite {
+            path,
+            image: TreeImage { head: format!("snapshot {}", seq).into_bytes(), payloads: Vec::new() },
+            seq,
+            persisted: persisted.clone(),
+            written: Arc::default(),
+            health: health.clone(),
+        }
+    }
+
+    #[test]
+    fn fsync_writes_leave_no_temporary_file() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("docs.bin");
+        let (persisted, health) = (Arc::default(), Arc::default());
+        assert_eq!(pending(path.clone(), 1, &persisted, &health).write(true).unwrap(), Durability::Fsync);
+        assert_eq!(fs::read(&path).unwrap(), b"snapshot 1");
+        assert!(!directory.path().join("docs.bin.tmp").exists());
+
+        // An older snapshot leaves the newer file alone but still syncs it
+        assert_eq!(pending(path.clone(), 2, &persisted, &health).write(false).unwrap(), Durability::Buffered);
+        assert_eq!(pending(path.clone(), 1, &persisted, &health).write(true).unwrap(), Durability::Fsync);
+        assert_eq!(fs::read(&path).unwrap(), b"snapshot 2");
+        assert_eq!(health.failures(), 0);
+    }
+
+    #[test]
+    fn failed_writes_count_against_save_health() {
+        let directory = tempfile::tempdir().unwrap();
+        let (persisted, health) = (Arc::default(), Arc::default());
+        let missing = directory.path().join("missing").join("docs.bin");
+        assert!(pending(missing.clone(), 1, &persisted, &health).write(true).is_err());
+        assert_eq!(health.failures(), 1);
+        assert_eq!(health.failure().unwrap().consecutive, 1);
+
+        // Syncing a newer snapshot that is not there fails the same way
+        *persisted.lock().unwrap() = 5;
+        assert!(pending(missing, 1, &persisted, &health).write(true).is_err());
+        assert_eq!(health.failure().unwrap().consecutive, 2);
+
+        let path = directory.path().join("docs.bin");
+        pending(path, 6, &persisted, &health).write(true).unwrap();
+        assert!(health.failure().is_none());
+        assert_eq!(health.failures(), 2);
+    }
+
+    // Runs `policy` over attempts that fail `failures` times before succeeding, and
+    // returns the result with the number of attempts and the waits between them
+    fn run_failing(policy: &RetryPolicy, failures: u32, seed: u64) -> (io::Result<u32>, u32, Vec<Duration>) {
+        let (mut attempts, mut waits) = (0, Vec::new());
+        let result = policy.run(
+            &mut SplitMix64::new(seed),
+            || {
+                attempts += 1;
+                match attempts <= failures {
+                    true => Err(io::Error::other(format!("attempt {} failed", attempts))),
+                    false => Ok(attempts),
+                }
+            },
+            |wait| waits.push(wait),
+        );
+        (result, attempts, waits)
+    }
+
+    #[test]
+    fn retries_back_off_exponentially_with_jitter() {
+        let policy = RetryPolicy { attempts: 5, base: Duration::from_millis(100), max: Duration::from_secs(10) };
+        let (result, attempts, waits) = run_failing(&policy, 4, 7);
+        assert_eq!((result.unwrap(), attempts), (5, 5));
+        // The same draws as the policy made, since the rng is seeded alike
+        let mut rng = SplitMix64::new(7);
+        assert_eq!(waits, (1..=4).map(|retry| policy.delay(retry, &mut rng)).collect::<Vec<_>>());
+        for (wait, backoff) in waits.iter().zip([100, 200, 400, 800]) {
+            let backoff = Duration::from_millis(backoff);
+            assert!(*wait >= backoff / 2 && *wait <= backoff, "{:?} for a backoff of {:?}", wait, backoff);
+        }
+    }
+
+    #[test]
+    fn retry_waits_are_capped_and_jittered_differently_per_seed() {
+        let policy = RetryPolicy { attempts: 40, base: Duration::from_millis(100), max: Duration::from_millis(300) };
+        let (_, _, waits) = run_failing(&policy, 39, 1);
+        assert!(waits.iter().all(|wait| *wait <= policy.max));
+        assert!(waits[2..].iter().all(|wait| *wait >= policy.max / 2));
+        let (_, _, other) = run_failing(&policy, 39, 2);
+        assert_ne!(waits, other);
+    }
+
+    #[test]
+    fn retries_stop_at_the_attempt_limit() {
+        let policy = RetryPolicy { attempts: 3, ..RetryPolicy::default() };
+        let (result, attempts, waits) = run_failing(&policy, u32::MAX, 1);
+        assert_eq!(result.unwrap_err().to_string(), "attempt 3 failed");
+        assert_eq!((attempts, waits.len()), (3, 2));
+
+        // Success ends the retries early
+        let (result, attempts, waits) = run_failing(&policy, 1, 1);
+        assert_eq!((result.unwrap(), attempts, waits.len()), (2, 2, 1));
+
+        // Zero attempts still tries once
+        let policy = RetryPolicy { attempts: 0, ..RetryPolicy::default() };
+        let (result, attempts, waits) = run_failing(&policy, u32::MAX, 1);
+        assert!(result.is_err());
+        assert_eq!((attempts, waits.len()), (1, 0));
+    }
+
+    #[test]
+    fn failed_saves_escalate_until_one_succeeds() {
+        let health = SaveHealth::default();
+        assert!(health.failure().is_none());
+        assert!(!health.backing_off());
+        assert_eq!(health.failing_for(), Duration::ZERO);
+
+        let failed: io::Result<()> = Err(io::Error::other("disk full"));
+        health.record(&failed);
+        let first = health.failure().unwrap();
+        assert_eq!((first.consecutive, first.error.as_str()), (1, "disk full"));
+        // The background flush leaves the tree alone for a while
+        assert!(health.backing_off());
+        for consecutive in 2..=4 {
+            health.record(&failed);
+            let failure = health.failure().unwrap();
+            assert_eq!(failure.consecutive, consecutive);
+            // The streak keeps the time of its first failure, which the deadline runs from
+            assert_eq!(failure.since, first.since);
+        }
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(health.failing_for() >= Duration::from_millis(5));
+        assert_eq!(health.failures(), 4);
+
+        health.record(&Ok::<(), io::Error>(()));
+        assert!(health.failure().is_none());
+        assert!(!health.backing_off());
+        assert_eq!(health.failing_for(), Duration::ZERO);
+        // Failures since startup are kept
+        assert_eq!(health.failures(), 4);
+        health.record(&failed);
+        assert_eq!(health.failure().unwrap().consecutive, 1);
+    }
+
+    #[test]
+    fn retried_writes_count_once_against_save_health() {
+        let directory = tempfile::tempdir().unwrap();
+        let (persisted, health) = (Arc::default(), Arc::default());
+        let missing = directory.path().join("missing").join("docs.bin");
+        let policy = RetryPolicy { attempts: 3, base: Duration::from_millis(1), max: Duration::from_millis(1) };
+        assert!(pending(missing, 1, &persisted, &health).write_with(false, &policy).is_err());
+        assert_eq!(health.failures(), 1);
+        assert_eq!(health.failure().unwrap().consecutive, 1);
+    }
+}
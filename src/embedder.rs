@@ -0,0 +1,130 @@
+// Client of the embedding provider named by EMBEDDING_URL. It speaks the OpenAI-style
+// embeddings API that most hosted providers and local embedding servers offer: a JSON
+// body with an `input` list (and `model` when set), answered by `data` entries carrying
+// an `embedding` and the `index` of their input. Calls block, so they run off the async
+// workers.
+use serde::{Deserialize, Serialize};
+use std::thread;
+use std::time::Duration;
+
+use crate::config::Settings;
+
+// Wait before the first retry; doubled for every further one
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+#[derive(Debug, thiserror::Error)]
+pub enum EmbedError {
+    #[error("embedding request failed: {0}")]
+    Request(String),
+    #[error("embedding provider answered {status}: {body}")]
+    Status { status: u16, body: String },
+    #[error("invalid embedding response: {0}")]
+    InvalidResponse(String),
+}
+
+impl EmbedError {
+    // Worth another try: the provider may be overloaded or briefly unreachable
+    fn is_transient(&self) -> bool {
+        match self {
+            EmbedError::Request(_) => true,
+            EmbedError::Status { status, .. } => *status == 429 || *status >= 500,
+            EmbedError::InvalidResponse(_) => false,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct EmbeddingRequest<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<&'a str>,
+    input: &'a [&'a str],
+}
+
+#[derive(Deserialize)]
+struct EmbeddingResponse {
+    data: Vec<EmbeddingData>,
+}
+
+#[derive(Deserialize)]
+struct EmbeddingData {
+    embedding: Vec<f64>,
+    index: Option<usize>, // Position of the input; entries are taken in order when absent
+}
+
+pub struct Embedder {
+    url: String,
+    model: Option<String>,
+    api_key: Option<String>,
+    max_retries: u32,
+    agent: ureq::Agent,
+}
+
+impl Embedder {
+    // None when no EMBEDDING_URL is configured
+    pub fn from_settings(settings: &Settings) -> Option<Embedder> {
+        let url = settings.embedding_url.clone()?;
+        Some(Embedder {
+            url,
+            model: settings.embedding_model.clone(),
+            api_key: settings.embedding_api_key.clone(),
+            max_retries: settings.embedding_max_retries,
+            agent: ureq::AgentBuilder::new().timeout(Duration::from_secs(settings.embedding_timeout_secs)).build(),
+        })
+    }
+
+    // One embedding per input, in input order. Transient failures are retried with
+    // exponential backoff, calling `on_retry` before each wait.
+    pub fn embed(&self, inputs: &[&str], on_retry: impl Fn(&EmbedError)) -> Result<Vec<Vec<f64>>, EmbedError> {
+        let mut attempt = 0;
+        loop {
+            match self.request(inputs) {
+                Err(e) if e.is_transient() && attempt < self.max_retries => {
+                    on_retry(&e);
+                    thread::sleep(RETRY_BACKOFF * 2u32.pow(attempt));
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn request(&self, inputs: &[&str]) -> Result<Vec<Vec<f64>>, EmbedError> {
+        let body = serde_json::to_string(&EmbeddingRequest { model: self.model.as_deref(), input: inputs })
+            .map_err(|e| EmbedError::Request(e.to_string()))?;
+        let mut request = self.agent.post(&self.url).set("Content-Type", "application/json");
+        if let Some(api_key) = &self.api_key {
+            request = request.set("Authorization", &format!("Bearer {}", api_key));
+        }
+        let response = match request.send_string(&body) {
+            Ok(response) => response,
+            Err(ureq::Error::Status(status, response)) => {
+                let body = response.into_string().unwrap_or_default();
+                return Err(EmbedError::Status { status, body });
+            }
+            Err(e) => return Err(EmbedError::Request(e.to_string())),
+        };
+        let text = response.into_string().map_err(|e| EmbedError::Request(e.to_string()))?;
+        let response: EmbeddingResponse = serde_json::from_str(&text).map_err(|e| EmbedError::InvalidResponse(e.to_string()))?;
+        order_embeddings(response.data, inputs.len())
+    }
+}
+
+// Puts the returned embeddings in input order and checks there is exactly one usable
+// embedding per input
+fn order_embeddings(data: Vec<EmbeddingData>, inputs: usize) -> Result<Vec<Vec<f64>>, EmbedError> {
+    if data.len() != inputs {
+        return Err(EmbedError::InvalidResponse(format!("{} embeddings for {} inputs", data.len(), inputs)));
+    }
+    let mut ordered = vec![None; inputs];
+    for (position, entry) in data.into_iter().enumerate() {
+        let index = entry.index.unwrap_or(position);
+        if entry.embedding.is_empty() || entry.embedding.iter().any(|component| !component.is_finite()) {
+            return Err(EmbedError::InvalidResponse(format!("embedding {} is empty or not finite", index)));
+        }
+        match ordered.get_mut(index) {
+            Some(slot @ None) => *slot = Some(entry.embedding),
+            _ => return Err(EmbedError::InvalidResponse(format!("unexpected or repeated index {}", index))),
+        }
+    }
+    Ok(ordered.into_iter().flatten().collect())
+}
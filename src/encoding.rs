@@ -0,0 +1,138 @@
+// Embeddings as base64 of packed little-endian floats. For high-dimensional vectors,
+// formatting and parsing JSON numbers is most of what an insert or search costs, so
+// points may send `embedding_b64` instead of `embedding`, and endpoints returning points
+// send it back with `encoding=b64`. Values are stored as f64 whatever was sent.
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::de::IgnoredAny;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fmt;
+
+use crate::kdtree::Point;
+use crate::metadata::Metadata;
+
+// Type of the packed values. f32 is what embedding models produce; f64 round-trips the
+// stored components exactly.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Dtype {
+    #[default]
+    F32,
+    F64,
+}
+
+impl Dtype {
+    pub fn width(self) -> usize {
+        match self {
+            Dtype::F32 => 4,
+            Dtype::F64 => 8,
+        }
+    }
+}
+
+impl fmt::Display for Dtype {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Dtype::F32 => "f32",
+            Dtype::F64 => "f64",
+        })
+    }
+}
+
+// How endpoints returning points write their embeddings
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum EmbeddingEncoding {
+    #[default]
+    Json, // An array of numbers under `embedding`
+    B64,  // A string under `embedding_b64`
+}
+
+// The type returned embeddings are packed as, None when they are sent as JSON arrays
+pub fn packing(encoding: Option<EmbeddingEncoding>, dtype: Option<Dtype>) -> Option<Dtype> {
+    (encoding == Some(EmbeddingEncoding::B64)).then(|| dtype.unwrap_or_default())
+}
+
+pub fn encode(components: &[f64], dtype: Dtype) -> String {
+    let mut bytes = Vec::with_capacity(components.len() * dtype.width());
+    for component in components {
+        match dtype {
+            Dtype::F32 => bytes.extend_from_slice(&(*component as f32).to_le_bytes()),
+            Dtype::F64 => bytes.extend_from_slice(&component.to_le_bytes()),
+        }
+    }
+    STANDARD.encode(bytes)
+}
+
+// Errors say which field is wrong and how
+pub fn decode(encoded: &str, dtype: Dtype) -> Result<Vec<f64>, String> {
+    let bytes = STANDARD.decode(encoded).map_err(|e| format!("embedding_b64 is not valid base64: {}", e))?;
+    if bytes.len() % dtype.width() != 0 {
+        return Err(format!(
+            "embedding_b64 decodes to {} bytes, which is not a whole number of {} values ({} bytes each)",
+            bytes.len(), dtype, dtype.width()
+        ));
+    }
+    let components: Vec<f64> = match dtype {
+        Dtype::F32 => bytes.chunks_exact(4).map(|value| f32::from_le_bytes(value.try_into().unwrap()) as f64).collect(),
+        Dtype::F64 => bytes.chunks_exact(8).map(|value| f64::from_le_bytes(value.try_into().unwrap())).collect(),
+    };
+    if let Some(index) = components.iter().position(|component| !component.is_finite()) {
+        return Err(format!("embedding_b64 component {} is not a finite number", index));
+    }
+    Ok(components)
+}
+
+// A point as request bodies send it: the embedding either as a JSON array or packed in
+// `embedding_b64`. Points as /export and searches return them are accepted as they are,
+// the fields only those have ignored; any other field is an error.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct PointInput {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub embedding: Option<Vec<f64>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub embedding_b64: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub embedding_dtype: Option<Dtype>, // Of `embedding_b64`, defaults to f32
+    #[serde(default)]
+    pub data: Option<String>,
+    #[serde(default)]
+    pub metadata: Metadata,
+    #[serde(rename = "seq", default, skip_serializing)]
+    _seq: IgnoredAny,
+    #[serde(rename = "inserted_at", default, skip_serializing)]
+    _inserted_at: IgnoredAny,
+    #[serde(rename = "distance", default, skip_serializing)]
+    _distance: IgnoredAny,
+    #[serde(rename = "mmr_score", default, skip_serializing)]
+    _mmr_score: IgnoredAny,
+    #[serde(rename = "collection", default, skip_serializing)]
+    _collection: IgnoredAny,
+}
+
+impl PointInput {
+    // The embedding, decoded if it was packed. An empty one is left to the caller.
+    pub fn embedding(&self) -> Result<Vec<f64>, String> {
+        match (&self.embedding, &self.embedding_b64) {
+            (Some(_), Some(_)) => Err("Send either embedding or embedding_b64, not both".to_string()),
+            (Some(embedding), None) if self.embedding_dtype.is_none() => Ok(embedding.clone()),
+            (_, None) if self.embedding_dtype.is_some() => Err("embedding_dtype only applies to embedding_b64".to_string()),
+            (None, Some(encoded)) => decode(encoded, self.embedding_dtype.unwrap_or_default()),
+            _ => Err("embedding or embedding_b64 is required".to_string()),
+        }
+    }
+
+    pub fn into_point(self) -> Result<Point, String> {
+        let embedding = self.embedding()?;
+        Ok(Point { embedding, data: self.data, metadata: self.metadata, ..Default::default() })
+    }
+}
+
+impl From<Point> for PointInput {
+    fn from(point: Point) -> Self {
+        let data = point.data().map(Cow::into_owned);
+        PointInput { embedding: Some(point.embedding), data, metadata: point.metadata, ..Default::default() }
+    }
+}
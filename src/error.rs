@@ -0,0 +1,90 @@
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use serde::Serialize;
+use serde_json::json;
+use std::error::Error;
+use std::fmt;
+
+use crate::kdtree::KdTreeError;
+
+// A single problem with one request field
+#[derive(Serialize, Debug, Clone)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: &str, message: impl Into<String>) -> Self {
+        FieldError {
+            field: field.to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+// Errors surfaced to HTTP clients
+#[derive(Debug)]
+pub enum ApiError {
+    Validation(Vec<FieldError>),
+    Tree { tree_name: String, error: KdTreeError }, // Loading or changing a tree failed
+}
+
+impl ApiError {
+    pub fn tree(tree_name: &str, error: KdTreeError) -> Self {
+        ApiError::Tree { tree_name: tree_name.to_string(), error }
+    }
+
+    // Status and plain-text body, for handlers that build their own responses
+    pub fn into_parts(self) -> (StatusCode, String) {
+        (self.status_code(), self.to_string())
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Validation(errors) => {
+                let fields: Vec<_> = errors.iter().map(|e| format!("{}: {}", e.field, e.message)).collect();
+                write!(f, "Invalid parameters: {}", fields.join(", "))
+            }
+            ApiError::Tree { tree_name, error } if error.is_not_found() => write!(f, "Tree {} not found", tree_name),
+            ApiError::Tree { tree_name, error } => {
+                write!(f, "Error in tree {}: {}", tree_name, error)?;
+                // The whole chain, so a corrupt file's log line says what was wrong with it
+                let mut source = error.source();
+                while let Some(cause) = source {
+                    write!(f, ": {}", cause)?;
+                    source = cause.source();
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::Validation(_) => StatusCode::BAD_REQUEST,
+            ApiError::Tree { error, .. } => match error {
+                KdTreeError::Io(e) if e.kind() == std::io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+                KdTreeError::DimensionMismatch { .. } | KdTreeError::ZeroDimensions => StatusCode::BAD_REQUEST,
+                KdTreeError::Io(_)
+                | KdTreeError::Serialization(_)
+                | KdTreeError::Corrupt { .. }
+                | KdTreeError::UnsupportedVersion(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                KdTreeError::LoadBackoff { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            },
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            ApiError::Validation(errors) => HttpResponse::build(self.status_code()).json(json!({
+                "error": "invalid_parameters",
+                "fields": errors,
+            })),
+            ApiError::Tree { .. } => HttpResponse::build(self.status_code()).body(self.to_string()),
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use serde_json::Value;
+use std::cmp::Ordering;
+
+use crate::kdtree::Point;
+use crate::metadata::{Metadata, MetadataValue};
+
+// Conditions a stored point must meet, evaluated while the tree is traversed
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    pub metadata: Option<Condition>,     // Parsed by `Condition::parse`
+    pub inserted_after: Option<u64>,     // Unix seconds, exclusive
+    pub inserted_before: Option<u64>,    // Unix seconds, exclusive
+    pub since_seq: Option<u64>,          // Only points inserted after this sequence number
+}
+
+impl Filter {
+    pub fn matches(&self, point: &Point) -> bool {
+        self.since_seq.is_none_or(|since_seq| point.seq > since_seq)
+            && self.inserted_after.is_none_or(|after| point.inserted_at > after)
+            && self.inserted_before.is_none_or(|before| point.inserted_at < before)
+            && self.metadata.as_ref().is_none_or(|condition| condition.matches(&point.metadata))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+}
+
+// A metadata condition. The JSON form nests explicitly, so there is no precedence to
+// resolve: the keys of one object are ANDed, and `$and`/`$or` take a list of objects.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    // Legacy `field:value`: compares the value's plain text form, so `tier:3` matches the
+    // number 3 and `lang:en` the string "en"
+    Text(String, String),
+    Compare(String, Comparison, MetadataValue),
+    In(String, Vec<MetadataValue>, bool), // Negated for `$nin`
+    Exists(String, bool),
+    And(Vec<Condition>),
+    Or(Vec<Condition>),
+}
+
+impl Condition {
+    // Accepts the JSON form, e.g. `{"tier": {"$gte": 2}, "$or": [{"lang": "en"}, {"lang": "de"}]}`,
+    // or the legacy `field:value,field:value` list. Errors name the offending clause.
+    pub fn parse(spec: &str) -> Result<Condition, String> {
+        if spec.trim_start().starts_with('{') {
+            let value: Value = serde_json::from_str(spec).map_err(|e| format!("invalid JSON: {}", e))?;
+            return parse_clauses(&value, "");
+        }
+
+        let mut parsed = Vec::new();
+        let mut invalid = Vec::new();
+        for condition in spec.split(',').map(str::trim).filter(|condition| !condition.is_empty()) {
+            match condition.split_once(':') {
+                Some((field, value)) if !field.is_empty() => {
+                    parsed.push(Condition::Text(field.to_string(), value.to_string()))
+                }
+                _ => invalid.push(condition),
+            }
+        }
+        if invalid.is_empty() {
+            Ok(Condition::And(parsed))
+        } else {
+            Err(format!("expected field:value, got: {}", invalid.join(", ")))
+        }
+    }
+
+    // A filter without conditions, such as `{}` or an empty list, which every point meets
+    pub fn is_empty(&self) -> bool {
+        matches!(self, Condition::And(conditions) if conditions.is_empty())
+    }
+
+    // Metadata fields the condition refers to, in clause order
+    pub fn fields(&self) -> Vec<&str> {
+        match self {
+            Condition::Text(field, _)
+            | Condition::Compare(field, _, _)
+            | Condition::In(field, _, _)
+            | Condition::Exists(field, _) => vec![field.as_str()],
+            Condition::And(conditions) | Condition::Or(conditions) => conditions.iter().flat_map(Condition::fields).collect(),
+        }
+    }
+
+    pub fn matches(&self, metadata: &Metadata) -> bool {
+        match self {
+            Condition::Text(field, value) => metadata.get(field).is_some_and(|stored| stored.to_string() == *value),
+            // A missing field is not equal to anything, so `$ne` matches it
+            Condition::Compare(field, Comparison::Ne, value) => metadata.get(field) != Some(value),
+            Condition::Compare(field, comparison, value) => metadata.get(field).is_some_and(|stored| {
+                match (comparison, compare(stored, value)) {
+                    (Comparison::Eq, Some(ordering)) => ordering == Ordering::Equal,
+                    (Comparison::Gt, Some(ordering)) => ordering == Ordering::Greater,
+                    (Comparison::Gte, Some(ordering)) => ordering != Ordering::Less,
+                    (Comparison::Lt, Some(ordering)) => ordering == Ordering::Less,
+                    (Comparison::Lte, Some(ordering)) => ordering != Ordering::Greater,
+                    _ => false,
+                }
+            }),
+            Condition::In(field, values, negated) => {
+                metadata.get(field).is_some_and(|stored| values.contains(stored)) != *negated
+            }
+            Condition::Exists(field, exists) => metadata.contains_key(field) == *exists,
+            Condition::And(conditions) => conditions.iter().all(|condition| condition.matches(metadata)),
+            Condition::Or(conditions) => conditions.iter().any(|condition| condition.matches(metadata)),
+        }
+    }
+}
+
+// Values only order against values of the same type; booleans only compare equal or not
+fn compare(stored: &MetadataValue, value: &MetadataValue) -> Option<Ordering> {
+    match (stored, value) {
+        (MetadataValue::Number(stored), MetadataValue::Number(value)) => stored.partial_cmp(value),
+        (MetadataValue::String(stored), MetadataValue::String(value)) => Some(stored.cmp(value)),
+        (MetadataValue::Bool(stored), MetadataValue::Bool(value)) if stored == value => Some(Ordering::Equal),
+        _ => None,
+    }
+}
+
+// An object whose keys are ANDed: field names, `$and` or `$or`
+fn parse_clauses(value: &Value, path: &str) -> Result<Condition, String> {
+    let Some(clauses) = value.as_object() else {
+        return Err(format!("{}: expected an object", describe(path)));
+    };
+    let mut conditions = Vec::new();
+    for (key, operand) in clauses {
+        let path = join(path, key);
+        conditions.push(match key.as_str() {
+            "$and" | "$or" => {
+                let Some(items) = operand.as_array().filter(|items| !items.is_empty()) else {
+                    return Err(format!("{}: expected a non-empty list of objects", describe(&path)));
+                };
+                let parsed = items
+                    .iter()
+                    .enumerate()
+                    .map(|(index, item)| parse_clauses(item, &format!("{}[{}]", path, index)))
+                    .collect::<Result<Vec<_>, _>>()?;
+                if key == "$and" { Condition::And(parsed) } else { Condition::Or(parsed) }
+            }
+            operator if operator.starts_with('$') => {
+                return Err(format!("{}: unknown operator, expected a field name, $and or $or", describe(&path)));
+            }
+            field => parse_field(field, operand, &path)?,
+        });
+    }
+    Ok(if conditions.len() == 1 { conditions.remove(0) } else { Condition::And(conditions) })
+}
+
+// A bare value means equality; an object holds operators, which are ANDed
+fn parse_field(field: &str, operand: &Value, path: &str) -> Result<Condition, String> {
+    let Some(operators) = operand.as_object() else {
+        return Ok(Condition::Compare(field.to_string(), Comparison::Eq, parse_value(operand, path)?));
+    };
+    if operators.is_empty() {
+        return Err(format!("{}: expected at least one operator", describe(path)));
+    }
+    let mut conditions = Vec::new();
+    for (operator, value) in operators {
+        let path = join(path, operator);
+        let comparison = match operator.as_str() {
+            "$eq" => Comparison::Eq,
+            "$ne" => Comparison::Ne,
+            "$gt" => Comparison::Gt,
+            "$gte" => Comparison::Gte,
+            "$lt" => Comparison::Lt,
+            "$lte" => Comparison::Lte,
+            "$in" | "$nin" => {
+                let Some(items) = value.as_array() else {
+                    return Err(format!("{}: expected a list of values", describe(&path)));
+                };
+                let values = items.iter().map(|item| parse_value(item, &path)).collect::<Result<_, _>>()?;
+                conditions.push(Condition::In(field.to_string(), values, operator == "$nin"));
+                continue;
+            }
+            "$exists" => {
+                let Some(exists) = value.as_bool() else {
+                    return Err(format!("{}: expected true or false", describe(&path)));
+                };
+                conditions.push(Condition::Exists(field.to_string(), exists));
+                continue;
+            }
+            _ => {
+                return Err(format!(
+                    "{}: unknown operator, expected one of $eq, $ne, $gt, $gte, $lt, $lte, $in, $nin, $exists",
+                    describe(&path)
+                ));
+            }
+        };
+        let value = parse_value(value, &path)?;
+        if matches!(value, MetadataValue::Bool(_)) && !matches!(comparison, Comparison::Eq | Comparison::Ne) {
+            return Err(format!("{}: expected a number or string", describe(&path)));
+        }
+        conditions.push(Condition::Compare(field.to_string(), comparison, value));
+    }
+    Ok(if conditions.len() == 1 { conditions.remove(0) } else { Condition::And(conditions) })
+}
+
+fn parse_value(value: &Value, path: &str) -> Result<MetadataValue, String> {
+    match value {
+        Value::Bool(value) => Ok(MetadataValue::Bool(*value)),
+        Value::Number(number) => number
+            .as_f64()
+            .map(MetadataValue::Number)
+            .ok_or_else(|| format!("{}: number out of range", describe(path))),
+        Value::String(value) => Ok(MetadataValue::String(value.clone())),
+        _ => Err(format!("{}: expected a string, number or boolean, got {}", describe(path), value)),
+    }
+}
+
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() { key.to_string() } else { format!("{}.{}", path, key) }
+}
+
+fn describe(path: &str) -> String {
+    if path.is_empty() { "filter".to_string() } else { format!("clause `{}`", path) }
+}
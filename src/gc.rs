@@ -0,0 +1,72 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+// Why a leftover file in the bin directory may be removed
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GarbageKind {
+    Temp,           // `.tmp` file of an interrupted write, older than the age limit
+    OrphanedFilter, // `{tree_name}.bloom` of a tree that no longer exists
+    OldBackup,      // `{tree_name}.bin.v{N}` migration backup beyond the retention count
+}
+
+#[derive(Debug, Clone)]
+pub struct Garbage {
+    pub path: PathBuf,
+    pub file_name: String,
+    pub tree_name: String, // The tree the file belongs to; nothing else may touch it meanwhile
+    pub kind: GarbageKind,
+    pub bytes: u64,
+}
+
+// Lists removal candidates in `bin_directory` without removing anything. Tree files,
+// live filters and the manifest are never listed. Whether a filter's tree still
+// exists, and whether the tree is busy, is for the caller to decide under its lock.
+pub fn scan(bin_directory: &Path, temp_max_age: Duration, backup_retention: usize) -> io::Result<Vec<Garbage>> {
+    let cutoff = SystemTime::now().checked_sub(temp_max_age);
+    let mut garbage = Vec::new();
+    let mut backups: HashMap<String, Vec<(u32, Garbage)>> = HashMap::new();
+    for entry in fs::read_dir(bin_directory)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() {
+            continue;
+        }
+        let Some(file_name) = entry.file_name().to_str().map(str::to_string) else { continue };
+        // Tree names never contain a dot, so everything before the first one is the tree
+        let Some((tree_name, suffix)) = file_name.split_once('.') else { continue };
+        let found = |kind| Garbage {
+            path: entry.path(),
+            file_name: file_name.clone(),
+            tree_name: tree_name.to_string(),
+            kind,
+            bytes: metadata.len(),
+        };
+
+        if file_name.ends_with(".tmp") {
+            // A recent temp file may belong to a write that is still running
+            let modified = metadata.modified()?;
+            if cutoff.is_some_and(|cutoff| modified < cutoff) {
+                garbage.push(found(GarbageKind::Temp));
+            }
+        } else if suffix == "bloom" {
+            if !bin_directory.join(format!("{}.bin", tree_name)).exists() {
+                garbage.push(found(GarbageKind::OrphanedFilter));
+            }
+        } else if let Some(version) = suffix.strip_prefix("bin.v").and_then(|version| version.parse().ok()) {
+            backups.entry(tree_name.to_string()).or_default().push((version, found(GarbageKind::OldBackup)));
+        }
+    }
+
+    // The highest versions come from the latest migrations and are the ones kept
+    for mut versions in backups.into_values() {
+        versions.sort_by(|(a, _), (b, _)| b.cmp(a));
+        garbage.extend(versions.into_iter().skip(backup_retention).map(|(_, backup)| backup));
+    }
+    garbage.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+    Ok(garbage)
+}
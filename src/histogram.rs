@@ -0,0 +1,121 @@
+use serde::Serialize;
+
+pub const DEFAULT_BUCKETS: usize = 20;
+pub const MAX_BUCKETS: usize = 1000;
+
+// How `buckets=` divides the distances: a number of equal buckets spanning the observed
+// range, or explicit ascending edges
+#[derive(Debug, Clone, PartialEq)]
+pub enum Buckets {
+    Auto(usize),
+    Edges(Vec<f64>),
+}
+
+impl Buckets {
+    // `20` or `0,0.25,0.5,1`. Errors describe what is wrong with the value.
+    pub fn parse(spec: &str) -> Result<Buckets, String> {
+        if !spec.contains(',') {
+            return match spec.trim().parse::<usize>() {
+                Ok(count) if (1..=MAX_BUCKETS).contains(&count) => Ok(Buckets::Auto(count)),
+                _ => Err(format!("expected a bucket count between 1 and {} or a list of edges", MAX_BUCKETS)),
+            };
+        }
+        let edges = spec
+            .split(',')
+            .map(|edge| edge.trim().parse::<f64>().ok().filter(|edge| edge.is_finite()))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| "edges must be finite numbers".to_string())?;
+        if edges.len() > MAX_BUCKETS + 1 {
+            return Err(format!("at most {} edges", MAX_BUCKETS + 1));
+        }
+        if edges.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err("edges must be strictly ascending".to_string());
+        }
+        Ok(Buckets::Edges(edges))
+    }
+}
+
+// Distances of every candidate a search accepted, recorded as the traversal meets them,
+// including the ones that later fall out of the top n
+#[derive(Debug)]
+pub enum DistanceHistogram {
+    // The range is only known at the end, so distances are kept until then
+    Auto { buckets: usize, distances: Vec<f64> },
+    Edges { edges: Vec<f64>, counts: Vec<u64>, below: u64, above: u64, min: f64, max: f64 },
+}
+
+// The histogram as returned with search results. Bucket i holds distances in
+// [edges[i], edges[i + 1]); the last one also holds its upper edge.
+#[derive(Serialize, Debug, Clone)]
+pub struct Histogram {
+    pub edges: Vec<f64>,
+    pub counts: Vec<u64>,
+    pub candidates: u64,
+    pub below: u64, // Below the first explicit edge
+    pub above: u64, // Above the last explicit edge
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+
+impl DistanceHistogram {
+    pub fn new(buckets: &Buckets) -> Self {
+        match buckets {
+            Buckets::Auto(buckets) => DistanceHistogram::Auto { buckets: *buckets, distances: Vec::new() },
+            Buckets::Edges(edges) => DistanceHistogram::Edges {
+                edges: edges.clone(),
+                counts: vec![0; edges.len() - 1],
+                below: 0,
+                above: 0,
+                min: f64::INFINITY,
+                max: f64::NEG_INFINITY,
+            },
+        }
+    }
+
+    pub fn record(&mut self, distance: f64) {
+        match self {
+            DistanceHistogram::Auto { distances, .. } => distances.push(distance),
+            DistanceHistogram::Edges { edges, counts, below, above, min, max } => {
+                *min = min.min(distance);
+                *max = max.max(distance);
+                if distance < edges[0] {
+                    *below += 1;
+                } else if distance > edges[edges.len() - 1] {
+                    *above += 1;
+                } else {
+                    // The last edge itself belongs to the last bucket
+                    let bucket = (edges.partition_point(|edge| *edge <= distance) - 1).min(counts.len() - 1);
+                    counts[bucket] += 1;
+                }
+            }
+        }
+    }
+
+    pub fn finish(self) -> Histogram {
+        match self {
+            DistanceHistogram::Auto { buckets, distances } => {
+                let min = distances.iter().copied().reduce(f64::min);
+                let max = distances.iter().copied().reduce(f64::max);
+                let (Some(low), Some(high)) = (min, max) else {
+                    return Histogram { edges: Vec::new(), counts: Vec::new(), candidates: 0, below: 0, above: 0, min, max };
+                };
+                // All distances equal: one bucket holding them all
+                let buckets = if high > low { buckets } else { 1 };
+                let width = (high - low) / buckets as f64;
+                let mut edges: Vec<f64> = (0..buckets).map(|bucket| low + width * bucket as f64).collect();
+                edges.push(high);
+                let mut counts = vec![0; buckets];
+                for distance in &distances {
+                    let bucket = if width > 0.0 { ((distance - low) / width) as usize } else { 0 };
+                    counts[bucket.min(buckets - 1)] += 1;
+                }
+                Histogram { edges, counts, candidates: distances.len() as u64, below: 0, above: 0, min, max }
+            }
+            DistanceHistogram::Edges { edges, counts, below, above, min, max } => {
+                let candidates = counts.iter().sum::<u64>() + below + above;
+                let (min, max) = if candidates > 0 { (Some(min), Some(max)) } else { (None, None) };
+                Histogram { edges, counts, candidates, below, above, min, max }
+            }
+        }
+    }
+}
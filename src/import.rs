@@ -0,0 +1,191 @@
+use arrow_array::cast::AsArray;
+use arrow_array::types::{Float32Type, Float64Type, Int32Type, Int64Type, UInt32Type, UInt64Type};
+use arrow_array::{Array, ArrayRef};
+use arrow_schema::DataType;
+use parquet::arrow::arrow_reader::{ArrowReaderMetadata, ParquetRecordBatchReaderBuilder};
+use parquet::arrow::ProjectionMask;
+use std::fs::File;
+use std::path::Path;
+
+use crate::api::{RejectedRow, RowGroupImport};
+use crate::kdtree::Point;
+
+// Rejected rows listed in an import report; the rest are only counted
+pub const MAX_REPORTED_REJECTIONS: usize = 100;
+
+// Which columns hold what, and what a row must satisfy to be imported
+pub struct ParquetColumns<'a> {
+    pub embedding: &'a str,      // list<float>, list<double> or a fixed-size list of either
+    pub data: Option<&'a str>,   // string
+    pub echo: Option<&'a str>,   // string or integer, only read to label rejected rows
+    pub dimensions: Option<usize>, // Required length; the first accepted row sets it when None
+    pub max_dimensions: usize,
+    pub max_data_bytes: usize,
+}
+
+pub struct ParquetImport {
+    pub points: Vec<Point>,
+    pub dimensions: Option<usize>,
+    pub row_groups: Vec<RowGroupImport>,
+    pub rejected: usize,
+    pub rejected_rows: Vec<RejectedRow>, // The first `MAX_REPORTED_REJECTIONS`
+}
+
+// Reads the file one row group at a time, only decoding the two columns. Rows that can't
+// become points are rejected and reported; a file or schema that can't be read at all
+// is an error. `progress` is called after every row group.
+pub fn read_parquet(
+    path: &Path,
+    columns: &ParquetColumns,
+    mut progress: impl FnMut(&RowGroupImport, usize),
+) -> Result<ParquetImport, String> {
+    let file = File::open(path).map_err(|e| format!("cannot open {:?}: {}", path, e))?;
+    let metadata = ArrowReaderMetadata::load(&file, Default::default()).map_err(|e| format!("not a readable Parquet file: {}", e))?;
+    let schema = metadata.schema().clone();
+
+    let embedding_index = schema.index_of(columns.embedding).map_err(|_| format!("no column `{}`", columns.embedding))?;
+    check_embedding_type(columns.embedding, schema.field(embedding_index).data_type())?;
+    let mut roots = vec![embedding_index];
+    if let Some(data) = columns.data {
+        let data_index = schema.index_of(data).map_err(|_| format!("no column `{}`", data))?;
+        if !matches!(schema.field(data_index).data_type(), DataType::Utf8 | DataType::LargeUtf8) {
+            return Err(format!("column `{}` must hold strings, found {}", data, schema.field(data_index).data_type()));
+        }
+        roots.push(data_index);
+    }
+    if let Some(echo) = columns.echo {
+        let echo_index = schema.index_of(echo).map_err(|_| format!("no column `{}`", echo))?;
+        if !matches!(
+            schema.field(echo_index).data_type(),
+            DataType::Utf8 | DataType::LargeUtf8 | DataType::Int32 | DataType::Int64 | DataType::UInt32 | DataType::UInt64
+        ) {
+            return Err(format!("column `{}` must hold strings or integers, found {}", echo, schema.field(echo_index).data_type()));
+        }
+        roots.push(echo_index);
+    }
+    let mask = ProjectionMask::roots(metadata.parquet_schema(), roots);
+
+    let num_row_groups = metadata.metadata().num_row_groups();
+    let mut import = ParquetImport {
+        points: Vec::new(),
+        dimensions: columns.dimensions,
+        row_groups: Vec::with_capacity(num_row_groups),
+        rejected: 0,
+        rejected_rows: Vec::new(),
+    };
+    let mut row = 0;
+    for row_group in 0..num_row_groups {
+        let file = file.try_clone().map_err(|e| format!("cannot read {:?}: {}", path, e))?;
+        let reader = ParquetRecordBatchReaderBuilder::new_with_metadata(file, metadata.clone())
+            .with_projection(mask.clone())
+            .with_row_groups(vec![row_group])
+            .build()
+            .map_err(|e| format!("row group {}: {}", row_group, e))?;
+
+        let mut report = RowGroupImport { row_group, rows: 0, imported: 0, rejected: 0 };
+        for batch in reader {
+            let batch = batch.map_err(|e| format!("row group {}: {}", row_group, e))?;
+            let embeddings = batch.column_by_name(columns.embedding).unwrap();
+            let data = columns.data.and_then(|data| batch.column_by_name(data));
+            let echo = columns.echo.and_then(|echo| batch.column_by_name(echo));
+            for index in 0..batch.num_rows() {
+                match import.point_at(embeddings, data, index, columns) {
+                    Ok(point) => {
+                        import.points.push(point);
+                        report.imported += 1;
+                    }
+                    Err(reason) => {
+                        import.rejected += 1;
+                        report.rejected += 1;
+                        if import.rejected_rows.len() < MAX_REPORTED_REJECTIONS {
+                            let reference = echo.and_then(|column| reference_at(column, index));
+                            import.rejected_rows.push(RejectedRow { row, reason, reference });
+                        }
+                    }
+                }
+                report.rows += 1;
+                row += 1;
+            }
+        }
+        progress(&report, num_row_groups);
+        import.row_groups.push(report);
+    }
+    Ok(import)
+}
+
+impl ParquetImport {
+    fn point_at(&mut self, embeddings: &ArrayRef, data: Option<&ArrayRef>, index: usize, columns: &ParquetColumns) -> Result<Point, String> {
+        let embedding = embedding_at(embeddings, index)?;
+        match self.dimensions {
+            Some(dimensions) if embedding.len() != dimensions => {
+                return Err(format!("embedding has {} values but the tree has {} dimensions", embedding.len(), dimensions));
+            }
+            Some(_) => {}
+            None if embedding.is_empty() => return Err("embedding is empty".to_string()),
+            None if embedding.len() > columns.max_dimensions => {
+                return Err(format!("embedding has {} values but MAX_DIMENSIONS is {}", embedding.len(), columns.max_dimensions));
+            }
+            None => self.dimensions = Some(embedding.len()),
+        }
+        if embedding.iter().any(|value| !value.is_finite()) {
+            return Err("embedding contains NaN or infinite values".to_string());
+        }
+
+        let data = match data {
+            Some(column) if !column.is_null(index) => Some(match column.data_type() {
+                DataType::LargeUtf8 => column.as_string::<i64>().value(index).to_string(),
+                _ => column.as_string::<i32>().value(index).to_string(),
+            }),
+            _ => None,
+        };
+        if let Some(size) = data.as_ref().map(String::len).filter(|size| *size > columns.max_data_bytes) {
+            return Err(format!("data is {} bytes but MAX_DATA_BYTES is {}", size, columns.max_data_bytes));
+        }
+        Ok(Point { embedding, data, ..Default::default() })
+    }
+}
+
+// A row's value in the echo column, passed back to the client as it is
+fn reference_at(column: &ArrayRef, index: usize) -> Option<serde_json::Value> {
+    if column.is_null(index) {
+        return None;
+    }
+    Some(match column.data_type() {
+        DataType::Utf8 => column.as_string::<i32>().value(index).into(),
+        DataType::LargeUtf8 => column.as_string::<i64>().value(index).into(),
+        DataType::Int32 => column.as_primitive::<Int32Type>().value(index).into(),
+        DataType::Int64 => column.as_primitive::<Int64Type>().value(index).into(),
+        DataType::UInt32 => column.as_primitive::<UInt32Type>().value(index).into(),
+        _ => column.as_primitive::<UInt64Type>().value(index).into(),
+    })
+}
+
+fn check_embedding_type(column: &str, data_type: &DataType) -> Result<(), String> {
+    let item = match data_type {
+        DataType::List(item) | DataType::LargeList(item) | DataType::FixedSizeList(item, _) => Some(item.data_type()),
+        _ => None,
+    };
+    match item {
+        Some(DataType::Float32 | DataType::Float64) => Ok(()),
+        _ => Err(format!("column `{}` must be a list of float or double, found {}", column, data_type)),
+    }
+}
+
+// Single-precision values widen to f64 exactly, which is what the tree stores
+fn embedding_at(column: &ArrayRef, index: usize) -> Result<Vec<f64>, String> {
+    if column.is_null(index) {
+        return Err("embedding is null".to_string());
+    }
+    let values = match column.data_type() {
+        DataType::LargeList(_) => column.as_list::<i64>().value(index),
+        DataType::FixedSizeList(_, _) => column.as_fixed_size_list().value(index),
+        _ => column.as_list::<i32>().value(index),
+    };
+    if values.null_count() > 0 {
+        return Err("embedding contains null values".to_string());
+    }
+    Ok(match values.data_type() {
+        DataType::Float32 => values.as_primitive::<Float32Type>().values().iter().map(|value| f64::from(*value)).collect(),
+        _ => values.as_primitive::<Float64Type>().values().to_vec(),
+    })
+}
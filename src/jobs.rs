@@ -0,0 +1,231 @@
+// Long-running work that answers its request right away and runs in the background,
+// polled through /jobs. With persistence enabled the registry is kept in `jobs.json` in
+// the bin directory, rewritten whenever a job starts or ends, so finished jobs can still
+// be looked up after a restart; jobs a restart cut short are reported as interrupted.
+// Work that must survive a restart keeps its own checkpoint and is resumed by starting
+// it again.
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::api::{JobState, JobStatus};
+use crate::cancel::CancellationToken;
+use crate::manifest::unix_seconds;
+
+pub const JOBS_FILE: &str = "jobs.json";
+
+// Finished jobs kept for polling; older ones are forgotten first
+const MAX_FINISHED_JOBS: usize = 64;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobKind {
+    Reembed,
+    Rebuild,
+    Import,
+    DeleteByFilter,
+    Restore,
+    AuditSearch,
+    VerifyAll,
+}
+
+// How a job ended
+#[derive(Debug, Clone)]
+struct Finished {
+    at: SystemTime,
+    state: JobState,
+    http_status: Option<u16>,
+    result: Option<Value>,
+    error: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct Job {
+    pub id: u64, // 0 for work run in place, which is never registered
+    pub kind: JobKind,
+    pub tree_name: Option<String>,
+    pub target: Option<String>, // Tree the job writes to, when not `tree_name` itself
+    started_at: SystemTime,
+    pub processed: AtomicU64,
+    pub total: AtomicU64,
+    pub failures: AtomicU64,    // Items given up on; the job carries on without them
+    pub retries: AtomicU64,     // Retried calls to outside services
+    pub resumed_after_seq: AtomicU64, // Sequence number the job picked up after, 0 for a fresh start
+    pub cancel: CancellationToken, // Cancelled by DELETE /jobs/{id}; the work stops at its next safe point
+    stopped: AtomicBool,        // The work noticed the cancellation and gave up
+    finished: Mutex<Option<Finished>>,
+}
+
+impl Job {
+    fn new(id: u64, kind: JobKind, tree_name: Option<&str>, target: Option<&str>) -> Self {
+        Job {
+            id,
+            kind,
+            tree_name: tree_name.map(str::to_string),
+            target: target.map(str::to_string),
+            started_at: SystemTime::now(),
+            processed: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+            retries: AtomicU64::new(0),
+            resumed_after_seq: AtomicU64::new(0),
+            cancel: CancellationToken::new(),
+            stopped: AtomicBool::new(false),
+            finished: Mutex::new(None),
+        }
+    }
+
+    // For work run in place: the same progress counters, but nobody can poll or cancel it
+    pub fn detached(kind: JobKind, tree_name: Option<&str>) -> Self {
+        Job::new(0, kind, tree_name, None)
+    }
+
+    // A job read back from `jobs.json`. One that was still running when it was written
+    // never got to finish.
+    fn restored(status: JobStatus) -> Self {
+        let mut job = Job::new(status.id, status.kind, status.tree_name.as_deref(), status.target.as_deref());
+        job.started_at = UNIX_EPOCH + Duration::from_secs(status.started_at);
+        job.processed = AtomicU64::new(status.processed);
+        job.total = AtomicU64::new(status.total);
+        job.failures = AtomicU64::new(status.failures);
+        job.retries = AtomicU64::new(status.retries);
+        job.resumed_after_seq = AtomicU64::new(status.resumed_after_seq.unwrap_or_default());
+        let interrupted = status.state == JobState::Running;
+        job.finished = Mutex::new(Some(Finished {
+            at: job.started_at + Duration::from_secs(status.elapsed_secs),
+            state: if interrupted { JobState::Interrupted } else { status.state },
+            http_status: status.http_status,
+            result: status.result,
+            error: match interrupted {
+                true => Some("The server restarted while the job was running".to_string()),
+                false => status.error,
+            },
+        }));
+        job
+    }
+
+    // Called by the work at its safe points: whether it should give up now. Once it says
+    // so the job ends as cancelled, whatever the work reports afterwards.
+    pub fn stop_if_cancelled(&self) -> bool {
+        if self.cancel.is_cancelled() {
+            self.stopped.store(true, Ordering::Relaxed);
+        }
+        self.stopped.load(Ordering::Relaxed)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished.lock().unwrap().is_some()
+    }
+
+    pub fn status(&self) -> JobStatus {
+        let finished = self.finished.lock().unwrap().clone();
+        let resumed_after_seq = self.resumed_after_seq.load(Ordering::Relaxed);
+        let ended_at = finished.as_ref().map_or_else(SystemTime::now, |finished| finished.at);
+        JobStatus {
+            id: self.id,
+            kind: self.kind,
+            tree_name: self.tree_name.clone(),
+            target: self.target.clone(),
+            state: finished.as_ref().map_or(JobState::Running, |finished| finished.state),
+            started_at: unix_seconds(self.started_at),
+            elapsed_secs: ended_at.duration_since(self.started_at).map_or(0, |d| d.as_secs()),
+            processed: self.processed.load(Ordering::Relaxed),
+            total: self.total.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+            retries: self.retries.load(Ordering::Relaxed),
+            resumed_after_seq: (resumed_after_seq > 0).then_some(resumed_after_seq),
+            cancel_requested: self.cancel.is_cancelled(),
+            http_status: finished.as_ref().and_then(|finished| finished.http_status),
+            result: finished.as_ref().and_then(|finished| finished.result.clone()),
+            error: finished.and_then(|finished| finished.error),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Jobs {
+    next_id: AtomicU64,
+    jobs: Mutex<BTreeMap<u64, Arc<Job>>>,
+    directory: Option<PathBuf>, // Where `jobs.json` is kept; None when persistence is disabled
+}
+
+impl Jobs {
+    // Reads `jobs.json` from `directory` if there is one; an unreadable file is reported
+    // and ignored, since it only holds history. Ids go on from the current time in
+    // milliseconds, so a client polling across a restart never gets somebody else's job.
+    pub fn open(directory: Option<&Path>) -> Self {
+        let mut jobs = BTreeMap::new();
+        if let Some(path) = directory.map(|directory| directory.join(JOBS_FILE)) {
+            match fs::read(&path).map(|bytes| serde_json::from_slice::<Vec<JobStatus>>(&bytes)) {
+                Ok(Ok(statuses)) => jobs.extend(statuses.into_iter().map(|status| (status.id, Arc::new(Job::restored(status))))),
+                Ok(Err(e)) => println!("Ignoring unreadable job history {:?}: {}", path, e),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => println!("Ignoring unreadable job history {:?}: {}", path, e),
+            }
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(1, |d| d.as_millis() as u64);
+        let first = jobs.keys().next_back().map_or(now, |last| now.max(last + 1));
+        Jobs { next_id: AtomicU64::new(first), jobs: Mutex::new(jobs), directory: directory.map(Path::to_path_buf) }
+    }
+
+    // Registers a running job, forgetting the oldest finished ones beyond the limit
+    pub fn start(&self, kind: JobKind, tree_name: Option<&str>, target: Option<&str>) -> Arc<Job> {
+        let job = Arc::new(Job::new(self.next_id.fetch_add(1, Ordering::Relaxed), kind, tree_name, target));
+        let mut jobs = self.jobs.lock().unwrap();
+        let finished: Vec<u64> = jobs.values().filter(|job| job.is_finished()).map(|job| job.id).collect();
+        for id in finished.iter().take(finished.len().saturating_sub(MAX_FINISHED_JOBS - 1)) {
+            jobs.remove(id);
+        }
+        jobs.insert(job.id, job.clone());
+        self.save(&jobs);
+        job
+    }
+
+    // Records how the job ended: `http_status` and the body are what the request would
+    // have been answered had it not run in the background
+    pub fn finish(&self, job: &Job, http_status: Option<u16>, outcome: Result<Option<Value>, String>) {
+        let state = match (&outcome, job.stopped.load(Ordering::Relaxed)) {
+            (_, true) => JobState::Cancelled,
+            (Ok(_), false) => JobState::Succeeded,
+            (Err(_), false) => JobState::Failed,
+        };
+        let (result, error) = match outcome {
+            Ok(result) => (result, None),
+            Err(e) => (None, Some(e)),
+        };
+        *job.finished.lock().unwrap() = Some(Finished { at: SystemTime::now(), state, http_status, result, error });
+        if job.id != 0 {
+            self.save(&self.jobs.lock().unwrap());
+        }
+    }
+
+    pub fn get(&self, id: u64) -> Option<Arc<Job>> {
+        self.jobs.lock().unwrap().get(&id).cloned()
+    }
+
+    // Newest first
+    pub fn list(&self) -> Vec<JobStatus> {
+        self.jobs.lock().unwrap().values().rev().map(|job| job.status()).collect()
+    }
+
+    // Written to a temporary file and renamed so a crash never leaves a torn file. A
+    // failure is only logged: the jobs themselves go on.
+    fn save(&self, jobs: &BTreeMap<u64, Arc<Job>>) {
+        let Some(directory) = &self.directory else { return };
+        let path = directory.join(JOBS_FILE);
+        let tmp_path = path.with_extension("json.tmp");
+        let statuses: Vec<JobStatus> = jobs.values().map(|job| job.status()).collect();
+        let saved = serde_json::to_vec_pretty(&statuses).map_err(io::Error::other)
+            .and_then(|bytes| fs::write(&tmp_path, bytes))
+            .and_then(|()| fs::rename(&tmp_path, &path));
+        if let Err(e) = saved {
+            println!("Failed to save job history {:?}: {}", path, e);
+        }
+    }
+}
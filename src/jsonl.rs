@@ -0,0 +1,215 @@
+// JSONL imports: one point per line, as /export writes them, with the embedding either as
+// an array or packed in `embedding_b64`. The file is read a chunk of lines at a time, so
+// a bad line only costs itself and nothing but the tree being built grows with the file.
+// Progress is kept next to the tree file as `{tree_name}.import`, with the partly imported
+// tree as `{tree_name}.bin.import`, so an import cut short by a crash or a failure picks
+// up after its last checkpoint when started again.
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::api::{ChunkImport, RejectedLine};
+use crate::encoding::PointInput;
+use crate::import::MAX_REPORTED_REJECTIONS;
+use crate::kdtree::Point;
+use crate::schema::Schema;
+
+// Lines parsed, validated and inserted at a time
+pub const IMPORT_CHUNK_LINES: usize = 10_000;
+
+// What a line must satisfy to be imported
+pub struct LineRules<'a> {
+    pub dimensions: Option<usize>, // Required length; the first accepted line sets it when None
+    pub max_dimensions: usize,
+    pub max_data_bytes: usize,
+    pub max_line_bytes: usize,
+    pub schema: Option<&'a Schema>,
+}
+
+// How far into the file an import got, always at a line boundary
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Position {
+    pub offset: u64,
+    pub lines: usize,
+    pub chunks: usize,
+}
+
+// What an import run did, for its response
+#[derive(Debug, Default)]
+pub struct JsonlImport {
+    pub imported: usize,
+    pub content_bytes: usize, // Content of the imported points, see `Point::content_size`
+    pub rejected: usize,
+    pub resumed_from_line: Option<usize>,
+    pub chunks: Vec<ChunkImport>,
+    pub rejected_lines: Vec<RejectedLine>, // The first `MAX_REPORTED_REJECTIONS`
+}
+
+pub struct Chunk {
+    pub points: Vec<Point>,
+    pub report: ChunkImport,
+}
+
+pub struct JsonlReader {
+    reader: BufReader<File>,
+    position: Position,
+    line: Vec<u8>,
+}
+
+impl JsonlReader {
+    pub fn open(path: &Path, position: Position) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(position.offset))?;
+        Ok(JsonlReader { reader: BufReader::new(file), position, line: Vec::new() })
+    }
+
+    pub fn position(&self) -> Position {
+        self.position
+    }
+
+    // The next IMPORT_CHUNK_LINES lines, or None once the file is read. Lines that can't
+    // become points are counted, and listed in `rejected_lines` up to
+    // MAX_REPORTED_REJECTIONS; blank lines are skipped. Only reading the file can fail.
+    pub fn next_chunk(&mut self, rules: &mut LineRules, rejected_lines: &mut Vec<RejectedLine>) -> io::Result<Option<Chunk>> {
+        let first_line = self.position.lines + 1;
+        let mut chunk = Chunk {
+            points: Vec::new(),
+            report: ChunkImport { chunk: self.position.chunks, first_line, lines: 0, imported: 0, rejected: 0 },
+        };
+        while chunk.report.lines < IMPORT_CHUNK_LINES {
+            let Some(read) = self.read_line(rules.max_line_bytes)? else { break };
+            chunk.report.lines += 1;
+            if read.is_ok() && self.line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            match read.and_then(|()| point_from_line(&self.line, rules)) {
+                Ok(point) => {
+                    chunk.points.push(point);
+                    chunk.report.imported += 1;
+                }
+                Err(reason) => {
+                    chunk.report.rejected += 1;
+                    if rejected_lines.len() < MAX_REPORTED_REJECTIONS {
+                        rejected_lines.push(RejectedLine { line: self.position.lines, reason });
+                    }
+                }
+            }
+        }
+        if chunk.report.lines == 0 {
+            return Ok(None);
+        }
+        self.position.chunks += 1;
+        Ok(Some(chunk))
+    }
+
+    // Reads the next line, without its line break, into `self.line`. A line longer than
+    // `max_bytes` is skipped to its end and rejected. None at the end of the file.
+    fn read_line(&mut self, max_bytes: usize) -> io::Result<Option<Result<(), String>>> {
+        self.line.clear();
+        let read = (&mut self.reader).take(max_bytes as u64 + 1).read_until(b'\n', &mut self.line)?;
+        if read == 0 {
+            return Ok(None);
+        }
+        self.position.offset += read as u64;
+        self.position.lines += 1;
+        if self.line.last() == Some(&b'\n') {
+            self.line.pop();
+            if self.line.last() == Some(&b'\r') {
+                self.line.pop();
+            }
+        } else if self.line.len() > max_bytes {
+            loop {
+                let available = self.reader.fill_buf()?;
+                if available.is_empty() {
+                    break;
+                }
+                let (consumed, done) = match available.iter().position(|byte| *byte == b'\n') {
+                    Some(end) => (end + 1, true),
+                    None => (available.len(), false),
+                };
+                self.reader.consume(consumed);
+                self.position.offset += consumed as u64;
+                if done {
+                    break;
+                }
+            }
+            return Ok(Some(Err(format!("line is longer than {} bytes", max_bytes))));
+        }
+        Ok(Some(Ok(())))
+    }
+}
+
+fn point_from_line(line: &[u8], rules: &mut LineRules) -> Result<Point, String> {
+    let input: PointInput = serde_json::from_slice(line).map_err(|e| format!("not a JSON point: {}", e))?;
+    let point = input.into_point()?;
+    let dimensions = point.embedding.len();
+    match rules.dimensions {
+        Some(expected) if dimensions != expected => {
+            return Err(format!("embedding has {} values but the tree has {} dimensions", dimensions, expected));
+        }
+        Some(_) => {}
+        None if dimensions == 0 => return Err("embedding is empty".to_string()),
+        None if dimensions > rules.max_dimensions => {
+            return Err(format!("embedding has {} values but MAX_DIMENSIONS is {}", dimensions, rules.max_dimensions));
+        }
+        None => {}
+    }
+    if let Some(size) = point.data.as_ref().map(String::len).filter(|size| *size > rules.max_data_bytes) {
+        return Err(format!("data is {} bytes but MAX_DATA_BYTES is {}", size, rules.max_data_bytes));
+    }
+    if let Some(Err(problem)) = rules.schema.map(|schema| schema.check(&point.metadata)) {
+        return Err(format!("metadata does not match the schema: {}", problem));
+    }
+    rules.dimensions = Some(dimensions);
+    Ok(point)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ImportCheckpoint {
+    pub path: PathBuf,       // The file being imported
+    pub file_size: u64,      // Its size when the import started; a file changed since starts over
+    pub base_next_seq: u64,  // The tree's next sequence number before the import, 0 for a new tree; a tree changed since starts over
+    pub staged_next_seq: u64, // The staged tree's next sequence number as of `position`; later points are from a chunk saved after the checkpoint
+    pub position: Position,  // Lines before it are in the staged tree
+}
+
+impl ImportCheckpoint {
+    pub fn path(bin_directory: &Path, tree_name: &str) -> PathBuf {
+        bin_directory.join(format!("{}.import", tree_name))
+    }
+
+    // Not `.bin`, so it is never taken for a tree of its own
+    pub fn staged_path(bin_directory: &Path, tree_name: &str) -> PathBuf {
+        bin_directory.join(format!("{}.bin.import", tree_name))
+    }
+
+    // None when no import into the tree was cut short
+    pub fn load(bin_directory: &Path, tree_name: &str) -> io::Result<Option<ImportCheckpoint>> {
+        match fs::read(Self::path(bin_directory, tree_name)) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map(Some).map_err(io::Error::other),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Written to a temporary file and renamed so a crash never leaves a torn checkpoint.
+    // The staged tree must be saved first.
+    pub fn save(&self, bin_directory: &Path, tree_name: &str) -> io::Result<()> {
+        let path = Self::path(bin_directory, tree_name);
+        let tmp_path = path.with_extension("import.tmp");
+        fs::write(&tmp_path, serde_json::to_vec(self).map_err(io::Error::other)?)?;
+        fs::rename(tmp_path, path)
+    }
+
+    // Removes the checkpoint and the staged tree
+    pub fn remove(bin_directory: &Path, tree_name: &str) -> io::Result<()> {
+        for path in [Self::path(bin_directory, tree_name), Self::staged_path(bin_directory, tree_name)] {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
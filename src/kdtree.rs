@@ -1,14 +1,130 @@
 use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fs::File;
-use std::io::{self};
-use bincode;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::borrow::Cow;
 use std::cmp::Ordering;
+use std::fmt;
+
+use crate::cancel::{CancellationToken, Cancelled, CHECK_INTERVAL};
+use crate::distance::{self, Euclidean, Metric};
+use crate::metadata::{Metadata, MetadataValue};
+use crate::payload::{self, Payload, PayloadFile, PayloadRef, TreeImage};
+use crate::reduction::RandomProjection;
+use crate::rng::SplitMix64;
+use crate::schema::Schema;
+use crate::stats::TreeStats;
+use crate::histogram::DistanceHistogram;
+use crate::trace::{Branch, Trace};
+
+// Every tree file starts with this magic followed by a little-endian format version.
+// Files without it predate the header and use the v0 layout. Since version 7 the
+// serialized tree is followed by the payload lengths and the payload section. Version 8
+// adds the metadata schema, version 9 the embedding model tag, version 10 each point's norm.
+pub const FILE_MAGIC: &[u8; 4] = b"VODB";
+pub const FORMAT_VERSION: u32 = 10;
+
+// Relative difference `validate` allows between a stored norm and the recomputed one
+pub const NORM_EPSILON: f64 = 1e-9;
+
+// Version 9 points, which scans decode without loading the tree
+pub(crate) use legacy::PointV9;
+
+// Why a tree operation failed. Callers tell a file that is missing or unreadable (`Io`)
+// from one that was read but holds no valid tree (`Corrupt`), and both from requests
+// the tree can't satisfy.
+#[derive(Debug, thiserror::Error)]
+pub enum KdTreeError {
+    #[error(transparent)]
+    Io(io::Error),
+    #[error("Failed to serialize tree: {0}")]
+    Serialization(#[source] bincode::Error),
+    #[error("Corrupt tree file: {detail}")]
+    Corrupt {
+        detail: String,
+        #[source]
+        source: Option<bincode::Error>,
+    },
+    #[error("Expected {expected} dimensions but got {got}")]
+    DimensionMismatch { expected: usize, got: usize },
+    #[error("KD-Tree dimension must be at least 1")]
+    ZeroDimensions,
+    #[error("Unsupported tree file version {0}")]
+    UnsupportedVersion(u32),
+    #[error("Failed to load {failures} times in a row, next attempt in {retry_in_secs}s: {error}")]
+    LoadBackoff { error: String, failures: u32, retry_in_secs: u64 },
+}
+
+impl KdTreeError {
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, KdTreeError::Io(e) if e.kind() == io::ErrorKind::NotFound)
+    }
+
+    // A failure to deserialize `filename`. Read errors stay I/O errors, but running out
+    // of bytes means the file was cut short.
+    pub(crate) fn unreadable(filename: &str, e: bincode::ErrorKind) -> Self {
+        match e {
+            bincode::ErrorKind::Io(e) if e.kind() != io::ErrorKind::UnexpectedEof => KdTreeError::Io(e),
+            kind => KdTreeError::Corrupt {
+                detail: format!("{} does not hold a readable tree", filename),
+                source: Some(Box::new(kind)),
+            },
+        }
+    }
+}
+
+impl From<io::Error> for KdTreeError {
+    fn from(e: io::Error) -> Self {
+        KdTreeError::Io(e)
+    }
+}
+
+// Write failures; deserialization goes through `unreadable` instead
+impl From<bincode::Error> for KdTreeError {
+    fn from(e: bincode::Error) -> Self {
+        match *e {
+            bincode::ErrorKind::Io(e) => KdTreeError::Io(e),
+            kind => KdTreeError::Serialization(Box::new(kind)),
+        }
+    }
+}
+
+// For callers that only deal in `io::Error`, e.g. embedded `Collection` use. The tree
+// error is kept as the source.
+impl From<KdTreeError> for io::Error {
+    fn from(e: KdTreeError) -> Self {
+        let kind = match &e {
+            KdTreeError::Io(e) => e.kind(),
+            KdTreeError::DimensionMismatch { .. } | KdTreeError::ZeroDimensions => io::ErrorKind::InvalidInput,
+            KdTreeError::Corrupt { .. } | KdTreeError::UnsupportedVersion(_) => io::ErrorKind::InvalidData,
+            KdTreeError::Serialization(_) => io::ErrorKind::Other,
+            KdTreeError::LoadBackoff { .. } => io::ErrorKind::WouldBlock,
+        };
+        match e {
+            KdTreeError::Io(e) => e,
+            e => io::Error::new(kind, e),
+        }
+    }
+}
 
 // Struct to hold the embedding and associated data
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct Point {
-    pub embedding: Vec<f64>, // Embedding vector
-    pub data: String,        // Associated data (chunk)
+    pub embedding: Vec<f64>,  // Embedding vector
+    #[serde(default)]
+    pub data: Option<String>, // Associated data (chunk), absent for embedding-only points
+    #[serde(default)]
+    pub metadata: Metadata,   // Optional attributes, e.g. the document a chunk belongs to
+    #[serde(default)]
+    pub seq: u64,             // Assigned by the tree on insert, increasing in insertion order
+    #[serde(default)]
+    pub inserted_at: u64,     // Unix seconds, assigned on insert; 0 for points from older files
+    #[serde(default, skip_serializing)]
+    pub norm: f64,            // L2 norm of the stored embedding, set by the tree; never sent to clients
+    #[serde(skip)]
+    pub stored: Option<PayloadRef>, // Where `data` is on disk, for points whose data was left there on load
 }
 
 impl Point {
@@ -16,34 +132,503 @@ impl Point {
     pub fn len(&self) -> usize {
         self.embedding.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.embedding.is_empty()
+    }
+
+    pub fn metadata_value(&self, field: &str) -> Option<&MetadataValue> {
+        self.metadata.get(field)
+    }
+
+    // The associated data, read from the tree file if it was left on disk. A payload
+    // that can no longer be read is reported and treated as absent.
+    pub fn data(&self) -> Option<Cow<'_, str>> {
+        if let Some(data) = &self.data {
+            return Some(Cow::Borrowed(data));
+        }
+        match self.stored.as_ref()?.read() {
+            Ok(data) => Some(Cow::Owned(data.to_string())),
+            Err(e) => {
+                println!("Failed to read point data from its tree file: {}", e);
+                None
+            }
+        }
+    }
+
+    // Approximate heap bytes owned by this point
+    pub fn heap_size(&self) -> usize {
+        let embedding = self.embedding.capacity() * std::mem::size_of::<f64>();
+        let data = self.data.as_ref().map_or(0, String::capacity);
+        let metadata: usize = self.metadata.iter().map(|(key, value)| {
+            let value_size = match value {
+                MetadataValue::String(value) => value.capacity(),
+                _ => 0,
+            };
+            key.capacity() + std::mem::size_of::<MetadataValue>() + value_size
+        }).sum();
+        embedding + data + metadata
+    }
+
+    // Bytes of content the point carries: its components, data, and metadata keys and
+    // values, without the overhead of holding or storing them. Write amplification is
+    // measured against it.
+    pub fn content_size(&self) -> usize {
+        let embedding = self.embedding.len() * std::mem::size_of::<f64>();
+        let data = self.data.as_ref().map_or(0, String::len);
+        let metadata: usize = self.metadata.iter().map(|(key, value)| {
+            key.len() + match value {
+                MetadataValue::Bool(_) => 1,
+                MetadataValue::Number(_) => std::mem::size_of::<f64>(),
+                MetadataValue::String(value) => value.len(),
+            }
+        }).sum();
+        embedding + data + metadata
+    }
+}
+
+// A group value from a grouped search with its closest points
+pub type GroupHits<'a> = (Option<&'a MetadataValue>, Vec<(f64, &'a Point)>);
+
+// A group copied out of the tree
+pub type OwnedGroupHits = (Option<MetadataValue>, Vec<(f64, Point)>);
+
+// Clones scored hits out of the tree, for callers that must not keep borrowing it
+pub fn owned_hits(hits: Vec<(f64, &Point)>) -> Vec<(f64, Point)> {
+    hits.into_iter().map(|(distance, point)| (distance, point.clone())).collect()
+}
+
+// Point as written in the tree section of a file: everything but the data, which goes
+// to the payload section. Same layout as a deserialized `Point` with no data.
+#[derive(Serialize)]
+struct StoredPoint<'a> {
+    embedding: &'a [f64],
+    data: Option<&'a str>,
+    metadata: &'a Metadata,
+    seq: u64,
+    inserted_at: u64,
+    norm: f64,
+}
+
+fn serialize_without_data<S: serde::Serializer>(point: &Point, serializer: S) -> Result<S::Ok, S::Error> {
+    StoredPoint {
+        embedding: &point.embedding,
+        data: None,
+        metadata: &point.metadata,
+        seq: point.seq,
+        inserted_at: point.inserted_at,
+        norm: point.norm,
+    }.serialize(serializer)
 }
 
 // KD-Tree Node
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Node {
+    #[serde(serialize_with = "serialize_without_data")]
     point: Point,
     pub left: Option<Box<Node>>,
     pub right: Option<Box<Node>>,
     axis: usize,
 }
 
+impl Node {
+    pub fn point(&self) -> &Point {
+        &self.point
+    }
+
+    // Axis this node splits on
+    pub fn axis(&self) -> usize {
+        self.axis
+    }
+
+    // Coordinate of the splitting plane on `axis`
+    pub fn split_value(&self, axis: usize) -> f64 {
+        self.point.embedding[axis]
+    }
+
+    // Side of the plane an embedding belongs to. Values equal to the split go right,
+    // for inserts, searches and median builds alike.
+    pub fn side_of(&self, embedding: &[f64], axis: usize) -> Branch {
+        if embedding[axis] < self.split_value(axis) { Branch::Left } else { Branch::Right }
+    }
+
+    // The child on the target's side of the plane, then the other one
+    pub fn branches(&self, target: &[f64], axis: usize) -> (&Option<Box<Node>>, &Option<Box<Node>>, Branch) {
+        match self.side_of(target, axis) {
+            Branch::Left => (&self.left, &self.right, Branch::Left),
+            Branch::Right => (&self.right, &self.left, Branch::Right),
+        }
+    }
+
+    // The child slot an embedding is inserted into
+    fn branch_mut(&mut self, embedding: &[f64], axis: usize) -> &mut Option<Box<Node>> {
+        match self.side_of(embedding, axis) {
+            Branch::Left => &mut self.left,
+            Branch::Right => &mut self.right,
+        }
+    }
+
+    // Distance from the target to the splitting plane along `axis`
+    pub fn plane_distance(&self, target: &[f64], axis: usize) -> f64 {
+        (target[axis] - self.split_value(axis)).abs()
+    }
+
+    // Whether the far side of the plane can hold a point closer than `bound`
+    pub fn far_side_may_beat(&self, target: &[f64], axis: usize, bound: f64) -> bool {
+        Euclidean.lower_bound_on_axis(self.plane_distance(target, axis)).is_none_or(|lower_bound| lower_bound < bound)
+    }
+
+    // Whether the far side of the plane can hold a point at most `bound` away
+    pub fn far_side_may_reach(&self, target: &[f64], axis: usize, bound: f64) -> bool {
+        Euclidean.lower_bound_on_axis(self.plane_distance(target, axis)).is_none_or(|lower_bound| lower_bound <= bound)
+    }
+}
+
 // KD-Tree structure
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct KDTree {
     pub root: Option<Box<Node>>,
     k: usize,  // Number of dimensions
+    partition_field: Option<String>,                    // Metadata field that buckets points into subtrees
+    partitions: BTreeMap<String, Option<Box<Node>>>,  // One subtree per partition value
+    next_seq: u64,  // Sequence number given to the next inserted point
+    stats: TreeStats,  // Distribution of the embeddings, updated on every insert
+    reduction: Option<RandomProjection>,  // Applied to embeddings before they reach the tree
+    schema: Option<Schema>,  // Metadata points must carry; any metadata when None
+    model: Option<String>,   // Embedding model the tree's vectors come from, if it was named
+    #[serde(skip)]
+    len: usize,  // Number of points, recounted on load rather than stored
 }
 
 impl KDTree {
-    pub fn new(k: usize) -> Self {
-        KDTree { root: None, k }
+    pub fn new(k: usize) -> Result<Self, KdTreeError> {
+        // Axis selection is `depth % k`, so a zero-dimensional tree can never work
+        if k == 0 {
+            return Err(KdTreeError::ZeroDimensions);
+        }
+        Ok(KDTree {
+            root: None,
+            k,
+            partition_field: None,
+            partitions: BTreeMap::new(),
+            next_seq: 1,
+            stats: TreeStats::new(k),
+            reduction: None,
+            schema: None,
+            model: None,
+            len: 0,
+        })
+    }
+
+    // A tree whose points are bucketed by the value of their `field` metadata. Points
+    // without the field stay in the shared root.
+    pub fn with_partition_field(k: usize, field: &str) -> Result<Self, KdTreeError> {
+        let mut tree = KDTree::new(k)?;
+        tree.partition_field = Some(field.to_string());
+        Ok(tree)
+    }
+
+    // A tree that stores embeddings projected down to `reduction`'s output dimensions;
+    // callers keep passing embeddings of its input dimensions
+    pub fn with_reduction(mut self, reduction: RandomProjection) -> Result<Self, KdTreeError> {
+        if reduction.output_dimensions() != self.k {
+            return Err(KdTreeError::DimensionMismatch { expected: self.k, got: reduction.output_dimensions() });
+        }
+        self.reduction = Some(reduction);
+        Ok(self)
+    }
+
+    // Dimensions of the stored embeddings
+    pub fn dimensions(&self) -> usize {
+        self.k
+    }
+
+    // Dimensions of the embeddings callers insert and query with
+    pub fn input_dimensions(&self) -> usize {
+        self.reduction.as_ref().map_or(self.k, RandomProjection::input_dimensions)
+    }
+
+    pub fn reduction(&self) -> Option<&RandomProjection> {
+        self.reduction.as_ref()
+    }
+
+    // The point as stored: with its embedding projected if the tree reduces dimensions
+    pub fn reduce<'a>(&self, point: Cow<'a, Point>) -> Cow<'a, Point> {
+        match &self.reduction {
+            Some(reduction) => {
+                let mut point = point.into_owned();
+                point.embedding = reduction.apply(&point.embedding);
+                Cow::Owned(point)
+            }
+            None => point,
+        }
+    }
+
+    pub fn stats(&self) -> &TreeStats {
+        &self.stats
+    }
+
+    pub fn partition_field(&self) -> Option<&str> {
+        self.partition_field.as_deref()
+    }
+
+    pub fn schema(&self) -> Option<&Schema> {
+        self.schema.as_ref()
+    }
+
+    // Replaces the metadata schema. Stored points are not checked against it.
+    pub fn set_schema(&mut self, schema: Option<Schema>) {
+        self.schema = schema;
+    }
+
+    pub fn model(&self) -> Option<&str> {
+        self.model.as_deref()
+    }
+
+    pub fn set_model(&mut self, model: Option<String>) {
+        self.model = model;
+    }
+
+    // The partition a point belongs to, or None when it lives in the shared root
+    pub fn partition_of(&self, point: &Point) -> Option<String> {
+        let field = self.partition_field.as_deref()?;
+        point.metadata_value(field).map(|value| value.to_string())
+    }
+
+    // Subtrees a search walks: just the named partition, or everything when unfiltered
+    fn search_roots<'a>(&'a self, partition: Option<&str>) -> Vec<&'a Option<Box<Node>>> {
+        match partition {
+            Some(partition) => self.partitions.get(partition).into_iter().collect(),
+            None => std::iter::once(&self.root).chain(self.partitions.values()).collect(),
+        }
+    }
+
+    // Root nodes of the shared tree and every partition subtree
+    pub fn roots(&self) -> impl Iterator<Item = &Node> {
+        self.search_roots(None).into_iter().filter_map(|root| root.as_deref())
+    }
+
+    // Like `roots`, paired with the partition value each subtree holds, None for the shared tree
+    pub fn labelled_roots(&self) -> impl Iterator<Item = (Option<&str>, &Node)> {
+        std::iter::once((None, &self.root))
+            .chain(self.partitions.iter().map(|(partition, root)| (Some(partition.as_str()), root)))
+            .filter_map(|(partition, root)| root.as_deref().map(|root| (partition, root)))
+    }
+
+    // Number of points stored in each partition
+    pub fn partition_counts(&self) -> BTreeMap<String, usize> {
+        self.partitions
+            .iter()
+            .map(|(partition, root)| (partition.clone(), Self::count_nodes(root)))
+            .collect()
+    }
+
+    // Rebuilds the shared root and every partition subtree as balanced trees
+    pub fn rebuilt(self) -> Result<Self, KdTreeError> {
+        let mut tree = KDTree::new(self.k)?;
+        tree.partition_field = self.partition_field;
+        tree.reduction = self.reduction;
+        tree.schema = self.schema;
+        tree.model = self.model;
+        tree.next_seq = self.next_seq;
+        tree.len = self.len;
+        tree.root = KDTree::build_recursive(Self::collect_points(self.root), 0, self.k);
+        for (partition, root) in self.partitions {
+            let root = KDTree::build_recursive(Self::collect_points(root), 0, self.k);
+            tree.partitions.insert(partition, root);
+        }
+        // Recomputed rather than carried over so rounding drift from inserts is dropped
+        tree.recompute_stats();
+        Ok(tree)
+    }
+
+    // Adds a large batch of points and rebuilds every subtree balanced, far cheaper than
+    // inserting them one by one. Points are stamped and partitioned as by `insert` and
+    // must already be reduced to the stored dimensions.
+    pub fn extended(mut self, points: Vec<Point>) -> Result<Self, KdTreeError> {
+        if let Some(point) = points.iter().find(|point| point.embedding.len() != self.k) {
+            return Err(KdTreeError::DimensionMismatch { expected: self.k, got: point.embedding.len() });
+        }
+        let mut shared = Self::collect_points(self.root.take());
+        let mut partitions: BTreeMap<String, Vec<Point>> = std::mem::take(&mut self.partitions)
+            .into_iter()
+            .map(|(partition, root)| (partition, Self::collect_points(root)))
+            .collect();
+        self.len += points.len();
+        for mut point in points {
+            self.stamp(&mut point);
+            match self.partition_of(&point) {
+                Some(partition) => partitions.entry(partition).or_default().push(point),
+                None => shared.push(point),
+            }
+        }
+        self.root = KDTree::build_recursive(shared, 0, self.k);
+        for (partition, points) in partitions {
+            let root = KDTree::build_recursive(points, 0, self.k);
+            self.partitions.insert(partition, root);
+        }
+        Ok(self)
+    }
+
+    // An empty tree with the same dimensions, partition field, projection, schema and model. Sequence
+    // numbers carry on, so an export resumed with an old `since_seq` still sees new points.
+    pub fn emptied(&self) -> Result<Self, KdTreeError> {
+        let mut tree = KDTree::new(self.k)?;
+        tree.partition_field = self.partition_field.clone();
+        tree.reduction = self.reduction.clone();
+        tree.schema = self.schema.clone();
+        tree.model = self.model.clone();
+        tree.next_seq = self.next_seq;
+        Ok(tree)
+    }
+
+    // Removes the points `predicate` accepts and rebuilds what is left balanced. Partitions
+    // left empty are dropped, and sequence numbers of removed points are not handed out
+    // again. Returns the tree and how many points were removed.
+    pub fn without(self, predicate: impl Fn(&Point) -> bool) -> Result<(Self, usize), KdTreeError> {
+        let mut tree = KDTree::new(self.k)?;
+        tree.partition_field = self.partition_field;
+        tree.reduction = self.reduction;
+        tree.schema = self.schema;
+        tree.model = self.model;
+        tree.next_seq = self.next_seq;
+        let mut removed = 0;
+        let mut retain = |root| {
+            let mut points = Self::collect_points(root);
+            let before = points.len();
+            points.retain(|point| !predicate(point));
+            removed += before - points.len();
+            points
+        };
+        tree.root = KDTree::build_recursive(retain(self.root), 0, self.k);
+        for (partition, root) in self.partitions {
+            let points = retain(root);
+            if !points.is_empty() {
+                tree.partitions.insert(partition, KDTree::build_recursive(points, 0, self.k));
+            }
+        }
+        tree.len = self.len - removed;
+        tree.recompute_stats();
+        Ok((tree, removed))
+    }
+
+    // Builds a balanced tree in one pass by splitting on the median of each axis.
+    // Points equal to the median always go right, matching `insert`.
+    pub fn build(k: usize, points: Vec<Point>) -> Result<Self, KdTreeError> {
+        let mut tree = KDTree::new(k)?;
+        if let Some(point) = points.iter().find(|point| point.embedding.len() != k) {
+            return Err(KdTreeError::DimensionMismatch { expected: k, got: point.embedding.len() });
+        }
+        tree.len = points.len();
+        tree.stats = TreeStats::from_embeddings(k, points.iter().map(|point| point.embedding.as_slice()));
+        tree.next_seq = points.iter().map(|point| point.seq + 1).max().unwrap_or(1);
+        tree.root = KDTree::build_recursive(points, 0, k);
+        Ok(tree)
+    }
+
+    fn build_recursive(mut points: Vec<Point>, depth: usize, k: usize) -> Option<Box<Node>> {
+        if points.is_empty() {
+            return None;
+        }
+        let axis = depth % k;
+        points.sort_by(|a, b| a.embedding[axis].partial_cmp(&b.embedding[axis]).unwrap_or(Ordering::Equal));
+
+        // Step back over equal values so everything left of the median is strictly smaller
+        let mut median = points.len() / 2;
+        while median > 0 && points[median - 1].embedding[axis] == points[median].embedding[axis] {
+            median -= 1;
+        }
+
+        let right = points.split_off(median + 1);
+        let mut point = points.pop().unwrap();
+        // Rebuilds recompute the norm rather than trust whatever the point carried
+        point.norm = distance::norm(&point.embedding);
+        Some(Box::new(Node {
+            point,
+            left: KDTree::build_recursive(points, depth + 1, k),
+            right: KDTree::build_recursive(right, depth + 1, k),
+            axis,
+        }))
+    }
+
+    // The root of the subtree a point is inserted into
+    fn slot_for(&mut self, point: &Point) -> &mut Option<Box<Node>> {
+        match self.partition_of(point) {
+            Some(partition) => self.partitions.entry(partition).or_default(),
+            None => &mut self.root,
+        }
+    }
+
+    // Gives a point about to be inserted its sequence number, insertion time and norm,
+    // and counts it in the statistics
+    fn stamp(&mut self, point: &mut Point) {
+        point.seq = self.next_seq;
+        point.norm = distance::norm(&point.embedding);
+        point.inserted_at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        self.next_seq += 1;
+        self.stats.add(&point.embedding);
     }
 
-    pub fn insert(&mut self, point: Point) {
-        self.root = KDTree::insert_recursive(self.root.take(), point, 0, self.k);
+    pub fn insert(&mut self, mut point: Point) {
+        self.stamp(&mut point);
+        let k = self.k;
+        let slot = self.slot_for(&point);
+        *slot = KDTree::insert_recursive(slot.take(), point, 0, k);
+        self.len += 1;
 //        self.save_to_file("kd_tree.bin").unwrap();
     }
 
+    // Inserts like `insert`, but if the new point lands deeper than
+    // `depth_factor * log2(n)` the lowest ancestor whose subtree is too deep for its
+    // own size is rebuilt with median splits, so sorted insertion orders can't turn
+    // the tree into a list. Returns whether a subtree was rebuilt.
+    // Identical points can't be split, so a run of duplicates stays a chain.
+    pub fn insert_bounded(&mut self, mut point: Point, depth_factor: f64) -> bool {
+        self.stamp(&mut point);
+        let limit = (depth_factor * ((self.len + 1) as f64).log2()).ceil() as usize;
+        let k = self.k;
+        let slot = self.slot_for(&point);
+        let (root, unwind) = KDTree::insert_bounded_recursive(slot.take(), point, 0, k, limit, depth_factor);
+        *slot = root;
+        self.len += 1;
+        unwind == Unwind::Rebuilt
+    }
+
+    fn insert_bounded_recursive(
+        node: Option<Box<Node>>,
+        point: Point,
+        depth: usize,
+        k: usize,
+        limit: usize,
+        depth_factor: f64,
+    ) -> (Option<Box<Node>>, Unwind) {
+        let Some(mut current_node) = node else {
+            let unwind = if depth > limit { Unwind::Climbing { height: 0, size: 1 } } else { Unwind::Done };
+            return (Some(Box::new(Node { point, left: None, right: None, axis: depth % k })), unwind);
+        };
+
+        let axis = depth % k;
+        let side = current_node.side_of(&point.embedding, axis);
+        let branch = current_node.branch_mut(&point.embedding, axis);
+        let (child, unwind) = KDTree::insert_bounded_recursive(branch.take(), point, depth + 1, k, limit, depth_factor);
+        *branch = child;
+        let other = if side == Branch::Left { &current_node.right } else { &current_node.left };
+
+        // Only a too-deep insertion pays for sizing the subtrees along its path
+        let Unwind::Climbing { height, size } = unwind else {
+            return (Some(current_node), unwind);
+        };
+        let height = height + 1;
+        let size = size + 1 + KDTree::count_nodes(other);
+        if height as f64 > depth_factor * (size as f64).log2() {
+            let points = KDTree::collect_points(Some(current_node));
+            return (KDTree::build_recursive(points, depth, k), Unwind::Rebuilt);
+        }
+        (Some(current_node), Unwind::Climbing { height, size })
+    }
+
     fn insert_recursive(
         node: Option<Box<Node>>,
         point: Point,
@@ -55,11 +640,8 @@ impl KDTree {
             if axis >= point.embedding.len() {
                 panic!("Axis {} is out of bounds for embedding length {}", axis, point.embedding.len());
             }
-            if point.embedding[axis] < current_node.point.embedding[axis] {
-                current_node.left = KDTree::insert_recursive(current_node.left.take(), point, depth + 1, k);
-            } else {
-                current_node.right = KDTree::insert_recursive(current_node.right.take(), point, depth + 1, k);
-            }
+            let branch = current_node.branch_mut(&point.embedding, axis);
+            *branch = KDTree::insert_recursive(branch.take(), point, depth + 1, k);
             Some(current_node)
         } else {
             Some(Box::new(Node {
@@ -71,34 +653,181 @@ impl KDTree {
         }
     }
 
-    pub fn save_to_file(&self, filename: &str) -> Result<(), io::Error> {
-        let file = File::create(filename)?;
-        bincode::serialize_into(file, self).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-        Ok(())
+    pub fn save_to_file(&self, filename: &str) -> Result<(), KdTreeError> {
+        payload::replace_file(Path::new(filename), false, |writer| {
+            self.write_head(writer)?;
+            let mut points = Vec::with_capacity(self.len);
+            self.for_each_point(|point| points.push(point));
+            Ok(points.into_iter().try_for_each(|point| payload::write_payload(writer, point))?)
+        })
+    }
+
+    // The complete file contents, for callers that write the file themselves. Data left
+    // on disk is only referenced and gets copied when the image is written.
+    pub fn to_image(&self) -> Result<TreeImage, KdTreeError> {
+        let mut head = Vec::new();
+        self.write_head(&mut head)?;
+        let mut payloads = Vec::new();
+        self.for_each_point(|point| payloads.extend(Payload::of(point)));
+        Ok(TreeImage { head, payloads })
+    }
+
+    // Everything in front of the payload section
+    fn write_head(&self, writer: &mut impl Write) -> Result<(), KdTreeError> {
+        writer.write_all(FILE_MAGIC)?;
+        writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        bincode::serialize_into(&mut *writer, self)?;
+        let mut points = Vec::with_capacity(self.len);
+        self.for_each_point(|point| points.push(point));
+        Ok(bincode::serialize_into(writer, &payload::payload_lengths(points))?)
+    }
+
+    pub fn load_from_file(filename: &str) -> Result<Self, KdTreeError> {
+        Self::load_versioned(filename).map(|(tree, _)| tree)
     }
 
-    pub fn load_from_file(filename: &str) -> Result<Self, io::Error> {
-        let file = File::open(filename)?;
-        let tree: KDTree = bincode::deserialize_from(file).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    // Loads a tree in any supported layout, converting it in memory to the current
+    // one, and reports the version the file was written with (0 for headerless files)
+    pub fn load_versioned(filename: &str) -> Result<(Self, u32), KdTreeError> {
+        let mut reader = BufReader::new(File::open(filename)?);
+        let mut header = [0u8; 8];
+        let has_header = reader.read_exact(&mut header).is_ok() && &header[..4] == FILE_MAGIC;
+
+        if !has_header {
+            reader.seek(SeekFrom::Start(0))?;
+            let tree: legacy::LegacyKDTree<legacy::PointV0> =
+                bincode::deserialize_from(reader).map_err(|e| KdTreeError::unreadable(filename, *e))?;
+            return Ok((tree.into(), 0));
+        }
+
+        let version = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        match version {
+            9 => {
+                let tree: legacy::KDTreeV9 = bincode::deserialize_from(&mut reader).map_err(|e| KdTreeError::unreadable(filename, *e))?;
+                Ok((Self::with_payload_section(tree.into(), reader, filename)?, 9))
+            }
+            8 => {
+                let tree: legacy::KDTreeV8 = bincode::deserialize_from(&mut reader).map_err(|e| KdTreeError::unreadable(filename, *e))?;
+                Ok((Self::with_payload_section(tree.into(), reader, filename)?, 8))
+            }
+            7 => {
+                let tree: legacy::KDTreeV7 = bincode::deserialize_from(&mut reader).map_err(|e| KdTreeError::unreadable(filename, *e))?;
+                Ok((Self::with_payload_section(tree.into(), reader, filename)?, 7))
+            }
+            6 => {
+                let tree: legacy::KDTreeV7 = bincode::deserialize_from(reader).map_err(|e| KdTreeError::unreadable(filename, *e))?;
+                Ok((tree.into(), 6))
+            }
+            5 => {
+                let tree: legacy::KDTreeV5 = bincode::deserialize_from(reader).map_err(|e| KdTreeError::unreadable(filename, *e))?;
+                Ok((tree.into(), 5))
+            }
+            4 => {
+                let tree: legacy::KDTreeV4 = bincode::deserialize_from(reader).map_err(|e| KdTreeError::unreadable(filename, *e))?;
+                Ok((tree.into(), 4))
+            }
+            3 => {
+                let tree: legacy::LegacyPartitionedKDTree<legacy::PointV2> =
+                    bincode::deserialize_from(reader).map_err(|e| KdTreeError::unreadable(filename, *e))?;
+                Ok((tree.into(), 3))
+            }
+            2 => {
+                let tree: legacy::LegacyKDTree<legacy::PointV2> =
+                    bincode::deserialize_from(reader).map_err(|e| KdTreeError::unreadable(filename, *e))?;
+                Ok((tree.into(), 2))
+            }
+            1 => {
+                let tree: legacy::LegacyKDTree<legacy::PointV1> =
+                    bincode::deserialize_from(reader).map_err(|e| KdTreeError::unreadable(filename, *e))?;
+                Ok((tree.into(), 1))
+            }
+            FORMAT_VERSION => {
+                let mut tree: KDTree = bincode::deserialize_from(&mut reader).map_err(|e| KdTreeError::unreadable(filename, *e))?;
+                tree.len = tree.count_all();
+                Ok((Self::with_payload_section(tree, reader, filename)?, FORMAT_VERSION))
+            }
+            _ => Err(KdTreeError::UnsupportedVersion(version)),
+        }
+    }
+
+    // Reads the payload lengths following the tree and points the tree's points at their
+    // data in the payload section after them
+    fn with_payload_section(mut tree: KDTree, mut reader: BufReader<File>, filename: &str) -> Result<Self, KdTreeError> {
+        let lengths: Vec<Option<u32>> = bincode::deserialize_from(&mut reader).map_err(|e| KdTreeError::unreadable(filename, *e))?;
+        let start = reader.stream_position()?;
+        let file = reader.into_inner();
+        let end = start + lengths.iter().flatten().map(|len| *len as u64).sum::<u64>();
+        if lengths.len() != tree.len || file.metadata()?.len() < end {
+            return Err(KdTreeError::Corrupt {
+                detail: format!("Payload section of {} does not match its tree", filename),
+                source: None,
+            });
+        }
+        tree.attach_payloads(Arc::new(PayloadFile::new(file)), start, &lengths);
         Ok(tree)
     }
 
-    pub fn nearest_neighbors_topn<'a>(&'a self, target: &Point, n: usize) -> Option<Vec<&'a Point>> {
-        let mut results: Vec<(f64, &'a Point)> = Vec::new();
-        self.nearest_recursive_n(&self.root, target, 0, self.k, &mut results); // Assuming this function populates `results`
-    
-        // Sort results based on distance
-        results.sort_by(|(dist_a, _), (dist_b, _)| dist_a.partial_cmp(dist_b).unwrap_or(Ordering::Equal));
-    
-        // Collect top N points
-        let top_n_points: Vec<&'a Point> = results.into_iter().take(n).map(|(_, point)| point).collect();
-    
-        // Return the top N points if there are any, otherwise return None
-        if top_n_points.is_empty() {
-            None
-        } else {
-            Some(top_n_points)
+    // Rewrites a file loaded from an older layout in the current format, keeping the
+    // original next to it as `<filename>.v<version>`
+    pub fn rewrite_legacy_file(&self, filename: &str, version: u32) -> Result<(), KdTreeError> {
+        let backup = format!("{}.v{}", filename, version);
+        std::fs::rename(filename, &backup)?;
+        if let Err(e) = self.save_to_file(filename) {
+            // Put the original back so a failed migration never loses the tree
+            let _ = std::fs::rename(&backup, filename);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // The n points closest to the target, closest first. Empty when the tree is, or when
+    // n is 0; never `None`, so an empty answer can't be mistaken for a missing tree.
+    pub fn nearest_neighbors_topn<'a>(&'a self, target: &Point, n: usize) -> Vec<&'a Point> {
+        self.nearest_neighbors_topn_scored(target, n).into_iter().map(|(_, point)| point).collect()
+    }
+
+    // Same as `nearest_neighbors_topn` but keeps each point's distance to the target
+    pub fn nearest_neighbors_topn_scored<'a>(&'a self, target: &Point, n: usize) -> Vec<(f64, &'a Point)> {
+        self.nearest_neighbors_topn_in(target, n, None)
+    }
+
+    // Scored top-n copied out of the tree, so the results outlive a borrow of it (and any
+    // lock guarding it). Only the n winners are cloned, never the candidates they beat.
+    pub fn nearest_neighbors_topn_owned(&self, target: &Point, n: usize) -> Vec<(f64, Point)> {
+        owned_hits(self.nearest_neighbors_topn_scored(target, n))
+    }
+
+    // Scored top-n restricted to one partition; `None` searches every partition and merges
+    pub fn nearest_neighbors_topn_in<'a>(
+        &'a self,
+        target: &Point,
+        n: usize,
+        partition: Option<&str>,
+    ) -> Vec<(f64, &'a Point)> {
+        self.nearest_neighbors_topn_traced(target, n, partition, &|_| true, None, None).0
+    }
+
+    // Scored top-n over the points accepted by `predicate`, which is checked per candidate
+    // during the traversal. Also records each visited node and pruning decision in `trace`,
+    // and the distance of every accepted candidate in `histogram`. Returns the number of
+    // nodes visited with the results.
+    pub fn nearest_neighbors_topn_traced<'a>(
+        &'a self,
+        target: &Point,
+        n: usize,
+        partition: Option<&str>,
+        predicate: &dyn Fn(&Point) -> bool,
+        mut trace: Option<&mut Trace>,
+        histogram: Option<&mut DistanceHistogram>,
+    ) -> (Vec<(f64, &'a Point)>, usize) {
+        if n == 0 {
+            return (Vec::new(), 0);
         }
+        let mut nearest = NearestCollector { n, predicate, histogram, results: Vec::new(), visited: 0 };
+        for root in self.search_roots(partition) {
+            self.nearest_recursive_n(root, target, 0, &mut nearest, trace.as_deref_mut());
+        }
+        (nearest.results, nearest.visited)
     }
     
     
@@ -107,41 +836,146 @@ impl KDTree {
         node: &'a Option<Box<Node>>, // Node reference
         target: &Point,              // Target point
         depth: usize,                // Current depth in the tree
-        k: usize,                    // Dimensionality
-        results: &mut Vec<(f64, &'a Point)>, // Results to collect distances and points
+        nearest: &mut NearestCollector<'_, 'a>, // Closest points so far
+        mut trace: Option<&mut Trace>,       // Explain mode only
     ) {
         if let Some(current_node) = node {
-            let axis = depth % k; // Determine axis based on depth
+            let axis = depth % self.k; // Determine axis based on depth
             let current_point = &current_node.point;
-            let dist = euclidean_distance(&current_point.embedding, &target.embedding); // Calculate distance
+            let dist = Euclidean.dist(&current_point.embedding, &target.embedding); // Calculate distance
     
-            // Add the current point and its distance to results
-            results.push((dist, current_point));
+            nearest.offer(dist, current_point);
     
             // Determine which branch to explore next
-            let (next_branch, other_branch) = if target.embedding[axis] < current_point.embedding[axis] {
-                (&current_node.left, &current_node.right)
-            } else {
-                (&current_node.right, &current_node.left)
-            };
+            let (next_branch, other_branch, branch) = current_node.branches(&target.embedding, axis);
+            let entry = trace.as_deref_mut()
+                .and_then(|trace| trace.visit(&current_point.embedding, depth, axis, dist, branch));
     
             // Recursively search the next branch
-            self.nearest_recursive_n(next_branch, target, depth + 1, k, results);
+            self.nearest_recursive_n(next_branch, target, depth + 1, nearest, trace.as_deref_mut());
     
-            // Check if we need to explore the other branch
-            if (target.embedding[axis] - current_point.embedding[axis]).abs() < 
-                results.iter().map(|(d, _)| *d).fold(f64::INFINITY, f64::min) {
-                self.nearest_recursive_n(other_branch, target, depth + 1, k, results);
+            // The far side can only matter if it may beat the n-th closest point so far
+            let plane_distance = current_node.plane_distance(&target.embedding, axis);
+            let bound = nearest.bound();
+            let explore = current_node.far_side_may_beat(&target.embedding, axis, bound);
+            if let Some(trace) = trace.as_deref_mut() {
+                trace.decide(entry, plane_distance, bound, !explore);
+            }
+            if explore {
+                self.nearest_recursive_n(other_branch, target, depth + 1, nearest, trace);
+            }
+        }
+    }
+
+    // Top `n` distinct groups by the metadata `field`, each holding its `group_size`
+    // closest points. Points without the field are grouped together under `None`.
+    // Grouping happens inside the traversal so pruning accounts for it. Returns the
+    // number of nodes visited with the groups.
+    pub fn nearest_groups_topn<'a>(
+        &'a self,
+        target: &Point,
+        n: usize,
+        group_size: usize,
+        field: &str,
+        partition: Option<&str>,
+        predicate: &dyn Fn(&Point) -> bool,
+    ) -> (Vec<GroupHits<'a>>, usize) {
+        let mut groups = GroupCollector::new(field, n, group_size, predicate);
+        for root in self.search_roots(partition) {
+            self.nearest_recursive_groups(root, target, 0, self.k, &mut groups);
+        }
+        let visited = groups.visited;
+        (groups.into_ranked(), visited)
+    }
+
+    fn nearest_recursive_groups<'a>(
+        &'a self,
+        node: &'a Option<Box<Node>>,
+        target: &Point,
+        depth: usize,
+        k: usize,
+        groups: &mut GroupCollector<'_, 'a>,
+    ) {
+        if let Some(current_node) = node {
+            let axis = depth % k;
+            let current_point = &current_node.point;
+            let dist = Euclidean.dist(&current_point.embedding, &target.embedding);
+            groups.offer(dist, current_point);
+
+            let (next_branch, other_branch, _) = current_node.branches(&target.embedding, axis);
+
+            self.nearest_recursive_groups(next_branch, target, depth + 1, k, groups);
+
+            if current_node.far_side_may_beat(&target.embedding, axis, groups.bound()) {
+                self.nearest_recursive_groups(other_branch, target, depth + 1, k, groups);
             }
         }
     }
 
+    // Any point within `distance` of the target, or `None`. The traversal stops at the
+    // first hit and never widens its bound past `distance`, so it is much cheaper than a
+    // top-1 search when hits are common. A distance of 0 is an exact-match lookup.
+    pub fn first_within<'a>(&'a self, target: &Point, distance: f64, partition: Option<&str>) -> Option<(f64, &'a Point)> {
+        self.search_roots(partition)
+            .into_iter()
+            .find_map(|root| self.first_within_recursive(root, target, 0, distance))
+    }
+
+    fn first_within_recursive<'a>(
+        &'a self,
+        node: &'a Option<Box<Node>>,
+        target: &Point,
+        depth: usize,
+        distance: f64,
+    ) -> Option<(f64, &'a Point)> {
+        let current_node = node.as_ref()?;
+        let axis = depth % self.k;
+        let current_point = &current_node.point;
+        let dist = Euclidean.dist(&current_point.embedding, &target.embedding);
+        if dist <= distance {
+            return Some((dist, current_point));
+        }
+
+        let (next_branch, other_branch, _) = current_node.branches(&target.embedding, axis);
+        if let Some(hit) = self.first_within_recursive(next_branch, target, depth + 1, distance) {
+            return Some(hit);
+        }
+
+        // Inclusive: a point exactly `distance` away still counts
+        if current_node.far_side_may_reach(&target.embedding, axis, distance) {
+            return self.first_within_recursive(other_branch, target, depth + 1, distance);
+        }
+        None
+    }
+
+    // The stored point with exactly this embedding, found by following the insert path:
+    // O(depth) rather than a nearest search. Ties on the split axis go right as on insert,
+    // so duplicates and values equal to a split are found too.
+    pub fn find_exact(&self, embedding: &[f64]) -> Option<&Point> {
+        if embedding.len() != self.k {
+            return None;
+        }
+        self.search_roots(None).into_iter().find_map(|root| {
+            let (mut node, mut depth) = (root, 0);
+            while let Some(current_node) = node {
+                if current_node.point.embedding == embedding {
+                    return Some(&current_node.point);
+                }
+                node = current_node.branches(embedding, depth % self.k).0;
+                depth += 1;
+            }
+            None
+        })
+    }
+
     //Nearest top
 
     pub fn nearest_neighbor<'a>(&'a self, target: &Point) -> Option<&'a Point> {
         let mut best: Option<&Point> = None;
         let mut best_distance = f64::INFINITY;
-        self.nearest_recursive(&self.root, target, 0, self.k, &mut best, &mut best_distance);
+        for root in self.search_roots(None) {
+            self.nearest_recursive(root, target, 0, self.k, &mut best, &mut best_distance);
+        }
         best
     }
 
@@ -157,51 +991,1459 @@ impl KDTree {
         if let Some(current_node) = node {
             let axis = depth % k;
             let current_point = &current_node.point;
-            let dist = euclidean_distance(&current_point.embedding, &target.embedding);
+            let dist = Euclidean.dist(&current_point.embedding, &target.embedding);
 
             if dist < *best_distance {
                 *best = Some(current_point);
                 *best_distance = dist;
             }
 
-            let (next_branch, other_branch) = if target.embedding[axis] < current_point.embedding[axis] {
-                (&current_node.left, &current_node.right)
-            } else {
-                (&current_node.right, &current_node.left)
-            };
+            let (next_branch, other_branch, _) = current_node.branches(&target.embedding, axis);
 
             self.nearest_recursive(next_branch, target, depth + 1, k, best, best_distance);
 
-            if (target.embedding[axis] - current_point.embedding[axis]).abs() < *best_distance {
+            if current_node.far_side_may_beat(&target.embedding, axis, *best_distance) {
                 self.nearest_recursive(other_branch, target, depth + 1, k, best, best_distance);
             }
         }
     }
 
-    pub fn len(&self) -> usize {
-        // Call a recursive helper function starting from the root
-        self.count_nodes(&self.root)
+    // Checks that every subtree satisfies the KD-tree invariant: each point is strictly
+    // below the split of every ancestor it lies left of, and at least the split of every
+    // ancestor it lies right of. Also checks dimensions and partition membership.
+    // Reports the first violation in traversal order. Stored norms must match the
+    // embeddings within `NORM_EPSILON`.
+    pub fn validate(&self) -> Result<(), InvariantError> {
+        self.validate_with(Some(NORM_EPSILON))
     }
 
-    fn count_nodes(&self, node: &Option<Box<Node>>) -> usize {
-        if let Some(ref current_node) = node {
-            // Recursively count nodes in the left and right subtrees
-            1 + self.count_nodes(&current_node.left) + self.count_nodes(&current_node.right)
-        } else {
-            0
+    // Like `validate`, checking stored norms within a relative `norm_epsilon`, or not at
+    // all when it is None
+    pub fn validate_with(&self, norm_epsilon: Option<f64>) -> Result<(), InvariantError> {
+        let subtrees = std::iter::once((None, &self.root))
+            .chain(self.partitions.iter().map(|(partition, root)| (Some(partition.as_str()), root)));
+        for (partition, root) in subtrees {
+            let mut bounds = vec![AxisBounds::default(); self.k];
+            let mut path = String::new();
+            self.validate_recursive(root, partition, 0, &mut bounds, &mut path, norm_epsilon)
+                .map_err(|(seq, message)| InvariantError {
+                    partition: partition.map(str::to_string),
+                    path: path.clone(),
+                    seq,
+                    message,
+                })?;
         }
+        Ok(())
     }
 
+    // On failure `path` is left pointing at the offending node
+    fn validate_recursive(
+        &self,
+        node: &Option<Box<Node>>,
+        partition: Option<&str>,
+        depth: usize,
+        bounds: &mut [AxisBounds],
+        path: &mut String,
+        norm_epsilon: Option<f64>,
+    ) -> Result<(), (u64, String)> {
+        let Some(current_node) = node else { return Ok(()) };
+        let point = &current_node.point;
+        if point.embedding.len() != self.k {
+            return Err((point.seq, format!("point has {} dimensions but the tree has {}", point.embedding.len(), self.k)));
+        }
+        if let Some(epsilon) = norm_epsilon {
+            let norm = distance::norm(&point.embedding);
+            // Equal norms pass even when infinite; NaN never matches
+            let matches = point.norm == norm || (point.norm - norm).abs() <= epsilon * norm.max(1.0);
+            if !matches {
+                return Err((point.seq, format!("stored norm {} does not match the embedding's norm {}", point.norm, norm)));
+            }
+        }
+        if self.partition_field.is_some() && self.partition_of(point).as_deref() != partition {
+            return Err((point.seq, format!(
+                "point belongs to partition {:?} but is stored in {:?}",
+                self.partition_of(point), partition
+            )));
+        }
+        // NaN compares as neither below nor at least anything, so it always fails
+        for (axis, axis_bounds) in bounds.iter().enumerate() {
+            let value = point.embedding[axis];
+            if let Some((split, ancestor)) = axis_bounds.below {
+                if value.partial_cmp(&split) != Some(Ordering::Less) {
+                    return Err((point.seq, format!(
+                        "embedding[{}] = {} lies left of the node at {:?} and must be below its split {}",
+                        axis, value, &path[..ancestor], split
+                    )));
+                }
+            }
+            if let Some((split, ancestor)) = axis_bounds.at_least {
+                if value.partial_cmp(&split).is_none_or(|ordering| ordering == Ordering::Less) {
+                    return Err((point.seq, format!(
+                        "embedding[{}] = {} lies right of the node at {:?} and must be at least its split {}",
+                        axis, value, &path[..ancestor], split
+                    )));
+                }
+            }
+        }
 
-}
+        let axis = depth % self.k;
+        let split = (current_node.split_value(axis), path.len());
+        for (child, side) in [(&current_node.left, 'L'), (&current_node.right, 'R')] {
+            let saved = bounds[axis];
+            if side == 'L' {
+                bounds[axis].below = Some(split);
+            } else {
+                bounds[axis].at_least = Some(split);
+            }
+            path.push(side);
+            self.validate_recursive(child, partition, depth + 1, bounds, path, norm_epsilon)?;
+            path.pop();
+            bounds[axis] = saved;
+        }
+        Ok(())
+    }
 
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Sequence number the next inserted point gets
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    fn count_all(&self) -> usize {
+        self.iter().count()
+    }
+
+    // Longest root-to-leaf path over all subtrees, counting the root as depth 1
+    pub fn depth(&self) -> usize {
+        self.iter_with_depth().map(|(depth, _)| depth + 1).max().unwrap_or(0)
+    }
+
+    pub(crate) fn count_nodes(node: &Option<Box<Node>>) -> usize {
+        Iter::new(vec![node]).count()
+    }
+
+    // Every stored point in pre-order: the shared tree, then each partition subtree in
+    // partition order. The same order as `for_each_point` and the payload section.
+    pub fn iter(&self) -> impl Iterator<Item = &Point> {
+        self.iter_with_depth().map(|(_, point)| point)
+    }
+
+    // Like `iter`, with the depth of each point's node, 0 for a root
+    pub fn iter_with_depth(&self) -> Iter<'_> {
+        Iter::new(self.search_roots(None))
+    }
 
+    // Consumes the tree and returns its points in pre-order
+    pub fn into_points(self) -> Vec<Point> {
+        let mut points = Self::collect_points(self.root);
+        for root in self.partitions.into_values() {
+            points.extend(Self::collect_points(root));
+        }
+        points
+    }
+
+    fn collect_points(root: Option<Box<Node>>) -> Vec<Point> {
+        let mut points = Vec::new();
+        let mut stack: Vec<Box<Node>> = root.into_iter().collect();
+        while let Some(node) = stack.pop() {
+            let node = *node;
+            points.push(node.point);
+            stack.extend(node.right);
+            stack.extend(node.left);
+        }
+        points
+    }
+
+    // Points of `partition` (or of every subtree) accepted by `predicate`, in sequence
+    // order. The predicate runs during the traversal, so rejected points cost nothing
+    // beyond the visit. Gives up once `cancel` is cancelled.
+    pub fn select<'a>(
+        &'a self,
+        partition: Option<&str>,
+        predicate: impl Fn(&Point) -> bool,
+        cancel: &CancellationToken,
+    ) -> Result<Vec<&'a Point>, Cancelled> {
+        let mut selected = Vec::new();
+        self.visit_cancellable(partition, cancel, |point| {
+            if predicate(point) {
+                selected.push(point);
+            }
+        })?;
+        selected.sort_by_key(|point| point.seq);
+        Ok(selected)
+    }
+
+    // Up to `count` points of `partition` (or of every subtree) accepted by `predicate`,
+    // drawn uniformly by reservoir sampling in one traversal, so only the sample is held.
+    // Returns the sample in sequence order and how many points matched. Gives up once
+    // `cancel` is cancelled.
+    pub fn sample<'a>(
+        &'a self,
+        partition: Option<&str>,
+        count: usize,
+        rng: &mut SplitMix64,
+        predicate: impl Fn(&Point) -> bool,
+        cancel: &CancellationToken,
+    ) -> Result<(Vec<&'a Point>, usize), Cancelled> {
+        let mut reservoir = Vec::with_capacity(count);
+        let mut matched = 0;
+        self.visit_cancellable(partition, cancel, |point| {
+            if !predicate(point) {
+                return;
+            }
+            matched += 1;
+            if reservoir.len() < count {
+                reservoir.push(point);
+            } else {
+                let slot = rng.next_below(matched as u64) as usize;
+                if slot < count {
+                    reservoir[slot] = point;
+                }
+            }
+        })?;
+        reservoir.sort_by_key(|point| point.seq);
+        Ok((reservoir, matched))
+    }
+
+    // Visits the points of `partition` (or of every subtree), checking `cancel` every
+    // `CHECK_INTERVAL` points
+    fn visit_cancellable<'a>(
+        &'a self,
+        partition: Option<&str>,
+        cancel: &CancellationToken,
+        mut f: impl FnMut(&'a Point),
+    ) -> Result<(), Cancelled> {
+        for (visited, (_, point)) in Iter::new(self.search_roots(partition)).enumerate() {
+            if (visited + 1).is_multiple_of(CHECK_INTERVAL) && cancel.is_cancelled() {
+                return Err(Cancelled);
+            }
+            f(point);
+        }
+        Ok(())
+    }
+
+    // Numbers points loaded from files that predate sequence numbers, in pre-order
+    fn assign_sequence_numbers(&mut self) {
+        fn assign(node: &mut Option<Box<Node>>, next_seq: &mut u64) {
+            if let Some(node) = node {
+                node.point.seq = *next_seq;
+                *next_seq += 1;
+                assign(&mut node.left, next_seq);
+                assign(&mut node.right, next_seq);
+            }
+        }
+        let mut next_seq = 1;
+        assign(&mut self.root, &mut next_seq);
+        for root in self.partitions.values_mut() {
+            assign(root, &mut next_seq);
+        }
+        self.next_seq = next_seq;
+    }
+
+    // Scored top-n found by measuring every stored point, slow but independent of the
+    // tree's pruning, to check searches against
+    pub fn brute_force_topn<'a>(&'a self, target: &Point, n: usize) -> Vec<(f64, &'a Point)> {
+        let mut scored = Vec::with_capacity(self.len);
+        self.for_each_point(|point| scored.push((Euclidean.dist(&point.embedding, &target.embedding), point)));
+        if n < scored.len() {
+            scored.select_nth_unstable_by(n, |a, b| a.0.total_cmp(&b.0));
+            scored.truncate(n);
+        }
+        scored.sort_by(|a, b| a.0.total_cmp(&b.0));
+        scored
+    }
+
+    // Rebuilds the distribution from the stored points
+    fn recompute_stats(&mut self) {
+        let mut stats = TreeStats::new(self.k);
+        self.for_each_point(|point| stats.add(&point.embedding));
+        self.stats = stats;
+    }
+
+    // Visits every stored point in the order of `iter`
+    pub fn for_each_point<'a>(&'a self, f: impl FnMut(&'a Point)) {
+        self.iter().for_each(f);
+    }
+
+    // Points each stored point at its data in a payload section starting at `start`,
+    // given the lengths in the order of `for_each_point`
+    fn attach_payloads(&mut self, file: Arc<PayloadFile>, start: u64, lengths: &[Option<u32>]) {
+        fn attach(node: &mut Option<Box<Node>>, file: &Arc<PayloadFile>, offset: &mut u64, lengths: &mut std::slice::Iter<Option<u32>>) {
+            if let Some(node) = node {
+                if let Some(Some(len)) = lengths.next() {
+                    node.point.stored = Some(PayloadRef { file: Arc::clone(file), offset: *offset, len: *len });
+                    *offset += *len as u64;
+                }
+                attach(&mut node.left, file, offset, lengths);
+                attach(&mut node.right, file, offset, lengths);
+            }
+        }
+        let mut offset = start;
+        let mut lengths = lengths.iter();
+        attach(&mut self.root, &file, &mut offset, &mut lengths);
+        for root in self.partitions.values_mut() {
+            attach(root, &file, &mut offset, &mut lengths);
+        }
+    }
+
+
+}
+
+// Pre-order walk over the points of a list of subtrees, returned by
+// `KDTree::iter_with_depth`. Nodes waiting for their turn are kept on an explicit stack
+// rather than the call stack, so a badly unbalanced tree can't overflow it; the stack
+// only ever holds the right children passed on the way down, not the whole tree.
+pub struct Iter<'a> {
+    roots: std::vec::IntoIter<&'a Option<Box<Node>>>,
+    stack: Vec<(usize, &'a Node)>,
+}
+
+impl<'a> Iter<'a> {
+    fn new(roots: Vec<&'a Option<Box<Node>>>) -> Self {
+        Iter { roots: roots.into_iter(), stack: Vec::new() }
+    }
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (usize, &'a Point);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((depth, node)) = self.stack.pop() {
+                // Right first, so the left subtree comes out before it
+                self.stack.extend(node.right.as_deref().map(|right| (depth + 1, right)));
+                self.stack.extend(node.left.as_deref().map(|left| (depth + 1, left)));
+                return Some((depth, &node.point));
+            }
+            let root = self.roots.next()?;
+            self.stack.extend(root.as_deref().map(|root| (0, root)));
+        }
+    }
+}
+
+
+// Progress reported back up the insertion path by `insert_bounded`
+#[derive(PartialEq)]
+enum Unwind {
+    Done,
+    Rebuilt,
+    Climbing { height: usize, size: usize }, // Too deep, no scapegoat found yet
+}
+
+// Where and how a tree breaks the KD-tree invariant, as reported by `KDTree::validate`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InvariantError {
+    pub partition: Option<String>, // Subtree of the offending node, None for the shared root
+    pub path: String,              // Branches from the subtree root to the node, e.g. `LRR`; empty for the root
+    pub seq: u64,                  // Sequence number of the offending point
+    pub message: String,
+}
+
+impl fmt::Display for InvariantError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(partition) = &self.partition {
+            write!(f, "partition {:?}, ", partition)?;
+        }
+        write!(f, "node at {:?} (seq {}): {}", self.path, self.seq, self.message)
+    }
+}
+
+impl std::error::Error for InvariantError {}
+
+// Splits of the ancestors constraining one axis during validation, each with the
+// length of the ancestor's path
+#[derive(Clone, Copy, Default)]
+struct AxisBounds {
+    below: Option<(f64, usize)>,    // Closest ancestor this subtree lies left of
+    at_least: Option<(f64, usize)>, // Closest ancestor this subtree lies right of
+}
+
+// The closest points accepted by the predicate so far, sorted by distance
+struct NearestCollector<'f, 'a> {
+    n: usize,
+    predicate: &'f dyn Fn(&Point) -> bool, // Points it rejects are traversed but never returned
+    histogram: Option<&'f mut DistanceHistogram>, // Sees every accepted candidate, not just the kept ones
+    results: Vec<(f64, &'a Point)>,
+    visited: usize, // Points offered, one per node the traversal visited
+}
+
+impl<'a> NearestCollector<'_, 'a> {
+    // Keeps the point if it is among the n closest so far
+    fn offer(&mut self, dist: f64, point: &'a Point) {
+        self.visited += 1;
+        let position = self.results.partition_point(|(d, _)| *d <= dist);
+        let accepted = match self.histogram.as_deref_mut() {
+            // Without a histogram the predicate is skipped for points too far to be kept
+            None => position < self.n && (self.predicate)(point),
+            Some(histogram) => {
+                if !(self.predicate)(point) {
+                    return;
+                }
+                histogram.record(dist);
+                position < self.n
+            }
+        };
+        if accepted {
+            self.results.insert(position, (dist, point));
+            self.results.truncate(self.n);
+        }
+    }
+
+    // Distance of the n-th closest point so far; only closer points can still get in
+    fn bound(&self) -> f64 {
+        if self.results.len() < self.n { f64::INFINITY } else { self.results[self.n - 1].0 }
+    }
+}
+
+// Candidate groups collected during a grouped search, keyed by the JSON form of the group
+// value. The heads of the `n` best groups are kept ranked next to them, so the pruning
+// bound is only recomputed when a point changes one of those groups, and groups that fall
+// out of the ranking with their head at or past the bound are dropped: every hit they hold
+// is as far away as the points the traversal prunes, so they go the same way.
+struct GroupCollector<'f, 'a> {
+    field: &'f str,
+    n: usize,
+    group_size: usize,
+    predicate: &'f dyn Fn(&Point) -> bool,
+    groups: HashMap<String, Group<'a>>,
+    ranked: BTreeSet<(Head, String)>, // Heads of the best `n` groups, closest first
+    bound: f64,
+    sweep_above: usize, // Number of groups at which those past the bound are swept out
+    visited: usize,     // Points offered, one per node the traversal visited
+}
+
+struct Group<'a> {
+    value: Option<&'a MetadataValue>,
+    hits: Vec<(f64, &'a Point)>, // Closest first, at most `group_size`
+    ranked: bool,                // Among the best `n`
+}
+
+impl Group<'_> {
+    fn head(&self) -> f64 {
+        self.hits[0].0
+    }
+
+    // Distance a point has to beat to get into the group, infinite until it is full
+    fn worst(&self, group_size: usize) -> f64 {
+        if self.hits.len() < group_size { f64::INFINITY } else { self.hits[self.hits.len() - 1].0 }
+    }
+}
+
+// A group head distance, ordered so heads can be ranked
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Head(f64);
+
+impl Eq for Head {}
+
+impl PartialOrd for Head {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Head {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+// Groups a grouped search holds before the first sweep, whatever its `n`
+const MIN_GROUP_SWEEP: usize = 64;
+
+impl<'f, 'a> GroupCollector<'f, 'a> {
+    fn new(field: &'f str, n: usize, group_size: usize, predicate: &'f dyn Fn(&Point) -> bool) -> Self {
+        GroupCollector {
+            field,
+            n,
+            group_size,
+            predicate,
+            groups: HashMap::new(),
+            ranked: BTreeSet::new(),
+            bound: f64::INFINITY,
+            sweep_above: MIN_GROUP_SWEEP.max(2 * n),
+            visited: 0,
+        }
+    }
+
+    fn offer(&mut self, dist: f64, point: &'a Point) {
+        self.visited += 1;
+        if !(self.predicate)(point) {
+            return;
+        }
+        let value = point.metadata_value(self.field);
+        let key = value.map_or_else(String::new, |value| serde_json::to_string(value).unwrap_or_default());
+        let group = match self.groups.get_mut(&key) {
+            Some(group) => group,
+            // A point at or past the bound can't bring a new group into the top n
+            None if dist >= self.bound => return,
+            None => self.groups.entry(key.clone()).or_insert(Group { value, hits: Vec::new(), ranked: false }),
+        };
+
+        let position = group.hits.partition_point(|(existing, _)| *existing <= dist);
+        if position >= self.group_size {
+            return;
+        }
+        let previous_head = group.hits.first().map(|(head, _)| *head);
+        group.hits.insert(position, (dist, point));
+        group.hits.truncate(self.group_size);
+
+        match (position, group.ranked) {
+            // A new head moves the group up the ranking, or into it
+            (0, true) => {
+                self.ranked.remove(&(Head(previous_head.unwrap()), key.clone()));
+                self.ranked.insert((Head(dist), key));
+            }
+            (0, false) => self.rank(key, dist),
+            // A closer worst entry of a ranked group may tighten the bound
+            (_, true) => {}
+            (_, false) => return,
+        }
+        self.rebound();
+        if self.groups.len() > self.sweep_above {
+            self.sweep();
+        }
+    }
+
+    // Ranks a group that got the new head `head`, if it is among the best n now
+    fn rank(&mut self, key: String, head: f64) {
+        if self.ranked.len() == self.n {
+            match self.ranked.last() {
+                Some((worst, _)) if worst.0 > head => {}
+                _ => return,
+            }
+            let (_, displaced) = self.ranked.pop_last().unwrap();
+            self.groups.get_mut(&displaced).unwrap().ranked = false;
+        }
+        self.groups.get_mut(&key).unwrap().ranked = true;
+        self.ranked.insert((Head(head), key));
+    }
+
+    // A point at or beyond the bound can neither bring a new group into the top `n` nor
+    // improve one of the groups already in it: the n-th best head, or the farthest entry
+    // of a ranked group if that is farther, infinite while either has room
+    fn rebound(&mut self) {
+        self.bound = match self.ranked.last() {
+            Some((nth, _)) if self.ranked.len() == self.n => self.ranked
+                .iter()
+                .map(|(_, key)| self.groups[key].worst(self.group_size))
+                .fold(nth.0, f64::max),
+            _ => f64::INFINITY,
+        };
+    }
+
+    // Drops the groups outside the ranking that could only get back in with points the
+    // traversal would prune anyway, then lets the groups grow to twice what is left
+    fn sweep(&mut self) {
+        let bound = self.bound;
+        self.groups.retain(|_, group| group.ranked || group.head() < bound);
+        self.sweep_above = MIN_GROUP_SWEEP.max(2 * self.n).max(2 * self.groups.len());
+    }
+
+    // The ranked groups, closest head first
+    fn into_ranked(mut self) -> Vec<GroupHits<'a>> {
+        self.ranked
+            .iter()
+            .map(|(_, key)| self.groups.remove(key).unwrap())
+            .map(|group| (group.value, group.hits))
+            .collect()
+    }
+
+    fn bound(&self) -> f64 {
+        self.bound
+    }
+}
+
+// Layouts written by earlier format versions. Versions 0-2 differ only in the point
+// shape, so the node and tree structure is shared and generic over the point.
+// Version 3 added partitions; older trees load unpartitioned. Version 4 added
+// sequence numbers, which older trees get assigned on load. Version 5 added the
+// embedding statistics, which older trees recompute on load. Version 6 added
+// dimensionality reduction, which older trees never use. Version 7 moved the data
+// into a payload section; version 6 files have the same tree with the data inline.
+// Version 10 added the point norms, which every older point gets computed on load.
+mod legacy {
+    use serde::Deserialize;
+    use std::collections::BTreeMap;
+
+    use super::{distance, KDTree, Node, Point, RandomProjection, Schema, TreeStats};
+
+    // Headerless files: points had no metadata
+    #[derive(Deserialize)]
+    pub struct PointV0 {
+        embedding: Vec<f64>,
+        data: String,
+    }
+
+    // Version 1: `data` was still mandatory
+    #[derive(Deserialize)]
+    pub struct PointV1 {
+        embedding: Vec<f64>,
+        data: String,
+        metadata: crate::metadata::Metadata,
+    }
+
+    // Versions 2 and 3: `data` optional, no sequence numbers
+    #[derive(Deserialize)]
+    pub struct PointV2 {
+        embedding: Vec<f64>,
+        data: Option<String>,
+        metadata: crate::metadata::Metadata,
+    }
+
+    impl From<PointV0> for Point {
+        fn from(point: PointV0) -> Self {
+            Point { embedding: point.embedding, data: Some(point.data), ..Default::default() }
+        }
+    }
+
+    impl From<PointV1> for Point {
+        fn from(point: PointV1) -> Self {
+            Point { embedding: point.embedding, data: Some(point.data), metadata: point.metadata, ..Default::default() }
+        }
+    }
+
+    impl From<PointV2> for Point {
+        fn from(point: PointV2) -> Self {
+            Point { embedding: point.embedding, data: point.data, metadata: point.metadata, ..Default::default() }
+        }
+    }
+
+    // Versions 4 to 9: the current point without its norm
+    #[derive(Deserialize)]
+    pub struct PointV9 {
+        embedding: Vec<f64>,
+        data: Option<String>,
+        metadata: crate::metadata::Metadata,
+        seq: u64,
+        inserted_at: u64,
+    }
+
+    impl From<PointV9> for Point {
+        fn from(point: PointV9) -> Self {
+            Point {
+                embedding: point.embedding,
+                data: point.data,
+                metadata: point.metadata,
+                seq: point.seq,
+                inserted_at: point.inserted_at,
+                ..Default::default()
+            }
+        }
+    }
+
+    type RootV9 = Option<Box<LegacyNode<PointV9>>>;
+
+    #[derive(Deserialize)]
+    pub struct LegacyNode<P> {
+        point: P,
+        left: Option<Box<LegacyNode<P>>>,
+        right: Option<Box<LegacyNode<P>>>,
+        axis: usize,
+    }
+
+    #[derive(Deserialize)]
+    pub struct LegacyKDTree<P> {
+        root: Option<Box<LegacyNode<P>>>,
+        k: usize,
+    }
+
+    #[derive(Deserialize)]
+    pub struct LegacyPartitionedKDTree<P> {
+        root: Option<Box<LegacyNode<P>>>,
+        k: usize,
+        partition_field: Option<String>,
+        partitions: BTreeMap<String, Option<Box<LegacyNode<P>>>>,
+    }
+
+    // Version 4: current points and nodes, no statistics
+    #[derive(Deserialize)]
+    pub struct KDTreeV4 {
+        root: RootV9,
+        k: usize,
+        partition_field: Option<String>,
+        partitions: BTreeMap<String, RootV9>,
+        next_seq: u64,
+    }
+
+    // Version 9: points without norms
+    #[derive(Deserialize)]
+    pub struct KDTreeV9 {
+        root: RootV9,
+        k: usize,
+        partition_field: Option<String>,
+        partitions: BTreeMap<String, RootV9>,
+        next_seq: u64,
+        stats: TreeStats,
+        reduction: Option<RandomProjection>,
+        schema: Option<Schema>,
+        model: Option<String>,
+    }
+
+    impl From<KDTreeV9> for KDTree {
+        fn from(tree: KDTreeV9) -> Self {
+            let mut converted = KDTree {
+                root: convert_root(tree.root),
+                k: tree.k,
+                partition_field: tree.partition_field,
+                partitions: convert_partitions(tree.partitions),
+                next_seq: tree.next_seq,
+                stats: tree.stats,
+                reduction: tree.reduction,
+                schema: tree.schema,
+                model: tree.model,
+                len: 0,
+            };
+            converted.len = converted.count_all();
+            converted
+        }
+    }
+
+    // Version 8: no embedding model tag
+    #[derive(Deserialize)]
+    pub struct KDTreeV8 {
+        root: RootV9,
+        k: usize,
+        partition_field: Option<String>,
+        partitions: BTreeMap<String, RootV9>,
+        next_seq: u64,
+        stats: TreeStats,
+        reduction: Option<RandomProjection>,
+        schema: Option<Schema>,
+    }
+
+    impl From<KDTreeV8> for KDTree {
+        fn from(tree: KDTreeV8) -> Self {
+            let mut converted = KDTree {
+                root: convert_root(tree.root),
+                k: tree.k,
+                partition_field: tree.partition_field,
+                partitions: convert_partitions(tree.partitions),
+                next_seq: tree.next_seq,
+                stats: tree.stats,
+                reduction: tree.reduction,
+                schema: tree.schema,
+                model: None,
+                len: 0,
+            };
+            converted.len = converted.count_all();
+            converted
+        }
+    }
+
+    // Versions 6 and 7: no metadata schema. Version 7 files go on with a payload section.
+    #[derive(Deserialize)]
+    pub struct KDTreeV7 {
+        root: RootV9,
+        k: usize,
+        partition_field: Option<String>,
+        partitions: BTreeMap<String, RootV9>,
+        next_seq: u64,
+        stats: TreeStats,
+        reduction: Option<RandomProjection>,
+    }
+
+    impl From<KDTreeV7> for KDTree {
+        fn from(tree: KDTreeV7) -> Self {
+            let mut converted = KDTree {
+                root: convert_root(tree.root),
+                k: tree.k,
+                partition_field: tree.partition_field,
+                partitions: convert_partitions(tree.partitions),
+                next_seq: tree.next_seq,
+                stats: tree.stats,
+                reduction: tree.reduction,
+                schema: None,
+                model: None,
+                len: 0,
+            };
+            converted.len = converted.count_all();
+            converted
+        }
+    }
+
+    // Version 5: no dimensionality reduction
+    #[derive(Deserialize)]
+    pub struct KDTreeV5 {
+        root: RootV9,
+        k: usize,
+        partition_field: Option<String>,
+        partitions: BTreeMap<String, RootV9>,
+        next_seq: u64,
+        stats: TreeStats,
+    }
+
+    impl From<KDTreeV5> for KDTree {
+        fn from(tree: KDTreeV5) -> Self {
+            let mut converted = KDTree {
+                root: convert_root(tree.root),
+                k: tree.k,
+                partition_field: tree.partition_field,
+                partitions: convert_partitions(tree.partitions),
+                next_seq: tree.next_seq,
+                stats: tree.stats,
+                reduction: None,
+                schema: None,
+                model: None,
+                len: 0,
+            };
+            converted.len = converted.count_all();
+            converted
+        }
+    }
+
+    impl From<KDTreeV4> for KDTree {
+        fn from(tree: KDTreeV4) -> Self {
+            let mut converted = KDTree {
+                root: convert_root(tree.root),
+                k: tree.k,
+                partition_field: tree.partition_field,
+                partitions: convert_partitions(tree.partitions),
+                next_seq: tree.next_seq,
+                stats: TreeStats::new(tree.k),
+                reduction: None,
+                schema: None,
+                model: None,
+                len: 0,
+            };
+            converted.len = converted.count_all();
+            converted.recompute_stats();
+            converted
+        }
+    }
+
+    fn convert_root<P: Into<Point>>(root: Option<Box<LegacyNode<P>>>) -> Option<Box<Node>> {
+        root.map(|root| Box::new((*root).into()))
+    }
+
+    fn convert_partitions<P: Into<Point>>(partitions: BTreeMap<String, Option<Box<LegacyNode<P>>>>) -> BTreeMap<String, Option<Box<Node>>> {
+        partitions.into_iter().map(|(partition, root)| (partition, convert_root(root))).collect()
+    }
+
+    impl<P: Into<Point>> From<LegacyNode<P>> for Node {
+        fn from(node: LegacyNode<P>) -> Self {
+            let mut point: Point = node.point.into();
+            point.norm = distance::norm(&point.embedding);
+            Node {
+                point,
+                left: node.left.map(|left| Box::new((*left).into())),
+                right: node.right.map(|right| Box::new((*right).into())),
+                axis: node.axis,
+            }
+        }
+    }
+
+    impl<P: Into<Point>> From<LegacyKDTree<P>> for KDTree {
+        fn from(tree: LegacyKDTree<P>) -> Self {
+            let mut converted = KDTree {
+                root: convert_root(tree.root),
+                k: tree.k,
+                partition_field: None,
+                partitions: Default::default(),
+                next_seq: 1,
+                stats: TreeStats::new(tree.k),
+                reduction: None,
+                schema: None,
+                model: None,
+                len: 0,
+            };
+            converted.len = converted.count_all();
+            converted.assign_sequence_numbers();
+            converted.recompute_stats();
+            converted
+        }
+    }
+
+    impl<P: Into<Point>> From<LegacyPartitionedKDTree<P>> for KDTree {
+        fn from(tree: LegacyPartitionedKDTree<P>) -> Self {
+            let mut converted = KDTree {
+                root: convert_root(tree.root),
+                k: tree.k,
+                partition_field: tree.partition_field,
+                partitions: convert_partitions(tree.partitions),
+                next_seq: 1,
+                stats: TreeStats::new(tree.k),
+                reduction: None,
+                schema: None,
+                model: None,
+                len: 0,
+            };
+            converted.len = converted.count_all();
+            converted.assign_sequence_numbers();
+            converted.recompute_stats();
+            converted
+        }
+    }
+}
 
 // Function to calculate Euclidean distance
-pub fn euclidean_distance(a: &Vec<f64>, b: &Vec<f64>) -> f64 {
-    a.iter()
-        .zip(b.iter())
-        .map(|(x, y)| (x - y).powi(2))
-        .sum::<f64>()
-        .sqrt()
+#[deprecated(note = "use `distance::Euclidean` through the `Metric` trait")]
+pub fn euclidean_distance(a: &[f64], b: &[f64]) -> f64 {
+    Euclidean.dist(a, b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn random_points(count: usize, k: usize, groups: u64, seed: u64) -> Vec<Point> {
+        let mut rng = SplitMix64::new(seed);
+        (0..count)
+            .map(|_| Point {
+                embedding: (0..k).map(|_| rng.next_unit() * 10.0).collect(),
+                metadata: Metadata::from([("doc".to_string(), MetadataValue::Number(rng.next_below(groups) as f64))]),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    // The `n` groups with the closest heads and each group's `group_size` closest
+    // distances, by checking every point
+    fn brute_force_groups(points: &[Point], target: &Point, n: usize, group_size: usize) -> Vec<(f64, Vec<f64>)> {
+        let mut groups: HashMap<String, Vec<f64>> = HashMap::new();
+        for point in points {
+            let key = serde_json::to_string(point.metadata_value("doc").unwrap()).unwrap();
+            groups.entry(key).or_default().push(Euclidean.dist(&point.embedding, &target.embedding));
+        }
+        let mut ranked: Vec<_> = groups
+            .into_values()
+            .map(|mut distances| {
+                distances.sort_by(f64::total_cmp);
+                distances.truncate(group_size);
+                (distances[0], distances)
+            })
+            .collect();
+        ranked.sort_by(|a, b| a.0.total_cmp(&b.0));
+        ranked.truncate(n);
+        ranked
+    }
+
+    #[test]
+    fn a_tree_needs_at_least_one_dimension() {
+        assert!(matches!(KDTree::new(0), Err(KdTreeError::ZeroDimensions)));
+        assert!(matches!(KDTree::build(0, Vec::new()), Err(KdTreeError::ZeroDimensions)));
+        assert_eq!(KDTree::new(1).unwrap().k, 1);
+    }
+
+    #[test]
+    fn build_rejects_points_of_another_dimension() {
+        let point = |embedding: Vec<f64>| Point { embedding, ..Default::default() };
+        for embedding in [vec![1.0], vec![1.0, 2.0, 3.0]] {
+            let got = embedding.len();
+            let result = KDTree::build(2, vec![point(vec![0.0, 0.0]), point(embedding)]);
+            assert!(matches!(result, Err(KdTreeError::DimensionMismatch { expected: 2, got: g }) if g == got));
+        }
+        assert_eq!(KDTree::build(2, vec![point(vec![0.0, 0.0])]).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn grouped_search_ranks_groups_like_brute_force() {
+        let points = random_points(2_000, 3, 60, 7);
+        let tree = KDTree::build(3, points.clone()).unwrap();
+        let mut rng = SplitMix64::new(11);
+        for _ in 0..50 {
+            let target = Point { embedding: (0..3).map(|_| rng.next_unit() * 10.0).collect(), ..Default::default() };
+            for (n, group_size) in [(1, 1), (5, 1), (5, 3), (20, 2)] {
+                let (groups, _) = tree.nearest_groups_topn(&target, n, group_size, "doc", None, &|_| true);
+                let expected = brute_force_groups(&points, &target, n, group_size);
+                let heads: Vec<f64> = groups.iter().map(|(_, hits)| hits[0].0).collect();
+                assert_eq!(heads, expected.iter().map(|(head, _)| *head).collect::<Vec<_>>());
+                for ((_, hits), (_, distances)) in groups.iter().zip(&expected) {
+                    assert!(hits.len() <= group_size);
+                    assert!(hits.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+                    // Single-hit groups have nothing the pruning could have cut
+                    if group_size == 1 {
+                        assert_eq!(hits[0].0, distances[0]);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn group_collector_drops_groups_that_cannot_rank() {
+        // Every point its own group, so without dropping the groups would grow with them.
+        // One hit fills a group, so the bound is finite once five groups are ranked.
+        let mut rng = SplitMix64::new(3);
+        let points: Vec<Point> = (0..10_000)
+            .map(|i| Point {
+                embedding: vec![rng.next_unit()],
+                metadata: Metadata::from([("doc".to_string(), MetadataValue::Number(i as f64))]),
+                ..Default::default()
+            })
+            .collect();
+        let accept_all = |_: &Point| true;
+        let mut collector = GroupCollector::new("doc", 5, 1, &accept_all);
+        let mut largest = 0;
+        for point in &points {
+            collector.offer(point.embedding[0], point);
+            largest = largest.max(collector.groups.len());
+        }
+        assert!(largest <= 2 * MIN_GROUP_SWEEP, "held {} groups", largest);
+
+        let mut closest: Vec<f64> = points.iter().map(|point| point.embedding[0]).collect();
+        closest.sort_by(f64::total_cmp);
+        let heads: Vec<f64> = collector.into_ranked().iter().map(|(_, hits)| hits[0].0).collect();
+        assert_eq!(heads, closest[..5]);
+    }
+
+    #[test]
+    fn group_bound_tightens_once_ranked_groups_fill() {
+        let point = |doc: &str| Point {
+            embedding: vec![0.0],
+            metadata: Metadata::from([("doc".to_string(), MetadataValue::String(doc.to_string()))]),
+            ..Default::default()
+        };
+        let points = [point("a"), point("a"), point("b"), point("a")];
+        let accept_all = |_: &Point| true;
+        let mut collector = GroupCollector::new("doc", 1, 2, &accept_all);
+        collector.offer(1.0, &points[0]);
+        assert_eq!(collector.bound(), f64::INFINITY); // Room for a second hit
+        collector.offer(3.0, &points[1]);
+        assert_eq!(collector.bound(), 3.0);
+        // Can't beat the head of the ranked group, so nothing changes
+        collector.offer(3.5, &points[2]);
+        assert_eq!(collector.groups.len(), 1);
+        collector.offer(2.0, &points[3]);
+        assert_eq!(collector.bound(), 2.0);
+        // A closer head ranks the other group instead, which still has room
+        collector.offer(0.5, &points[2]);
+        assert_eq!(collector.bound(), f64::INFINITY);
+        let ranked = collector.into_ranked();
+        assert_eq!(ranked.len(), 1);
+        assert_eq!(ranked[0].0, Some(&MetadataValue::String("b".to_string())));
+    }
+
+    // Every point of a 3x3x3 grid three times over, in shuffled order, so most values sit
+    // exactly on some split and every embedding is stored more than once
+    fn grid_with_duplicates(seed: u64) -> Vec<Point> {
+        let mut points: Vec<Point> = (0..81)
+            .map(|i| Point { embedding: vec![(i % 3) as f64, (i / 3 % 3) as f64, (i / 9 % 3) as f64], ..Default::default() })
+            .collect();
+        let mut rng = SplitMix64::new(seed);
+        for i in (1..points.len()).rev() {
+            points.swap(i, rng.next_below(i as u64 + 1) as usize);
+        }
+        points
+    }
+
+    #[test]
+    fn find_exact_follows_the_insert_path_through_duplicates_and_ties() {
+        let mut inserted = KDTree::new(3).unwrap();
+        let mut bounded = KDTree::new(3).unwrap();
+        for point in grid_with_duplicates(3) {
+            inserted.insert(point.clone());
+            bounded.insert_bounded(point, 1.0);
+        }
+        let built = KDTree::build(3, grid_with_duplicates(3)).unwrap();
+        for tree in [&inserted, &bounded, &built] {
+            for point in tree.iter() {
+                let found = tree.find_exact(&point.embedding).unwrap_or_else(|| panic!("{:?} not found", point.embedding));
+                assert_eq!(found.embedding, point.embedding);
+            }
+        }
+        // Later duplicates go right past the first one, which is met first on the way down
+        for point in inserted.iter() {
+            let first = inserted.iter().filter(|other| other.embedding == point.embedding).map(|other| other.seq).min();
+            assert_eq!(Some(inserted.find_exact(&point.embedding).unwrap().seq), first);
+        }
+    }
+
+    #[test]
+    fn find_exact_misses_anything_not_stored() {
+        let tree = KDTree::build(3, grid_with_duplicates(5)).unwrap();
+        // Equal to splits on some axes but not all of them
+        assert!(tree.find_exact(&[1.0, 1.0, 0.5]).is_none());
+        assert!(tree.find_exact(&[2.0, 2.0, 3.0]).is_none());
+        assert!(tree.find_exact(&[1.0, 1.0]).is_none());
+        assert!(tree.find_exact(&[]).is_none());
+        // -0.0 equals 0.0, and takes the same side of every split
+        assert_eq!(tree.find_exact(&[-0.0, 0.0, -0.0]).unwrap().embedding, [0.0, 0.0, 0.0]);
+        assert!(KDTree::new(3).unwrap().find_exact(&[0.0, 0.0, 0.0]).is_none());
+    }
+
+    #[test]
+    fn find_exact_looks_in_every_partition() {
+        let mut tree = KDTree::with_partition_field(3, "doc").unwrap();
+        let points = random_points(200, 3, 4, 6);
+        for point in &points {
+            tree.insert(point.clone());
+        }
+        for point in &points {
+            let found = tree.find_exact(&point.embedding).unwrap();
+            assert_eq!(found.metadata, point.metadata);
+        }
+    }
+
+    #[test]
+    fn sorted_inserts_stay_within_the_depth_bound() {
+        const POINTS: usize = 100_000;
+        const DEPTH_FACTOR: f64 = 2.0;
+        // Root counted as depth 1, on top of the deepest node the bound allows
+        let bound = (DEPTH_FACTOR * (POINTS as f64).log2()).ceil() as usize + 1;
+        let point = |x: usize| Point { embedding: vec![x as f64], data: Some(x.to_string()), ..Default::default() };
+        for ascending in [true, false] {
+            let mut tree = KDTree::new(1).unwrap();
+            let mut rebuilds = 0;
+            for i in 0..POINTS {
+                let x = if ascending { i } else { POINTS - 1 - i };
+                if tree.insert_bounded(point(x), DEPTH_FACTOR) {
+                    rebuilds += 1;
+                }
+            }
+            assert_eq!(tree.len(), POINTS);
+            assert!(tree.depth() <= bound, "depth {} over the bound {}", tree.depth(), bound);
+            assert!(rebuilds > 0, "a sorted order must have triggered rebuilds");
+            tree.validate().unwrap();
+
+            for x in [0, 1, 4_999, 50_000, 77_777, POINTS - 1] {
+                let target = Point { embedding: vec![x as f64 + 0.25], ..Default::default() };
+                let found: Vec<_> = tree.nearest_neighbors_topn(&target, 3).iter().map(|point| point.embedding[0]).collect();
+                let mut expected: Vec<f64> = [x as f64, x as f64 + 1.0, x as f64 - 1.0]
+                    .into_iter()
+                    .filter(|value| *value >= 0.0 && *value < POINTS as f64)
+                    .collect();
+                if expected.len() < 3 {
+                    expected.push(if x == 0 { 2.0 } else { x as f64 - 2.0 });
+                }
+                assert_eq!(found, expected, "nearest to {}", x as f64 + 0.25);
+            }
+        }
+    }
+
+    fn assert_norms_match(tree: &KDTree) {
+        for point in tree.iter() {
+            assert_eq!(point.norm, distance::norm(&point.embedding), "point {}", point.seq);
+        }
+    }
+
+    #[test]
+    fn points_carry_their_norms_however_they_were_added() {
+        let mut points = random_points(300, 4, 10, 5);
+        // Whatever norm a point arrives with is replaced
+        points.iter_mut().for_each(|point| point.norm = -1.0);
+        let mut tree = KDTree::build(4, points[..100].to_vec()).unwrap();
+        assert_norms_match(&tree);
+        for point in &points[100..200] {
+            tree.insert(point.clone());
+        }
+        for point in &points[200..250] {
+            tree.insert_bounded(point.clone(), 1.0);
+        }
+        tree = tree.extended(points[250..].to_vec()).unwrap();
+        assert_eq!(tree.len(), 300);
+        assert_norms_match(&tree);
+        tree.validate().unwrap();
+    }
+
+    #[test]
+    fn validate_rejects_a_stored_norm_that_does_not_match() {
+        let mut tree = KDTree::build(2, vec![Point { embedding: vec![3.0, 4.0], ..Default::default() }]).unwrap();
+        tree.validate().unwrap();
+        // Rounding stays within the tolerance
+        tree.root.as_mut().unwrap().point.norm = 5.0 + 1e-12;
+        tree.validate().unwrap();
+        tree.root.as_mut().unwrap().point.norm = 5.5;
+        let error = tree.validate().unwrap_err();
+        assert!(error.to_string().contains("stored norm 5.5"), "{}", error);
+        tree.validate_with(None).unwrap();
+    }
+
+    #[test]
+    fn version_9_files_load_with_norms_computed() {
+        // Version 9 as it was written: the current layout without the norms
+        #[derive(Serialize)]
+        struct PointV9<'a> {
+            embedding: &'a [f64],
+            data: Option<String>,
+            metadata: &'a Metadata,
+            seq: u64,
+            inserted_at: u64,
+        }
+        #[derive(Serialize)]
+        struct NodeV9<'a> {
+            point: PointV9<'a>,
+            left: Option<Box<NodeV9<'a>>>,
+            right: Option<Box<NodeV9<'a>>>,
+            axis: usize,
+        }
+        #[derive(Serialize)]
+        struct KDTreeV9<'a> {
+            root: Option<Box<NodeV9<'a>>>,
+            k: usize,
+            partition_field: &'a Option<String>,
+            partitions: BTreeMap<String, Option<Box<NodeV9<'a>>>>,
+            next_seq: u64,
+            stats: &'a TreeStats,
+            reduction: &'a Option<RandomProjection>,
+            schema: &'a Option<Schema>,
+            model: &'a Option<String>,
+        }
+        fn node_v9(node: &Option<Box<Node>>) -> Option<Box<NodeV9<'_>>> {
+            node.as_ref().map(|node| Box::new(NodeV9 {
+                point: PointV9 {
+                    embedding: &node.point.embedding,
+                    data: None,
+                    metadata: &node.point.metadata,
+                    seq: node.point.seq,
+                    inserted_at: node.point.inserted_at,
+                },
+                left: node_v9(&node.left),
+                right: node_v9(&node.right),
+                axis: node.axis,
+            }))
+        }
+
+        let tree = KDTree::build(3, random_points(200, 3, 5, 9)).unwrap();
+        let mut points = Vec::new();
+        tree.for_each_point(|point| points.push(point));
+        let mut file = Vec::new();
+        file.extend_from_slice(FILE_MAGIC);
+        file.extend_from_slice(&9u32.to_le_bytes());
+        bincode::serialize_into(&mut file, &KDTreeV9 {
+            root: node_v9(&tree.root),
+            k: tree.k,
+            partition_field: &tree.partition_field,
+            partitions: BTreeMap::new(),
+            next_seq: tree.next_seq,
+            stats: &tree.stats,
+            reduction: &tree.reduction,
+            schema: &tree.schema,
+            model: &tree.model,
+        }).unwrap();
+        bincode::serialize_into(&mut file, &payload::payload_lengths(points)).unwrap();
+
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("v9.bin");
+        std::fs::write(&path, file).unwrap();
+
+        // Scans read the file as it is, computing the norms of the points they keep
+        let target = tree.root.as_ref().unwrap().point.embedding.clone();
+        let query = crate::scan::ScanQuery { target: &target, n: 5, partition: None, predicate: &|_| true };
+        let scan = crate::scan::scan_file(&path, &query).unwrap();
+        assert_eq!(scan.scanned, 200);
+        let mut expected: Vec<f64> = tree.iter().map(|point| Euclidean.dist(&point.embedding, &target)).collect();
+        expected.sort_by(f64::total_cmp);
+        assert_eq!(scan.hits.iter().map(|(d, _)| *d).collect::<Vec<_>>(), expected[..5]);
+        for (_, point) in &scan.hits {
+            assert_eq!(point.norm, distance::norm(&point.embedding));
+        }
+
+        let (loaded, version) = KDTree::load_versioned(path.to_str().unwrap()).unwrap();
+        assert_eq!(version, 9);
+        assert_eq!(loaded.len(), 200);
+        assert_norms_match(&loaded);
+        loaded.validate().unwrap();
+
+        // Saved again in the current format, the norms are read back rather than recomputed
+        loaded.save_to_file(path.to_str().unwrap()).unwrap();
+        let (reloaded, version) = KDTree::load_versioned(path.to_str().unwrap()).unwrap();
+        assert_eq!(version, FORMAT_VERSION);
+        assert_norms_match(&reloaded);
+    }
+
+    #[test]
+    fn payload_section_round_trips_through_save_and_load() {
+        let payloads = [None, Some(String::new()), Some("short".to_string()), Some("ünïcødé ✓".to_string()), Some("x".repeat(100_000))];
+        let mut tree = KDTree::with_partition_field(3, "doc").unwrap();
+        for (i, mut point) in random_points(100, 3, 3, 4).into_iter().enumerate() {
+            point.data = payloads[i % payloads.len()].clone();
+            if i % 3 == 0 {
+                point.metadata.clear();
+            }
+            tree.insert(point);
+        }
+        let expected: BTreeMap<u64, (Option<String>, Vec<f64>, Metadata)> =
+            tree.iter().map(|point| (point.seq, (point.data.clone(), point.embedding.clone(), point.metadata.clone()))).collect();
+        let check = |loaded: &KDTree| {
+            assert_eq!(loaded.len(), expected.len());
+            for point in loaded.iter() {
+                let (data, embedding, metadata) = &expected[&point.seq];
+                // Data stays in the file until asked for
+                assert!(point.data.is_none());
+                assert_eq!(point.stored.is_some(), data.is_some(), "point {}", point.seq);
+                assert_eq!(point.data().as_deref(), data.as_deref(), "point {}", point.seq);
+                assert_eq!((&point.embedding, &point.metadata), (embedding, metadata));
+            }
+            loaded.validate().unwrap();
+        };
+
+        let directory = tempfile::tempdir().unwrap();
+        let first = directory.path().join("first.bin");
+        tree.save_to_file(first.to_str().unwrap()).unwrap();
+        let loaded = KDTree::load_from_file(first.to_str().unwrap()).unwrap();
+        check(&loaded);
+
+        // Saving a loaded tree copies the data still on disk into the new file, which then
+        // stands on its own
+        let second = directory.path().join("second.bin");
+        loaded.save_to_file(second.to_str().unwrap()).unwrap();
+        drop(loaded);
+        std::fs::remove_file(&first).unwrap();
+        check(&KDTree::load_from_file(second.to_str().unwrap()).unwrap());
+
+        // A file cut off inside its payload section is corrupt rather than silently short
+        let bytes = std::fs::read(&second).unwrap();
+        std::fs::write(&second, &bytes[..bytes.len() - 1000]).unwrap();
+        let error = KDTree::load_from_file(second.to_str().unwrap()).unwrap_err();
+        assert!(matches!(error, KdTreeError::Corrupt { .. }), "{}", error);
+    }
+
+    // Filters as clients send them: comparisons, set membership and presence checks on a
+    // few fields, nested in `$and`/`$or`
+    fn filter_spec() -> impl proptest::strategy::Strategy<Value = serde_json::Value> {
+        use proptest::prelude::*;
+        use serde_json::json;
+        let leaf = prop_oneof![
+            (prop::sample::select(vec!["$eq", "$ne", "$gt", "$gte", "$lt", "$lte"]), 0..6i64)
+                .prop_map(|(operator, tier)| json!({ "tier": { operator: tier } })),
+            (prop::bool::ANY, prop::sample::subsequence(vec!["en", "de", "fr", "ja"], 0..4))
+                .prop_map(|(negated, langs)| json!({ "lang": { if negated { "$nin" } else { "$in" }: langs } })),
+            prop::bool::ANY.prop_map(|exists| json!({ "flag": { "$exists": exists } })),
+            prop::bool::ANY.prop_map(|flag| json!({ "flag": flag })),
+        ];
+        leaf.prop_recursive(3, 12, 3, |inner| {
+            (prop::bool::ANY, prop::collection::vec(inner, 1..4))
+                .prop_map(|(and, clauses)| json!({ if and { "$and" } else { "$or" }: clauses }))
+        })
+    }
+
+    fn filtered_points() -> impl proptest::strategy::Strategy<Value = Vec<Point>> {
+        use proptest::prelude::*;
+        let point = (
+            prop::collection::vec(-10.0..10.0f64, 3),
+            0..6u8,
+            prop::sample::select(vec!["en", "de", "fr"]),
+            prop::option::of(prop::bool::ANY),
+        );
+        prop::collection::vec(point, 0..200).prop_map(|points| {
+            points
+                .into_iter()
+                .map(|(embedding, tier, lang, flag)| {
+                    let mut metadata = Metadata::from([
+                        ("tier".to_string(), MetadataValue::Number(tier as f64)),
+                        ("lang".to_string(), MetadataValue::String(lang.to_string())),
+                    ]);
+                    if let Some(flag) = flag {
+                        metadata.insert("flag".to_string(), MetadataValue::Bool(flag));
+                    }
+                    Point { embedding, metadata, ..Default::default() }
+                })
+                .collect()
+        })
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn filtered_search_matches_brute_force(
+            points in filtered_points(),
+            spec in filter_spec(),
+            target in proptest::collection::vec(-12.0..12.0f64, 3),
+            n in 1..30usize,
+            built in proptest::bool::ANY,
+        ) {
+            let condition = crate::filter::Condition::parse(&spec.to_string()).unwrap();
+            let tree = match built {
+                true => KDTree::build(3, points.clone()).unwrap(),
+                false => {
+                    let mut tree = KDTree::new(3).unwrap();
+                    points.iter().for_each(|point| tree.insert(point.clone()));
+                    tree
+                }
+            };
+            let target = Point { embedding: target, ..Default::default() };
+            let predicate = |point: &Point| condition.matches(&point.metadata);
+            let (found, _) = tree.nearest_neighbors_topn_traced(&target, n, None, &predicate, None, None);
+
+            let mut expected: Vec<f64> = points
+                .iter()
+                .filter(|point| condition.matches(&point.metadata))
+                .map(|point| Euclidean.dist(&point.embedding, &target.embedding))
+                .collect();
+            expected.sort_by(f64::total_cmp);
+            expected.truncate(n);
+            // Ties may pick different points, so the distances are compared
+            proptest::prop_assert_eq!(found.iter().map(|(distance, _)| *distance).collect::<Vec<_>>(), expected);
+            proptest::prop_assert!(found.iter().all(|(_, point)| condition.matches(&point.metadata)));
+        }
+    }
+
+    // Pre-order by recursion, to check the iterator's explicit stack against
+    fn preorder<'a>(node: &'a Option<Box<Node>>, depth: usize, out: &mut Vec<(usize, &'a Point)>) {
+        if let Some(node) = node {
+            out.push((depth, &node.point));
+            preorder(&node.left, depth + 1, out);
+            preorder(&node.right, depth + 1, out);
+        }
+    }
+
+    #[test]
+    fn iterating_an_empty_tree_yields_nothing() {
+        let tree = KDTree::new(3).unwrap();
+        assert_eq!(tree.iter().count(), 0);
+        assert_eq!(tree.iter_with_depth().count(), 0);
+        assert_eq!(tree.depth(), 0);
+        let partitioned = KDTree::with_partition_field(3, "lang").unwrap();
+        assert_eq!(partitioned.iter().count(), 0);
+    }
+
+    #[test]
+    fn iterating_a_single_node_yields_its_point_at_depth_zero() {
+        let mut tree = KDTree::new(2).unwrap();
+        tree.insert(Point { embedding: vec![1.0, 2.0], data: Some("only".to_string()), ..Default::default() });
+        let items: Vec<_> = tree.iter_with_depth().map(|(depth, point)| (depth, point.data.as_deref())).collect();
+        assert_eq!(items, [(0, Some("only"))]);
+        assert_eq!(tree.depth(), 1);
+    }
+
+    #[test]
+    fn iterating_a_degenerate_tree_needs_no_recursion() {
+        // Sorted inserts without the depth bound make a chain as deep as the tree is long,
+        // to the right when ascending and to the left when descending. Inserting and
+        // dropping such a chain still recurse, which bounds how long it can be here.
+        const POINTS: usize = 1_000;
+        for ascending in [true, false] {
+            let mut tree = KDTree::new(1).unwrap();
+            for i in 0..POINTS {
+                let x = if ascending { i } else { POINTS - 1 - i };
+                tree.insert(Point { embedding: vec![x as f64], ..Default::default() });
+            }
+            let depths: Vec<usize> = tree.iter_with_depth().map(|(depth, _)| depth).collect();
+            assert_eq!(depths, (0..POINTS).collect::<Vec<_>>());
+            let first = tree.iter().next().unwrap().embedding[0];
+            assert_eq!(first, if ascending { 0.0 } else { (POINTS - 1) as f64 });
+            assert_eq!(tree.depth(), POINTS);
+            assert_eq!(tree.count_all(), POINTS);
+        }
+    }
+
+    #[test]
+    fn iteration_is_preorder_over_the_shared_tree_then_each_partition() {
+        let mut tree = KDTree::with_partition_field(3, "doc").unwrap();
+        for (i, mut point) in random_points(300, 3, 4, 9).into_iter().enumerate() {
+            // Every fourth point lacks the field and stays in the shared tree
+            if i % 4 == 0 {
+                point.metadata.clear();
+            }
+            tree.insert(point);
+        }
+        let mut expected = Vec::new();
+        for root in tree.search_roots(None) {
+            preorder(root, 0, &mut expected);
+        }
+        let items: Vec<(usize, &Point)> = tree.iter_with_depth().collect();
+        assert_eq!(items.len(), 300);
+        assert!(items.iter().zip(&expected).all(|(a, b)| a.0 == b.0 && std::ptr::eq(a.1, b.1)));
+        // The shared tree's points come first
+        let shared = tree.iter().take_while(|point| point.metadata.is_empty()).count();
+        assert_eq!(shared, 75);
+        let seqs: Vec<u64> = tree.iter().map(|point| point.seq).collect();
+        assert_eq!(seqs, tree.clone().into_points().iter().map(|point| point.seq).collect::<Vec<_>>());
+    }
 }
@@ -0,0 +1,118 @@
+// Search latency split into the phases a search goes through, recorded per tree. A
+// search times its phases as it runs and records them once it is answered, which costs
+// a few atomic adds; /metrics exports the totals as Prometheus histograms.
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::lock::{WaitHistogram, WAIT_BUCKETS};
+use crate::server_timing;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Total,     // The whole request, query cache hits included
+    LockWait,  // Waiting for the trees lock
+    DiskLoad,  // Loading an offloaded tree, only on searches that had to
+    Traversal, // Walking the tree
+}
+
+impl Phase {
+    pub const ALL: [Phase; 4] = [Phase::Total, Phase::LockWait, Phase::DiskLoad, Phase::Traversal];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Phase::Total => "total",
+            Phase::LockWait => "lock_wait",
+            Phase::DiskLoad => "disk_load",
+            Phase::Traversal => "traversal",
+        }
+    }
+
+    // Name in the `Server-Timing` header
+    fn timing_name(self) -> &'static str {
+        match self {
+            Phase::Total => "total",
+            Phase::LockWait => "lock",
+            Phase::DiskLoad => "load",
+            Phase::Traversal => "search",
+        }
+    }
+}
+
+// Phases timed by one search so far. Each phase also goes into the request's
+// `Server-Timing` header.
+#[derive(Debug, Clone)]
+pub struct SearchTimings {
+    started: Instant,
+    phases: [Option<Duration>; Phase::ALL.len()], // Total is taken when recording
+}
+
+impl SearchTimings {
+    pub fn start() -> Self {
+        server_timing::enable();
+        SearchTimings { started: Instant::now(), phases: [None; Phase::ALL.len()] }
+    }
+
+    // Runs `work`, timing it as `phase`
+    pub fn time<T>(&mut self, phase: Phase, work: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let result = work();
+        self.set(phase, started.elapsed());
+        result
+    }
+
+    pub fn set(&mut self, phase: Phase, elapsed: Duration) {
+        self.phases[phase as usize] = Some(elapsed);
+        server_timing::record(phase.timing_name(), elapsed);
+    }
+}
+
+// Counts of one phase. Each bucket counts only its own range, so recording is a single
+// add; the cumulative form Prometheus expects is built when reading.
+#[derive(Debug, Default)]
+struct AtomicHistogram {
+    buckets: [AtomicU64; WAIT_BUCKETS.len() + 1], // The last one is above every bound
+    sum_micros: AtomicU64,
+}
+
+impl AtomicHistogram {
+    fn record(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let bucket = WAIT_BUCKETS.iter().position(|bound| seconds <= *bound).unwrap_or(WAIT_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> WaitHistogram {
+        let mut histogram = WaitHistogram::default();
+        for (index, bucket) in self.buckets.iter().enumerate() {
+            histogram.count += bucket.load(Ordering::Relaxed);
+            if let Some(cumulative) = histogram.buckets.get_mut(index) {
+                *cumulative = histogram.count;
+            }
+        }
+        histogram.sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        histogram
+    }
+}
+
+// Latency histograms of one tree, one per phase
+#[derive(Debug, Default)]
+pub struct SearchLatency {
+    phases: [AtomicHistogram; Phase::ALL.len()],
+}
+
+impl SearchLatency {
+    pub fn record(&self, timings: &SearchTimings) {
+        self.phases[Phase::Total as usize].record(timings.started.elapsed());
+        for (histogram, elapsed) in self.phases.iter().zip(timings.phases).skip(1) {
+            if let Some(elapsed) = elapsed {
+                histogram.record(elapsed);
+            }
+        }
+    }
+
+    // In the order of `Phase::ALL`
+    pub fn snapshot(&self) -> [WaitHistogram; Phase::ALL.len()] {
+        std::array::from_fn(|index| self.phases[index].snapshot())
+    }
+}
@@ -0,0 +1,52 @@
+// Library half of the vector store, shared by the server binary, the load
+// generator and the benchmarks
+pub mod advisor;
+pub mod api;
+pub mod archive;
+pub mod bloom;
+pub mod cancel;
+pub mod config;
+pub mod disk;
+pub mod distance;
+pub mod durability;
+pub mod embedder;
+pub mod encoding;
+pub mod error;
+pub mod filter;
+pub mod gc;
+pub mod histogram;
+pub mod import;
+pub mod jobs;
+pub mod jsonl;
+pub mod kdtree;
+pub mod latency;
+pub mod limiter;
+pub mod lock;
+pub mod maintenance;
+pub mod manifest;
+pub mod memory;
+pub mod metadata;
+pub mod metrics;
+pub mod mmr;
+pub mod operation;
+pub mod params;
+pub mod payload;
+pub mod projection;
+pub mod query;
+pub mod query_cache;
+pub mod rebalance;
+pub mod reembed;
+pub mod reduction;
+pub mod rng;
+pub mod routes;
+pub mod scan;
+pub mod schema;
+pub mod server;
+pub mod server_timing;
+pub mod snapshot;
+pub mod stats;
+pub mod store;
+pub mod structure;
+pub mod trace;
+pub mod usage;
+pub mod warm;
@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+// Bounds how many heavy requests (export, rebuild, ...) run at once so they can't
+// starve searches. Requests beyond the limit wait in a bounded queue; once that is
+// full they are turned away.
+pub struct HeavyLimiter {
+    permits: Semaphore,
+    max_concurrency: usize,
+    max_queued: usize,
+    queued: AtomicUsize,
+}
+
+// Decrements the queue length however the wait ends, including a dropped request
+struct Queued<'a>(&'a AtomicUsize);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl HeavyLimiter {
+    pub fn new(max_concurrency: usize, max_queued: usize) -> Self {
+        HeavyLimiter {
+            permits: Semaphore::new(max_concurrency),
+            max_concurrency,
+            max_queued,
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    // Waits for a slot, or returns None right away when the queue is full. The slot is
+    // released when the permit is dropped.
+    pub async fn acquire(&self) -> Option<SemaphorePermit<'_>> {
+        if let Ok(permit) = self.permits.try_acquire() {
+            return Some(permit);
+        }
+        let queued = self.queued.fetch_add(1, Ordering::Relaxed);
+        let _queued = Queued(&self.queued);
+        if queued >= self.max_queued {
+            return None;
+        }
+        self.permits.acquire().await.ok()
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.max_concurrency - self.permits.available_permits()
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+}
+
+// Every request costs at least this much
+const BASE_COST: f64 = 1.0;
+
+// Tree nodes a search may visit per unit of budget
+const VISITS_PER_UNIT: f64 = 100.0;
+
+// Extra share of a search's visit cost per filter condition, each checked per candidate
+const FILTER_TERM_WEIGHT: f64 = 0.25;
+
+// Points of a batch insert per unit of budget
+const POINTS_PER_UNIT: f64 = 10.0;
+
+// Visits per requested result assumed for a tree until one of its searches is measured
+const DEFAULT_VISITS_PER_RESULT: f64 = 20.0;
+
+// Weight of the latest measurement in a tree's moving average
+const SMOOTHING: f64 = 0.2;
+
+// Clients tracked before buckets that have refilled are forgotten
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+// Per-client token buckets where requests pay by how much work they are expected to do.
+// A bucket holds a minute's budget and refills continuously. Requests are charged an
+// estimate up front; searches then report the nodes they actually visited, which
+// settles the difference and refines the visits-per-result figure later estimates for
+// the same tree start from. A request is admitted while its cost (or a full bucket, for
+// requests costing more than that) is left, and may take the bucket below zero.
+pub struct CostLimiter {
+    budget: f64, // Bucket size, and what it refills per minute
+    buckets: Mutex<HashMap<String, Bucket>>,
+    visits_per_result: Mutex<HashMap<String, f64>>, // Moving average per tree
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+    spent: u64,
+    rejected: u64,
+}
+
+impl Bucket {
+    fn refill(&mut self, budget: f64, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * budget / 60.0).min(budget);
+        self.refilled_at = now;
+    }
+
+    // Whole seconds until the bucket holds `tokens` again
+    fn seconds_until(&self, tokens: f64, budget: f64) -> u64 {
+        ((tokens - self.tokens).max(0.0) * 60.0 / budget).ceil() as u64
+    }
+}
+
+// What an admitted request was charged, to settle once its real cost is known
+#[derive(Debug, Clone)]
+pub struct Charge {
+    pub client: String,
+    pub cost: u64,
+}
+
+// Why a request was turned away, for the headers of its 429
+#[derive(Debug, Clone, Copy)]
+pub struct Exhausted {
+    pub cost: u64,
+    pub budget: u64,
+    pub remaining: i64,
+    pub retry_after_secs: u64, // Until the request could be admitted
+    pub reset_secs: u64,       // Until the bucket is full again
+}
+
+// One client's accounting, for /metrics
+#[derive(Debug, Clone)]
+pub struct ClientCost {
+    pub client: String,
+    pub spent: u64,
+    pub rejected: u64,
+    pub remaining: i64,
+}
+
+impl CostLimiter {
+    // `budget` is what each client may spend per minute
+    pub fn new(budget: u64) -> Self {
+        CostLimiter {
+            budget: budget as f64,
+            buckets: Mutex::new(HashMap::new()),
+            visits_per_result: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // A search for `results` points of a tree, with `filter_terms` metadata conditions
+    pub fn search_cost(&self, tree_name: &str, results: usize, filter_terms: usize) -> u64 {
+        let per_result = self.visits_per_result.lock().unwrap().get(tree_name).copied().unwrap_or(DEFAULT_VISITS_PER_RESULT);
+        visit_cost(per_result * results.max(1) as f64, filter_terms)
+    }
+
+    // A lookup of any point within `distance`. Distance 0 is an exact match that follows
+    // one path down the tree; otherwise it may look as far as a top-1 search.
+    pub fn radius_cost(&self, tree_name: &str, distance: f64) -> u64 {
+        match distance > 0.0 {
+            true => self.search_cost(tree_name, 1, 0),
+            false => BASE_COST as u64,
+        }
+    }
+
+    // An insert of `points` points
+    pub fn batch_cost(points: usize) -> u64 {
+        (points as f64 / POINTS_PER_UNIT).ceil().max(BASE_COST) as u64
+    }
+
+    // What a search that visited `visits` nodes actually cost, and a refinement of the
+    // tree's estimate when it traversed the tree rather than being answered from a cache
+    pub fn measured(&self, tree_name: &str, results: usize, filter_terms: usize, visits: usize) -> u64 {
+        if visits > 0 {
+            let measured = visits as f64 / results.max(1) as f64;
+            self.visits_per_result
+                .lock()
+                .unwrap()
+                .entry(tree_name.to_string())
+                .and_modify(|average| *average += SMOOTHING * (measured - *average))
+                .or_insert(measured);
+        }
+        visit_cost(visits as f64, filter_terms)
+    }
+
+    pub fn charge(&self, client: &str, cost: u64) -> Result<Charge, Exhausted> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if !buckets.contains_key(client) && buckets.len() >= MAX_TRACKED_CLIENTS {
+            buckets.retain(|_, bucket| {
+                bucket.refill(self.budget, now);
+                bucket.tokens < self.budget
+            });
+        }
+        let bucket = buckets.entry(client.to_string()).or_insert(Bucket { tokens: self.budget, refilled_at: now, spent: 0, rejected: 0 });
+        bucket.refill(self.budget, now);
+        let needed = (cost as f64).min(self.budget);
+        if bucket.tokens < needed {
+            bucket.rejected += 1;
+            return Err(Exhausted {
+                cost,
+                budget: self.budget as u64,
+                remaining: bucket.tokens.floor() as i64,
+                retry_after_secs: bucket.seconds_until(needed, self.budget).max(1),
+                reset_secs: bucket.seconds_until(self.budget, self.budget),
+            });
+        }
+        bucket.tokens -= cost as f64;
+        bucket.spent += cost;
+        Ok(Charge { client: client.to_string(), cost })
+    }
+
+    // Replaces the estimate charged with what the request turned out to cost
+    pub fn settle(&self, charge: &Charge, cost: u64) {
+        let mut buckets = self.buckets.lock().unwrap();
+        if let Some(bucket) = buckets.get_mut(&charge.client) {
+            bucket.tokens = (bucket.tokens + charge.cost as f64 - cost as f64).min(self.budget);
+            bucket.spent = (bucket.spent + cost).saturating_sub(charge.cost);
+        }
+    }
+
+    // Every tracked client, refilled to now
+    pub fn clients(&self) -> Vec<ClientCost> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets
+            .iter_mut()
+            .map(|(client, bucket)| {
+                bucket.refill(self.budget, now);
+                ClientCost { client: client.clone(), spent: bucket.spent, rejected: bucket.rejected, remaining: bucket.tokens.floor() as i64 }
+            })
+            .collect()
+    }
+}
+
+fn visit_cost(visits: f64, filter_terms: usize) -> u64 {
+    (BASE_COST + visits / VISITS_PER_UNIT * (1.0 + FILTER_TERM_WEIGHT * filter_terms as f64)).ceil() as u64
+}
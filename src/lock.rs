@@ -0,0 +1,129 @@
+// A mutex that measures how long callers wait for it, attributed to the endpoint and
+// tree they were serving, and remembers who took it last so a long wait can name the
+// operation it was stuck behind
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::{LockResult, Mutex, MutexGuard, TryLockError};
+use std::time::{Duration, Instant};
+
+// Upper bounds of the wait histogram buckets, in seconds
+pub const WAIT_BUCKETS: [f64; 10] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+tokio::task_local! {
+    static CONTEXT: LockContext;
+}
+
+// What a task is doing while it takes locks: set per request by the server, and by the
+// tasks it spawns. Locks taken anywhere else count as `background`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct LockContext {
+    pub endpoint: String,
+    pub tree: String, // Empty when the work isn't about one tree
+}
+
+impl LockContext {
+    pub fn new(endpoint: impl Into<String>, tree: impl Into<String>) -> Self {
+        LockContext { endpoint: endpoint.into(), tree: tree.into() }
+    }
+
+    // Runs `future` with the locks it takes attributed to this context
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        CONTEXT.scope(self, future).await
+    }
+
+    fn current() -> LockContext {
+        CONTEXT.try_with(Clone::clone).unwrap_or_else(|_| LockContext::new("background", ""))
+    }
+}
+
+// Waits of one context, or search latencies of one phase, as a cumulative Prometheus
+// histogram
+#[derive(Debug, Clone, Default)]
+pub struct WaitHistogram {
+    pub buckets: [u64; WAIT_BUCKETS.len()], // Waits at most as long as the matching bound
+    pub count: u64,
+    pub sum: f64,
+}
+
+impl WaitHistogram {
+    fn record(&mut self, seconds: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(WAIT_BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+
+    // Folds another histogram over the same buckets into this one
+    pub fn add(&mut self, other: &WaitHistogram) {
+        for (bucket, count) in self.buckets.iter_mut().zip(other.buckets) {
+            *bucket += count;
+        }
+        self.count += other.count;
+        self.sum += other.sum;
+    }
+}
+
+pub struct TrackedMutex<T> {
+    inner: Mutex<T>,
+    name: &'static str,
+    warn_after: Option<Duration>,
+    holder: Mutex<Option<(LockContext, Instant)>>, // Who took the lock last, and when
+    waits: Mutex<BTreeMap<LockContext, WaitHistogram>>,
+}
+
+impl<T> TrackedMutex<T> {
+    // `name` labels the metric and warnings; waits of at least `warn_after` are logged
+    pub fn new(value: T, name: &'static str, warn_after: Option<Duration>) -> Self {
+        TrackedMutex {
+            inner: Mutex::new(value),
+            name,
+            warn_after,
+            holder: Mutex::new(None),
+            waits: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    // Same as `Mutex::lock`, recording the wait. An uncontended lock is recorded as no wait.
+    pub fn lock(&self) -> LockResult<MutexGuard<'_, T>> {
+        let context = LockContext::current();
+        let (result, waited, holder) = match self.inner.try_lock() {
+            Ok(guard) => (Ok(guard), Duration::ZERO, None),
+            Err(TryLockError::Poisoned(e)) => (Err(e), Duration::ZERO, None),
+            Err(TryLockError::WouldBlock) => {
+                let holder = self.holder.lock().unwrap().clone();
+                let started = Instant::now();
+                let result = self.inner.lock();
+                (result, started.elapsed(), holder)
+            }
+        };
+
+        if self.warn_after.is_some_and(|limit| waited >= limit) {
+            let holder = match holder {
+                Some((holder, since)) => format!(
+                    "{} (tree {:?}), which took it {}ms before the wait ended",
+                    holder.endpoint, holder.tree, since.elapsed().as_millis()
+                ),
+                None => "an unknown holder".to_string(),
+            };
+            println!(
+                "WARNING: {} (tree {:?}) waited {}ms for the {} lock, last taken by {}",
+                context.endpoint, context.tree, waited.as_millis(), self.name, holder
+            );
+        }
+        *self.holder.lock().unwrap() = Some((context.clone(), Instant::now()));
+        self.waits.lock().unwrap().entry(context).or_default().record(waited.as_secs_f64());
+        result
+    }
+
+    // Wait histograms recorded so far, by context
+    pub fn waits(&self) -> Vec<(LockContext, WaitHistogram)> {
+        self.waits.lock().unwrap().iter().map(|(context, waits)| (context.clone(), waits.clone())).collect()
+    }
+}
@@ -0,0 +1,164 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::api::{MaintenanceStatus, MaintenanceTaskStatus};
+
+const DAY: u64 = 24 * 60 * 60;
+
+// How far ahead `next_run` looks for an interval tick that falls inside the window
+const LOOKAHEAD: u64 = 7 * DAY;
+
+// When background maintenance may run, e.g. `02:00-04:00 UTC`. A task whose interval
+// fires outside the window skips that tick; its endpoint still works at any time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum MaintenanceWindow {
+    #[default]
+    Always,
+    Daily { start: u64, end: u64 }, // Seconds after midnight UTC; wraps past midnight when end < start
+}
+
+impl MaintenanceWindow {
+    pub fn contains(&self, unix_seconds: u64) -> bool {
+        match *self {
+            MaintenanceWindow::Always => true,
+            MaintenanceWindow::Daily { start, end } => {
+                let time = unix_seconds % DAY;
+                if start < end { start <= time && time < end } else { time >= start || time < end }
+            }
+        }
+    }
+}
+
+impl FromStr for MaintenanceWindow {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, String> {
+        let value = value.trim();
+        if value.eq_ignore_ascii_case("always") {
+            return Ok(MaintenanceWindow::Always);
+        }
+        let range = value.strip_suffix("UTC").unwrap_or(value).trim();
+        let parsed = range.split_once('-').and_then(|(start, end)| Some((time_of_day(start)?, time_of_day(end)?)));
+        match parsed {
+            Some((start, end)) if start != end => Ok(MaintenanceWindow::Daily { start, end }),
+            Some(_) => Err(format!("maintenance window {} is empty", value)),
+            None => Err(format!("expected `always` or HH:MM-HH:MM UTC, got: {}", value)),
+        }
+    }
+}
+
+impl TryFrom<String> for MaintenanceWindow {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, String> {
+        value.parse()
+    }
+}
+
+impl From<MaintenanceWindow> for String {
+    fn from(window: MaintenanceWindow) -> String {
+        window.to_string()
+    }
+}
+
+impl fmt::Display for MaintenanceWindow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MaintenanceWindow::Always => write!(f, "always"),
+            MaintenanceWindow::Daily { start, end } => write!(
+                f,
+                "{:02}:{:02}-{:02}:{:02} UTC",
+                start / 3600, start % 3600 / 60, end / 3600, end % 3600 / 60
+            ),
+        }
+    }
+}
+
+// `HH:MM` as seconds after midnight
+fn time_of_day(value: &str) -> Option<u64> {
+    let (hours, minutes) = value.trim().split_once(':')?;
+    let (hours, minutes): (u64, u64) = (hours.parse().ok()?, minutes.parse().ok()?);
+    (hours < 24 && minutes < 60).then_some(hours * 3600 + minutes * 60)
+}
+
+#[derive(Debug, Default)]
+struct TaskState {
+    interval: u64, // Seconds between ticks
+    running: bool,
+    last_started: Option<u64>,
+    last_finished: Option<u64>,
+    next_run: Option<u64>,
+    skipped: u64,
+}
+
+// Decides on every tick of a background task whether it runs, and remembers what each
+// task did for /status. Times are Unix seconds.
+pub struct Scheduler {
+    window: MaintenanceWindow,
+    tasks: Mutex<BTreeMap<&'static str, TaskState>>,
+}
+
+impl Scheduler {
+    pub fn new(window: MaintenanceWindow) -> Self {
+        Scheduler { window, tasks: Mutex::new(BTreeMap::new()) }
+    }
+
+    pub fn register(&self, task: &'static str, interval: Duration, now: u64) {
+        let interval = interval.as_secs().max(1);
+        let next_run = self.next_run(now, interval);
+        self.tasks.lock().unwrap().insert(task, TaskState { interval, next_run, ..Default::default() });
+    }
+
+    // Called on each tick. True when the task should run now: inside the window, with
+    // the previous run finished. A run still going skips this tick.
+    pub fn begin(&self, task: &'static str, now: u64) -> bool {
+        let mut tasks = self.tasks.lock().unwrap();
+        let Some(state) = tasks.get_mut(task) else { return false };
+        state.next_run = self.next_run(now, state.interval);
+        if !self.window.contains(now) {
+            return false;
+        }
+        if state.running {
+            state.skipped += 1;
+            println!("Skipping {}: the previous run is still going", task);
+            return false;
+        }
+        state.running = true;
+        state.last_started = Some(now);
+        true
+    }
+
+    pub fn finish(&self, task: &'static str, now: u64) {
+        if let Some(state) = self.tasks.lock().unwrap().get_mut(task) {
+            state.running = false;
+            state.last_finished = Some(now);
+        }
+    }
+
+    pub fn status(&self, now: u64) -> MaintenanceStatus {
+        let tasks = self.tasks.lock().unwrap();
+        MaintenanceStatus {
+            window: self.window.to_string(),
+            in_window: self.window.contains(now),
+            tasks: tasks.iter().map(|(task, state)| MaintenanceTaskStatus {
+                task: task.to_string(),
+                running: state.running,
+                last_started: state.last_started,
+                last_finished: state.last_finished,
+                next_run: state.next_run,
+                skipped: state.skipped,
+            }).collect(),
+        }
+    }
+
+    // The first later tick inside the window; None if no tick within a week lands in it,
+    // as happens when the window is shorter than the interval and never lines up
+    fn next_run(&self, now: u64, interval: u64) -> Option<u64> {
+        (1..=LOOKAHEAD / interval + 1).map(|ticks| now + ticks * interval).find(|time| self.window.contains(*time))
+    }
+}
@@ -0,0 +1,102 @@
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::archive::Tier;
+use crate::scan::OffloadedStrategy;
+
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+// What we remember about a tree between restarts, without having to load it
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TreeEntry {
+    pub dimensions: Option<usize>,
+    pub num_records: usize,
+    #[serde(default)]
+    pub depth: Option<usize>,  // Longest root-to-leaf path as of the last write
+    pub access_count: u64,
+    pub last_accessed_at: u64, // Unix seconds of the last data-path access
+    #[serde(default)]
+    pub tier: Tier,            // Archived entries are the stubs of trees moved to the archive directory
+    #[serde(default)]
+    pub searches: u64,
+    #[serde(default)]
+    pub inserts: u64,
+    #[serde(default)]
+    pub bytes_served: u64,
+    #[serde(default)]
+    pub bytes_inserted: u64,   // Content of inserted points
+    #[serde(default)]
+    pub bytes_written: u64,    // Tree file bytes written to disk
+    #[serde(default)]
+    pub saves: u64,            // Tree file writes
+    #[serde(default)]
+    pub last_write_at: u64,    // Unix seconds of the last applied insert, delete or truncation, 0 if never
+    #[serde(default)]
+    pub created_at: u64,       // Unix seconds the tree was created, 0 for trees from before it was recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>, // Embedding model tag, kept here so listings never load the tree
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offloaded_strategy: Option<OffloadedStrategy>, // Searches' default while the tree is offloaded
+}
+
+// Per-tree metadata persisted as `manifest.json` in the bin directory
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Manifest {
+    pub trees: BTreeMap<String, TreeEntry>,
+}
+
+impl Manifest {
+    pub fn path(bin_directory: &Path) -> PathBuf {
+        bin_directory.join(MANIFEST_FILE)
+    }
+
+    // A missing manifest is normal on first start; a broken one is reported and ignored
+    // since everything in it can be rebuilt from the tree files
+    pub fn load(bin_directory: &Path) -> Manifest {
+        let path = Self::path(bin_directory);
+        match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+                println!("Ignoring unreadable manifest {:?}: {}", path, e);
+                Manifest::default()
+            }),
+            Err(_) => Manifest::default(),
+        }
+    }
+
+    // Written to a temporary file and renamed so a crash never leaves a torn manifest
+    pub fn save(&self, bin_directory: &Path) -> io::Result<()> {
+        let path = Self::path(bin_directory);
+        let tmp_path = path.with_extension("json.tmp");
+        let bytes = serde_json::to_vec_pretty(self).map_err(io::Error::other)?;
+        fs::write(&tmp_path, bytes)?;
+        fs::rename(tmp_path, path)
+    }
+}
+
+pub fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+// `time` as an RFC 3339 timestamp in UTC with second precision, e.g. 2025-10-09T08:26:40Z
+pub fn rfc3339(time: SystemTime) -> String {
+    let seconds = unix_seconds(time);
+    let (days, second_of_day) = (seconds / 86_400, seconds % 86_400);
+    // Civil date from days since 1970-01-01, in 400-year eras starting on March 1st
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z % 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, second_of_day / 3600, second_of_day % 3600 / 60, second_of_day % 60
+    )
+}
@@ -0,0 +1,109 @@
+// Memory and disk accounting for trees. The server's eviction and the capacity
+// estimates both go through here so they can't disagree about what a point costs.
+use serde::{Deserialize, Serialize};
+
+use crate::error::FieldError;
+use crate::kdtree::{KDTree, Node, Point};
+
+// Rough ratios a general purpose compressor reaches on tree files: embedding bytes
+// are mostly high-entropy mantissas, while chunk text compresses well
+const EMBEDDING_COMPRESSION_RATIO: f64 = 0.9;
+const DATA_COMPRESSION_RATIO: f64 = 0.35;
+
+// Bytes of the magic and version written before the serialized tree
+const FILE_HEADER_BYTES: usize = 8;
+
+// Length prefix of the payload lengths that follow the serialized tree
+const PAYLOAD_LENGTHS_BYTES: usize = 8;
+
+pub fn estimate_memory_usage(tree: &KDTree) -> usize {
+    let mut total_size = 0;
+    total_size += std::mem::size_of::<KDTree>();
+    total_size += tree.reduction().map_or(0, |reduction| reduction.heap_size());
+    total_size += tree.iter().map(estimate_point_size).sum::<usize>();
+    total_size
+}
+
+// What a point adds to a tree once inserted: its node and the point's own heap data
+pub fn estimate_point_size(point: &Point) -> usize {
+    std::mem::size_of::<Node>() + point.heap_size()
+}
+
+// Footprint of a tree file of `file_bytes` holding `num_records` points once loaded,
+// without reading it: the serialized tree plus a node per point. The payload section
+// stays on disk but is counted too, so the estimate errs high.
+pub fn estimate_load_size(file_bytes: u64, num_records: usize) -> usize {
+    std::mem::size_of::<KDTree>() + file_bytes as usize + num_records * std::mem::size_of::<Node>()
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Precision {
+    F32,
+    #[default]
+    F64,
+}
+
+// A workload to size: `points` points of `dimensions` with `data_bytes_avg` of chunk text
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Workload {
+    pub dimensions: usize,
+    pub points: usize,
+    #[serde(default)]
+    pub precision: Precision, // Precision of the source embeddings; they are stored as f64
+    #[serde(default)]
+    pub data_bytes_avg: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Estimate {
+    pub in_memory_bytes: usize,
+    pub on_disk_bytes: usize,
+    pub on_disk_compressed_bytes: usize, // If the file were compressed; tree files currently aren't
+}
+
+// Largest workload dimension accepted; the estimate allocates one sample embedding
+pub const MAX_ESTIMATE_DIMENSIONS: usize = 65_536;
+
+impl Workload {
+    pub fn validate(&self) -> Result<(), Vec<FieldError>> {
+        if self.dimensions == 0 || self.dimensions > MAX_ESTIMATE_DIMENSIONS {
+            return Err(vec![FieldError::new(
+                "dimensions",
+                format!("must be between 1 and {}", MAX_ESTIMATE_DIMENSIONS),
+            )]);
+        }
+        Ok(())
+    }
+
+    pub fn estimate(&self) -> Estimate {
+        let embedding_bytes = self.dimensions * std::mem::size_of::<f64>();
+        let sample = Point { embedding: vec![0.0; self.dimensions], ..Default::default() };
+
+        // Same accounting as a resident node. Chunk text stays in the payload section
+        // once a tree is loaded, so it only counts on disk.
+        let node_bytes = std::mem::size_of::<Node>() + sample.heap_size();
+        let in_memory_bytes = std::mem::size_of::<KDTree>() + self.points * node_bytes;
+
+        // The serialized size of a one-point tree gives the exact per-point overhead,
+        // plus the point's entry in the payload lengths and its chunk text
+        let empty = KDTree::new(self.dimensions).map(|tree| serialized_size(&tree)).unwrap_or(0);
+        let single = KDTree::build(self.dimensions, vec![sample]).map(|tree| serialized_size(&tree)).unwrap_or(0);
+        let length = (self.data_bytes_avg > 0).then_some(0u32);
+        let per_point = single.saturating_sub(empty) + bincode::serialized_size(&length).unwrap_or(0) as usize + self.data_bytes_avg;
+        let on_disk_bytes = FILE_HEADER_BYTES + PAYLOAD_LENGTHS_BYTES + empty + self.points * per_point;
+
+        let overhead = per_point.saturating_sub(embedding_bytes + self.data_bytes_avg);
+        let compressed_per_point = (embedding_bytes as f64 * EMBEDDING_COMPRESSION_RATIO
+            + self.data_bytes_avg as f64 * DATA_COMPRESSION_RATIO) as usize
+            + overhead;
+        let on_disk_compressed_bytes = FILE_HEADER_BYTES + PAYLOAD_LENGTHS_BYTES + empty + self.points * compressed_per_point;
+
+        Estimate { in_memory_bytes, on_disk_bytes, on_disk_compressed_bytes }
+    }
+}
+
+fn serialized_size(tree: &KDTree) -> usize {
+    bincode::serialized_size(tree).map_or(0, |size| size as usize)
+}
@@ -0,0 +1,113 @@
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fmt;
+
+// Free-form key/value metadata attached to a point
+pub type Metadata = BTreeMap<String, MetadataValue>;
+
+// A single metadata value. JSON clients see plain strings, numbers and booleans,
+// while bincode stores an explicit tag since it cannot deserialize untagged data.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetadataValue {
+    Bool(bool),
+    Number(f64),
+    String(String),
+}
+
+#[derive(Serialize)]
+enum StoredRef<'a> {
+    Bool(bool),
+    Number(f64),
+    String(&'a str),
+}
+
+#[derive(Deserialize)]
+enum Stored {
+    Bool(bool),
+    Number(f64),
+    String(String),
+}
+
+impl Serialize for MetadataValue {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            match self {
+                MetadataValue::Bool(value) => serializer.serialize_bool(*value),
+                // Keep integral values looking like integers in JSON
+                MetadataValue::Number(value) if value.fract() == 0.0 && value.abs() < i64::MAX as f64 => {
+                    serializer.serialize_i64(*value as i64)
+                }
+                MetadataValue::Number(value) => serializer.serialize_f64(*value),
+                MetadataValue::String(value) => serializer.serialize_str(value),
+            }
+        } else {
+            match self {
+                MetadataValue::Bool(value) => StoredRef::Bool(*value),
+                MetadataValue::Number(value) => StoredRef::Number(*value),
+                MetadataValue::String(value) => StoredRef::String(value),
+            }
+            .serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for MetadataValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(MetadataValueVisitor)
+        } else {
+            Ok(match Stored::deserialize(deserializer)? {
+                Stored::Bool(value) => MetadataValue::Bool(value),
+                Stored::Number(value) => MetadataValue::Number(value),
+                Stored::String(value) => MetadataValue::String(value),
+            })
+        }
+    }
+}
+
+struct MetadataValueVisitor;
+
+impl Visitor<'_> for MetadataValueVisitor {
+    type Value = MetadataValue;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a string, number or boolean")
+    }
+
+    fn visit_bool<E: de::Error>(self, value: bool) -> Result<MetadataValue, E> {
+        Ok(MetadataValue::Bool(value))
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<MetadataValue, E> {
+        Ok(MetadataValue::Number(value as f64))
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<MetadataValue, E> {
+        Ok(MetadataValue::Number(value as f64))
+    }
+
+    fn visit_f64<E: de::Error>(self, value: f64) -> Result<MetadataValue, E> {
+        Ok(MetadataValue::Number(value))
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<MetadataValue, E> {
+        Ok(MetadataValue::String(value.to_string()))
+    }
+
+    fn visit_string<E: de::Error>(self, value: String) -> Result<MetadataValue, E> {
+        Ok(MetadataValue::String(value))
+    }
+}
+
+// Plain text form used where a value has to name something, e.g. a partition.
+// Rust already prints integral floats without a fraction, so `5` and `5.0` agree.
+impl fmt::Display for MetadataValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetadataValue::Bool(value) => write!(f, "{}", value),
+            MetadataValue::Number(value) => write!(f, "{}", value),
+            MetadataValue::String(value) => f.write_str(value),
+        }
+    }
+}
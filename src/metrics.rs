@@ -0,0 +1,526 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::{Display, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::api::{StatusTotals, TreeStatus};
+use crate::latency::Phase;
+use crate::limiter::ClientCost;
+use crate::lock::{LockContext, WaitHistogram, WAIT_BUCKETS};
+
+// Wait histograms of one tracked lock, by the context that waited
+pub type LockWaits = (&'static str, Vec<(LockContext, WaitHistogram)>);
+
+// Search latency histograms of one tree, in the order of `Phase::ALL`
+pub type TreeLatency = (String, [WaitHistogram; Phase::ALL.len()]);
+
+// Label shared by the trees beyond the most searched ones in histograms, and by lock
+// waits of requests naming a tree the server does not know
+pub const OTHER_TREES: &str = "other";
+
+// Label shared by the rate limited clients beyond the biggest spenders
+const OTHER_CLIENTS: &str = "other";
+
+// Process-wide counters exposed on /metrics in the Prometheus text format
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub offloaded_rejections: AtomicU64,  // Searches refused with 409 because of if_in_memory=true
+    pub model_mismatches: AtomicU64,      // Searches and inserts naming another model than their tree's
+    pub bloom_checks: AtomicU64,          // Inserts checked against a duplicate filter
+    pub bloom_positives: AtomicU64,       // Checks where the filter reported a possible duplicate
+    pub bloom_false_positives: AtomicU64, // Positives the exact search proved to be new points
+    pub duplicates_skipped: AtomicU64,    // Inserts skipped as exact duplicates
+    pub query_cache_hits: AtomicU64,      // Searches answered from the query result cache
+    pub query_cache_misses: AtomicU64,    // Cacheable searches that had to traverse the tree
+    pub partial_rebuilds: AtomicU64,      // Subtrees rebuilt because an insert made them too deep
+    pub self_test_failures: AtomicU64,    // Self-test checks that marked a tree suspect
+    pub trees_archived: AtomicU64,        // Idle trees moved to the archive directory
+    pub trees_restored: AtomicU64,        // Archived trees brought back by a request
+    pub ephemeral_loads: AtomicU64,       // Offloaded trees searched with cache=false and dropped again
+    pub deduplicated_loads: AtomicU64,    // cache=false searches that waited for another search's load instead
+    pub scan_searches: AtomicU64,         // Searches of offloaded trees answered by streaming the tree file
+    pub scanned_points: AtomicU64,        // Points those scans read
+    pub memory_rejections: AtomicU64,     // Loads and inserts refused because the tree can't fit the budget
+    pub background_evictions: AtomicU64,  // Trees offloaded by the background task above the soft memory limit
+    pub background_evicted_bytes: AtomicU64, // Estimated memory they freed
+    pub gc_files_removed: AtomicU64,      // Leftover files removed from the bin directory
+    pub gc_bytes_reclaimed: AtomicU64,    // Their total size
+    pub write_queue_depth: AtomicU64,     // Inserts waiting in tree write queues
+    pub writer_lag_ms: AtomicU64,         // Queue wait of the oldest insert in the latest batch
+    pub insert_save_failures: AtomicU64,  // Insert batches kept in memory because writing the tree failed
+    pub flush_failures: AtomicU64,        // Background flushes of a tree that failed after all their retries
+    pub degraded_rejections: AtomicU64,   // Writes refused with 503 because the tree's saves kept failing
+    pub heavy_in_flight: AtomicU64,       // Heavy requests holding a limiter slot
+    pub heavy_queued: AtomicU64,          // Heavy requests waiting for a slot
+    pub response_estimates: AtomicU64,    // Searches whose response size was estimated up front
+    pub response_estimated_bytes: AtomicU64, // Sum of those estimates
+    pub response_size_rejections: AtomicU64, // Searches refused with 413 above MAX_RESPONSE_MB
+    pub exports_cancelled: AtomicU64,     // Exports stopped early because their client disconnected
+}
+
+impl Metrics {
+    pub fn incr(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Counters plus the store gauges of a status snapshot, so /metrics reports exactly
+    // what /status and /trees would have for the same snapshot, the lock waits and the
+    // search latencies, and the cost accounting of rate limited clients. Histograms label
+    // only the `tree_labels` most searched trees by name and fold the rest into `other`;
+    // as many of the clients that spent the most are labelled, the rest summed as `other`.
+    pub fn render(
+        &self,
+        trees: &[TreeStatus],
+        totals: &StatusTotals,
+        locks: &[LockWaits],
+        latencies: &[TreeLatency],
+        clients: &[ClientCost],
+        tree_labels: usize,
+    ) -> String {
+        let labelled = most_searched(trees, tree_labels);
+        let mut out = String::new();
+        write_counter(
+            &mut out,
+            "vodb_offloaded_rejections_total",
+            "Searches rejected because the tree was offloaded and if_in_memory was set",
+            self.offloaded_rejections.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "vodb_model_mismatches_total",
+            "Searches and inserts whose model differed from their tree's, refused or warned about",
+            self.model_mismatches.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "vodb_bloom_checks_total",
+            "Inserts checked against the duplicate bloom filter",
+            self.bloom_checks.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "vodb_bloom_positives_total",
+            "Bloom filter checks that reported a possible duplicate",
+            self.bloom_positives.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "vodb_bloom_false_positives_total",
+            "Bloom filter positives that turned out not to be duplicates",
+            self.bloom_false_positives.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "vodb_duplicates_skipped_total",
+            "Inserts skipped because the exact embedding was already stored",
+            self.duplicates_skipped.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "vodb_query_cache_hits_total",
+            "Searches answered from the query result cache",
+            self.query_cache_hits.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "vodb_query_cache_misses_total",
+            "Searches that missed the query result cache",
+            self.query_cache_misses.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "vodb_partial_rebuilds_total",
+            "Subtrees rebuilt on insert because they exceeded the depth bound",
+            self.partial_rebuilds.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "vodb_self_test_failures_total",
+            "Self-test checks where a tree could not find one of its own points",
+            self.self_test_failures.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "vodb_trees_archived_total",
+            "Idle trees compressed into the archive directory",
+            self.trees_archived.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "vodb_trees_restored_total",
+            "Archived trees restored to the bin directory on request",
+            self.trees_restored.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "vodb_ephemeral_loads_total",
+            "Offloaded trees loaded for a single cache=false search without being cached",
+            self.ephemeral_loads.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "vodb_deduplicated_loads_total",
+            "cache=false searches that shared a concurrent search's load of the tree instead of reading it again",
+            self.deduplicated_loads.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "vodb_scan_searches_total",
+            "Searches of offloaded trees answered by streaming the tree file instead of loading it",
+            self.scan_searches.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "vodb_scanned_points_total",
+            "Points read from tree files by scan searches",
+            self.scanned_points.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "vodb_memory_rejections_total",
+            "Loads and inserts refused with 507 because the tree would not fit the memory budget",
+            self.memory_rejections.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "vodb_background_evictions_total",
+            "Trees offloaded in the background because resident memory was above the soft limit",
+            self.background_evictions.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "vodb_background_evicted_bytes_total",
+            "Estimated bytes freed by background evictions",
+            self.background_evicted_bytes.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "vodb_gc_files_removed_total",
+            "Leftover temp files, orphaned filters and old backups removed from the bin directory",
+            self.gc_files_removed.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "vodb_gc_bytes_reclaimed_total",
+            "Bytes freed by removing leftover files from the bin directory",
+            self.gc_bytes_reclaimed.load(Ordering::Relaxed),
+        );
+        write_gauge(
+            &mut out,
+            "vodb_write_queue_depth",
+            "Inserts queued for tree writers and not yet applied",
+            self.write_queue_depth.load(Ordering::Relaxed),
+        );
+        write_gauge(
+            &mut out,
+            "vodb_writer_lag_milliseconds",
+            "Time the oldest insert of the most recent write batch spent queued",
+            self.writer_lag_ms.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "vodb_insert_save_failures_total",
+            "Insert batches answered 202 because writing the tree failed; their points stay in memory for the background flush",
+            self.insert_save_failures.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "vodb_flush_failures_total",
+            "Background flushes of a tree that failed after all their retries; the tree stays dirty",
+            self.flush_failures.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "vodb_degraded_rejections_total",
+            "Writes rejected with 503 because the tree's saves had failed for longer than PERSISTENCE_DEADLINE_SECS",
+            self.degraded_rejections.load(Ordering::Relaxed),
+        );
+        write_gauge(
+            &mut out,
+            "vodb_heavy_requests_in_flight",
+            "Heavy requests (export, rebuild) currently running",
+            self.heavy_in_flight.load(Ordering::Relaxed),
+        );
+        write_gauge(
+            &mut out,
+            "vodb_heavy_requests_queued",
+            "Heavy requests waiting for a free slot",
+            self.heavy_queued.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "vodb_search_response_estimates_total",
+            "Searches whose response size was estimated before the tree was searched",
+            self.response_estimates.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "vodb_search_response_estimated_bytes_total",
+            "Sum of the estimated response sizes of searches",
+            self.response_estimated_bytes.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "vodb_search_response_rejections_total",
+            "Searches refused because their estimated response exceeded MAX_RESPONSE_MB",
+            self.response_size_rejections.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "vodb_exports_cancelled_total",
+            "Exports stopped before their last line because the client disconnected",
+            self.exports_cancelled.load(Ordering::Relaxed),
+        );
+        write_gauge(&mut out, "vodb_trees", "Trees known to the server", totals.trees as u64);
+        write_gauge(&mut out, "vodb_trees_in_memory", "Trees currently loaded in memory", totals.in_memory as u64);
+        write_gauge(&mut out, "vodb_records", "Points stored across all trees", totals.num_records as u64);
+        write_gauge(
+            &mut out,
+            "vodb_unhealthy_trees",
+            "Trees whose file failed to load or that the self-test marked suspect",
+            totals.unhealthy as u64,
+        );
+        write_gauge(
+            &mut out,
+            "vodb_persistence_degraded_trees",
+            "Trees refusing writes because saving them has failed for longer than PERSISTENCE_DEADLINE_SECS",
+            totals.persistence_degraded as u64,
+        );
+        write_gauge(
+            &mut out,
+            "vodb_estimated_bytes",
+            "Estimated memory held by loaded trees and their duplicate filters",
+            totals.estimated_bytes as u64,
+        );
+        write_tree_series(
+            &mut out,
+            "vodb_tree_records",
+            "gauge",
+            "Points stored in each tree",
+            trees.iter().map(|tree| (tree.tree_name.as_str(), tree.num_records)),
+        );
+        write_tree_series(
+            &mut out,
+            "vodb_tree_estimated_bytes",
+            "gauge",
+            "Estimated memory held by each tree, 0 when offloaded",
+            trees.iter().map(|tree| (tree.tree_name.as_str(), tree.estimated_bytes)),
+        );
+        write_tree_series(
+            &mut out,
+            "vodb_tree_searches_total",
+            "counter",
+            "Searches served by each tree, kept across restarts",
+            trees.iter().map(|tree| (tree.tree_name.as_str(), tree.usage.searches)),
+        );
+        write_tree_series(
+            &mut out,
+            "vodb_tree_inserts_total",
+            "counter",
+            "Points inserted into each tree, kept across restarts",
+            trees.iter().map(|tree| (tree.tree_name.as_str(), tree.usage.inserts)),
+        );
+        write_tree_series(
+            &mut out,
+            "vodb_tree_bytes_served_total",
+            "counter",
+            "Response bytes of searches served by each tree",
+            trees.iter().map(|tree| (tree.tree_name.as_str(), tree.usage.bytes_served)),
+        );
+        write_tree_series(
+            &mut out,
+            "vodb_tree_bytes_inserted_total",
+            "counter",
+            "Content bytes of the points inserted into each tree: components, data and metadata",
+            trees.iter().map(|tree| (tree.tree_name.as_str(), tree.usage.bytes_inserted)),
+        );
+        write_tree_series(
+            &mut out,
+            "vodb_tree_bytes_written_total",
+            "counter",
+            "Bytes of each tree's file written to disk",
+            trees.iter().map(|tree| (tree.tree_name.as_str(), tree.usage.bytes_written)),
+        );
+        write_tree_series(
+            &mut out,
+            "vodb_tree_saves_total",
+            "counter",
+            "Writes of each tree's file",
+            trees.iter().map(|tree| (tree.tree_name.as_str(), tree.usage.saves)),
+        );
+        write_tree_series(
+            &mut out,
+            "vodb_tree_write_amplification",
+            "gauge",
+            "Bytes written to disk per content byte inserted into each tree, for trees with inserts",
+            trees.iter().filter_map(|tree| Some((tree.tree_name.as_str(), tree.usage.write_amplification?))),
+        );
+        write_tree_series(
+            &mut out,
+            "vodb_tree_last_write_timestamp_seconds",
+            "gauge",
+            "Unix time of the last insert applied to each tree, 0 if never",
+            trees.iter().map(|tree| (tree.tree_name.as_str(), tree.usage.last_write_at)),
+        );
+        write_tree_series(
+            &mut out,
+            "vodb_tree_search_qps_1m",
+            "gauge",
+            "Searches per second of each tree over the last minute",
+            trees.iter().map(|tree| (tree.tree_name.as_str(), tree.usage.search_qps_1m)),
+        );
+        write_tree_series(
+            &mut out,
+            "vodb_tree_search_qps_1h",
+            "gauge",
+            "Searches per second of each tree over the last hour",
+            trees.iter().map(|tree| (tree.tree_name.as_str(), tree.usage.search_qps_1h)),
+        );
+        write_lock_waits(&mut out, locks, &labelled);
+        write_search_latency(&mut out, latencies, &labelled);
+        write_client_costs(&mut out, clients, tree_labels);
+        out
+    }
+}
+
+fn write_counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn write_gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+// One metric labelled by tree. Tree names are restricted to characters that need no escaping.
+fn write_tree_series<'a, V: Display>(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    values: impl Iterator<Item = (&'a str, V)>,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (tree_name, value) in values {
+        let _ = writeln!(out, "{}{{tree=\"{}\"}} {}", name, tree_name, value);
+    }
+}
+
+// The `limit` trees with the most searches, ties broken by name so the choice is stable
+fn most_searched(trees: &[TreeStatus], limit: usize) -> HashSet<&str> {
+    let mut ranked: Vec<_> = trees.iter().map(|tree| (tree.usage.searches, tree.tree_name.as_str())).collect();
+    ranked.sort_by(|(a_searches, a_name), (b_searches, b_name)| b_searches.cmp(a_searches).then(a_name.cmp(b_name)));
+    ranked.into_iter().take(limit).map(|(_, tree_name)| tree_name).collect()
+}
+
+// Work that isn't about one tree keeps its empty label
+fn tree_label<'a>(tree_name: &'a str, labelled: &HashSet<&str>) -> &'a str {
+    match tree_name.is_empty() || labelled.contains(tree_name) {
+        true => tree_name,
+        false => OTHER_TREES,
+    }
+}
+
+// Time spent waiting for tracked locks, by lock, endpoint and tree
+fn write_lock_waits(out: &mut String, locks: &[LockWaits], labelled: &HashSet<&str>) {
+    let name = "vodb_lock_wait_seconds";
+    let _ = writeln!(out, "# HELP {} Time spent waiting to acquire a lock, by the endpoint and tree that waited", name);
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    for (lock, waits) in locks {
+        let mut merged: BTreeMap<(&str, &str), WaitHistogram> = BTreeMap::new();
+        for (context, histogram) in waits {
+            merged.entry((&context.endpoint, tree_label(&context.tree, labelled))).or_default().add(histogram);
+        }
+        for ((endpoint, tree), histogram) in merged {
+            let labels = format!("lock=\"{}\",endpoint=\"{}\",tree=\"{}\"", lock, endpoint, tree);
+            write_histogram(out, name, &labels, &histogram);
+        }
+    }
+}
+
+// Budget spent, requests turned away and budget left by rate limited client. Clients
+// are API key hashes or addresses, neither of which needs escaping.
+fn write_client_costs(out: &mut String, clients: &[ClientCost], limit: usize) {
+    let mut ranked: Vec<&ClientCost> = clients.iter().collect();
+    ranked.sort_by(|a, b| b.spent.cmp(&a.spent).then(a.client.cmp(&b.client)));
+    let mut merged: Vec<ClientCost> = ranked.iter().take(limit).map(|client| (*client).clone()).collect();
+    if ranked.len() > limit {
+        let other = ranked[limit..].iter().fold(
+            ClientCost { client: OTHER_CLIENTS.to_string(), spent: 0, rejected: 0, remaining: 0 },
+            |mut other, client| {
+                other.spent += client.spent;
+                other.rejected += client.rejected;
+                other.remaining += client.remaining;
+                other
+            },
+        );
+        merged.push(other);
+    }
+    write_client_series(
+        out,
+        "vodb_rate_limit_cost_total",
+        "counter",
+        "Budget spent by each client, settled with the work searches measured",
+        merged.iter().map(|client| (client.client.as_str(), client.spent)),
+    );
+    write_client_series(
+        out,
+        "vodb_rate_limit_rejections_total",
+        "counter",
+        "Requests refused with 429 because the client's budget was exhausted",
+        merged.iter().map(|client| (client.client.as_str(), client.rejected)),
+    );
+    write_client_series(
+        out,
+        "vodb_rate_limit_remaining",
+        "gauge",
+        "Budget each client has left, negative while it pays off a search that cost more than estimated",
+        merged.iter().map(|client| (client.client.as_str(), client.remaining)),
+    );
+}
+
+fn write_client_series<'a, V: Display>(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    values: impl Iterator<Item = (&'a str, V)>,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (client, value) in values {
+        let _ = writeln!(out, "{}{{client=\"{}\"}} {}", name, client, value);
+    }
+}
+
+// Search latency by tree and phase
+fn write_search_latency(out: &mut String, latencies: &[TreeLatency], labelled: &HashSet<&str>) {
+    let name = "vodb_search_latency_seconds";
+    let _ = writeln!(out, "# HELP {} Search latency by tree and phase: total, lock_wait, disk_load or traversal", name);
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    let mut merged: BTreeMap<&str, [WaitHistogram; Phase::ALL.len()]> = BTreeMap::new();
+    for (tree_name, phases) in latencies {
+        let entry = merged.entry(tree_label(tree_name, labelled)).or_default();
+        for (merged, histogram) in entry.iter_mut().zip(phases) {
+            merged.add(histogram);
+        }
+    }
+    for (tree, phases) in merged {
+        for (phase, histogram) in Phase::ALL.iter().zip(&phases) {
+            let labels = format!("tree=\"{}\",phase=\"{}\"", tree, phase.label());
+            write_histogram(out, name, &labels, histogram);
+        }
+    }
+}
+
+fn write_histogram(out: &mut String, name: &str, labels: &str, histogram: &WaitHistogram) {
+    for (bound, count) in WAIT_BUCKETS.iter().zip(histogram.buckets) {
+        let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, bound, count);
+    }
+    let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, histogram.count);
+    let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, histogram.sum);
+    let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, histogram.count);
+}
@@ -0,0 +1,118 @@
+use crate::distance::Metric;
+use crate::kdtree::Point;
+
+// How many candidates a diversified search fetches per requested result
+pub const OVERFETCH: usize = 4;
+
+// A re-ranked hit: distance to the query, MMR score (higher is better) and the point
+pub type MmrHit<'a> = (f64, f64, &'a Point);
+
+// Maximal marginal relevance: greedily picks `n` of the candidates (closest first, with
+// their distances to the query), each time taking the one that best trades closeness
+// to the query against distance to everything already picked. `diversity` 0 keeps the
+// plain nearest-first order, 1 only spreads the results out. Candidates are compared
+// with the norms they carry, as points stored in a tree do.
+pub fn rerank<'a>(metric: &impl Metric, candidates: Vec<(f64, &'a Point)>, n: usize, diversity: f64) -> Vec<MmrHit<'a>> {
+    let mut remaining = candidates;
+    // Distance from each remaining candidate to its closest selected result
+    let mut separation = vec![f64::INFINITY; remaining.len()];
+    let mut selected = Vec::with_capacity(n.min(remaining.len()));
+
+    while selected.len() < n && !remaining.is_empty() {
+        let score = |index: usize| {
+            let (distance, _) = remaining[index];
+            // Nothing selected yet: only closeness counts
+            let spread = if separation[index].is_finite() { separation[index] } else { 0.0 };
+            diversity * spread - (1.0 - diversity) * distance
+        };
+        let best = (0..remaining.len())
+            .max_by(|a, b| score(*a).total_cmp(&score(*b)).then(b.cmp(a)))
+            .unwrap();
+        let best_score = score(best);
+        // Removed in order so ties keep going to the closer candidate
+        let (distance, point) = remaining.remove(best);
+        separation.remove(best);
+
+        for (index, (_, candidate)) in remaining.iter().enumerate() {
+            separation[index] = separation[index].min(metric.dist_with_norms(&candidate.embedding, candidate.norm, &point.embedding, point.norm));
+        }
+        selected.push((distance, best_score, point));
+    }
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::distance::{self, Euclidean};
+
+    fn point(label: &str, embedding: [f64; 2]) -> Point {
+        Point { norm: distance::norm(&embedding), embedding: embedding.to_vec(), data: Some(label.to_string()), ..Default::default() }
+    }
+
+    // Three copies of the closest vector, two of the next and one far off, closest first
+    fn candidates(points: &[Point]) -> Vec<(f64, &Point)> {
+        let mut candidates: Vec<_> = points.iter().map(|point| (Euclidean.dist(&point.embedding, &[0.0, 0.0]), point)).collect();
+        candidates.sort_by(|a, b| a.0.total_cmp(&b.0));
+        candidates
+    }
+
+    fn duplicated() -> Vec<Point> {
+        vec![
+            point("a", [1.0, 0.0]),
+            point("a", [1.0, 0.0]),
+            point("a", [1.0, 0.0]),
+            point("b", [0.0, 2.0]),
+            point("b", [0.0, 2.0]),
+            point("c", [-3.0, 0.0]),
+        ]
+    }
+
+    fn labels<'a>(hits: &[MmrHit<'a>]) -> Vec<&'a str> {
+        hits.iter().map(|(_, _, point)| point.data.as_deref().unwrap()).collect()
+    }
+
+    #[test]
+    fn duplicates_are_demoted_as_diversity_grows() {
+        let points = duplicated();
+        let mut previous = usize::MAX;
+        for diversity in [0.0, 0.25, 0.5, 0.75, 1.0] {
+            let hits = rerank(&Euclidean, candidates(&points), 3, diversity);
+            let copies = labels(&hits).iter().filter(|label| **label == "a").count();
+            assert!(copies <= previous, "diversity {}: {:?}", diversity, labels(&hits));
+            previous = copies;
+        }
+        assert_eq!(labels(&rerank(&Euclidean, candidates(&points), 3, 0.0)), ["a", "a", "a"]);
+        assert_eq!(labels(&rerank(&Euclidean, candidates(&points), 3, 0.5)), ["a", "c", "b"]);
+        // Only spreading out counts: after the first pick, duplicates score nothing
+        assert_eq!(labels(&rerank(&Euclidean, candidates(&points), 3, 1.0)), ["a", "c", "b"]);
+    }
+
+    #[test]
+    fn hits_keep_their_query_distance_and_carry_their_score() {
+        let points = duplicated();
+        let hits = rerank(&Euclidean, candidates(&points), 3, 0.5);
+        let distances: Vec<f64> = hits.iter().map(|(distance, _, _)| *distance).collect();
+        assert_eq!(distances, [1.0, 3.0, 2.0]);
+        // The first pick is scored on closeness alone, the rest also on their separation
+        assert_eq!(hits[0].1, -0.5);
+        assert_eq!(hits[1].1, 0.5 * 4.0 - 0.5 * 3.0);
+        assert!((hits[2].1 - (0.5 * 5f64.sqrt() - 0.5 * 2.0)).abs() < 1e-12, "{}", hits[2].1);
+    }
+
+    #[test]
+    fn no_diversity_keeps_the_nearest_first_order() {
+        let points = duplicated();
+        let hits = rerank(&Euclidean, candidates(&points), 6, 0.0);
+        let distances: Vec<f64> = hits.iter().map(|(distance, _, _)| *distance).collect();
+        assert_eq!(distances, [1.0, 1.0, 1.0, 2.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn asking_for_more_than_the_candidates_returns_them_all() {
+        let points = duplicated();
+        assert_eq!(rerank(&Euclidean, candidates(&points), 10, 0.5).len(), points.len());
+        assert!(rerank(&Euclidean, Vec::new(), 3, 0.5).is_empty());
+        assert!(rerank(&Euclidean, candidates(&points), 0, 0.5).is_empty());
+    }
+}
@@ -0,0 +1,53 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::api::OperationStatus;
+
+// Long-running structural operations that own a tree while they run
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OperationKind {
+    Rebuilding,
+    Importing,
+    Restoring,
+    Deleting,
+    Reembedding, // Held by the target tree of a /reembed job
+}
+
+impl OperationKind {
+    // Reads keep being served from the pre-operation tree; writes would be lost or
+    // reordered by the swap at the end, so every current operation rejects them
+    pub fn allows_writes(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TreeOperation {
+    pub kind: OperationKind,
+    pub started_at: SystemTime,
+}
+
+impl TreeOperation {
+    pub fn start(kind: OperationKind) -> Self {
+        TreeOperation { kind, started_at: SystemTime::now() }
+    }
+
+    pub fn describe(&self) -> OperationStatus {
+        OperationStatus {
+            operation: self.kind,
+            started_at: self.started_at.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+            elapsed_secs: self.started_at.elapsed().map_or(0, |d| d.as_secs()),
+        }
+    }
+
+    // Body of the 409 returned to requests that conflict with this operation
+    pub fn conflict(&self, tree_name: &str) -> Value {
+        json!({
+            "error": "operation_in_progress",
+            "tree_name": tree_name,
+            "in_progress": self.describe(),
+        })
+    }
+}
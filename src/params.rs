@@ -0,0 +1,1011 @@
+use actix_web::{dev::Payload, web, FromRequest, HttpRequest};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::future::{ready, Ready};
+use std::ops::Deref;
+
+use crate::durability::Durability;
+use crate::encoding::{packing, Dtype, EmbeddingEncoding};
+use crate::error::{ApiError, FieldError};
+use crate::filter::{Condition, Filter};
+use crate::histogram::{Buckets, DistanceHistogram, DEFAULT_BUCKETS};
+use crate::kdtree::Point;
+use crate::projection::{Projection, MAX_FLOAT_PRECISION};
+use crate::query::MultiInsertEntry;
+use crate::scan::OffloadedStrategy;
+use crate::schema::Schema;
+use crate::structure::{StructureFormat, MAX_STRUCTURE_DEPTH};
+
+pub const MAX_TREE_NAME_LEN: usize = 128;
+pub const MAX_N: usize = 10_000;
+pub const MAX_GROUP_SIZE: usize = 100;
+pub const DEFAULT_SAMPLE_COUNT: usize = 20;
+pub const DEFAULT_AUDIT_N: usize = 10;
+pub const DEFAULT_AUDIT_SAMPLES: usize = 100;
+pub const MAX_AUDIT_SAMPLES: usize = 10_000;
+pub const DEFAULT_AUDIT_BUDGET_MS: u64 = 10_000;
+pub const MAX_AUDIT_BUDGET_MS: u64 = 300_000;
+pub const MAX_MULTI_INSERT_ENTRIES: usize = 16;
+
+// Query parameters that know how to check themselves
+pub trait Validate {
+    fn validate(&self) -> Result<(), Vec<FieldError>>;
+}
+
+// Extractor that deserializes the query string into `T` and runs its validation,
+// rejecting the request with a 400 listing every offending field
+pub struct Valid<T>(pub T);
+
+impl<T> Deref for Valid<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: DeserializeOwned + Validate> FromRequest for Valid<T> {
+    type Error = ApiError;
+    type Future = Ready<Result<Self, ApiError>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let result = web::Query::<T>::from_query(req.query_string())
+            .map_err(|e| ApiError::Validation(vec![FieldError::new("query", e.to_string())]))
+            .and_then(|query| {
+                let params = query.into_inner();
+                params.validate().map_err(ApiError::Validation)?;
+                Ok(Valid(params))
+            });
+        ready(result)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct InsertParams {
+    pub tree_name: String,
+    pub durability: Option<Durability>, // Overrides INSERT_DURABILITY for this request
+    pub force: Option<bool>,             // Insert even if the tree won't fit the memory budget
+    pub model: Option<String>,           // Embedding model the point comes from; tags a tree this insert creates
+    pub strict_model: Option<bool>,      // `false` inserts despite a model mismatch and warns instead of answering 409
+}
+
+impl Validate for InsertParams {
+    fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        validate_tree_name(&self.tree_name, &mut errors);
+        validate_model(self.model.as_deref(), self.strict_model, &mut errors);
+        finish(errors)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct CreateTreeParams {
+    pub tree_name: String,
+    pub dimensions: Option<usize>,
+    pub partition_field: Option<String>, // Metadata field to bucket points by, e.g. `tenant_id`
+    pub project_to: Option<usize>,       // Store embeddings randomly projected to this many dimensions
+    pub seed: Option<u64>,               // Seed of the projection matrix
+    pub schema: Option<String>,          // Metadata schema as JSON, see `Schema::parse`
+    pub model: Option<String>,           // Embedding model the tree's vectors come from, e.g. `bge-small-en-v1.5`
+    pub offloaded_strategy: Option<OffloadedStrategy>, // What searches do by default while the tree is offloaded
+}
+
+impl CreateTreeParams {
+    pub fn schema(&self) -> Option<Schema> {
+        self.schema.as_deref().and_then(|schema| Schema::parse(schema).ok())
+    }
+}
+
+impl Validate for CreateTreeParams {
+    fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        validate_tree_name(&self.tree_name, &mut errors);
+        match self.dimensions {
+            None => errors.push(FieldError::new("dimensions", "is required")),
+            Some(0) => errors.push(FieldError::new("dimensions", "must be at least 1")),
+            Some(_) => {}
+        }
+        if self.partition_field.as_deref() == Some("") {
+            errors.push(FieldError::new("partition_field", "must not be empty"));
+        }
+        match (self.project_to, self.dimensions) {
+            (Some(0), _) => errors.push(FieldError::new("project_to", "must be at least 1")),
+            (Some(project_to), Some(dimensions)) if project_to >= dimensions => {
+                errors.push(FieldError::new("project_to", "must be below dimensions"));
+            }
+            _ => {}
+        }
+        if self.seed.is_some() && self.project_to.is_none() {
+            errors.push(FieldError::new("seed", "requires project_to"));
+        }
+        validate_model(self.model.as_deref(), None, &mut errors);
+        match self.schema.as_deref().map(Schema::parse) {
+            Some(Err(message)) => errors.push(FieldError::new("schema", message)),
+            Some(Ok(schema)) => {
+                if let Some(field) = self.partition_field.as_deref().filter(|field| !schema.fields.contains_key(*field)) {
+                    errors.push(FieldError::new("schema", format!("must declare the partition field `{}`", field)));
+                }
+            }
+            None => {}
+        }
+        finish(errors)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SchemaParams {
+    pub tree_name: String,
+    pub force: Option<bool>, // Apply a schema that stored points don't match
+}
+
+impl Validate for SchemaParams {
+    fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        validate_tree_name(&self.tree_name, &mut errors);
+        finish(errors)
+    }
+}
+
+// Parameters of administrative endpoints that act on a single tree
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TreeParams {
+    pub tree_name: String,
+}
+
+impl Validate for TreeParams {
+    fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        validate_tree_name(&self.tree_name, &mut errors);
+        finish(errors)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RebuildParams {
+    pub tree_name: String,
+    #[serde(rename = "async")]
+    pub run_async: Option<bool>, // Run as a background job and answer 202 with it
+}
+
+impl Validate for RebuildParams {
+    fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        validate_tree_name(&self.tree_name, &mut errors);
+        finish(errors)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct VerifyAllParams {
+    #[serde(rename = "async")]
+    pub run_async: Option<bool>, // Run as a background job and answer 202 with it
+}
+
+impl Validate for VerifyAllParams {
+    fn validate(&self) -> Result<(), Vec<FieldError>> {
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct StatsParams {
+    pub tree_name: String,
+    pub validate: Option<bool>, // Also check the KD-tree invariant, which walks the whole tree
+}
+
+impl Validate for StatsParams {
+    fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        validate_tree_name(&self.tree_name, &mut errors);
+        finish(errors)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TreeStructureParams {
+    pub tree_name: String,
+    pub max_depth: Option<usize>, // Deepest level described node by node, defaults to DEFAULT_STRUCTURE_DEPTH
+    pub format: Option<StructureFormat>,
+}
+
+impl Validate for TreeStructureParams {
+    fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        validate_tree_name(&self.tree_name, &mut errors);
+        if self.max_depth.is_some_and(|max_depth| max_depth > MAX_STRUCTURE_DEPTH) {
+            errors.push(FieldError::new("max_depth", format!("must be at most {}", MAX_STRUCTURE_DEPTH)));
+        }
+        finish(errors)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct DropCacheParams {
+    pub tree_name: String,
+    pub discard_unsaved: Option<bool>, // Required to drop a tree with changes not yet on disk
+}
+
+impl Validate for DropCacheParams {
+    fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        validate_tree_name(&self.tree_name, &mut errors);
+        finish(errors)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct DriftParams {
+    pub tree_name: String,
+    pub against: String, // Tree whose distribution `tree_name` is compared to
+}
+
+impl Validate for DriftParams {
+    fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        validate_tree_name(&self.tree_name, &mut errors);
+        validate_tree_name_field("against", &self.against, &mut errors);
+        finish(errors)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SearchParams {
+    pub tree_name: String,
+    pub n: Option<usize>,
+    pub offset: Option<usize>,      // Results (or groups) of the top `offset + n` skipped before the `n` returned
+    pub if_in_memory: Option<bool>, // Fail with 409 instead of loading an offloaded tree from disk
+    pub fields: Option<String>,     // Comma separated result fields, e.g. `data,distance`
+    pub float_precision: Option<u32>, // Significant digits of returned embeddings, overrides FLOAT_PRECISION
+    pub encoding: Option<EmbeddingEncoding>, // `b64` returns embeddings packed in `embedding_b64`
+    pub embedding_dtype: Option<Dtype>,      // Of returned `embedding_b64`, defaults to f32
+    pub group_by: Option<String>,   // Metadata field to collapse results on, e.g. `doc_id`
+    pub group_size: Option<usize>,  // Hits returned per group, defaults to 1
+    pub cache: Option<bool>,        // `false` bypasses the query result cache and never caches the tree
+    pub partition: Option<String>,  // Only search this partition of a partitioned tree
+    pub explain: Option<bool>,      // Return a trace of the traversal alongside the results
+    pub diversity: Option<f64>,     // MMR re-ranking weight in [0, 1], 0 is plain nearest-first
+    pub filter: Option<String>,     // Metadata conditions candidates must meet, see `Condition::parse`
+    pub histogram: Option<bool>,    // Return a histogram of every candidate distance alongside the results
+    pub buckets: Option<String>,    // Histogram bucket count or edges, see `Buckets::parse`
+    pub model: Option<String>,      // Embedding model the query comes from, checked against the tree's
+    pub strict_model: Option<bool>, // `false` searches despite a model mismatch and warns instead of answering 409
+    pub offloaded_strategy: Option<OffloadedStrategy>, // What to do if the tree is offloaded, overrides the tree's default
+}
+
+impl SearchParams {
+    // Whether a scan of the tree file can answer the search: only plain top-n searches,
+    // filtered or not, can
+    pub fn scannable(&self) -> bool {
+        self.group_by.is_none() && self.diversity.is_none() && self.explain != Some(true) && self.histogram != Some(true)
+    }
+
+    pub fn filter(&self) -> Option<Condition> {
+        self.filter.as_deref().and_then(|filter| Condition::parse(filter).ok())
+    }
+
+    // The histogram to record, if one was asked for
+    pub fn histogram(&self) -> Option<DistanceHistogram> {
+        if self.histogram != Some(true) {
+            return None;
+        }
+        let buckets = self.buckets.as_deref()
+            .and_then(|buckets| Buckets::parse(buckets).ok())
+            .unwrap_or(Buckets::Auto(DEFAULT_BUCKETS));
+        Some(DistanceHistogram::new(&buckets))
+    }
+
+    // `default_precision` is FLOAT_PRECISION
+    pub fn projection(&self, default_precision: Option<u32>) -> Projection {
+        Projection::parse(self.fields.as_deref()).unwrap_or_default()
+            .with_precision(self.float_precision.or(default_precision))
+            .with_packing(packing(self.encoding, self.embedding_dtype))
+    }
+}
+
+impl Validate for SearchParams {
+    fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        validate_tree_name(&self.tree_name, &mut errors);
+        match self.n {
+            None => errors.push(FieldError::new("n", "is required")),
+            Some(n) if n == 0 || n > MAX_N => {
+                errors.push(FieldError::new("n", format!("must be between 1 and {}", MAX_N)))
+            }
+            Some(_) => {}
+        }
+        if let (Some(n), Some(offset)) = (self.n, self.offset) {
+            if n.saturating_add(offset) > MAX_N {
+                errors.push(FieldError::new("offset", format!("n + offset must be at most {}", MAX_N)));
+            }
+        }
+        validate_fields(self.fields.as_deref(), &mut errors);
+        validate_float_precision(self.float_precision, &mut errors);
+        validate_encoding(self.encoding, self.embedding_dtype, self.float_precision, &mut errors);
+        validate_model(self.model.as_deref(), self.strict_model, &mut errors);
+        if self.partition.as_deref() == Some("") {
+            errors.push(FieldError::new("partition", "must not be empty"));
+        }
+        if self.group_by.as_deref() == Some("") {
+            errors.push(FieldError::new("group_by", "must not be empty"));
+        }
+        match self.group_size {
+            Some(_) if self.group_by.is_none() => {
+                errors.push(FieldError::new("group_size", "requires group_by"))
+            }
+            Some(size) if size == 0 || size > MAX_GROUP_SIZE => {
+                errors.push(FieldError::new("group_size", format!("must be between 1 and {}", MAX_GROUP_SIZE)))
+            }
+            _ => {}
+        }
+        validate_filter(self.filter.as_deref(), &mut errors);
+        if self.explain == Some(true) && self.group_by.is_some() {
+            errors.push(FieldError::new("explain", "is not supported with group_by"));
+        }
+        match self.diversity {
+            Some(diversity) if !(0.0..=1.0).contains(&diversity) => {
+                errors.push(FieldError::new("diversity", "must be between 0 and 1"))
+            }
+            Some(_) if self.group_by.is_some() => {
+                errors.push(FieldError::new("diversity", "is not supported with group_by"))
+            }
+            Some(_) if self.explain == Some(true) => {
+                errors.push(FieldError::new("diversity", "is not supported with explain"))
+            }
+            _ => {}
+        }
+        if self.histogram == Some(true) && self.group_by.is_some() {
+            errors.push(FieldError::new("histogram", "is not supported with group_by"));
+        }
+        match self.buckets.as_deref().map(Buckets::parse) {
+            Some(_) if self.histogram != Some(true) => {
+                errors.push(FieldError::new("buckets", "requires histogram=true"))
+            }
+            Some(Err(message)) => errors.push(FieldError::new("buckets", message)),
+            _ => {}
+        }
+        match self.offloaded_strategy {
+            Some(strategy) if strategy != OffloadedStrategy::Reject && self.if_in_memory == Some(true) => {
+                errors.push(FieldError::new("offloaded_strategy", "conflicts with if_in_memory=true"))
+            }
+            Some(OffloadedStrategy::Scan) if !self.scannable() => errors.push(FieldError::new(
+                "offloaded_strategy",
+                "scan is not supported with group_by, explain, diversity or histogram",
+            )),
+            _ => {}
+        }
+        finish(errors)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct LookupParams {
+    pub tree_name: String,
+    pub fields: Option<String>, // Comma separated point fields, defaults to the full point
+    pub float_precision: Option<u32>, // Significant digits of the returned embedding, overrides FLOAT_PRECISION
+    pub encoding: Option<EmbeddingEncoding>, // `b64` returns the embedding packed in `embedding_b64`
+    pub embedding_dtype: Option<Dtype>,      // Of the returned `embedding_b64`, defaults to f32
+}
+
+impl LookupParams {
+    // `default_precision` is FLOAT_PRECISION
+    pub fn projection(&self, default_precision: Option<u32>) -> Projection {
+        Projection::parse(self.fields.as_deref()).unwrap_or_default()
+            .with_precision(self.float_precision.or(default_precision))
+            .with_packing(packing(self.encoding, self.embedding_dtype))
+    }
+}
+
+impl Validate for LookupParams {
+    fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        validate_tree_name(&self.tree_name, &mut errors);
+        validate_fields(self.fields.as_deref(), &mut errors);
+        validate_float_precision(self.float_precision, &mut errors);
+        validate_encoding(self.encoding, self.embedding_dtype, self.float_precision, &mut errors);
+        finish(errors)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ExistsWithinParams {
+    pub tree_name: String,
+    pub distance: Option<f64>,     // Largest distance that counts as a hit, 0 for exact matches
+    pub partition: Option<String>, // Only look in this partition of a partitioned tree
+}
+
+impl Validate for ExistsWithinParams {
+    fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        validate_tree_name(&self.tree_name, &mut errors);
+        match self.distance {
+            None => errors.push(FieldError::new("distance", "is required")),
+            Some(distance) if !distance.is_finite() || distance < 0.0 => {
+                errors.push(FieldError::new("distance", "must be a finite number of at least 0"))
+            }
+            Some(_) => {}
+        }
+        if self.partition.as_deref() == Some("") {
+            errors.push(FieldError::new("partition", "must not be empty"));
+        }
+        finish(errors)
+    }
+}
+
+// Imports a Parquet file found below IMPORT_DIRECTORY
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ImportParquetParams {
+    pub tree_name: String,
+    pub path: String,                     // Relative to IMPORT_DIRECTORY
+    pub embedding_column: Option<String>, // Defaults to `embedding`
+    pub data_column: Option<String>,      // Points get no data when unset
+    pub echo: Option<String>,             // Column whose value is echoed as `ref` on rejected rows; never stored
+    #[serde(rename = "async")]
+    pub run_async: Option<bool>,          // Run as a background job and answer 202 with it
+}
+
+impl ImportParquetParams {
+    pub fn embedding_column(&self) -> &str {
+        self.embedding_column.as_deref().unwrap_or("embedding")
+    }
+}
+
+impl Validate for ImportParquetParams {
+    fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        validate_tree_name(&self.tree_name, &mut errors);
+        if self.path.is_empty() {
+            errors.push(FieldError::new("path", "must not be empty"));
+        }
+        if self.embedding_column.as_deref() == Some("") {
+            errors.push(FieldError::new("embedding_column", "must not be empty"));
+        }
+        if self.data_column.as_deref() == Some("") {
+            errors.push(FieldError::new("data_column", "must not be empty"));
+        }
+        if self.echo.as_deref() == Some("") {
+            errors.push(FieldError::new("echo", "must not be empty"));
+        }
+        finish(errors)
+    }
+}
+
+// Imports a JSONL file of points found below IMPORT_DIRECTORY
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ImportJsonlParams {
+    pub tree_name: String,
+    pub path: String,             // Relative to IMPORT_DIRECTORY
+    pub restart: Option<bool>,    // Discard the checkpoint of an earlier run instead of resuming from it
+    #[serde(rename = "async")]
+    pub run_async: Option<bool>,  // Run as a background job and answer 202 with it
+}
+
+impl Validate for ImportJsonlParams {
+    fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        validate_tree_name(&self.tree_name, &mut errors);
+        if self.path.is_empty() {
+            errors.push(FieldError::new("path", "must not be empty"));
+        }
+        finish(errors)
+    }
+}
+
+// Usage history over [from, to), in Unix seconds; `from` is rounded down to its hour
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct UsageParams {
+    pub tree_name: Option<String>, // Restrict the report to a single tree
+    pub from: Option<u64>,         // Defaults to the oldest hour retained
+    pub to: Option<u64>,           // Defaults to now
+}
+
+impl Validate for UsageParams {
+    fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        if let Some(tree_name) = &self.tree_name {
+            validate_tree_name(tree_name, &mut errors);
+        }
+        if let (Some(from), Some(to)) = (self.from, self.to) {
+            if from >= to {
+                errors.push(FieldError::new("from", "must be before `to`"));
+            }
+        }
+        finish(errors)
+    }
+}
+
+// Re-embeds the data of every point of a tree into a new tree
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ReembedParams {
+    pub tree_name: String,
+    pub target: String, // Tree to build; must not exist unless an earlier run into it is being resumed
+}
+
+impl Validate for ReembedParams {
+    fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        validate_tree_name(&self.tree_name, &mut errors);
+        validate_tree_name_field("target", &self.target, &mut errors);
+        if self.target == self.tree_name {
+            errors.push(FieldError::new("target", "must differ from tree_name"));
+        }
+        finish(errors)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TruncateParams {
+    pub tree_name: String,
+    pub confirm: Option<bool>, // Must be true, as every point is deleted
+}
+
+impl Validate for TruncateParams {
+    fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        validate_tree_name(&self.tree_name, &mut errors);
+        if self.confirm != Some(true) {
+            errors.push(FieldError::new("confirm", "must be true to delete every point of the tree"));
+        }
+        finish(errors)
+    }
+}
+
+// Deletes the points matching a metadata filter
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct DeleteByFilterParams {
+    pub tree_name: String,
+    pub filter: Option<String>,           // Same grammar as for searches, see `Condition::parse`
+    pub dry_run: Option<bool>,            // Only count the matching points
+    pub confirm_delete_all: Option<bool>, // Required with an empty filter, which matches every point
+    #[serde(rename = "async")]
+    pub run_async: Option<bool>,          // Run as a background job and answer 202 with it
+}
+
+impl DeleteByFilterParams {
+    // An absent filter matches every point, like an empty one
+    pub fn filter(&self) -> Condition {
+        self.filter.as_deref().and_then(|filter| Condition::parse(filter).ok()).unwrap_or(Condition::And(Vec::new()))
+    }
+}
+
+impl Validate for DeleteByFilterParams {
+    fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        validate_tree_name(&self.tree_name, &mut errors);
+        validate_filter(self.filter.as_deref(), &mut errors);
+        let matches_all = self.filter.as_deref().map(Condition::parse).is_none_or(|filter| filter.is_ok_and(|filter| filter.is_empty()));
+        if matches_all && self.dry_run != Some(true) && self.confirm_delete_all != Some(true) {
+            errors.push(FieldError::new("filter", "is empty and would delete every point; pass confirm_delete_all=true to do that"));
+        }
+        finish(errors)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ExportParams {
+    pub tree_name: String,
+    pub partition: Option<String>,    // Only export this partition of a partitioned tree
+    pub filter: Option<String>,       // Metadata conditions, e.g. `lang:en,tier:gold`
+    pub inserted_after: Option<u64>,  // Unix seconds
+    pub inserted_before: Option<u64>, // Unix seconds
+    pub since_seq: Option<u64>,       // Resume after the last sequence number received
+    pub fields: Option<String>,       // Comma separated point fields, defaults to the full point
+    pub float_precision: Option<u32>, // Significant digits of returned embeddings, overrides FLOAT_PRECISION
+    pub encoding: Option<EmbeddingEncoding>, // `b64` returns embeddings packed in `embedding_b64`
+    pub embedding_dtype: Option<Dtype>,      // Of returned `embedding_b64`, defaults to f32
+}
+
+impl ExportParams {
+    pub fn filter(&self) -> Filter {
+        Filter {
+            metadata: self.filter.as_deref().and_then(|filter| Condition::parse(filter).ok()),
+            inserted_after: self.inserted_after,
+            inserted_before: self.inserted_before,
+            since_seq: self.since_seq,
+        }
+    }
+
+    // `default_precision` is FLOAT_PRECISION
+    pub fn projection(&self, default_precision: Option<u32>) -> Projection {
+        Projection::parse(self.fields.as_deref()).unwrap_or_default()
+            .with_precision(self.float_precision.or(default_precision))
+            .with_packing(packing(self.encoding, self.embedding_dtype))
+    }
+}
+
+impl Validate for ExportParams {
+    fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        validate_tree_name(&self.tree_name, &mut errors);
+        validate_fields(self.fields.as_deref(), &mut errors);
+        validate_float_precision(self.float_precision, &mut errors);
+        validate_encoding(self.encoding, self.embedding_dtype, self.float_precision, &mut errors);
+        if self.partition.as_deref() == Some("") {
+            errors.push(FieldError::new("partition", "must not be empty"));
+        }
+        validate_filter(self.filter.as_deref(), &mut errors);
+        finish(errors)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SampleParams {
+    pub tree_name: String,
+    pub count: Option<usize>,         // Points to sample, defaults to DEFAULT_SAMPLE_COUNT
+    pub seed: Option<u64>,            // Makes the sample reproducible; random when unset
+    pub partition: Option<String>,    // Only sample this partition of a partitioned tree
+    pub filter: Option<String>,       // Metadata conditions, e.g. `lang:en,tier:gold`
+    pub inserted_after: Option<u64>,  // Unix seconds
+    pub inserted_before: Option<u64>, // Unix seconds
+    pub fields: Option<String>,       // Comma separated point fields, defaults to the full point
+    pub float_precision: Option<u32>, // Significant digits of returned embeddings, overrides FLOAT_PRECISION
+    pub encoding: Option<EmbeddingEncoding>, // `b64` returns embeddings packed in `embedding_b64`
+    pub embedding_dtype: Option<Dtype>,      // Of returned `embedding_b64`, defaults to f32
+}
+
+impl SampleParams {
+    pub fn filter(&self) -> Filter {
+        Filter {
+            metadata: self.filter.as_deref().and_then(|filter| Condition::parse(filter).ok()),
+            inserted_after: self.inserted_after,
+            inserted_before: self.inserted_before,
+            since_seq: None,
+        }
+    }
+
+    // `default_precision` is FLOAT_PRECISION
+    pub fn projection(&self, default_precision: Option<u32>) -> Projection {
+        Projection::parse(self.fields.as_deref()).unwrap_or_default()
+            .with_precision(self.float_precision.or(default_precision))
+            .with_packing(packing(self.encoding, self.embedding_dtype))
+    }
+}
+
+impl Validate for SampleParams {
+    fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        validate_tree_name(&self.tree_name, &mut errors);
+        validate_fields(self.fields.as_deref(), &mut errors);
+        validate_float_precision(self.float_precision, &mut errors);
+        validate_encoding(self.encoding, self.embedding_dtype, self.float_precision, &mut errors);
+        if self.count.is_some_and(|count| count == 0 || count > MAX_N) {
+            errors.push(FieldError::new("count", format!("must be between 1 and {}", MAX_N)));
+        }
+        if self.partition.as_deref() == Some("") {
+            errors.push(FieldError::new("partition", "must not be empty"));
+        }
+        validate_filter(self.filter.as_deref(), &mut errors);
+        finish(errors)
+    }
+}
+
+// Checks tree searches against a brute-force scan of the same tree
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct AuditSearchParams {
+    pub tree_name: String,
+    pub n: Option<usize>,         // Results compared per query, defaults to DEFAULT_AUDIT_N
+    pub samples: Option<usize>,   // Stored points queried with when the body has no queries
+    pub seed: Option<u64>,        // Makes the sampled queries reproducible; random when unset
+    pub budget_ms: Option<u64>,   // Queries stop once this is spent, defaults to DEFAULT_AUDIT_BUDGET_MS
+    #[serde(rename = "async")]
+    pub run_async: Option<bool>,  // Run as a background job and answer 202 with it
+}
+
+impl Validate for AuditSearchParams {
+    fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        validate_tree_name(&self.tree_name, &mut errors);
+        if self.n.is_some_and(|n| n == 0 || n > MAX_N) {
+            errors.push(FieldError::new("n", format!("must be between 1 and {}", MAX_N)));
+        }
+        if self.samples.is_some_and(|samples| samples == 0 || samples > MAX_AUDIT_SAMPLES) {
+            errors.push(FieldError::new("samples", format!("must be between 1 and {}", MAX_AUDIT_SAMPLES)));
+        }
+        if self.budget_ms.is_some_and(|budget| budget == 0 || budget > MAX_AUDIT_BUDGET_MS) {
+            errors.push(FieldError::new("budget_ms", format!("must be between 1 and {}", MAX_AUDIT_BUDGET_MS)));
+        }
+        finish(errors)
+    }
+}
+
+// A byte range of a tree file. Without `offset` and `length` the `Range` header, if any,
+// picks the bytes.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SnapshotParams {
+    pub tree_name: String,
+    pub offset: Option<u64>, // Defaults to 0 when only `length` is given
+    pub length: Option<u64>, // Defaults to the rest of the file
+}
+
+impl Validate for SnapshotParams {
+    fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        validate_tree_name(&self.tree_name, &mut errors);
+        if self.length == Some(0) {
+            errors.push(FieldError::new("length", "must be at least 1"));
+        }
+        finish(errors)
+    }
+}
+
+// One chunk of a tree file being uploaded
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RestoreChunkParams {
+    pub tree_name: String,
+    pub offset: u64, // At most the number of bytes staged so far
+}
+
+impl Validate for RestoreChunkParams {
+    fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        validate_tree_name(&self.tree_name, &mut errors);
+        finish(errors)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RestoreCommitParams {
+    pub tree_name: String,
+    pub sha256: String, // Of the whole file, in hex
+    #[serde(rename = "async")]
+    pub run_async: Option<bool>, // Run as a background job and answer 202 with it
+}
+
+impl Validate for RestoreCommitParams {
+    fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        validate_tree_name(&self.tree_name, &mut errors);
+        if self.sha256.len() != 64 || !self.sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+            errors.push(FieldError::new("sha256", "must be 64 hexadecimal digits"));
+        }
+        finish(errors)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct StatusParams {
+    pub tree_name: Option<String>, // Restrict the report to a single tree
+}
+
+impl Validate for StatusParams {
+    fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        if let Some(tree_name) = &self.tree_name {
+            validate_tree_name(tree_name, &mut errors);
+        }
+        finish(errors)
+    }
+}
+
+// Tree names end up as file names, so only allow a conservative charset
+fn validate_tree_name(tree_name: &str, errors: &mut Vec<FieldError>) {
+    validate_tree_name_field("tree_name", tree_name, errors);
+}
+
+// Same rules for a parameter that names a tree under another key
+fn validate_tree_name_field(field: &str, tree_name: &str, errors: &mut Vec<FieldError>) {
+    if is_valid_tree_name(tree_name) {
+        return;
+    }
+    if tree_name.is_empty() || tree_name.len() > MAX_TREE_NAME_LEN {
+        errors.push(FieldError::new(
+            field,
+            format!("must be between 1 and {} characters", MAX_TREE_NAME_LEN),
+        ));
+    } else {
+        errors.push(FieldError::new(
+            field,
+            "may only contain ASCII letters, digits, '_' and '-'",
+        ));
+    }
+}
+
+// Whether a name is accepted for a tree, e.g. when adopting tree files found on disk
+pub fn is_valid_tree_name(tree_name: &str) -> bool {
+    !tree_name.is_empty()
+        && tree_name.len() <= MAX_TREE_NAME_LEN
+        && tree_name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+fn validate_model(model: Option<&str>, strict_model: Option<bool>, errors: &mut Vec<FieldError>) {
+    if model == Some("") {
+        errors.push(FieldError::new("model", "must not be empty"));
+    }
+    if strict_model.is_some() && model.is_none() {
+        errors.push(FieldError::new("strict_model", "requires model"));
+    }
+}
+
+fn validate_filter(filter: Option<&str>, errors: &mut Vec<FieldError>) {
+    if let Some(Err(message)) = filter.map(Condition::parse) {
+        errors.push(FieldError::new("filter", message));
+    }
+}
+
+fn validate_fields(fields: Option<&str>, errors: &mut Vec<FieldError>) {
+    if let Err(unknown) = Projection::parse(fields) {
+        errors.push(FieldError::new(
+            "fields",
+            format!("unknown field(s): {}", unknown.join(", ")),
+        ));
+    }
+}
+
+fn validate_float_precision(precision: Option<u32>, errors: &mut Vec<FieldError>) {
+    if precision.is_some_and(|precision| !(1..=MAX_FLOAT_PRECISION).contains(&precision)) {
+        errors.push(FieldError::new("float_precision", format!("must be between 1 and {}", MAX_FLOAT_PRECISION)));
+    }
+}
+
+fn validate_encoding(encoding: Option<EmbeddingEncoding>, dtype: Option<Dtype>, precision: Option<u32>, errors: &mut Vec<FieldError>) {
+    if encoding == Some(EmbeddingEncoding::B64) {
+        if precision.is_some() {
+            errors.push(FieldError::new("float_precision", "does not apply with encoding=b64"));
+        }
+    } else if dtype.is_some() {
+        errors.push(FieldError::new("embedding_dtype", "requires encoding=b64"));
+    }
+}
+
+// Checks an /insert_multi body before any tree is touched, returning the tree and the
+// point of each entry with its embedding decoded. Fields are named by entry, e.g.
+// `[1].point.embedding`.
+pub fn validate_multi_insert(entries: Vec<MultiInsertEntry>, max_data_bytes: usize) -> Result<Vec<(String, Point)>, Vec<FieldError>> {
+    let mut errors = Vec::new();
+    if entries.is_empty() || entries.len() > MAX_MULTI_INSERT_ENTRIES {
+        errors.push(FieldError::new("entries", format!("must be between 1 and {} entries", MAX_MULTI_INSERT_ENTRIES)));
+    }
+    let mut points: Vec<(String, Point)> = Vec::with_capacity(entries.len());
+    for (index, entry) in entries.into_iter().enumerate() {
+        validate_tree_name_field(&format!("[{}].tree_name", index), &entry.tree_name, &mut errors);
+        if points.iter().any(|(earlier, _)| *earlier == entry.tree_name) {
+            errors.push(FieldError::new(&format!("[{}].tree_name", index), "names a tree an earlier entry already inserts into"));
+        }
+        // The payload is kept in memory and in every snapshot of the tree
+        if let Some(size) = entry.point.data.as_ref().map(String::len).filter(|size| *size > max_data_bytes) {
+            errors.push(FieldError::new(
+                &format!("[{}].point.data", index),
+                format!("is {} bytes but MAX_DATA_BYTES is {}", size, max_data_bytes),
+            ));
+        }
+        match entry.point.into_point() {
+            Ok(point) if point.embedding.is_empty() => {
+                errors.push(FieldError::new(&format!("[{}].point.embedding", index), "must not be empty"));
+            }
+            Ok(point) => points.push((entry.tree_name, point)),
+            Err(message) => errors.push(FieldError::new(&format!("[{}].point", index), message)),
+        }
+    }
+    finish(errors).map(|()| points)
+}
+
+fn finish(errors: Vec<FieldError>) -> Result<(), Vec<FieldError>> {
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    // Runs the extractor over `query`, returning the parameters or the fields it rejected
+    fn extract<T: DeserializeOwned + Validate>(query: &str) -> Result<T, Vec<String>> {
+        let request = TestRequest::with_uri(&format!("/?{}", query)).to_http_request();
+        match Valid::<T>::from_request(&request, &mut Payload::None).into_inner() {
+            Ok(Valid(params)) => Ok(params),
+            Err(ApiError::Validation(errors)) => Err(errors.into_iter().map(|error| error.field).collect()),
+            Err(error) => panic!("not a validation error: {}", error),
+        }
+    }
+
+    fn rejected<T: DeserializeOwned + Validate + std::fmt::Debug>(query: &str) -> Vec<String> {
+        extract::<T>(query).expect_err(query)
+    }
+
+    #[test]
+    fn accepted_search_params_come_through_decoded() {
+        let params: SearchParams = extract("tree_name=docs&n=5").unwrap();
+        assert_eq!((params.tree_name.as_str(), params.n), ("docs", Some(5)));
+        let params: SearchParams = extract(&format!("tree_name=a-b_9&n={}&offset=0", MAX_N)).unwrap();
+        assert_eq!(params.n, Some(MAX_N));
+        let params: SearchParams = extract("tree_name=docs&n=3&group_by=doc_id&group_size=2&filter=lang:en").unwrap();
+        assert_eq!(params.group_by.as_deref(), Some("doc_id"));
+        assert!(params.filter().is_some());
+        extract::<SearchParams>("tree_name=docs&n=3&diversity=0.5&histogram=true&buckets=4").unwrap();
+        extract::<SearchParams>("tree_name=docs&n=3&if_in_memory=true&offloaded_strategy=reject").unwrap();
+        extract::<SearchParams>("tree_name=docs&n=3&encoding=b64&embedding_dtype=f64").unwrap();
+    }
+
+    #[test]
+    fn search_params_reject_out_of_range_and_conflicting_values() {
+        assert_eq!(rejected::<SearchParams>("tree_name=docs"), ["n"]);
+        assert_eq!(rejected::<SearchParams>("tree_name=docs&n=0"), ["n"]);
+        assert_eq!(rejected::<SearchParams>(&format!("tree_name=docs&n={}", MAX_N + 1)), ["n"]);
+        assert_eq!(rejected::<SearchParams>(&format!("tree_name=docs&n=10&offset={}", MAX_N)), ["offset"]);
+        assert_eq!(rejected::<SearchParams>("tree_name=docs&n=1&group_size=2"), ["group_size"]);
+        assert_eq!(rejected::<SearchParams>("tree_name=docs&n=1&group_by=doc&group_size=0"), ["group_size"]);
+        assert_eq!(rejected::<SearchParams>("tree_name=docs&n=1&group_by="), ["group_by"]);
+        assert_eq!(rejected::<SearchParams>("tree_name=docs&n=1&partition="), ["partition"]);
+        assert_eq!(rejected::<SearchParams>("tree_name=docs&n=1&diversity=1.5"), ["diversity"]);
+        assert_eq!(rejected::<SearchParams>("tree_name=docs&n=1&group_by=doc&explain=true"), ["explain"]);
+        assert_eq!(rejected::<SearchParams>("tree_name=docs&n=1&buckets=4"), ["buckets"]);
+        assert_eq!(rejected::<SearchParams>("tree_name=docs&n=1&fields=data,colour"), ["fields"]);
+        assert_eq!(rejected::<SearchParams>("tree_name=docs&n=1&float_precision=0"), ["float_precision"]);
+        assert_eq!(rejected::<SearchParams>("tree_name=docs&n=1&embedding_dtype=f64"), ["embedding_dtype"]);
+        assert_eq!(rejected::<SearchParams>("tree_name=docs&n=1&strict_model=true"), ["strict_model"]);
+        assert_eq!(
+            rejected::<SearchParams>("tree_name=docs&n=1&if_in_memory=true&offloaded_strategy=load"),
+            ["offloaded_strategy"]
+        );
+        assert_eq!(
+            rejected::<SearchParams>("tree_name=docs&n=1&diversity=0.5&offloaded_strategy=scan"),
+            ["offloaded_strategy"]
+        );
+    }
+
+    #[test]
+    fn every_offending_field_is_reported_at_once() {
+        assert_eq!(
+            rejected::<SearchParams>("tree_name=no%20spaces&n=0&diversity=-1&float_precision=99"),
+            ["tree_name", "n", "float_precision", "diversity"]
+        );
+    }
+
+    #[test]
+    fn tree_names_are_checked_after_decoding() {
+        assert_eq!(extract::<TreeParams>("tree_name=%64ocs").unwrap().tree_name, "docs");
+        assert_eq!(rejected::<TreeParams>("tree_name="), ["tree_name"]);
+        assert_eq!(rejected::<TreeParams>(&format!("tree_name={}", "a".repeat(MAX_TREE_NAME_LEN + 1))), ["tree_name"]);
+        extract::<TreeParams>(&format!("tree_name={}", "a".repeat(MAX_TREE_NAME_LEN))).unwrap();
+        for name in ["../etc", "a.b", "caf%C3%A9", "a%2Fb"] {
+            assert_eq!(rejected::<TreeParams>(&format!("tree_name={}", name)), ["tree_name"], "{}", name);
+        }
+    }
+
+    #[test]
+    fn unknown_and_malformed_params_fail_deserialization() {
+        // Parameters of another endpoint are not silently ignored
+        assert_eq!(rejected::<InsertParams>("tree_name=docs&n=5"), ["query"]);
+        assert_eq!(rejected::<SearchParams>("tree_name=docs&n=five"), ["query"]);
+        assert_eq!(rejected::<SearchParams>("n=5"), ["query"]);
+        assert_eq!(rejected::<SearchParams>("tree_name=docs&n=5&offloaded_strategy=later"), ["query"]);
+    }
+
+    #[test]
+    fn insert_params_accept_an_optional_durability_and_model() {
+        let params: InsertParams = extract("tree_name=docs&durability=fsync&model=minilm").unwrap();
+        assert_eq!(params.durability, Some(Durability::Fsync));
+        assert_eq!(params.model.as_deref(), Some("minilm"));
+        extract::<InsertParams>("tree_name=docs").unwrap();
+        assert_eq!(rejected::<InsertParams>("tree_name=docs&strict_model=false"), ["strict_model"]);
+    }
+}
@@ -0,0 +1,177 @@
+// Point data kept on disk. Tree files end with a payload section holding every point's
+// `data`; loading a tree reads only the tree in front of it and leaves each point a
+// reference into the open file, so searches never hold chunk text in memory and the
+// winners read theirs on demand. Tree files are replaced rather than rewritten, which
+// keeps the file a loaded tree refers to readable until its points are dropped.
+use lru::LruCache;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::kdtree::Point;
+
+// Payloads remembered per tree file, enough for a few pages of results
+const CACHE_ENTRIES: usize = 256;
+
+// The file a loaded tree came from, with the payloads read from it recently
+pub struct PayloadFile {
+    file: Mutex<File>,
+    cache: Mutex<LruCache<u64, Arc<str>>>,
+}
+
+impl PayloadFile {
+    pub fn new(file: File) -> Self {
+        PayloadFile {
+            file: Mutex::new(file),
+            cache: Mutex::new(LruCache::new(NonZeroUsize::new(CACHE_ENTRIES).unwrap())),
+        }
+    }
+
+    fn read_at(&self, offset: u64, len: u32) -> io::Result<String> {
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start(offset))?;
+        let mut bytes = vec![0; len as usize];
+        file.read_exact(&mut bytes)?;
+        String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+impl fmt::Debug for PayloadFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("PayloadFile")
+    }
+}
+
+// Where a point's data lies in the file its tree was loaded from
+#[derive(Debug, Clone)]
+pub struct PayloadRef {
+    pub file: Arc<PayloadFile>,
+    pub offset: u64,
+    pub len: u32,
+}
+
+impl PayloadRef {
+    // Reads the data through the file's cache, for results
+    pub fn read(&self) -> io::Result<Arc<str>> {
+        // An empty payload starts where the next one does, so it must stay out of the
+        // cache, which is keyed by offset
+        if self.len == 0 {
+            return Ok(Arc::from(""));
+        }
+        if let Some(data) = self.file.cache.lock().unwrap().get(&self.offset) {
+            return Ok(Arc::clone(data));
+        }
+        let data: Arc<str> = self.file.read_at(self.offset, self.len)?.into();
+        self.file.cache.lock().unwrap().put(self.offset, Arc::clone(&data));
+        Ok(data)
+    }
+
+    // Reads the data without caching it, for copying whole trees
+    fn read_uncached(&self) -> io::Result<String> {
+        self.file.read_at(self.offset, self.len)
+    }
+}
+
+// A point's data as it goes into the payload section
+#[derive(Debug)]
+pub enum Payload {
+    Inline(String),
+    Stored(PayloadRef),
+}
+
+impl Payload {
+    pub fn of(point: &Point) -> Option<Payload> {
+        match (&point.data, &point.stored) {
+            (Some(data), _) => Some(Payload::Inline(data.clone())),
+            (None, Some(stored)) => Some(Payload::Stored(stored.clone())),
+            (None, None) => None,
+        }
+    }
+}
+
+// Length of each point's data in payload section order, None for embedding-only points
+pub fn payload_lengths<'a>(points: impl IntoIterator<Item = &'a Point>) -> Vec<Option<u32>> {
+    points
+        .into_iter()
+        .map(|point| match (&point.data, &point.stored) {
+            (Some(data), _) => Some(data.len() as u32),
+            (None, Some(stored)) => Some(stored.len),
+            (None, None) => None,
+        })
+        .collect()
+}
+
+// Writes a point's data into the payload section, copying it from the old file if it
+// was never loaded
+pub fn write_payload(writer: &mut impl Write, point: &Point) -> io::Result<()> {
+    match (&point.data, &point.stored) {
+        (Some(data), _) => writer.write_all(data.as_bytes()),
+        (None, Some(stored)) => writer.write_all(stored.read_uncached()?.as_bytes()),
+        (None, None) => Ok(()),
+    }
+}
+
+// A complete tree file taken under the trees lock: the serialized tree and payload
+// lengths, and the payloads themselves, which are only read from disk when written
+#[derive(Debug)]
+pub struct TreeImage {
+    pub head: Vec<u8>,
+    pub payloads: Vec<Payload>,
+}
+
+impl TreeImage {
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(&self.head)?;
+        for payload in &self.payloads {
+            match payload {
+                Payload::Inline(data) => writer.write_all(data.as_bytes())?,
+                Payload::Stored(stored) => writer.write_all(stored.read_uncached()?.as_bytes())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// Writes `path` through a temporary file renamed over it, so points still reading from
+// the previous version of the file keep seeing it. With `fsync` the temporary file is
+// synced before the rename and the directory after it, so once this returns the new
+// contents survive a crash under the file's name.
+pub fn replace_file<E: From<io::Error>>(
+    path: &Path,
+    fsync: bool,
+    write: impl FnOnce(&mut BufWriter<File>) -> Result<(), E>,
+) -> Result<(), E> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let mut writer = BufWriter::new(File::create(&temp)?);
+    let written = write(&mut writer).and_then(|()| {
+        writer.flush()?;
+        if fsync {
+            writer.get_ref().sync_all()?;
+        }
+        Ok(())
+    });
+    drop(writer);
+    if let Err(e) = written {
+        let _ = fs::remove_file(&temp);
+        return Err(e);
+    }
+    fs::rename(&temp, path)?;
+    if fsync {
+        sync_directory(path)?;
+    }
+    Ok(())
+}
+
+// Syncs the directory holding `path`, which makes a rename or creation of the file in
+// it durable
+pub fn sync_directory(path: &Path) -> io::Result<()> {
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(directory)?.sync_all()
+}
@@ -0,0 +1,212 @@
+use serde::ser::{Serialize, SerializeSeq, Serializer};
+use serde_json::{json, Map, Value};
+
+use crate::encoding::{encode, Dtype};
+use crate::kdtree::{GroupHits, Point};
+
+// Significant digits of an f64; rounding to this many changes nothing
+pub const MAX_FLOAT_PRECISION: u32 = 17;
+
+// Rough serialized sizes used to estimate a response before it is built. A component
+// takes its digits plus a sign, decimal point, leading zero and separator; data and
+// metadata aren't known until the points are found, so they count at a typical size.
+const COMPONENT_OVERHEAD_BYTES: u64 = 5;
+const DATA_ESTIMATE_BYTES: u64 = 1024;
+const METADATA_ESTIMATE_BYTES: u64 = 256;
+const DISTANCE_BYTES: u64 = 32;
+const ENTRY_OVERHEAD_BYTES: u64 = 32; // Braces, keys and separators
+
+// Fields a result entry can carry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Embedding,
+    Data,
+    Metadata,
+    Distance,
+    Collection, // Once per search response rather than per result
+}
+
+impl Field {
+    fn parse(name: &str) -> Option<Field> {
+        match name {
+            "embedding" => Some(Field::Embedding),
+            "data" => Some(Field::Data),
+            "metadata" => Some(Field::Metadata),
+            "distance" => Some(Field::Distance),
+            "collection" => Some(Field::Collection),
+            _ => None,
+        }
+    }
+}
+
+// Controls which fields are serialized per result, shared by every endpoint returning points
+#[derive(Debug, Clone)]
+pub struct Projection {
+    fields: Vec<Field>,
+    precision: Option<u32>, // Significant digits of embedding components, full when unset
+    packing: Option<Dtype>, // Embeddings go out as `embedding_b64` of this type instead of arrays
+}
+
+impl Default for Projection {
+    // Today's full payload: the stored point as-is, and searches describe the tree
+    fn default() -> Self {
+        Projection { fields: vec![Field::Embedding, Field::Data, Field::Metadata, Field::Collection], precision: None, packing: None }
+    }
+}
+
+// An embedding serialized with its components rounded to `precision` significant digits.
+// A rounded component is the double closest to a short decimal, which serde_json prints
+// as that decimal, e.g. `0.123457` instead of `0.12345678901234568`.
+pub struct RoundedEmbedding<'a> {
+    pub components: &'a [f64],
+    pub precision: Option<u32>,
+}
+
+impl Serialize for RoundedEmbedding<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.components.len()))?;
+        for component in self.components {
+            match self.precision {
+                Some(precision) => seq.serialize_element(&round_significant(*component, precision))?,
+                None => seq.serialize_element(component)?,
+            }
+        }
+        seq.end()
+    }
+}
+
+// Rounds to `digits` significant digits. Scaling by an exact power of ten and rounding
+// once keeps the result the double nearest to the rounded decimal.
+fn round_significant(value: f64, digits: u32) -> f64 {
+    if value == 0.0 || !value.is_finite() || digits >= MAX_FLOAT_PRECISION {
+        return value;
+    }
+    let shift = digits as i32 - 1 - value.abs().log10().floor() as i32;
+    if (0..=22).contains(&shift) {
+        let scale = 10f64.powi(shift);
+        (value * scale).round() / scale
+    } else if (-22..0).contains(&shift) {
+        let scale = 10f64.powi(-shift);
+        (value / scale).round() * scale
+    } else {
+        // Beyond the exact powers of ten: far smaller or larger than any embedding
+        value
+    }
+}
+
+impl Projection {
+    // Parses a comma separated `fields` parameter, returning the unknown names on failure
+    pub fn parse(fields: Option<&str>) -> Result<Self, Vec<String>> {
+        let Some(fields) = fields else {
+            return Ok(Projection::default());
+        };
+
+        let mut parsed = Vec::new();
+        let mut unknown = Vec::new();
+        for name in fields.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            match Field::parse(name) {
+                Some(field) if !parsed.contains(&field) => parsed.push(field),
+                Some(_) => {}
+                None => unknown.push(name.to_string()),
+            }
+        }
+
+        if unknown.is_empty() {
+            Ok(Projection { fields: parsed, precision: None, packing: None })
+        } else {
+            Err(unknown)
+        }
+    }
+
+    // Rounds embedding components to `precision` significant digits; distances are
+    // never rounded
+    pub fn with_precision(mut self, precision: Option<u32>) -> Self {
+        self.precision = precision;
+        self
+    }
+
+    // Packs embeddings into base64 of `packing` values; precision then has no effect
+    pub fn with_packing(mut self, packing: Option<Dtype>) -> Self {
+        self.packing = packing;
+        self
+    }
+
+    // Whether a search response should carry the `collection` summary
+    pub fn includes_collection(&self) -> bool {
+        self.fields.contains(&Field::Collection)
+    }
+
+    // Approximate serialized size of `results` entries with embeddings of `dimensions`,
+    // known before any point is read
+    pub fn estimated_bytes(&self, results: usize, dimensions: usize) -> u64 {
+        let digits = self.precision.unwrap_or(MAX_FLOAT_PRECISION) as u64;
+        let per_result: u64 = self.fields.iter().map(|field| match field {
+            Field::Embedding => match self.packing {
+                Some(dtype) => (dimensions * dtype.width()).div_ceil(3) as u64 * 4,
+                None => dimensions as u64 * (digits + COMPONENT_OVERHEAD_BYTES),
+            },
+            Field::Data => DATA_ESTIMATE_BYTES,
+            Field::Metadata => METADATA_ESTIMATE_BYTES,
+            Field::Distance => DISTANCE_BYTES,
+            Field::Collection => 0,
+        }).sum();
+        (per_result + ENTRY_OVERHEAD_BYTES).saturating_mul(results as u64)
+    }
+
+    pub fn project(&self, point: &Point, distance: Option<f64>) -> Value {
+        let mut entry = Map::new();
+        for field in &self.fields {
+            match field {
+                Field::Embedding => match self.packing {
+                    Some(dtype) => {
+                        entry.insert("embedding_b64".to_string(), Value::from(encode(&point.embedding, dtype)));
+                    }
+                    None => {
+                        let embedding = RoundedEmbedding { components: &point.embedding, precision: self.precision };
+                        entry.insert("embedding".to_string(), json!(embedding));
+                    }
+                },
+                Field::Data => {
+                    // Embedding-only points simply have no data entry
+                    if let Some(data) = point.data() {
+                        entry.insert("data".to_string(), Value::from(data.into_owned()));
+                    }
+                }
+                Field::Metadata => {
+                    // Points stored without metadata keep their original shape
+                    if !point.metadata.is_empty() {
+                        entry.insert("metadata".to_string(), json!(point.metadata));
+                    }
+                }
+                Field::Distance => {
+                    if let Some(distance) = distance {
+                        entry.insert("distance".to_string(), Value::from(distance));
+                    }
+                }
+                Field::Collection => {}
+            }
+        }
+        Value::Object(entry)
+    }
+
+    pub fn project_all<'a>(&self, results: impl IntoIterator<Item = (f64, &'a Point)>) -> Vec<Value> {
+        results
+            .into_iter()
+            .map(|(distance, point)| self.project(point, Some(distance)))
+            .collect()
+    }
+
+    // Nests each group's hits under its group value
+    pub fn project_groups<'a>(
+        &self,
+        groups: impl IntoIterator<Item = GroupHits<'a>>,
+    ) -> Vec<Value> {
+        groups
+            .into_iter()
+            .map(|(group, hits)| json!({
+                "group": group,
+                "hits": self.project_all(hits),
+            }))
+            .collect()
+    }
+}
@@ -0,0 +1,136 @@
+use serde::de::IgnoredAny;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::encoding::PointInput;
+use crate::kdtree::Point;
+
+// How the embeddings of a multi-point query become the one vector searched with
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Combine {
+    #[default]
+    Mean,
+    Sum,
+}
+
+// Body of /nearesttop: a single query point, or several points whose embeddings are
+// combined, e.g. to find points near the average of a few liked items. Fields a stored
+// point would also have, like `data`, are accepted and ignored.
+#[derive(Deserialize, Debug, Default)]
+pub struct SearchBody {
+    #[serde(flatten)]
+    pub query: PointInput, // Searched with unless `points` is set
+    pub points: Option<Vec<PointInput>>,
+    pub combine: Option<Combine>, // Only with `points`, defaults to `mean`
+    // What neither `query` nor the fields above took. `deny_unknown_fields` does not
+    // work through `flatten`, so `query_point` refuses these instead.
+    #[serde(flatten)]
+    unknown: BTreeMap<String, IgnoredAny>,
+}
+
+impl SearchBody {
+    pub fn is_combined(&self) -> bool {
+        self.points.is_some()
+    }
+
+    // The point to search with. Errors describe what is wrong with the body.
+    pub fn query_point(&self) -> Result<Point, String> {
+        if !self.unknown.is_empty() {
+            let fields: Vec<&str> = self.unknown.keys().map(String::as_str).collect();
+            return Err(format!("Unknown field(s) in the search body: {}", fields.join(", ")));
+        }
+        let Some(points) = &self.points else {
+            if self.combine.is_some() {
+                return Err("combine requires points".to_string());
+            }
+            let embedding = self.query.embedding()?;
+            if embedding.is_empty() {
+                return Err("Query embedding must not be empty".to_string());
+            }
+            return Ok(Point { embedding, ..Default::default() });
+        };
+        if self.query.embedding.as_ref().is_some_and(|embedding| !embedding.is_empty()) || self.query.embedding_b64.is_some() {
+            return Err("Send either embedding or points, not both".to_string());
+        }
+        let embeddings = points.iter().enumerate()
+            .map(|(index, point)| point.embedding().map_err(|message| format!("points[{}]: {}", index, message)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let Some(first) = embeddings.first() else {
+            return Err("points must not be empty".to_string());
+        };
+        if first.is_empty() {
+            return Err("points[0] has an empty embedding".to_string());
+        }
+        let mut combined = vec![0.0; first.len()];
+        for (index, embedding) in embeddings.iter().enumerate() {
+            if embedding.len() != combined.len() {
+                return Err(format!(
+                    "points[{}] has {} dimensions but points[0] has {}",
+                    index, embedding.len(), combined.len()
+                ));
+            }
+            for (sum, value) in combined.iter_mut().zip(embedding) {
+                *sum += value;
+            }
+        }
+        if self.combine.unwrap_or_default() == Combine::Mean {
+            let count = points.len() as f64;
+            combined.iter_mut().for_each(|value| *value /= count);
+        }
+        Ok(Point { embedding: combined, ..Default::default() })
+    }
+}
+
+// One entry of the /insert_multi body: a point for one tree. Each entry names a
+// different tree, e.g. the same document embedded by two models.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct MultiInsertEntry {
+    pub tree_name: String,
+    pub point: PointInput,
+    #[serde(default, rename = "ref", skip_serializing_if = "Option::is_none")]
+    pub reference: Option<serde_json::Value>, // Echoed on the entry's outcome; never stored
+}
+
+// Optional body of /audit_search: queries to check instead of sampled stored points,
+// with the dimensions callers insert with
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct AuditSearchBody {
+    pub queries: Vec<Vec<f64>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn misspelled_fields_are_refused() {
+        let error = serde_json::from_value::<PointInput>(json!({ "embeding": [1.0, 2.0] })).unwrap_err();
+        assert!(error.to_string().contains("unknown field `embeding`"), "{}", error);
+
+        // The search body takes its query point's fields itself, so it refuses leftovers
+        let body: SearchBody = serde_json::from_value(json!({ "embedding": [1.0, 2.0], "metdata": {} })).unwrap();
+        assert_eq!(body.query_point().unwrap_err(), "Unknown field(s) in the search body: metdata");
+    }
+
+    #[test]
+    fn points_are_accepted_as_exports_and_searches_return_them() {
+        let returned = json!({
+            "embedding": [1.0, 2.0],
+            "data": "a",
+            "metadata": { "lang": "en" },
+            "seq": 7,
+            "inserted_at": 1700000000,
+            "distance": 0.5,
+            "mmr_score": 0.25,
+            "collection": { "name": "docs" },
+        });
+        let point = serde_json::from_value::<PointInput>(returned.clone()).unwrap().into_point().unwrap();
+        assert_eq!((point.embedding, point.data.as_deref()), (vec![1.0, 2.0], Some("a")));
+        let body: SearchBody = serde_json::from_value(returned).unwrap();
+        assert_eq!(body.query_point().unwrap().embedding, [1.0, 2.0]);
+    }
+}
@@ -0,0 +1,111 @@
+use lru::LruCache;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::encoding::{Dtype, EmbeddingEncoding};
+use crate::params::SearchParams;
+
+// Identifies one search. The embedding is kept as raw bits rather than a digest so
+// two different queries can never collide onto the same cached answer.
+#[derive(Hash, PartialEq, Eq)]
+struct QueryKey {
+    tree_name: String,
+    generation: u64,
+    embedding: Vec<u64>,
+    n: Option<usize>,
+    offset: Option<usize>,
+    fields: Option<String>,
+    float_precision: Option<u32>,
+    encoding: Option<EmbeddingEncoding>,
+    embedding_dtype: Option<Dtype>,
+    group_by: Option<String>,
+    group_size: Option<usize>,
+    partition: Option<String>,
+    diversity: Option<u64>,
+    filter: Option<String>,
+    model: Option<String>,      // A mismatched model is answered with a warning or a 409,
+    strict_model: Option<bool>, // so both decide what the cached answer is
+}
+
+struct Inner {
+    entries: LruCache<QueryKey, (Instant, Value)>,
+    // Bumped whenever a tree changes, so stale entries simply stop matching and age out
+    generations: HashMap<String, u64>,
+}
+
+// Small LRU of serialized search responses for clients that repeat identical queries.
+// Entries are owned JSON so they stay valid after the tree is evicted.
+pub struct QueryCache {
+    inner: Mutex<Inner>,
+    ttl: Duration,
+}
+
+impl QueryCache {
+    pub fn new(capacity: NonZeroUsize, ttl: Duration) -> Self {
+        QueryCache {
+            inner: Mutex::new(Inner {
+                entries: LruCache::new(capacity),
+                generations: HashMap::new(),
+            }),
+            ttl,
+        }
+    }
+
+    pub fn get(&self, embedding: &[f64], params: &SearchParams) -> Option<Value> {
+        let mut inner = self.inner.lock().unwrap();
+        let generation = inner.generations.get(&params.tree_name).copied().unwrap_or(0);
+        let key = Self::key(generation, embedding, params);
+        match inner.entries.get(&key) {
+            Some((inserted, value)) if inserted.elapsed() < self.ttl => Some(value.clone()),
+            Some(_) => {
+                inner.entries.pop(&key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    // The tree's current generation. Read it while the tree is still locked for the search,
+    // then pass it to `put` once the answer is built.
+    pub fn generation(&self, tree_name: &str) -> u64 {
+        self.inner.lock().unwrap().generations.get(tree_name).copied().unwrap_or(0)
+    }
+
+    // Stores an answer under the generation it was computed at. If the tree changed in
+    // between, the entry never matches and simply ages out.
+    pub fn put(&self, embedding: &[f64], params: &SearchParams, generation: u64, value: Value) {
+        let mut inner = self.inner.lock().unwrap();
+        let key = Self::key(generation, embedding, params);
+        inner.entries.put(key, (Instant::now(), value));
+    }
+
+    // Drops every cached answer for `tree_name`; call after any change to the tree
+    pub fn invalidate(&self, tree_name: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner.generations.entry(tree_name.to_string()).or_insert(0) += 1;
+    }
+
+    fn key(generation: u64, embedding: &[f64], params: &SearchParams) -> QueryKey {
+        QueryKey {
+            tree_name: params.tree_name.clone(),
+            generation,
+            embedding: embedding.iter().map(|value| value.to_bits()).collect(),
+            n: params.n,
+            offset: params.offset,
+            fields: params.fields.clone(),
+            float_precision: params.float_precision,
+            encoding: params.encoding,
+            embedding_dtype: params.embedding_dtype,
+            group_by: params.group_by.clone(),
+            group_size: params.group_size,
+            partition: params.partition.clone(),
+            diversity: params.diversity.map(f64::to_bits),
+            filter: params.filter.clone(),
+            model: params.model.clone(),
+            strict_model: params.strict_model,
+        }
+    }
+}
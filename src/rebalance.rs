@@ -0,0 +1,122 @@
+// Rebuilding unbalanced trees at startup, before the server takes traffic, so a store
+// filled by incremental inserts doesn't serve its first searches from degenerate trees
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::archive::Tier;
+use crate::store::{load_tree, offload_tree, KDTreeCache};
+
+// Which trees `REBALANCE_ON_STARTUP` rebuilds
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Rebalance {
+    #[default]
+    Off,
+    Auto, // Trees deeper than the depth bound
+    All,
+}
+
+impl FromStr for Rebalance {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, String> {
+        match value {
+            "off" => Ok(Rebalance::Off),
+            "auto" => Ok(Rebalance::Auto),
+            "all" => Ok(Rebalance::All),
+            _ => Err(format!("unknown rebalance mode: {}", value)),
+        }
+    }
+}
+
+// Deepest a tree of `num_records` points may get before it counts as unbalanced, the
+// same bound inserts keep to
+pub fn depth_limit(num_records: usize, depth_factor: f64) -> usize {
+    (depth_factor * ((num_records + 1) as f64).log2()).ceil() as usize
+}
+
+// Rebuilds the trees `mode` selects with median splits, `concurrency` at a time, and
+// records their new depth. `auto` goes by the depth persisted in the manifest and loads
+// trees whose depth isn't known yet to find out. Files are replaced by a rename, so a
+// crash leaves either the old tree or the rebuilt one. Trees are not kept in memory.
+pub fn rebalance_trees(
+    bin_directory: &Path,
+    trees: &mut HashMap<String, KDTreeCache>,
+    mode: Rebalance,
+    depth_factor: Option<f64>,
+    concurrency: usize,
+    auto_migrate: bool,
+) {
+    let bound = match (mode, depth_factor) {
+        (Rebalance::Off, _) => return,
+        (Rebalance::All, _) => None,
+        (Rebalance::Auto, Some(factor)) => Some(factor),
+        (Rebalance::Auto, None) => {
+            println!("WARNING: REBALANCE_ON_STARTUP=auto does nothing while MAX_DEPTH_FACTOR disables the depth bound");
+            return;
+        }
+    };
+    let mut queue: Vec<String> = trees
+        .iter()
+        .filter(|(_, cache)| cache.tier == Tier::Hot)
+        .filter(|(_, cache)| match (bound, cache.depth) {
+            (Some(factor), Some(depth)) => depth > depth_limit(cache.num_records, factor),
+            _ => true,
+        })
+        .map(|(tree_name, _)| tree_name.clone())
+        .collect();
+    queue.sort();
+    let total = queue.len();
+    println!("Rebalancing up to {} trees with concurrency {}", total, concurrency);
+
+    let started = Instant::now();
+    let queue = Mutex::new(VecDeque::from(queue));
+    let depths = Mutex::new(Vec::new());
+    std::thread::scope(|scope| {
+        for _ in 0..concurrency.clamp(1, total.max(1)) {
+            scope.spawn(|| loop {
+                let next = queue.lock().unwrap().pop_front();
+                let Some(tree_name) = next else { break };
+                let tree_started = Instant::now();
+                let tree = match load_tree(bin_directory, &tree_name, auto_migrate) {
+                    Ok(tree) => tree,
+                    Err(e) => {
+                        println!("Failed to rebalance tree {}: {}", tree_name, e);
+                        continue;
+                    }
+                };
+                let depth = tree.depth();
+                if bound.is_some_and(|factor| depth <= depth_limit(tree.len(), factor)) {
+                    depths.lock().unwrap().push((tree_name, depth));
+                    continue;
+                }
+                let rebuilt = tree.rebuilt().and_then(|tree| {
+                    offload_tree(bin_directory, &tree_name, &tree)?;
+                    Ok(tree)
+                });
+                match rebuilt {
+                    Ok(tree) => {
+                        let mut depths = depths.lock().unwrap();
+                        depths.push((tree_name.clone(), tree.depth()));
+                        println!(
+                            "Rebalance: tree {} depth {} -> {} in {}ms ({}/{})",
+                            tree_name, depth, tree.depth(), tree_started.elapsed().as_millis(), depths.len(), total,
+                        );
+                    }
+                    Err(e) => println!("Failed to rebalance tree {}: {}", tree_name, e),
+                }
+            });
+        }
+    });
+
+    for (tree_name, depth) in depths.into_inner().unwrap() {
+        if let Some(cache) = trees.get_mut(&tree_name) {
+            cache.depth = Some(depth);
+        }
+    }
+    println!("Rebalance finished in {:.1}s", started.elapsed().as_secs_f64());
+}
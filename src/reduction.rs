@@ -0,0 +1,74 @@
+use serde::{Deserialize, Serialize};
+
+use crate::rng::SplitMix64;
+
+// Used when a tree is created with `project_to` but no `seed`
+pub const DEFAULT_SEED: u64 = 0;
+
+// Johnson-Lindenstrauss random projection: a fixed Gaussian matrix that maps
+// embeddings to fewer dimensions while roughly preserving distances. The matrix is
+// generated from the seed alone, so two trees created with the same seed and
+// dimensions project identically and stay comparable.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RandomProjection {
+    seed: u64,
+    input_dimensions: usize,
+    output_dimensions: usize,
+    matrix: Vec<f64>, // output_dimensions rows of input_dimensions, row-major
+}
+
+impl RandomProjection {
+    pub fn new(seed: u64, input_dimensions: usize, output_dimensions: usize) -> Self {
+        // Entries ~ N(0, 1/output_dimensions) keep expected squared lengths unchanged
+        let scale = 1.0 / (output_dimensions as f64).sqrt();
+        let mut gaussian = Gaussian::new(seed);
+        let matrix = (0..input_dimensions * output_dimensions).map(|_| gaussian.next() * scale).collect();
+        RandomProjection { seed, input_dimensions, output_dimensions, matrix }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn input_dimensions(&self) -> usize {
+        self.input_dimensions
+    }
+
+    pub fn output_dimensions(&self) -> usize {
+        self.output_dimensions
+    }
+
+    pub fn apply(&self, embedding: &[f64]) -> Vec<f64> {
+        self.matrix
+            .chunks_exact(self.input_dimensions)
+            .map(|row| row.iter().zip(embedding).map(|(weight, value)| weight * value).sum())
+            .collect()
+    }
+
+    pub fn heap_size(&self) -> usize {
+        self.matrix.capacity() * std::mem::size_of::<f64>()
+    }
+}
+
+// Standard normal samples through the Box-Muller transform
+struct Gaussian {
+    rng: SplitMix64,
+    spare: Option<f64>,
+}
+
+impl Gaussian {
+    fn new(seed: u64) -> Self {
+        Gaussian { rng: SplitMix64::new(seed), spare: None }
+    }
+
+    fn next(&mut self) -> f64 {
+        if let Some(spare) = self.spare.take() {
+            return spare;
+        }
+        // next_unit never returns 0, so the logarithm is finite
+        let radius = (-2.0 * self.rng.next_unit().ln()).sqrt();
+        let angle = 2.0 * std::f64::consts::PI * self.rng.next_unit();
+        self.spare = Some(radius * angle.sin());
+        radius * angle.cos()
+    }
+}
@@ -0,0 +1,45 @@
+// Progress of a re-embedding into a new tree, kept next to the tree file as
+// `{target}.reembed` so a job cut short by a crash or a provider outage picks up where it
+// stopped when started again. Source points are re-embedded in sequence order, so one
+// sequence number says how far the job got, much like `since_seq` on /export.
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub source: String,
+    pub last_seq: u64,        // Source points up to this sequence number are in the target
+    pub target_next_seq: u64, // Target's next sequence number as of `last_seq`; later points are from a batch saved after the checkpoint
+}
+
+impl Checkpoint {
+    pub fn path(bin_directory: &Path, target: &str) -> PathBuf {
+        bin_directory.join(format!("{}.reembed", target))
+    }
+
+    // None when the target is not being re-embedded into
+    pub fn load(bin_directory: &Path, target: &str) -> io::Result<Option<Checkpoint>> {
+        match fs::read(Self::path(bin_directory, target)) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map(Some).map_err(io::Error::other),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Written to a temporary file and renamed so a crash never leaves a torn checkpoint
+    pub fn save(&self, bin_directory: &Path, target: &str) -> io::Result<()> {
+        let path = Self::path(bin_directory, target);
+        let tmp_path = path.with_extension("reembed.tmp");
+        fs::write(&tmp_path, serde_json::to_vec(self).map_err(io::Error::other)?)?;
+        fs::rename(tmp_path, path)
+    }
+
+    pub fn remove(bin_directory: &Path, target: &str) -> io::Result<()> {
+        match fs::remove_file(Self::path(bin_directory, target)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
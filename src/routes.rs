@@ -0,0 +1,124 @@
+// Route tables. The server registers its endpoints from tables of `Route` and /routes
+// describes them from the same tables, so what tooling discovers is what is served.
+// Besides its method and path, an entry says whether it changes data and which query
+// parameters it can't do without; its handler is attached by a function, since the
+// handlers themselves live with the server.
+use actix_web::http::Method;
+use actix_web::web;
+use serde::{Deserialize, Serialize};
+
+use crate::api::RouteInfo;
+
+// The administrative routes are also served under this prefix, without the fields the
+// unversioned routes keep sending for one more release
+pub const V1_PREFIX: &str = "/v1";
+
+// Least privilege an API key needs to call a route. The server does not check keys
+// itself; a gateway in front of it enforces these.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyScope {
+    Read,
+    Write, // Routes that change trees or server state
+    Admin, // Operational routes, on the ADMIN_PORT listener when it is set
+}
+
+// Attaches a handler to an actix route, e.g. `|route| route.to(get_status)`
+pub type Attach = fn(actix_web::Route) -> actix_web::Route;
+
+pub struct Route {
+    pub method: Method,
+    pub path: &'static str,
+    pub mutates: bool,
+    pub required: &'static [&'static str], // Query parameters it answers 400 without
+    pub payload_limit: Option<usize>,      // Body limit in bytes, instead of MAX_PAYLOAD_MB
+    pub handler: Attach,
+}
+
+impl Route {
+    pub const fn new(method: Method, path: &'static str, handler: Attach) -> Self {
+        Route { method, path, mutates: false, required: &[], payload_limit: None, handler }
+    }
+
+    pub const fn get(path: &'static str, handler: Attach) -> Self {
+        Route::new(Method::GET, path, handler)
+    }
+
+    pub const fn post(path: &'static str, handler: Attach) -> Self {
+        Route::new(Method::POST, path, handler)
+    }
+
+    pub const fn mutating(mut self) -> Self {
+        self.mutates = true;
+        self
+    }
+
+    pub const fn requiring(mut self, required: &'static [&'static str]) -> Self {
+        self.required = required;
+        self
+    }
+
+    pub const fn with_payload_limit(mut self, bytes: usize) -> Self {
+        self.payload_limit = Some(bytes);
+        self
+    }
+
+    fn scope(&self, admin: bool) -> KeyScope {
+        match (admin, self.mutates) {
+            (true, _) => KeyScope::Admin,
+            (false, true) => KeyScope::Write,
+            (false, false) => KeyScope::Read,
+        }
+    }
+
+    // The API version a route belongs to, None for unversioned routes
+    fn version(&self) -> Option<&'static str> {
+        self.path.strip_prefix(V1_PREFIX).filter(|rest| rest.starts_with('/')).map(|_| &V1_PREFIX[1..])
+    }
+}
+
+// Registers the routes with one resource per path, so the methods of a path share its
+// settings and any other method answers 405
+pub fn register(config: &mut web::ServiceConfig, routes: &[Route]) {
+    let mut paths: Vec<&str> = Vec::new();
+    for route in routes {
+        if !paths.contains(&route.path) {
+            paths.push(route.path);
+        }
+    }
+    for path in paths {
+        let mut resource = web::resource(path);
+        for route in routes.iter().filter(|route| route.path == path) {
+            if let Some(limit) = route.payload_limit {
+                resource = resource.app_data(web::PayloadConfig::new(limit));
+            }
+            resource = resource.route((route.handler)(web::method(route.method.clone())));
+        }
+        config.service(resource);
+    }
+}
+
+// How /routes lists the routes of one table; `admin` tables are the operational ones
+pub fn describe(routes: &[Route], admin: bool) -> impl Iterator<Item = RouteInfo> + '_ {
+    routes.iter().map(move |route| RouteInfo {
+        method: route.method.to_string(),
+        path: route.path.to_string(),
+        mutates: route.mutates,
+        admin,
+        required_params: route.required.iter().map(|param| param.to_string()).collect(),
+        scope: route.scope(admin),
+        version: route.version().map(str::to_string),
+    })
+}
+
+// First path segment of every route, e.g. `/jobs` for `/jobs/{id}`, for the startup log
+pub fn prefixes(routes: &[Route]) -> Vec<&'static str> {
+    let mut prefixes = Vec::new();
+    for route in routes {
+        let end = route.path[1..].find('/').map_or(route.path.len(), |end| end + 1);
+        if !prefixes.contains(&&route.path[..end]) {
+            prefixes.push(&route.path[..end]);
+        }
+    }
+    prefixes
+}
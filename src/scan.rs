@@ -0,0 +1,302 @@
+// Searches that read a tree file instead of loading it. Archival trees that are rarely
+// searched can be answered where they lie rather than evicting hot trees to make room:
+// the tree section is decoded one node at a time in the order it was written, each
+// point is kept if it is among the n closest so far and dropped otherwise, and only the
+// winners' data is read from the payload section afterwards. Every point is compared, so
+// a scan costs a read of the whole file but memory for the results alone.
+use serde::de::{self, DeserializeSeed, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use bincode::Options;
+
+use crate::distance::{self, Euclidean, Metric};
+use crate::kdtree::{KdTreeError, Point, PointV9, FILE_MAGIC, FORMAT_VERSION};
+use crate::reduction::RandomProjection;
+use crate::schema::Schema;
+use crate::stats::TreeStats;
+
+// What a search does with a tree that is not in memory
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OffloadedStrategy {
+    #[default]
+    Load,   // Load it into the cache, evicting other trees if memory requires
+    Scan,   // Stream its file, leaving the cache alone
+    Reject, // Fail with 409, like `if_in_memory=true`
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ScanError {
+    #[error(transparent)]
+    Tree(#[from] KdTreeError),
+    #[error("{0}")]
+    Unsupported(String), // The file can be loaded but not scanned
+}
+
+pub struct ScanQuery<'a> {
+    pub target: &'a [f64],
+    pub n: usize,
+    pub partition: Option<&'a str>, // Only this partition's points are compared
+    pub predicate: &'a dyn Fn(&Point) -> bool,
+}
+
+// The n closest points, with what the scan read about the tree after them
+#[derive(Debug)]
+pub struct Scan {
+    pub hits: Vec<(f64, Point)>,
+    pub scanned: usize, // Points read, whether compared or not
+    pub partition_field: Option<String>,
+    pub schema: Option<Schema>,
+    pub model: Option<String>,
+}
+
+// Scans the tree file at `path`. Files in the current format and in v9, whose points
+// get their norms computed as they are read, are scanned; older files and trees that
+// store projected embeddings are refused, since the projection is stored after the
+// points and the query can't be projected before they are read.
+pub fn scan_file(path: &Path, query: &ScanQuery) -> Result<Scan, ScanError> {
+    let filename = path.to_string_lossy();
+    let mut reader = BufReader::new(File::open(path).map_err(KdTreeError::from)?);
+    let mut header = [0u8; 8];
+    let version = match reader.read_exact(&mut header) {
+        Ok(()) if &header[..4] == FILE_MAGIC => u32::from_le_bytes([header[4], header[5], header[6], header[7]]),
+        _ => 0,
+    };
+    if version != FORMAT_VERSION && version != 9 {
+        return Err(ScanError::Unsupported(format!(
+            "{} is a format v{} file and scans only read v9 and v{}; load the tree once to migrate it",
+            filename, version, FORMAT_VERSION
+        )));
+    }
+
+    let mut scanner = Scanner {
+        query,
+        with_norms: version == FORMAT_VERSION,
+        searching: false,
+        hits: Vec::new(),
+        scanned: 0,
+        unsupported: None,
+    };
+    // The options `bincode::deserialize_from` uses, which wrote the file
+    let options = bincode::DefaultOptions::new().with_fixint_encoding().allow_trailing_bytes();
+    let tree = TreeSeed(&mut scanner).deserialize(&mut bincode::Deserializer::with_reader(&mut reader, options));
+    if let Some(problem) = scanner.unsupported {
+        return Err(ScanError::Unsupported(problem));
+    }
+    let trailer = tree.map_err(|e| KdTreeError::unreadable(&filename, *e))?;
+
+    let lengths: Vec<Option<u32>> = bincode::deserialize_from(&mut reader).map_err(|e| KdTreeError::unreadable(&filename, *e))?;
+    let start = reader.stream_position().map_err(KdTreeError::from)?;
+    let end = start + lengths.iter().flatten().map(|len| *len as u64).sum::<u64>();
+    let size = reader.get_ref().metadata().map_err(KdTreeError::from)?.len();
+    if lengths.len() != scanner.scanned || size < end {
+        return Err(KdTreeError::Corrupt {
+            detail: format!("Payload section of {} does not match its tree", filename),
+            source: None,
+        }.into());
+    }
+
+    // The winners' data, read in file order with one pass over the lengths
+    let mut winners: Vec<usize> = (0..scanner.hits.len()).collect();
+    winners.sort_by_key(|&winner| scanner.hits[winner].1);
+    let (mut offset, mut position) = (start, 0);
+    for winner in winners {
+        let (_, index, point) = &mut scanner.hits[winner];
+        offset += lengths[position..*index].iter().flatten().map(|len| *len as u64).sum::<u64>();
+        position = *index;
+        if let Some(len) = lengths[*index] {
+            let mut bytes = vec![0; len as usize];
+            reader.seek(SeekFrom::Start(offset)).map_err(KdTreeError::from)?;
+            reader.read_exact(&mut bytes).map_err(KdTreeError::from)?;
+            point.data = Some(String::from_utf8(bytes).map_err(|e| KdTreeError::Corrupt {
+                detail: format!("Payload of point {} in {} is not UTF-8: {}", index, filename, e),
+                source: None,
+            })?);
+        }
+    }
+
+    Ok(Scan {
+        hits: scanner.hits.into_iter().map(|(distance, _, point)| (distance, point)).collect(),
+        scanned: scanner.scanned,
+        partition_field: trailer.partition_field,
+        schema: trailer.schema,
+        model: trailer.model,
+    })
+}
+
+struct Scanner<'q> {
+    query: &'q ScanQuery<'q>,
+    with_norms: bool, // Whether the file stores the point norms, which v9 files don't
+    searching: bool, // Whether the subtree being read is one the query compares
+    hits: Vec<(f64, usize, Point)>, // Closest first, with each point's position in the file
+    scanned: usize,
+    unsupported: Option<String>, // Why the scan stopped early
+}
+
+impl Scanner<'_> {
+    // Keeps the point if it is among the n closest so far. Fails the scan on the first
+    // point whose embedding can't be compared with the query.
+    fn offer(&mut self, point: Point) -> Result<(), String> {
+        let index = self.scanned;
+        self.scanned += 1;
+        if !self.searching {
+            return Ok(());
+        }
+        if point.embedding.len() != self.query.target.len() {
+            let problem = format!(
+                "The tree stores {}-dimensional projected embeddings, which a scan can't compare \
+                 the query with; search it with offloaded_strategy=load",
+                point.embedding.len()
+            );
+            self.unsupported = Some(problem.clone());
+            return Err(problem);
+        }
+        let distance = Euclidean.dist(&point.embedding, self.query.target);
+        let position = self.hits.partition_point(|(d, _, _)| *d <= distance);
+        if position < self.query.n && (self.query.predicate)(&point) {
+            self.hits.insert(position, (distance, index, point));
+            self.hits.truncate(self.query.n);
+        }
+        Ok(())
+    }
+}
+
+// Fields of the tree section that follow the points
+struct Trailer {
+    partition_field: Option<String>,
+    schema: Option<Schema>,
+    model: Option<String>,
+}
+
+// Field lists of `KDTree` and `Node` as serialized, which bincode reads as tuples
+const TREE_FIELDS: &[&str] = &[
+    "root", "k", "partition_field", "partitions", "next_seq", "stats", "reduction", "schema", "model",
+];
+const NODE_FIELDS: &[&str] = &["point", "left", "right", "axis"];
+
+fn element<'de, T: Deserialize<'de>, A: SeqAccess<'de>>(seq: &mut A, index: usize) -> Result<T, A::Error> {
+    seq.next_element()?.ok_or_else(|| de::Error::invalid_length(index, &"a complete tree"))
+}
+
+// The whole tree section, in the layout `KDTree` is serialized with
+struct TreeSeed<'s, 'q>(&'s mut Scanner<'q>);
+
+impl<'de> DeserializeSeed<'de> for TreeSeed<'_, '_> {
+    type Value = Trailer;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Trailer, D::Error> {
+        deserializer.deserialize_struct("KDTree", TREE_FIELDS, self)
+    }
+}
+
+impl<'de> Visitor<'de> for TreeSeed<'_, '_> {
+    type Value = Trailer;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a tree")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Trailer, A::Error> {
+        // The shared root only holds points of no partition
+        self.0.searching = self.0.query.partition.is_none();
+        seq.next_element_seed(SubtreeSeed(&mut *self.0))?
+            .ok_or_else(|| de::Error::invalid_length(0, &"a complete tree"))?;
+        let _k: usize = element(&mut seq, 1)?;
+        let partition_field: Option<String> = element(&mut seq, 2)?;
+        seq.next_element_seed(PartitionsSeed(&mut *self.0))?
+            .ok_or_else(|| de::Error::invalid_length(3, &"a complete tree"))?;
+        let _next_seq: u64 = element(&mut seq, 4)?;
+        let _stats: TreeStats = element(&mut seq, 5)?;
+        let _reduction: Option<RandomProjection> = element(&mut seq, 6)?;
+        let schema = element(&mut seq, 7)?;
+        let model = element(&mut seq, 8)?;
+        Ok(Trailer { partition_field, schema, model })
+    }
+}
+
+// The partition subtrees, by partition value
+struct PartitionsSeed<'s, 'q>(&'s mut Scanner<'q>);
+
+impl<'de> DeserializeSeed<'de> for PartitionsSeed<'_, '_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for PartitionsSeed<'_, '_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("partition subtrees")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some(partition) = map.next_key::<String>()? {
+            self.0.searching = self.0.query.partition.is_none_or(|searched| searched == partition);
+            map.next_value_seed(SubtreeSeed(&mut *self.0))?;
+        }
+        Ok(())
+    }
+}
+
+// A possibly empty subtree, `Option<Box<Node>>` as serialized
+struct SubtreeSeed<'s, 'q>(&'s mut Scanner<'q>);
+
+impl<'de> DeserializeSeed<'de> for SubtreeSeed<'_, '_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_option(self)
+    }
+}
+
+impl<'de> Visitor<'de> for SubtreeSeed<'_, '_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an optional tree node")
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_struct("Node", NODE_FIELDS, NodeVisitor(self.0))
+    }
+}
+
+// A node: its point, offered to the scanner before its children are read, then the
+// children in pre-order, which is also the order of the payload section
+struct NodeVisitor<'s, 'q>(&'s mut Scanner<'q>);
+
+impl<'de> Visitor<'de> for NodeVisitor<'_, '_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a tree node")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        let point = if self.0.with_norms {
+            element(&mut seq, 0)?
+        } else {
+            let mut point: Point = element::<PointV9, _>(&mut seq, 0)?.into();
+            point.norm = distance::norm(&point.embedding);
+            point
+        };
+        self.0.offer(point).map_err(de::Error::custom)?;
+        for index in [1, 2] {
+            seq.next_element_seed(SubtreeSeed(&mut *self.0))?
+                .ok_or_else(|| de::Error::invalid_length(index, &"a complete node"))?;
+        }
+        let _axis: usize = element(&mut seq, 3)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,101 @@
+// Metadata schemas. A tree may declare the metadata fields its points carry, with a type
+// and whether each is required; inserts and filters are then checked against it, so a
+// misspelled field is refused instead of stored where no filter finds it. Trees without
+// a schema take any metadata.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::filter::Condition;
+use crate::metadata::{Metadata, MetadataValue};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldType {
+    String,
+    Number,
+    Bool,
+}
+
+impl FieldType {
+    fn of(value: &MetadataValue) -> FieldType {
+        match value {
+            MetadataValue::String(_) => FieldType::String,
+            MetadataValue::Number(_) => FieldType::Number,
+            MetadataValue::Bool(_) => FieldType::Bool,
+        }
+    }
+}
+
+impl fmt::Display for FieldType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FieldType::String => "string",
+            FieldType::Number => "number",
+            FieldType::Bool => "bool",
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FieldSpec {
+    #[serde(rename = "type")]
+    pub field_type: FieldType,
+    #[serde(default)]
+    pub required: bool,
+}
+
+// Field name to spec, e.g. `{"lang": {"type": "string", "required": true}}`
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(transparent)]
+pub struct Schema {
+    pub fields: BTreeMap<String, FieldSpec>,
+}
+
+impl Schema {
+    // Accepts the JSON form, naming what is wrong with it
+    pub fn parse(spec: &str) -> Result<Schema, String> {
+        let schema: Schema = serde_json::from_str(spec).map_err(|e| format!("invalid schema: {}", e))?;
+        if schema.fields.keys().any(String::is_empty) {
+            return Err("field names must not be empty".to_string());
+        }
+        Ok(schema)
+    }
+
+    // Every problem with a point's metadata: unknown fields, missing required ones and
+    // values of the wrong type
+    pub fn check(&self, metadata: &Metadata) -> Result<(), String> {
+        let mut problems = Vec::new();
+        for (field, value) in metadata {
+            match self.fields.get(field) {
+                None => problems.push(format!("unknown field `{}`", field)),
+                Some(spec) if spec.field_type != FieldType::of(value) => problems.push(format!(
+                    "field `{}` must be a {}, got {}", field, spec.field_type, FieldType::of(value)
+                )),
+                Some(_) => {}
+            }
+        }
+        for (field, spec) in &self.fields {
+            if spec.required && !metadata.contains_key(field) {
+                problems.push(format!("missing required field `{}`", field));
+            }
+        }
+        match problems.is_empty() {
+            true => Ok(()),
+            false => Err(problems.join(", ")),
+        }
+    }
+
+    // Fails on the first field a filter names that the schema doesn't declare
+    pub fn check_condition(&self, condition: &Condition) -> Result<(), String> {
+        match condition.fields().into_iter().find(|field| !self.fields.contains_key(*field)) {
+            Some(field) => Err(format!("filter names undeclared field `{}`", field)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn required_fields(&self) -> Vec<&str> {
+        self.fields.iter().filter(|(_, spec)| spec.required).map(|(field, _)| field.as_str()).collect()
+    }
+}
@@ -0,0 +1,4992 @@
+// The HTTP server: request handlers, the route tables and the background tasks. The
+// `vodb` binary runs it with `run`; tests serve `app` in-process over a state built by
+// `build_state`, without the background tasks.
+use actix_web::{web, App, HttpRequest, HttpServer, HttpResponse, HttpResponseBuilder, Responder};
+use actix_web::body::MessageBody;
+use actix_web::dev::{Service, ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::error::{InternalError, JsonPayloadError};
+use actix_web::http::{Method, StatusCode};
+use actix_web::http::header::{self, ContentType, HeaderName, HeaderValue};
+use actix_web::rt::task::JoinHandle;
+use actix_web::web::Bytes;
+use std::borrow::Cow;
+use std::convert::Infallible;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::Ordering;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::num::NonZeroUsize;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::fs;
+use std::future::{ready, Future};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use serde::Serialize;
+use serde_json::json;
+use dotenv::dotenv;
+use tokio::sync::{mpsc, oneshot};
+use futures_util::future::{try_join, BoxFuture, Either, FutureExt, Shared};
+use clap::{Parser, Subcommand};
+
+use crate::advisor;
+use crate::api::{AdvisorResponse, AuditDivergence, AuditSearchResponse, BatchSummary, CacheEntry, CollectionInfo, CreateTreeResponse, DeleteByFilterResponse, DropCacheResponse, DriftResponse, ExistsWithinResponse, GcResponse, ImportResponse, InsertResponse, JobsResponse, JsonlImportResponse, LoadFailureStatus, MultiInsertOutcome, MultiInsertResponse, MultiInsertStatus, RebuildResponse, RemovedFile, RestoreResponse, RoutesResponse, SaveFailureStatus, SchemaResponse, StatsResponse, StatusResponse, StatusTotals, TreeState, TreeStatus, TreeSummary, TreeUsageStatus, TreeVerification, TreesResponse, TruncateResponse, UploadResponse, UsageResponse, VerifyAllResponse};
+use crate::archive::{compress_file, decompress_file, Tier};
+use crate::bloom::{BloomFilter, BloomSettings};
+use crate::cancel::CancellationToken;
+use crate::distance::Euclidean;
+use crate::config::Settings;
+use crate::disk::{DiskSpace, InsufficientSpace};
+use crate::durability::{Durability, PendingWrite, RetryPolicy, SaveHealth};
+use crate::embedder::Embedder;
+use crate::encoding::PointInput;
+use crate::error::ApiError;
+use crate::filter::{Condition, Filter};
+use crate::gc::{self, GarbageKind};
+use crate::histogram::{DistanceHistogram, Histogram};
+use crate::import::{read_parquet, ParquetColumns, ParquetImport};
+use crate::jobs::{Job, JobKind, Jobs};
+use crate::jsonl::{ImportCheckpoint, JsonlImport, JsonlReader, LineRules, Position};
+use crate::kdtree::{owned_hits, KDTree, KdTreeError, OwnedGroupHits, Point, Node, FORMAT_VERSION};
+use crate::latency::{Phase, SearchTimings};
+use crate::limiter::{Charge, CostLimiter, Exhausted, HeavyLimiter};
+use crate::lock::LockContext;
+use crate::maintenance::Scheduler;
+use crate::manifest::{rfc3339, unix_seconds, Manifest, TreeEntry};
+use crate::memory::{estimate_memory_usage, estimate_point_size, Workload};
+use crate::metrics::{Metrics, OTHER_TREES};
+use crate::mmr;
+use crate::operation::{OperationKind, TreeOperation};
+use crate::params::{AuditSearchParams, CreateTreeParams, DeleteByFilterParams, DriftParams, DropCacheParams, ExistsWithinParams, ExportParams, ImportJsonlParams, ImportParquetParams, LookupParams, InsertParams, RebuildParams, ReembedParams, RestoreChunkParams, RestoreCommitParams, SampleParams, SchemaParams, SearchParams, SnapshotParams, StatsParams, StatusParams, TreeParams, TreeStructureParams, TruncateParams, UsageParams, Valid, VerifyAllParams, DEFAULT_AUDIT_BUDGET_MS, DEFAULT_AUDIT_N, DEFAULT_AUDIT_SAMPLES, DEFAULT_SAMPLE_COUNT, MAX_AUDIT_SAMPLES, MAX_N};
+use crate::params::{is_valid_tree_name, validate_multi_insert, MAX_TREE_NAME_LEN};
+use crate::projection::Projection;
+use crate::query::{AuditSearchBody, MultiInsertEntry, SearchBody};
+use crate::query_cache::QueryCache;
+use crate::rebalance::rebalance_trees;
+use crate::reduction::{RandomProjection, DEFAULT_SEED};
+use crate::reembed::Checkpoint;
+use crate::rng::SplitMix64;
+use crate::routes::{self, Route, V1_PREFIX};
+use crate::scan::{scan_file, OffloadedStrategy, ScanError, ScanQuery};
+use crate::schema::Schema;
+use crate::server_timing;
+use crate::snapshot::{get_upload_file_path, parse_range, sha256_of, staged_len, write_chunk, FileVersion, HashCache, MAX_UPLOAD_CHUNK_BYTES};
+use crate::store::{ensure_bin_directory, get_bin_file_path, get_bloom_file_path, model_mismatch, offload_tree, quarantine_tree, register_trees, resident_memory_usage, CollectionError, InsertChecks, KDTreeCache, Persistence, PreparedInsert, Store, StoreOptions};
+use crate::structure::{self, StructureFormat, DEFAULT_STRUCTURE_DEPTH};
+use crate::trace::Trace;
+use crate::usage::{self, UsageRegistry, HOUR_SECS};
+use crate::warm::{warm_file, WarmFiles};
+
+pub struct APPState {
+    store: Store,                 // Trees, their files and the memory budget
+    metrics: Metrics,
+    bloom: Option<BloomSettings>, // Insert-time duplicate filtering, disabled when None
+    query_cache: Option<QueryCache>, // Repeated-search result cache, disabled when None
+    default_durability: Durability, // Used by inserts that don't ask for a level
+    writers: Mutex<HashMap<String, TreeWriter>>, // Per-tree insert queues
+    write_queue_capacity: usize,  // Inserts a tree's queue holds before callers wait
+    archive: ArchiveSettings,
+    settings: Settings,           // Effective configuration, served redacted by /config
+    heavy: HeavyLimiter,          // Shared by export and rebuild so they can't crowd out searches
+    cost_limiter: Option<CostLimiter>, // Per-client budgets for searches and inserts, disabled when None
+    snapshot_hashes: HashCache,   // SHA-256 of tree files served by /snapshot
+    maintenance: Scheduler,       // Lets background archival and GC run only in MAINTENANCE_WINDOW
+    ephemeral_loads: Mutex<HashMap<String, SharedLoad>>, // cache=false loads in progress, by tree
+    disk_space: DiskSpace,        // Free space of the bin directory, checked before writes
+    embedder: Option<Embedder>,   // Provider used by /reembed, disabled when None
+    jobs: Jobs,                   // Background jobs polled through /jobs, kept in jobs.json
+}
+
+// A private copy of a tree being read for cache=false searches, which every search of the
+// tree that arrives meanwhile waits for instead of reading the file again
+type SharedLoad = Shared<BoxFuture<'static, Result<Arc<KDTree>, (StatusCode, String)>>>;
+
+impl APPState {
+    // Every per-tree fact the administrative endpoints report, read from the cache
+    // entries in one pass under one lock acquisition. Read-only: trees are never loaded
+    // and LRU recency is left alone. `only` restricts the snapshot to a single tree.
+    fn snapshot(&self, only: Option<&str>) -> (Vec<TreeStatus>, StatusTotals) {
+        self.count_writes();
+        let trees = self.store.trees.lock().unwrap();
+        let now = Instant::now();
+        let mut status: Vec<TreeStatus> = trees.iter()
+            .filter(|(tree_name, _)| only.is_none_or(|wanted| wanted == *tree_name))
+            .map(|(tree_name, cache)| TreeStatus {
+                tree_name: tree_name.clone(),
+                tier: cache.tier,
+                num_records: cache.num_records,
+                dimensions: cache.dimensions,
+                model: cache.model.clone(),
+                in_memory: cache.tree.is_some(),
+                estimated_bytes: cache.resident_bytes(),
+                dirty: cache.dirty,
+                last_accessed: Some(now.saturating_duration_since(cache.last_accessed).as_secs()),
+                last_accessed_at: accessed_at(cache).map(rfc3339),
+                seconds_since_access: accessed_at(cache).map(seconds_since),
+                created_at: cache.created_at.map(rfc3339),
+                updated_at: None,
+                warmed_at: cache.warmed_at.map(rfc3339),
+                access_count: cache.access_count,
+                operation: cache.operation.as_ref().map(TreeOperation::describe),
+                suspect: cache.suspect,
+                state: match (&cache.tree, &cache.load_failure) {
+                    (Some(_), _) => TreeState::Loaded,
+                    (None, Some(_)) => TreeState::LoadFailed,
+                    (None, None) => TreeState::Offloaded,
+                },
+                load_failure: cache.load_failure.as_ref().filter(|_| cache.tree.is_none()).map(|failure| LoadFailureStatus {
+                    error: failure.error.clone(),
+                    attempted_at: unix_seconds(failure.attempted_at),
+                    consecutive_failures: failure.consecutive,
+                    retry_in_secs: failure.retry_in().as_secs(),
+                }),
+                save_failures: cache.save_health.failures(),
+                save_failure: cache.save_health.failure().map(|failure| SaveFailureStatus {
+                    error: failure.error,
+                    failed_at: unix_seconds(failure.failed_at),
+                    consecutive_failures: failure.consecutive,
+                    failing_secs: failure.since.elapsed().as_secs(),
+                    degraded: self.persistence_degraded(&cache.save_health),
+                }),
+                usage: TreeUsageStatus::default(),
+            })
+            .collect();
+        drop(trees);
+        for tree in &mut status {
+            if let Some(usage) = self.store.usage.get(&tree.tree_name) {
+                tree.usage = usage.status();
+            }
+            if tree.usage.last_write_at > 0 {
+                tree.updated_at = Some(rfc3339(UNIX_EPOCH + Duration::from_secs(tree.usage.last_write_at)));
+            }
+        }
+
+        status.sort_by(|a, b| a.tree_name.cmp(&b.tree_name));
+        let totals = status.iter().fold(StatusTotals::default(), |mut totals, tree| {
+            totals.trees += 1;
+            totals.in_memory += usize::from(tree.in_memory);
+            totals.num_records += tree.num_records;
+            totals.estimated_bytes += tree.estimated_bytes;
+            totals.unhealthy += usize::from(tree.state == TreeState::LoadFailed || tree.suspect);
+            totals.persistence_degraded += usize::from(tree.save_failure.as_ref().is_some_and(|failure| failure.degraded));
+            totals
+        });
+        (status, totals)
+    }
+
+    // Whether saves of a tree have failed for longer than PERSISTENCE_DEADLINE_SECS
+    fn persistence_degraded(&self, health: &SaveHealth) -> bool {
+        let deadline = self.settings.persistence_deadline_secs;
+        deadline > 0 && health.failing_for() > Duration::from_secs(deadline)
+    }
+
+    // The retries of a failing background flush
+    fn save_retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            attempts: self.settings.save_retry_attempts,
+            base: Duration::from_millis(self.settings.save_retry_base_ms),
+            ..RetryPolicy::default()
+        }
+    }
+
+    // Moves the tree file writes tallied since the last call into the usage counters
+    fn count_writes(&self) {
+        let written: Vec<(String, (u64, u64))> = self.store.trees.lock().unwrap().iter()
+            .map(|(tree_name, cache)| (tree_name.clone(), cache.written.take()))
+            .filter(|(_, (_, saves))| *saves > 0)
+            .collect();
+        let now = unix_seconds(SystemTime::now());
+        for (tree_name, (bytes, saves)) in written {
+            self.store.usage.tree(&tree_name).record_saves(saves, bytes, now);
+        }
+    }
+}
+
+// Wall-clock time of the tree's last data-path access, None if it was never accessed
+fn accessed_at(cache: &KDTreeCache) -> Option<SystemTime> {
+    (cache.last_accessed_at > UNIX_EPOCH).then_some(cache.last_accessed_at)
+}
+
+fn seconds_since(time: SystemTime) -> u64 {
+    SystemTime::now().duration_since(time).map_or(0, |d| d.as_secs())
+}
+
+const LAST_ACCESSED_DEPRECATION: &str = "last_accessed is deprecated and will be removed from the unversioned \
+    routes in the next release; use seconds_since_access, or last_accessed_at for the time itself. The /v1 routes \
+    already leave it out.";
+
+// Cold-tier storage for trees nobody has used in a while
+#[derive(Clone)]
+struct ArchiveSettings {
+    directory: PathBuf,
+    after: Option<Duration>, // Idle time before a tree is archived, archival disabled when None
+    restore_wait: Duration,  // How long a request waits for a restore before getting a 503
+}
+
+// Suggested by the 429 returned when the heavy request queue is full
+const HEAVY_RETRY_AFTER_SECS: u64 = 1;
+
+// Suggested by the 503 returned while an archived tree is being restored
+const RESTORE_RETRY_AFTER_SECS: u64 = 5;
+
+// Suggested by the 503 returned while a tree's saves keep failing, about as long as the
+// background flush backs off at most
+const DEGRADED_RETRY_AFTER_SECS: u64 = 60;
+
+// A tree's insert queue and the task draining it
+type TreeWriter = (mpsc::Sender<QueuedInsert>, JoinHandle<()>);
+
+// Most queued inserts a writer applies under one lock acquisition and one file write
+const WRITE_BATCH_SIZE: usize = 256;
+
+// Background tasks gated by MAINTENANCE_WINDOW, as named in /status
+const ARCHIVAL_TASK: &str = "archival";
+const GC_TASK: &str = "gc";
+
+// How often the background task compares resident memory with the soft limit
+const EVICTION_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Mismatching queries /audit_search describes in full
+const MAX_AUDIT_DIVERGENCES: usize = 5;
+
+// Size of the reads a /snapshot response is streamed in
+const SNAPSHOT_BLOCK_BYTES: u64 = 1024 * 1024;
+
+// Distance every tree ranks points by, as reported in search responses
+const SEARCH_METRIC: &str = "euclidean";
+
+// How often a re-embedding job saves its target and checkpoints, since saving rewrites
+// the whole file
+const REEMBED_CHECKPOINT_INTERVAL: Duration = Duration::from_secs(30);
+
+// How often a JSONL import saves the tree it is building, for the same reason
+const IMPORT_CHECKPOINT_INTERVAL: Duration = Duration::from_secs(30);
+
+// Background check that resident trees still find their own points
+#[derive(Clone)]
+struct SelfTestSettings {
+    interval: Duration,
+    samples: usize,              // Stored points queried per tree
+    budget: Duration,            // Time a cycle may spend before leaving trees for the next one
+    webhook_url: Option<String>, // Receives a JSON POST for every suspect tree
+    reload: bool,                // Reload suspect trees from disk
+}
+
+#[derive(Parser)]
+#[command(about = "Disk-persistent KD-Tree vector store")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Leave tree files in the working directory alone instead of moving them into BIN_DIRECTORY
+    #[arg(long)]
+    no_migrate: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Rewrite legacy tree files in the current format, keeping the originals as `.bin.v<N>`
+    Migrate {
+        /// Directory to migrate, defaults to BIN_DIRECTORY
+        directory: Option<PathBuf>,
+    },
+    /// Check that every tree file satisfies the KD-tree invariant, without changing any
+    Verify {
+        /// Directory to verify, defaults to BIN_DIRECTORY
+        directory: Option<PathBuf>,
+    },
+}
+
+fn get_archive_file_path(archive_directory: &Path, tree_name: &str) -> PathBuf {
+    archive_directory.join(format!("{}.bin.gz", tree_name))
+}
+
+// Older builds saved trees as `{tree_name}.bin` in the working directory. Moves every
+// such file that loads as a tree into the bin directory and records it in the
+// manifest; a name already taken there gets a `-cwd` suffix. Files that don't load
+// are left where they are. Returns how many files were moved.
+fn adopt_orphaned_trees(directory: &Path, bin_directory: &Path, manifest: &mut Manifest) -> usize {
+    // Nothing is orphaned when the server is run from inside its bin directory
+    if fs::canonicalize(directory).ok().is_some_and(|directory| fs::canonicalize(bin_directory).ok() == Some(directory)) {
+        return 0;
+    }
+    let Ok(entries) = fs::read_dir(directory) else { return 0 };
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file() && path.extension().is_some_and(|extension| extension == "bin"))
+        .collect();
+    files.sort();
+
+    let mut adopted = 0;
+    for path in files {
+        let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) else { continue };
+        let tree = match KDTree::load_versioned(path.to_str().unwrap()) {
+            Ok((tree, _)) => tree,
+            Err(e) => {
+                println!("Leaving {:?} in place: not a readable tree file ({})", path, e);
+                continue;
+            }
+        };
+
+        let taken = |name: &str| manifest.trees.contains_key(name) || get_bin_file_path(bin_directory, name).exists();
+        let mut tree_name = stem.to_string();
+        if !is_valid_tree_name(&tree_name) {
+            // Leaves room for the suffix below
+            tree_name = stem.chars()
+                .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+                .take(MAX_TREE_NAME_LEN - 16)
+                .collect();
+        }
+        let base = tree_name.clone();
+        let mut attempt = 1;
+        while taken(&tree_name) {
+            tree_name = if attempt == 1 { format!("{}-cwd", base) } else { format!("{}-cwd{}", base, attempt) };
+            attempt += 1;
+        }
+        if tree_name != stem {
+            println!("Warning: {:?} is adopted as tree {} since {} is taken or not a valid name", path, tree_name, stem);
+        }
+
+        let target = get_bin_file_path(bin_directory, &tree_name);
+        // A rename fails across file systems, where the file is copied instead
+        let moved = fs::rename(&path, &target)
+            .or_else(|_| fs::copy(&path, &target).and_then(|_| fs::remove_file(&path)));
+        if let Err(e) = moved {
+            println!("Failed to move {:?} to {:?}: {}", path, target, e);
+            continue;
+        }
+        manifest.trees.insert(tree_name.clone(), TreeEntry {
+            dimensions: Some(tree.input_dimensions()),
+            num_records: tree.len(),
+            ..TreeEntry::default()
+        });
+        println!("Moved {:?} to {:?}", path, target);
+        adopted += 1;
+    }
+    adopted
+}
+
+// Rewrites every legacy tree file in `directory` in the current format
+fn migrate_directory(directory: &Path) -> io::Result<()> {
+    let mut files: Vec<PathBuf> = fs::read_dir(directory)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|extension| extension == "bin"))
+        .collect();
+    files.sort();
+
+    let (mut migrated, mut current, mut failed) = (0, 0, 0);
+    for (index, path) in files.iter().enumerate() {
+        let filename = path.to_str().unwrap();
+        let outcome = KDTree::load_versioned(filename).and_then(|(tree, version)| {
+            if version < FORMAT_VERSION {
+                tree.rewrite_legacy_file(filename, version).map(|_| Some(version))
+            } else {
+                Ok(None)
+            }
+        });
+        match outcome {
+            Ok(Some(version)) => {
+                migrated += 1;
+                println!("[{}/{}] {:?}: migrated v{} -> v{}", index + 1, files.len(), path, version, FORMAT_VERSION);
+            }
+            Ok(None) => {
+                current += 1;
+                println!("[{}/{}] {:?}: already current", index + 1, files.len(), path);
+            }
+            Err(e) => {
+                failed += 1;
+                println!("[{}/{}] {:?}: failed: {}", index + 1, files.len(), path, e);
+            }
+        }
+    }
+    println!("Migration finished: {} migrated, {} already current, {} failed", migrated, current, failed);
+    Ok(())
+}
+
+// Loads and validates every tree file in `directory`. Fails if any file doesn't load
+// or breaks the invariant, so scripts can act on the exit status.
+fn verify_directory(directory: &Path) -> io::Result<()> {
+    let mut files: Vec<PathBuf> = fs::read_dir(directory)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|extension| extension == "bin"))
+        .collect();
+    files.sort();
+
+    let (mut valid, mut failed) = (0, 0);
+    for (index, path) in files.iter().enumerate() {
+        match KDTree::load_versioned(path.to_str().unwrap()) {
+            Ok((tree, _)) => match tree.validate() {
+                Ok(()) => {
+                    valid += 1;
+                    println!("[{}/{}] {:?}: valid, {} points", index + 1, files.len(), path, tree.len());
+                }
+                Err(violation) => {
+                    failed += 1;
+                    println!("[{}/{}] {:?}: invalid: {}", index + 1, files.len(), path, violation);
+                }
+            },
+            Err(e) => {
+                failed += 1;
+                println!("[{}/{}] {:?}: failed to load: {}", index + 1, files.len(), path, e);
+            }
+        }
+    }
+    println!("Verification finished: {} valid, {} failed", valid, failed);
+    if failed > 0 {
+        return Err(io::Error::other(format!("{} tree files failed verification", failed)));
+    }
+    Ok(())
+}
+
+// An insert waiting in its tree's write queue
+struct QueuedInsert {
+    point: Point,
+    durability: Durability,
+    force: bool, // Skip the memory budget check
+    model: Option<String>, // Model the point comes from
+    strict_model: bool,    // Refuse the point if its tree was created for another model
+    enqueued_at: Instant,
+    respond: oneshot::Sender<(InsertOutcome, BatchTimings)>,
+}
+
+// Where the writer spent its time on the batch an insert was in, reported in the
+// insert's `Server-Timing` header
+#[derive(Debug, Clone, Copy)]
+struct BatchTimings {
+    started: Instant,        // When the writer took the batch off the queue
+    lock: Duration,          // Waiting for the trees lock
+    apply: Duration,         // Inserting the batch under the lock
+    write: Option<Duration>, // Writing the tree file, if the batch asked for it
+}
+
+impl BatchTimings {
+    fn record(&self, enqueued_at: Instant) {
+        server_timing::record("queue", self.started.saturating_duration_since(enqueued_at));
+        server_timing::record("lock", self.lock);
+        server_timing::record("apply", self.apply);
+        if let Some(write) = self.write {
+            server_timing::record("write", write);
+        }
+    }
+}
+
+// What a tree's writer reports back to the handler waiting on an insert
+enum InsertOutcome {
+    Inserted(Durability, bool), // Whether this insert created the tree
+    Unsaved(bool, String),      // Applied in memory, but writing the tree failed; the flush retries it
+    Duplicate,
+    Conflict(serde_json::Value),
+    Failed(StatusCode, String),
+}
+
+impl InsertOutcome {
+    // `model_warning` is only reported with an applied insert
+    fn into_response(self, model_warning: Option<String>) -> HttpResponse {
+        match self {
+            InsertOutcome::Inserted(durability, implicitly_created) => HttpResponse::Ok().json(InsertResponse {
+                message: "Point inserted into KD-Tree".to_string(),
+                durability,
+                implicitly_created,
+                save_error: None,
+                model_warning,
+            }),
+            // Not a failure: the point is in the tree and retrying would add it again
+            InsertOutcome::Unsaved(implicitly_created, error) => HttpResponse::Accepted().json(InsertResponse {
+                message: "Point inserted into KD-Tree in memory; it is saved by the background flush".to_string(),
+                durability: Durability::None,
+                implicitly_created,
+                save_error: Some(format!("Failed to save KD-Tree: {}", error)),
+                model_warning,
+            }),
+            InsertOutcome::Duplicate => HttpResponse::Ok().json("Duplicate point skipped"),
+            InsertOutcome::Conflict(body) => HttpResponse::Conflict().json(body),
+            InsertOutcome::Failed(status, body) => HttpResponse::build(status).body(body),
+        }
+    }
+}
+
+// Returns the queue of `tree_name`'s writer, starting the writer on first use
+fn writer_for(state: &web::Data<APPState>, tree_name: &str) -> mpsc::Sender<QueuedInsert> {
+    let mut writers = state.writers.lock().unwrap();
+    if let Some((queue, _)) = writers.get(tree_name) {
+        return queue.clone();
+    }
+    let (queue, receiver) = mpsc::channel(state.write_queue_capacity);
+    let context = LockContext::new("writer", tree_name);
+    let writer = actix_web::rt::spawn(context.scope(tree_writer(state.clone(), tree_name.to_string(), receiver)));
+    writers.insert(tree_name.to_string(), (queue.clone(), writer));
+    queue
+}
+
+// The single writer of one tree: applies queued inserts in arrival order, persists
+// once per batch and answers every insert in the batch. Exits once its queue is
+// closed and drained.
+async fn tree_writer(state: web::Data<APPState>, tree_name: String, mut queue: mpsc::Receiver<QueuedInsert>) {
+    let mut batch = Vec::new();
+    while queue.recv_many(&mut batch, WRITE_BATCH_SIZE).await > 0 {
+        state.metrics.write_queue_depth.fetch_sub(batch.len() as u64, Ordering::Relaxed);
+        let lag = batch[0].enqueued_at.elapsed();
+        state.metrics.writer_lag_ms.store(lag.as_millis() as u64, Ordering::Relaxed);
+
+        let started = Instant::now();
+        let (outcomes, strongest, pending, lock) = apply_inserts(&state, &tree_name, batch.drain(..));
+        let mut timings = BatchTimings { started, lock, apply: started.elapsed().saturating_sub(lock), write: None };
+
+        // Everyone in the batch gets the durability the batch's write reached, capped at
+        // what they asked for
+        let achieved = match pending {
+            None => Ok(Durability::None),
+            Some(pending) => {
+                let fsync = strongest == Some(Durability::Fsync);
+                let writing = Instant::now();
+                let written = web::block(move || pending.write(fsync)).await;
+                timings.write = Some(writing.elapsed());
+                let written = match written {
+                    Ok(Ok(achieved)) => Ok(achieved),
+                    Ok(Err(e)) => Err(e.to_string()),
+                    Err(e) => Err(e.to_string()),
+                };
+                // The points stay in the tree, so leave the file to the background flush
+                if let Err(e) = &written {
+                    println!("Failed to save tree {} after inserts: {}", tree_name, e);
+                    Metrics::incr(&state.metrics.insert_save_failures);
+                    if let Some(cache) = state.store.trees.lock().unwrap().get_mut(&tree_name) {
+                        cache.dirty = true;
+                    }
+                }
+                written
+            }
+        };
+        for (respond, outcome) in outcomes {
+            let outcome = match (outcome, &achieved) {
+                (InsertOutcome::Inserted(requested, created), Ok(achieved)) => {
+                    InsertOutcome::Inserted(requested.min(*achieved), created)
+                }
+                (InsertOutcome::Inserted(_, created), Err(e)) => InsertOutcome::Unsaved(created, e.clone()),
+                (outcome, _) => outcome,
+            };
+            // The handler may have gone away, e.g. the client disconnected
+            let _ = respond.send((outcome, timings));
+        }
+        retire_writer(&state, &tree_name);
+    }
+}
+
+// Forgets the writer of a tree that doesn't exist, e.g. because every insert into it was
+// refused. The writer still applies inserts already handed to its queue, and exits once
+// those handlers are done with it; the next insert starts a new one.
+fn retire_writer(state: &APPState, tree_name: &str) {
+    let mut writers = state.writers.lock().unwrap();
+    if !state.store.trees.lock().unwrap().contains_key(tree_name) {
+        writers.remove(tree_name);
+    }
+}
+
+type BatchOutcomes = Vec<(oneshot::Sender<(InsertOutcome, BatchTimings)>, InsertOutcome)>;
+
+// Applies a batch under the trees lock and snapshots the tree for the strongest
+// durability any insert asked for. Inserted points report their requested level
+// until the write has happened. Also returns how long the lock took to get.
+fn apply_inserts(
+    state: &APPState,
+    tree_name: &str,
+    batch: impl Iterator<Item = QueuedInsert>,
+) -> (BatchOutcomes, Option<Durability>, Option<PendingWrite>, Duration) {
+    let waiting = Instant::now();
+    let mut trees = state.store.trees.lock().unwrap();
+    let lock = waiting.elapsed();
+    let registered = trees.contains_key(tree_name);
+    let cache = trees.entry(tree_name.to_string()).or_default();
+
+    let known_dimensions = cache.dimensions.is_some();
+    let mut outcomes = Vec::new();
+    let mut strongest = None;
+    // Estimated once per batch and then grown by each inserted point
+    let mut footprint = None;
+    let mut content = 0;
+    for queued in batch {
+        let checks = InsertChecks { force: queued.force, model: queued.model.as_deref(), strict_model: queued.strict_model };
+        let size = queued.point.content_size();
+        let outcome = insert_into_cache(state, cache, tree_name, queued.point, checks, &mut footprint);
+        if let InsertOutcome::Inserted(_, created) = outcome {
+            content += size;
+            strongest = strongest.max(Some(queued.durability));
+            outcomes.push((queued.respond, InsertOutcome::Inserted(queued.durability, created)));
+        } else {
+            outcomes.push((queued.respond, outcome));
+        }
+    }
+
+    let inserted = outcomes.iter().filter(|(_, outcome)| matches!(outcome, InsertOutcome::Inserted(..))).count();
+    // A tree the batch would have created but that took no point is forgotten again, so
+    // refused inserts into an unknown tree don't leave an empty one behind
+    if !registered && inserted == 0 {
+        trees.remove(tree_name);
+        return (outcomes, None, None, lock);
+    }
+    if inserted > 0 {
+        state.store.usage.tree(tree_name).record_inserts(inserted, content);
+    }
+
+    // Snapshot the tree now, write it once the lock is released
+    let pending = match strongest {
+        None => None,
+        Some(Durability::None) => {
+            cache.dirty = true;
+            None
+        }
+        Some(_) => match cache.snapshot(state.store.disk(), tree_name) {
+            Ok(pending) => pending,
+            Err(e) => {
+                println!("Failed to save tree {} after inserts: {}", tree_name, e);
+                Metrics::incr(&state.metrics.insert_save_failures);
+                for (_, outcome) in outcomes.iter_mut() {
+                    if let InsertOutcome::Inserted(_, created) = outcome {
+                        *outcome = InsertOutcome::Unsaved(*created, e.to_string());
+                    }
+                }
+                cache.dirty = true;
+                None
+            }
+        },
+    };
+    if let (Some(_), Some(filter)) = (strongest, &cache.bloom) {
+        // A stale filter is rebuilt on the next load, so this is not fatal
+        if let Err(e) = state.store.save_bloom(tree_name, filter) {
+            println!("Failed to save duplicate filter for tree {}: {}", tree_name, e);
+        }
+    }
+
+    // A new tree's dimension goes into the manifest right away, so inserts with another
+    // dimension keep being rejected even if the tree is evicted before its first save
+    let created = !known_dimensions && cache.dimensions.is_some();
+    if created {
+        if let Err(e) = state.store.save_manifest(&trees) {
+            println!("Failed to save manifest: {}", e);
+        }
+    }
+
+    // Manage memory if the usage exceeds limits
+    state.store.manage_memory(&mut trees);
+    (outcomes, strongest, pending, lock)
+}
+
+fn insert_into_cache(
+    state: &APPState,
+    cache: &mut KDTreeCache,
+    tree_name: &str,
+    point: Point,
+    checks: InsertChecks,
+    footprint: &mut Option<usize>,
+) -> InsertOutcome {
+    match prepare_insert(state, cache, tree_name, point, checks, footprint) {
+        Ok(prepared) => apply_insert(state, cache, tree_name, prepared, footprint),
+        Err(outcome) => outcome,
+    }
+}
+
+// Checks an insert through the tree's collection, which brings the tree into memory and
+// creates it if it has no file yet, then against the duplicate filter, but inserts
+// nothing. Duplicates and failures come back as the outcome to report. Creation can't
+// race: every insert of a tree goes through its single writer or /insert_multi, and
+// those and create_tree all check for an existing tree under the same trees lock this
+// runs under.
+fn prepare_insert(
+    state: &APPState,
+    cache: &mut KDTreeCache,
+    tree_name: &str,
+    point: Point,
+    checks: InsertChecks,
+    footprint: &mut Option<usize>,
+) -> Result<PreparedInsert, InsertOutcome> {
+    let prepared = state.store.collection(tree_name).prepare_insert(cache, point, checks, footprint).map_err(|e| match e {
+        CollectionError::Busy(conflict) => InsertOutcome::Conflict(conflict),
+        e => {
+            let (status, body) = collection_error(state, tree_name, e);
+            InsertOutcome::Failed(status, body)
+        }
+    })?;
+
+    // Bring the duplicate filter in alongside the tree
+    if let (Some(settings), Some(tree), None) = (state.bloom, &cache.tree, &cache.bloom) {
+        cache.bloom = Some(state.store.load_bloom(tree_name, tree, settings));
+    }
+
+    // Only a filter positive pays for the exact-match confirmation lookup
+    if let (Some(tree), Some(filter)) = (&cache.tree, &cache.bloom) {
+        Metrics::incr(&state.metrics.bloom_checks);
+        if filter.contains(&prepared.point.embedding) {
+            Metrics::incr(&state.metrics.bloom_positives);
+            if tree.find_exact(&prepared.point.embedding).is_some() {
+                Metrics::incr(&state.metrics.duplicates_skipped);
+                return Err(InsertOutcome::Duplicate);
+            }
+            Metrics::incr(&state.metrics.bloom_false_positives);
+        }
+    }
+    Ok(prepared)
+}
+
+// Inserts a prepared point into its tree, which `prepare_insert` left resident
+fn apply_insert(
+    state: &APPState,
+    cache: &mut KDTreeCache,
+    tree_name: &str,
+    prepared: PreparedInsert,
+    footprint: &mut Option<usize>,
+) -> InsertOutcome {
+    let created = prepared.created;
+    if state.store.collection(tree_name).apply_insert(cache, prepared, footprint) {
+        Metrics::incr(&state.metrics.partial_rebuilds);
+    }
+    if let Some(query_cache) = &state.query_cache {
+        query_cache.invalidate(tree_name);
+    }
+    InsertOutcome::Inserted(Durability::None, created)
+}
+
+// The status and message to answer a refusal of a tree's collection with, counted in
+// the metric of its kind
+fn collection_error(state: &APPState, tree_name: &str, error: CollectionError) -> (StatusCode, String) {
+    match error {
+        CollectionError::Invalid(message) => (StatusCode::BAD_REQUEST, message),
+        CollectionError::NotFound(message) => (StatusCode::NOT_FOUND, message),
+        CollectionError::Archived(message) => (StatusCode::SERVICE_UNAVAILABLE, message),
+        CollectionError::Busy(conflict) => (StatusCode::CONFLICT, conflict.to_string()),
+        CollectionError::ModelMismatch(message) => {
+            Metrics::incr(&state.metrics.model_mismatches);
+            (StatusCode::CONFLICT, message)
+        }
+        CollectionError::OverBudget(message) => {
+            Metrics::incr(&state.metrics.memory_rejections);
+            (StatusCode::INSUFFICIENT_STORAGE, message)
+        }
+        CollectionError::Load(e) => tree_error(state, tree_name, e).into_parts(),
+    }
+}
+
+// Validates the point and hands it to the tree's writer, answering once the writer
+// has applied it at the requested durability
+async fn insert_point(
+    request: HttpRequest,
+    data: web::Json<PointInput>,
+    query: Valid<InsertParams>,
+    state: web::Data<APPState>
+) -> impl Responder {
+    server_timing::enable();
+    if let Err(exhausted) = charge_budget(&state, &request, CostLimiter::batch_cost(1)) {
+        return budget_exhausted(exhausted);
+    }
+    let data = match data.into_inner().into_point() {
+        Ok(point) => point,
+        Err(message) => return HttpResponse::BadRequest().body(message),
+    };
+    if data.embedding.is_empty() {
+        return HttpResponse::BadRequest().body("Embedding must not be empty");
+    }
+    // The payload is kept in memory and in every snapshot of the tree
+    if let Some(size) = data.data.as_ref().map(String::len).filter(|size| *size > state.settings.max_data_bytes) {
+        return HttpResponse::BadRequest().body(format!(
+            "Point data is {} bytes but MAX_DATA_BYTES is {}",
+            size, state.settings.max_data_bytes
+        ));
+    }
+    if let Err(response) = ensure_hot(&state, &query.tree_name).await {
+        return response;
+    }
+    if let Some(response) = degraded_rejection(&state, &query.tree_name) {
+        return response;
+    }
+    // The tree file is rewritten whole, next to the old one
+    if let Some(response) = disk_rejection(&state, state.store.file_size(&query.tree_name)) {
+        return response;
+    }
+
+    let (respond, outcome) = oneshot::channel();
+    let enqueued_at = Instant::now();
+    let queued = QueuedInsert {
+        point: data,
+        durability: query.durability.unwrap_or(state.default_durability),
+        force: query.force.unwrap_or(false),
+        model: query.model.clone(),
+        strict_model: query.strict_model.unwrap_or(true),
+        enqueued_at,
+        respond,
+    };
+    let queue = writer_for(&state, &query.tree_name);
+    state.metrics.write_queue_depth.fetch_add(1, Ordering::Relaxed);
+    if queue.send(queued).await.is_err() {
+        state.metrics.write_queue_depth.fetch_sub(1, Ordering::Relaxed);
+        return HttpResponse::ServiceUnavailable().body("Server is shutting down");
+    }
+    match outcome.await {
+        Ok((outcome, timings)) => {
+            timings.record(enqueued_at);
+            // A strict mismatch was refused by the writer; a lenient one went ahead
+            let mismatch = query.model.as_deref().and_then(|requested| {
+                let trees = state.store.trees.lock().unwrap();
+                let tree_model = trees.get(&query.tree_name).and_then(|cache| cache.model.as_deref());
+                model_mismatch(&query.tree_name, tree_model, Some(requested))
+            });
+            if mismatch.is_some() && matches!(outcome, InsertOutcome::Inserted(..) | InsertOutcome::Unsaved(..)) {
+                Metrics::incr(&state.metrics.model_mismatches);
+            }
+            outcome.into_response(mismatch)
+        }
+        Err(_) => HttpResponse::InternalServerError().body("Tree writer stopped before applying the insert"),
+    }
+}
+
+// Inserts one point into each of several trees, all or nothing. Every entry is checked
+// (and its tree loaded) before any tree changes, then the points are applied and the
+// trees saved without releasing the trees lock, which also orders this with the tree
+// writers. If a save fails, every tree that took its point is put back and saved again.
+async fn insert_multi(
+    request: HttpRequest,
+    body: web::Json<Vec<MultiInsertEntry>>,
+    state: web::Data<APPState>,
+) -> Result<HttpResponse, ApiError> {
+    server_timing::enable();
+    let started = Instant::now();
+    if let Err(exhausted) = charge_budget(&state, &request, CostLimiter::batch_cost(body.len())) {
+        return Ok(budget_exhausted(exhausted));
+    }
+    let references: Vec<_> = body.iter().map(|entry| entry.reference.clone()).collect();
+    let entries = validate_multi_insert(body.into_inner(), state.settings.max_data_bytes).map_err(ApiError::Validation)?;
+    for (tree_name, _) in &entries {
+        if let Err(response) = ensure_hot(&state, tree_name).await {
+            return Ok(response);
+        }
+        if let Some(response) = degraded_rejection(&state, tree_name) {
+            return Ok(response);
+        }
+    }
+    let needed = entries.iter().map(|(tree_name, _)| state.store.file_size(tree_name)).sum();
+    if let Some(response) = disk_rejection(&state, needed) {
+        return Ok(response);
+    }
+
+    let mut outcomes: Vec<MultiInsertOutcome> = entries.iter().zip(references)
+        .map(|((tree_name, _), reference)| MultiInsertOutcome {
+            tree_name: tree_name.clone(),
+            status: MultiInsertStatus::NotApplied,
+            implicitly_created: false,
+            error: None,
+            reference,
+        })
+        .collect();
+    let mut trees = server_timing::time("lock", || state.store.trees.lock().unwrap());
+    let applying = Instant::now();
+
+    // Check everything first. Trees created along the way are forgotten again if a later
+    // entry fails, so a rejected request leaves no empty trees behind.
+    let mut prepared = Vec::new(); // (entry, whether the tree was registered before, insert)
+    for (index, (tree_name, point)) in entries.into_iter().enumerate() {
+        let registered = trees.contains_key(&tree_name);
+        let cache = trees.entry(tree_name.clone()).or_default();
+        match prepare_insert(&state, cache, &tree_name, point, InsertChecks::default(), &mut None) {
+            Ok(insert) => {
+                outcomes[index].implicitly_created = insert.created;
+                prepared.push((index, registered, Some(insert)));
+            }
+            Err(InsertOutcome::Duplicate) => {
+                outcomes[index].status = MultiInsertStatus::Duplicate;
+                prepared.push((index, registered, None));
+            }
+            Err(outcome) => {
+                let (status, error) = match outcome {
+                    InsertOutcome::Conflict(body) => (StatusCode::CONFLICT, body.to_string()),
+                    InsertOutcome::Failed(status, body) => (status, body),
+                    InsertOutcome::Inserted(..) | InsertOutcome::Unsaved(..) | InsertOutcome::Duplicate => unreachable!(),
+                };
+                outcomes[index].status = MultiInsertStatus::Failed;
+                outcomes[index].error = Some(error);
+                if !registered {
+                    trees.remove(&outcomes[index].tree_name);
+                }
+                for (index, registered, insert) in prepared {
+                    if insert.is_some_and(|insert| insert.created) {
+                        forget_created(&mut trees, &outcomes[index].tree_name, registered);
+                    }
+                    outcomes[index].implicitly_created = false;
+                }
+                state.store.manage_memory(&mut trees);
+                return Ok(HttpResponse::build(status).json(MultiInsertResponse::new(false, outcomes, started)));
+            }
+        }
+    }
+
+    // Apply, remembering each point's sequence number so it can be taken out again
+    let mut applied = Vec::new(); // (entry, whether the tree was registered before, seq, created, content size)
+    for (index, registered, insert) in prepared {
+        let Some(insert) = insert else { continue };
+        let tree_name = &outcomes[index].tree_name;
+        let cache = trees.get_mut(tree_name).unwrap();
+        let seq = cache.tree.as_ref().unwrap().next_seq();
+        let (created, size) = (insert.created, insert.point.content_size());
+        apply_insert(&state, cache, tree_name, insert, &mut None);
+        outcomes[index].status = MultiInsertStatus::Inserted;
+        applied.push((index, registered, seq, created, size));
+    }
+    server_timing::record("apply", applying.elapsed());
+
+    let writing = Instant::now();
+    let mut failed = None;
+    for (position, (index, ..)) in applied.iter().enumerate() {
+        let tree_name = &outcomes[*index].tree_name;
+        if let Err(e) = trees.get_mut(tree_name).unwrap().save_now(state.store.disk(), tree_name) {
+            failed = Some((position, format!("Failed to save KD-Tree: {}", e)));
+            break;
+        }
+    }
+    server_timing::record("write", writing.elapsed());
+
+    if let Some((failed_at, error)) = failed {
+        for (position, (index, registered, seq, created, _)) in applied.into_iter().enumerate() {
+            let tree_name = outcomes[index].tree_name.clone();
+            let saved = position < failed_at;
+            if let Err(e) = roll_back_insert(&state, &mut trees, &tree_name, registered, seq, created, saved) {
+                println!("Failed to roll back insert_multi on tree {}: {}", tree_name, e);
+            }
+            outcomes[index].status = MultiInsertStatus::RolledBack;
+            outcomes[index].implicitly_created = false;
+            if position == failed_at {
+                outcomes[index].status = MultiInsertStatus::Failed;
+                outcomes[index].error = Some(error.clone());
+            }
+        }
+        state.store.manage_memory(&mut trees);
+        return Ok(HttpResponse::InternalServerError().json(MultiInsertResponse::new(false, outcomes, started)));
+    }
+
+    let mut created = false;
+    for (index, _, _, implicitly_created, size) in &applied {
+        let tree_name = &outcomes[*index].tree_name;
+        if let Some(filter) = &trees[tree_name].bloom {
+            // A stale filter is rebuilt on the next load, so this is not fatal
+            if let Err(e) = state.store.save_bloom(tree_name, filter) {
+                println!("Failed to save duplicate filter for tree {}: {}", tree_name, e);
+            }
+        }
+        state.store.usage.tree(tree_name).record_inserts(1, *size);
+        created |= *implicitly_created;
+    }
+    if created {
+        if let Err(e) = state.store.save_manifest(&trees) {
+            println!("Failed to save manifest: {}", e);
+        }
+    }
+    state.store.manage_memory(&mut trees);
+    Ok(HttpResponse::Ok().json(MultiInsertResponse::new(true, outcomes, started)))
+}
+
+// Undoes the creation of a tree by /insert_multi: a tree that was not registered before
+// is dropped, one that was only a placeholder goes back to having no dimension
+fn forget_created(trees: &mut HashMap<String, KDTreeCache>, tree_name: &str, registered: bool) {
+    if !registered {
+        trees.remove(tree_name);
+    } else if let Some(cache) = trees.get_mut(tree_name) {
+        cache.take_tree();
+        cache.dimensions = None;
+        cache.model = None;
+        cache.num_records = 0;
+    }
+}
+
+// Takes the point /insert_multi inserted as `seq` back out of its tree, saving the tree
+// again if it was already `saved` with the point. The tree is rebuilt without it; the
+// duplicate filter keeps the point, which only costs a false positive later.
+fn roll_back_insert(
+    state: &APPState,
+    trees: &mut HashMap<String, KDTreeCache>,
+    tree_name: &str,
+    registered: bool,
+    seq: u64,
+    created: bool,
+    saved: bool,
+) -> Result<(), KdTreeError> {
+    if created {
+        if let (true, Some(bin_directory)) = (saved, state.store.disk()) {
+            fs::remove_file(get_bin_file_path(bin_directory, tree_name))?;
+        }
+        forget_created(trees, tree_name, registered);
+        return Ok(());
+    }
+    let cache = trees.get_mut(tree_name).unwrap();
+    let Some(tree) = cache.take_tree() else { return Ok(()) };
+    let (tree, _) = Arc::unwrap_or_clone(tree).without(|point| point.seq == seq)?;
+    cache.set_tree(tree);
+    if let Some(query_cache) = &state.query_cache {
+        query_cache.invalidate(tree_name);
+    }
+    if saved {
+        if let Err(e) = cache.save_now(state.store.disk(), tree_name) {
+            // The flush retries, until then the file still holds the point
+            cache.dirty = true;
+            return Err(e.into());
+        }
+    }
+    Ok(())
+}
+
+async fn nearest_neighbor_top_n(
+    request: HttpRequest,
+    body: web::Json<SearchBody>,
+    query: Valid<SearchParams>,
+    state: web::Data<APPState>
+) -> impl Responder {
+    let mut timings = SearchTimings::start();
+    let charge = match charge_search(&state, &request, &query) {
+        Ok(charge) => charge,
+        Err(exhausted) => return budget_exhausted(exhausted),
+    };
+    let data = match body.query_point() {
+        Ok(point) => point,
+        Err(message) => return HttpResponse::BadRequest().body(message),
+    };
+
+    // Callers that prefer a fast failure over a disk load bail out here, before the query
+    // cache, so whether they fail does not depend on what happens to be cached
+    let strategy = offloaded_strategy(&state, &query);
+    if strategy == OffloadedStrategy::Reject && is_offloaded(&state, &state.store.trees.lock().unwrap(), &query.tree_name) {
+        return offloaded_rejection(&state);
+    }
+
+    // Explained searches and histograms always traverse, and are never cached
+    let traversed = query.explain.unwrap_or(false) || query.histogram.unwrap_or(false);
+    let use_query_cache = query.cache.unwrap_or(true) && !traversed;
+    if let (true, Some(query_cache)) = (use_query_cache, &state.query_cache) {
+        if let Some(cached) = query_cache.get(&data.embedding, &query) {
+            Metrics::incr(&state.metrics.query_cache_hits);
+            // A cached answer is still an access, which keeps the tree from looking cold
+            if let Some(cache) = state.store.trees.lock().unwrap().get_mut(&query.tree_name) {
+                cache.touch();
+            }
+            settle_search(&state, charge.as_ref(), &query, 0);
+            return serve_search(&state, &query.tree_name, &mut HttpResponse::Ok(), &cached, &timings);
+        }
+        Metrics::incr(&state.metrics.query_cache_misses);
+    }
+
+    // An archived tree is not in memory either, so rejecting failed it above
+    if strategy != OffloadedStrategy::Reject {
+        if let Err(response) = ensure_hot(&state, &query.tree_name).await {
+            return response;
+        }
+    }
+
+    let tree_name = &query.tree_name;
+    let resident = state.store.trees.lock().unwrap().get(tree_name).is_some_and(|cache| cache.tree.is_some());
+
+    if strategy == OffloadedStrategy::Scan && !resident && state.store.disk().is_some() {
+        return scan_search(&state, &data, &query, charge.as_ref(), timings).await;
+    }
+
+    // One-off searches of an offloaded tree load a private copy off the lock and drop it
+    // afterwards, so they neither evict hot trees nor count as an access
+    if query.cache == Some(false) && strategy == OffloadedStrategy::Load && !resident {
+        return ephemeral_search(&state, &body, &data, &query, charge.as_ref(), timings).await;
+    }
+
+    let mut trees = timings.time(Phase::LockWait, || state.store.trees.lock().unwrap());
+
+    // Checked again under the lock, the tree may have been evicted since
+    if strategy == OffloadedStrategy::Reject && is_offloaded(&state, &trees, tree_name) {
+        return offloaded_rejection(&state);
+    }
+
+    let loading = Instant::now();
+    if let Err((status, body)) = load_into_cache(&state, &mut trees, tree_name) {
+        return HttpResponse::build(status).body(body);
+    }
+    if !resident {
+        timings.set(Phase::DiskLoad, loading.elapsed());
+    }
+    if query.cache != Some(false) {
+        trees.get_mut(tree_name).unwrap().touch();
+    }
+
+    let cache = &trees[tree_name];
+    let tree = cache.tree.as_ref().unwrap();
+    let mismatch = model_mismatch(tree_name, tree.model(), query.model.as_deref());
+    if let Some(response) = model_rejection(&state, mismatch.as_ref(), query.strict_model) {
+        return response;
+    }
+    if let Some(response) = response_size_rejection(&state, &query, tree.dimensions()) {
+        return response;
+    }
+    let results = match timings.time(Phase::Traversal, || search_tree(&state.store, tree, &data, &query)) {
+        Ok(results) => results,
+        Err((status, body)) => return HttpResponse::build(status).body(body),
+    };
+    settle_search(&state, charge.as_ref(), &query, results.visited);
+    let collection = collection_info(&state, tree_name, tree.input_dimensions(), tree.model(), cache.num_records);
+    // Read under the lock, so an insert that lands while the answer is built outside it
+    // keeps the answer from ever being served from the cache
+    let generation = state.query_cache.as_ref().map(|query_cache| query_cache.generation(tree_name));
+    drop(trees);
+    let projection = query.projection(state.settings.float_precision);
+    let mut response = server_timing::time("serialize", || results.render(&projection));
+    explain_combination(&mut response, &body, &data, &query);
+    attach_collection(&mut response, &projection, collection);
+    attach_model_warning(&mut response, mismatch);
+    if let (true, Some(query_cache), Some(generation)) = (use_query_cache, &state.query_cache, generation) {
+        query_cache.put(&data.embedding, &query, generation, response.clone());
+    }
+    serve_search(&state, tree_name, &mut HttpResponse::Ok(), &response, &timings)
+}
+
+// Answers a search from a copy of the tree read straight from disk, which is dropped
+// once the answer is built. The cache entry and memory accounting are left untouched,
+// so a concurrent regular request for the same tree loads it into the cache as usual.
+// Legacy files are converted in memory but never rewritten from here.
+async fn ephemeral_search(
+    state: &web::Data<APPState>,
+    body: &SearchBody,
+    data: &Point,
+    query: &SearchParams,
+    charge: Option<&Charge>,
+    mut timings: SearchTimings,
+) -> HttpResponse {
+    let started = Instant::now();
+    let tree = match load_ephemeral(state, &query.tree_name).await {
+        Ok(tree) => tree,
+        Err((status, body)) => return HttpResponse::build(status).body(body),
+    };
+    timings.set(Phase::DiskLoad, started.elapsed());
+    let load_ms = started.elapsed().as_millis();
+    let mismatch = model_mismatch(&query.tree_name, tree.model(), query.model.as_deref());
+    if let Some(response) = model_rejection(state, mismatch.as_ref(), query.strict_model) {
+        return response;
+    }
+    if let Some(response) = response_size_rejection(state, query, tree.dimensions()) {
+        return response;
+    }
+
+    let projection = query.projection(state.settings.float_precision);
+    let mut response = match timings.time(Phase::Traversal, || search_tree(&state.store, &tree, data, query)) {
+        Ok(results) => {
+            settle_search(state, charge, query, results.visited);
+            server_timing::time("serialize", || results.render(&projection))
+        }
+        Err((status, body)) => return HttpResponse::build(status).body(body),
+    };
+    explain_combination(&mut response, body, data, query);
+    let collection = collection_info(state, &query.tree_name, tree.input_dimensions(), tree.model(), tree.len());
+    attach_collection(&mut response, &projection, collection);
+    attach_model_warning(&mut response, mismatch);
+    response["ephemeral_load_ms"] = json!(load_ms);
+    let mut builder = HttpResponse::Ok();
+    builder.insert_header(("X-Ephemeral-Load-Ms", load_ms.to_string()));
+    serve_search(state, &query.tree_name, &mut builder, &response, &timings)
+}
+
+// Whether the tree exists but is not in memory. A tree that does not exist at all is
+// left to the load, which answers 404.
+fn is_offloaded(state: &APPState, trees: &HashMap<String, KDTreeCache>, tree_name: &str) -> bool {
+    match trees.get(tree_name) {
+        Some(cache) => cache.tree.is_none(),
+        None => state.store.has_file(tree_name),
+    }
+}
+
+fn offloaded_rejection(state: &APPState) -> HttpResponse {
+    Metrics::incr(&state.metrics.offloaded_rejections);
+    HttpResponse::Conflict().json("tree_offloaded")
+}
+
+// How a search treats its tree if it turns out to be offloaded: as the request asks,
+// else by the tree's default, which only applies scans to searches a scan can answer
+fn offloaded_strategy(state: &APPState, query: &SearchParams) -> OffloadedStrategy {
+    if query.if_in_memory == Some(true) {
+        return OffloadedStrategy::Reject;
+    }
+    if let Some(strategy) = query.offloaded_strategy {
+        return strategy;
+    }
+    let default = state.store.trees.lock().unwrap().get(&query.tree_name).and_then(|cache| cache.offloaded_strategy);
+    match default {
+        Some(OffloadedStrategy::Scan) if !query.scannable() => OffloadedStrategy::Load,
+        default => default.unwrap_or_default(),
+    }
+}
+
+// Answers a search of an offloaded tree by streaming its file, see `scan`. The tree is
+// neither loaded nor touched, so hot trees stay in memory. What is known about the tree
+// without loading it is checked before the file is read, the rest once it has been.
+async fn scan_search(
+    state: &web::Data<APPState>,
+    data: &Point,
+    query: &SearchParams,
+    charge: Option<&Charge>,
+    mut timings: SearchTimings,
+) -> HttpResponse {
+    let tree_name = &query.tree_name;
+    let (dimensions, model) = match state.store.trees.lock().unwrap().get(tree_name) {
+        Some(cache) => (cache.dimensions, cache.model.clone()),
+        None => (None, None),
+    };
+    if let Some(dimensions) = dimensions.filter(|dimensions| *dimensions != data.embedding.len()) {
+        return HttpResponse::BadRequest().body(format!(
+            "Query has {} dimensions but tree {} has {}",
+            data.embedding.len(), tree_name, dimensions
+        ));
+    }
+    let mismatch = model_mismatch(tree_name, model.as_deref(), query.model.as_deref());
+    if let Some(response) = model_rejection(state, mismatch.as_ref(), query.strict_model) {
+        return response;
+    }
+    if let Some(response) = response_size_rejection(state, query, data.embedding.len()) {
+        return response;
+    }
+
+    let started = Instant::now();
+    let path = get_bin_file_path(&state.store.bin_directory, tree_name);
+    let target = data.embedding.clone();
+    let offset = query.offset.unwrap_or(0);
+    let n = query.n.unwrap_or(0) + offset;
+    let (partition, filter) = (query.partition.clone(), query.filter());
+    let scanned = web::block(move || {
+        let predicate = |point: &Point| filter.as_ref().is_none_or(|condition| condition.matches(&point.metadata));
+        scan_file(&path, &ScanQuery { target: &target, n, partition: partition.as_deref(), predicate: &predicate })
+    }).await;
+    let scan = match scanned {
+        Ok(Ok(scan)) => scan,
+        Ok(Err(ScanError::Unsupported(problem))) => {
+            return HttpResponse::Conflict().body(format!("Tree {} can't be scanned: {}", tree_name, problem));
+        }
+        Ok(Err(ScanError::Tree(e))) => {
+            let (status, body) = tree_error(state, tree_name, e).into_parts();
+            return HttpResponse::build(status).body(body);
+        }
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error scanning tree: {}", e)),
+    };
+    timings.set(Phase::Traversal, started.elapsed());
+    let scan_ms = started.elapsed().as_millis();
+    Metrics::incr(&state.metrics.scan_searches);
+    state.metrics.scanned_points.fetch_add(scan.scanned as u64, Ordering::Relaxed);
+    settle_search(state, charge, query, scan.scanned);
+    if query.partition.is_some() && scan.partition_field.is_none() {
+        return HttpResponse::BadRequest().body(format!("Tree {} is not partitioned", tree_name));
+    }
+    if let Err((status, body)) = check_filter_schema(scan.schema.as_ref(), tree_name, query.filter().as_ref()) {
+        return HttpResponse::build(status).body(body);
+    }
+
+    let projection = query.projection(state.settings.float_precision);
+    let answer = SearchAnswer {
+        results: SearchResults::Nearest(page(scan.hits, offset)),
+        histogram: None,
+        tree_size: scan.scanned,
+        visited: scan.scanned,
+    };
+    let mut response = server_timing::time("serialize", || answer.render(&projection));
+    let collection = collection_info(state, tree_name, data.embedding.len(), scan.model.as_deref(), scan.scanned);
+    attach_collection(&mut response, &projection, collection);
+    attach_model_warning(&mut response, mismatch);
+    response["scan_ms"] = json!(scan_ms);
+    response["scanned_points"] = json!(scan.scanned);
+    let mut builder = HttpResponse::Ok();
+    builder.insert_header(("X-Scan-Ms", scan_ms.to_string()));
+    builder.insert_header(("X-Scanned-Points", scan.scanned.to_string()));
+    serve_search(state, tree_name, &mut builder, &response, &timings)
+}
+
+// Reads a private copy of an offloaded tree. Concurrent searches of the same tree share
+// one read: the first starts it and the rest wait for it, up to LOAD_WAIT_TIMEOUT_SECS,
+// and answer 503 if it fails. The read runs in its own task, so it completes and is
+// cleared even if the search that started it goes away.
+async fn load_ephemeral(state: &web::Data<APPState>, tree_name: &str) -> Result<Arc<KDTree>, (StatusCode, String)> {
+    let (load, joined) = {
+        let mut loads = state.ephemeral_loads.lock().unwrap();
+        match loads.get(tree_name) {
+            Some(load) => (load.clone(), true),
+            None => {
+                let load = spawn_ephemeral_load(state.clone(), tree_name.to_string());
+                loads.insert(tree_name.to_string(), load.clone());
+                (load, false)
+            }
+        }
+    };
+    if !joined {
+        Metrics::incr(&state.metrics.ephemeral_loads);
+        return load.await;
+    }
+
+    Metrics::incr(&state.metrics.deduplicated_loads);
+    let wait = Duration::from_secs(state.settings.load_wait_timeout_secs);
+    match actix_web::rt::time::timeout(wait, load).await {
+        Ok(Ok(tree)) => Ok(tree),
+        // A tree that doesn't exist is no failed load
+        Ok(Err((StatusCode::NOT_FOUND, body))) => Err((StatusCode::NOT_FOUND, body)),
+        Ok(Err((_, body))) => Err((StatusCode::SERVICE_UNAVAILABLE, format!(
+            "Tree {} failed to load for a concurrent search: {}", tree_name, body
+        ))),
+        Err(_) => Err((StatusCode::SERVICE_UNAVAILABLE, format!(
+            "Timed out after {}s waiting for a concurrent search to load tree {}", wait.as_secs(), tree_name
+        ))),
+    }
+}
+
+fn spawn_ephemeral_load(state: web::Data<APPState>, tree_name: String) -> SharedLoad {
+    let loading = actix_web::rt::spawn(async move {
+        let (reading, name) = (state.clone(), tree_name.clone());
+        let backoff = state.store.trees.lock().unwrap().get(&tree_name).and_then(KDTreeCache::load_backoff);
+        let read = match backoff {
+            Some(e) => Ok(Err(e)),
+            None => web::block(move || {
+                let read = reading.store.read_tree(&name, false);
+                record_load(&reading, &name, &read);
+                read
+            }).await,
+        };
+        let loaded = match read {
+            Ok(Ok(tree)) => Ok(Arc::new(tree)),
+            Ok(Err(e)) => Err(tree_error(&state, &tree_name, e).into_parts()),
+            Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Error loading tree: {}", e))),
+        };
+        state.ephemeral_loads.lock().unwrap().remove(&tree_name);
+        loaded
+    });
+    loading
+        .map(|loaded| loaded.unwrap_or_else(|e| Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Error loading tree: {}", e)))))
+        .boxed()
+        .shared()
+}
+
+// Refuses a search whose answer is estimated above MAX_RESPONSE_MB before the tree is
+// searched, so an oversized answer is never built. Every estimate goes to the metrics.
+fn response_size_rejection(state: &APPState, query: &SearchParams, dimensions: usize) -> Option<HttpResponse> {
+    let per_group = query.group_by.as_ref().map_or(1, |_| query.group_size.unwrap_or(1));
+    let results = query.n.unwrap_or(0).saturating_mul(per_group);
+    let estimate = query.projection(state.settings.float_precision).estimated_bytes(results, dimensions);
+    Metrics::incr(&state.metrics.response_estimates);
+    state.metrics.response_estimated_bytes.fetch_add(estimate, Ordering::Relaxed);
+    let limit = state.settings.max_response_mb.saturating_mul(1024 * 1024);
+    if limit == 0 || estimate <= limit {
+        return None;
+    }
+    Metrics::incr(&state.metrics.response_size_rejections);
+    Some(HttpResponse::PayloadTooLarge().body(format!(
+        "The response is estimated at {} MB, above the MAX_RESPONSE_MB limit of {} MB. Leave out embeddings with \
+         `fields`, round them with `float_precision`, or fetch fewer results at a time with `n` and `offset`",
+        estimate.div_ceil(1024 * 1024), state.settings.max_response_mb
+    )))
+}
+
+// Summary of the searched tree from what is already counted, so including it in every
+// search response costs nothing
+fn collection_info(state: &APPState, tree_name: &str, dimensions: usize, model: Option<&str>, num_records: usize) -> CollectionInfo {
+    CollectionInfo {
+        dimensions,
+        num_records,
+        metric: SEARCH_METRIC.to_string(),
+        last_write_at: state.store.usage.tree(tree_name).last_write_at(),
+        model: model.map(str::to_string),
+    }
+}
+
+// Counts a mismatch and refuses it with 409 unless the request passed strict_model=false
+fn model_rejection(state: &APPState, mismatch: Option<&String>, strict_model: Option<bool>) -> Option<HttpResponse> {
+    let mismatch = mismatch?;
+    Metrics::incr(&state.metrics.model_mismatches);
+    strict_model.unwrap_or(true).then(|| HttpResponse::Conflict().body(mismatch.clone()))
+}
+
+// Adds the mismatch a lenient request went ahead with
+fn attach_model_warning(response: &mut serde_json::Value, mismatch: Option<String>) {
+    if let Some(mismatch) = mismatch {
+        response["model_warning"] = json!(mismatch);
+    }
+}
+
+// Adds the tree summary unless `fields` leaves it out. Cached answers keep it: any write
+// to the tree drops them.
+fn attach_collection(response: &mut serde_json::Value, projection: &Projection, collection: CollectionInfo) {
+    if projection.includes_collection() {
+        response["collection"] = json!(collection);
+    }
+}
+
+// Adds the vector an explained multi-point query actually searched with
+fn explain_combination(response: &mut serde_json::Value, body: &SearchBody, data: &Point, query: &SearchParams) {
+    if query.explain == Some(true) && body.is_combined() {
+        response["query"] = json!({
+            "combined": data.embedding,
+            "combine": body.combine.unwrap_or_default(),
+            "points": body.points.as_ref().map_or(0, Vec::len),
+        });
+    }
+}
+
+// Serializes a search answer and records it, with its size and latency, in the tree's
+// usage counters
+fn serve_search(
+    state: &APPState,
+    tree_name: &str,
+    builder: &mut HttpResponseBuilder,
+    response: &impl Serialize,
+    timings: &SearchTimings,
+) -> HttpResponse {
+    match server_timing::time("serialize", || serde_json::to_vec(response)) {
+        Ok(body) => {
+            let usage = state.store.usage.tree(tree_name);
+            usage.record_search(body.len());
+            usage.latency.record(timings);
+            builder.content_type(ContentType::json()).body(body)
+        }
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error serializing response: {}", e)),
+    }
+}
+
+// A search answer and, when asked for, the histogram of its candidate distances
+struct SearchAnswer {
+    results: SearchResults,
+    histogram: Option<Histogram>,
+    tree_size: usize, // Points in the tree searched, reported when nothing was found
+    visited: usize,   // Nodes the traversal visited, fed back to the cost limiter
+}
+
+impl SearchAnswer {
+    // An empty answer carries the tree size, which tells an empty tree from one whose
+    // points were all filtered out
+    fn render(&self, projection: &Projection) -> serde_json::Value {
+        let mut response = self.results.render(projection);
+        if let Some(histogram) = &self.histogram {
+            response["histogram"] = json!(histogram);
+        }
+        if self.results.is_empty() {
+            response["tree_size"] = json!(self.tree_size);
+        }
+        response
+    }
+}
+
+// Search results copied out of the tree, so the trees lock can be released before they
+// are projected and serialized
+enum SearchResults {
+    Nearest(Vec<(f64, Point)>),
+    Explained(Vec<(f64, Point)>, Trace),
+    Grouped(Vec<OwnedGroupHits>),
+    Diversified { hits: Vec<(f64, f64, Point)>, diversity: f64, candidates: usize },
+}
+
+impl SearchResults {
+    fn is_empty(&self) -> bool {
+        match self {
+            SearchResults::Nearest(hits) | SearchResults::Explained(hits, _) => hits.is_empty(),
+            SearchResults::Grouped(groups) => groups.is_empty(),
+            SearchResults::Diversified { hits, .. } => hits.is_empty(),
+        }
+    }
+
+    // Always an object with the hits under `results`, so extras like the collection
+    // summary are added as keys and never change the shape of the answer
+    fn render(&self, projection: &Projection) -> serde_json::Value {
+        match self {
+            SearchResults::Nearest(hits) => json!({ "results": projection.project_all(borrowed(hits)) }),
+            SearchResults::Explained(hits, trace) => json!({
+                "results": projection.project_all(borrowed(hits)),
+                "trace": trace,
+            }),
+            SearchResults::Grouped(groups) => json!({
+                "results": projection.project_groups(groups.iter().map(|(group, hits)| (group.as_ref(), borrowed(hits)))),
+            }),
+            SearchResults::Diversified { hits, diversity, candidates } => {
+                let results: Vec<_> = hits.iter().map(|(distance, score, point)| {
+                    let mut hit = projection.project(point, Some(*distance));
+                    hit["distance"] = json!(distance);
+                    hit["mmr_score"] = json!(score);
+                    hit
+                }).collect();
+                json!({
+                    "results": results,
+                    "mmr": { "diversity": diversity, "candidates": candidates },
+                })
+            }
+        }
+    }
+}
+
+fn borrowed(hits: &[(f64, Point)]) -> Vec<(f64, &Point)> {
+    hits.iter().map(|(distance, point)| (*distance, point)).collect()
+}
+
+// Runs the search described by `query` against one tree. Only the winning points are
+// cloned; rendering them is left to the caller.
+fn search_tree(store: &Store, tree: &KDTree, data: &Point, query: &SearchParams) -> Result<SearchAnswer, (StatusCode, String)> {
+    let tree_name = &query.tree_name;
+    let Some(n) = query.n else {
+        return Err((StatusCode::BAD_REQUEST, "n is required".to_string()));
+    };
+    let reduced = store.collection(tree_name).query(tree, data).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let data = reduced.as_ref();
+    if query.partition.is_some() && tree.partition_field().is_none() {
+        return Err((StatusCode::BAD_REQUEST, format!("Tree {} is not partitioned", tree_name)));
+    }
+    let partition = query.partition.as_deref();
+    let filter = query.filter();
+    check_filter_schema(tree.schema(), tree_name, filter.as_ref())?;
+    let predicate = |point: &Point| filter.as_ref().is_none_or(|condition| condition.matches(&point.metadata));
+    let mut histogram = query.histogram();
+    // A page further down is the tail of a longer top list; only the page is cloned
+    let offset = query.offset.unwrap_or(0);
+    let k = n + offset;
+    let (results, visited) = if let Some(field) = &query.group_by {
+        let (groups, visited) = tree.nearest_groups_topn(data, k, query.group_size.unwrap_or(1), field, partition, &predicate);
+        let groups = page(groups, offset).into_iter().map(|(group, hits)| (group.cloned(), owned_hits(hits))).collect();
+        (SearchResults::Grouped(groups), visited)
+    } else if query.explain.unwrap_or(false) {
+        let mut trace = Trace::default();
+        let (nearest_neighbors, visited) =
+            tree.nearest_neighbors_topn_traced(data, k, partition, &predicate, Some(&mut trace), histogram.as_mut());
+        (SearchResults::Explained(owned_hits(page(nearest_neighbors, offset)), trace), visited)
+    } else if let Some(diversity) = query.diversity {
+        // Over-fetch so there is something to diversify with
+        let fetched = k.saturating_mul(mmr::OVERFETCH).min(MAX_N);
+        let (candidates, visited) = tree.nearest_neighbors_topn_traced(data, fetched, partition, &predicate, None, histogram.as_mut());
+        let hits = page(mmr::rerank(&Euclidean, candidates, k, diversity), offset)
+            .into_iter()
+            .map(|(distance, score, point)| (distance, score, point.clone()))
+            .collect();
+        (SearchResults::Diversified { hits, diversity, candidates: fetched }, visited)
+    } else {
+        let (nearest_neighbors, visited) = tree.nearest_neighbors_topn_traced(data, k, partition, &predicate, None, histogram.as_mut());
+        (SearchResults::Nearest(owned_hits(page(nearest_neighbors, offset))), visited)
+    };
+    let histogram = histogram.map(DistanceHistogram::finish);
+    Ok(SearchAnswer { results, histogram, tree_size: tree.len(), visited })
+}
+
+// What is left of a ranked list once the first `offset` entries are skipped
+fn page<T>(mut ranked: Vec<T>, offset: usize) -> Vec<T> {
+    ranked.drain(..offset.min(ranked.len()));
+    ranked
+}
+
+// Dedup check: whether any point lies within `distance` of the query. Stops at the first
+// hit, so the answer is some point within the bound rather than the closest one.
+async fn exists_within(
+    request: HttpRequest,
+    data: web::Json<PointInput>,
+    query: Valid<ExistsWithinParams>,
+    state: web::Data<APPState>,
+) -> impl Responder {
+    if let Some(limiter) = &state.cost_limiter {
+        let cost = limiter.radius_cost(&query.tree_name, query.distance.unwrap_or_default());
+        if let Err(exhausted) = charge_budget(&state, &request, cost) {
+            return budget_exhausted(exhausted);
+        }
+    }
+    let data = match data.into_inner().into_point() {
+        Ok(point) => point,
+        Err(message) => return HttpResponse::BadRequest().body(message),
+    };
+    let tree_name = &query.tree_name;
+    let mut timings = SearchTimings::start();
+    if let Err(response) = ensure_hot(&state, tree_name).await {
+        return response;
+    }
+    let mut trees = timings.time(Phase::LockWait, || state.store.trees.lock().unwrap());
+    let resident = trees.get(tree_name).is_some_and(|cache| cache.tree.is_some());
+    let loading = Instant::now();
+    if let Err((status, body)) = load_into_cache(&state, &mut trees, tree_name) {
+        return HttpResponse::build(status).body(body);
+    }
+    if !resident {
+        timings.set(Phase::DiskLoad, loading.elapsed());
+    }
+
+    let cache = trees.get_mut(tree_name).unwrap();
+    cache.touch();
+    let tree = cache.tree.as_ref().unwrap();
+    if data.embedding.len() != tree.input_dimensions() {
+        return HttpResponse::BadRequest().body(format!(
+            "Query has {} dimensions but tree {} has {}",
+            data.embedding.len(), tree_name, tree.input_dimensions()
+        ));
+    }
+    if query.partition.is_some() && tree.partition_field().is_none() {
+        return HttpResponse::BadRequest().body(format!("Tree {} is not partitioned", tree_name));
+    }
+    let target = tree.reduce(Cow::Borrowed(&data));
+    let hit = timings.time(Phase::Traversal, || {
+        tree.first_within(&target, query.distance.unwrap_or_default(), query.partition.as_deref())
+    });
+    let response = ExistsWithinResponse {
+        found: hit.is_some(),
+        distance: hit.map(|(distance, _)| distance),
+        data: hit.and_then(|(_, point)| point.data().map(Cow::into_owned)),
+    };
+
+    state.store.manage_memory(&mut trees);
+    drop(trees);
+    serve_search(&state, tree_name, &mut HttpResponse::Ok(), &response, &timings)
+}
+
+// Exact-match lookup: the stored point with this embedding, found along the insert path
+// instead of by a nearest search
+async fn get_by_embedding(
+    data: web::Json<PointInput>,
+    query: Valid<LookupParams>,
+    state: web::Data<APPState>,
+) -> impl Responder {
+    let data = match data.into_inner().into_point() {
+        Ok(point) => point,
+        Err(message) => return HttpResponse::BadRequest().body(message),
+    };
+    let tree_name = &query.tree_name;
+    let mut timings = SearchTimings::start();
+    if let Err(response) = ensure_hot(&state, tree_name).await {
+        return response;
+    }
+    let mut trees = timings.time(Phase::LockWait, || state.store.trees.lock().unwrap());
+    let resident = trees.get(tree_name).is_some_and(|cache| cache.tree.is_some());
+    let loading = Instant::now();
+    if let Err((status, body)) = load_into_cache(&state, &mut trees, tree_name) {
+        return HttpResponse::build(status).body(body);
+    }
+    if !resident {
+        timings.set(Phase::DiskLoad, loading.elapsed());
+    }
+
+    let cache = trees.get_mut(tree_name).unwrap();
+    cache.touch();
+    let tree = cache.tree.as_ref().unwrap();
+    if data.embedding.len() != tree.input_dimensions() {
+        return HttpResponse::BadRequest().body(format!(
+            "Query has {} dimensions but tree {} has {}",
+            data.embedding.len(), tree_name, tree.input_dimensions()
+        ));
+    }
+    let target = tree.reduce(Cow::Borrowed(&data));
+    let found = timings.time(Phase::Traversal, || tree.find_exact(&target.embedding).cloned());
+
+    state.store.manage_memory(&mut trees);
+    drop(trees);
+    let response = found.map(|point| {
+        let mut found = query.projection(state.settings.float_precision).project(&point, None);
+        found["seq"] = json!(point.seq);
+        found["inserted_at"] = json!(point.inserted_at);
+        found
+    });
+    match response {
+        Some(response) => serve_search(&state, tree_name, &mut HttpResponse::Ok(), &response, &timings),
+        None => {
+            // A miss is still a lookup the tree served
+            let body = format!("No point with this embedding in tree {}", tree_name);
+            let usage = state.store.usage.tree(tree_name);
+            usage.record_search(body.len());
+            usage.latency.record(&timings);
+            HttpResponse::NotFound().body(body)
+        }
+    }
+}
+
+// Structural operation: rebuilds the tree with median splits. Searches keep using the
+// current tree while the balanced copy is built off the lock; inserts get a 409.
+async fn rebuild_tree(query: Valid<RebuildParams>, state: web::Data<APPState>) -> impl Responder {
+    let tree_name = query.tree_name.clone();
+    run_job(&state, JobKind::Rebuild, Some(&tree_name), query.run_async, |job| rebuild(tree_name.clone(), state.clone(), job)).await
+}
+
+async fn rebuild(tree_name: String, state: web::Data<APPState>, job: Arc<Job>) -> HttpResponse {
+    if let Err(response) = ensure_hot(&state, &tree_name).await {
+        return response;
+    }
+    if let Some(response) = disk_rejection(&state, state.store.file_size(&tree_name)) {
+        return response;
+    }
+    let Some(_permit) = state.heavy.acquire().await else {
+        return heavy_rejection();
+    };
+
+    let snapshot = {
+        let mut trees = state.store.trees.lock().unwrap();
+        if let Some(operation) = trees.get(&tree_name).and_then(|cache| cache.operation.as_ref()) {
+            return HttpResponse::Conflict().json(operation.conflict(&tree_name));
+        }
+        if let Err((status, body)) = load_into_cache(&state, &mut trees, &tree_name) {
+            return HttpResponse::build(status).body(body);
+        }
+        let cache = trees.get_mut(&tree_name).unwrap();
+        cache.operation = Some(TreeOperation::start(OperationKind::Rebuilding));
+        // Shared, not copied: the copy is made off the lock and doubles the tree's
+        // footprint until the swap below
+        cache.shared_tree().unwrap()
+    };
+
+    let rebuilt = web::block(move || Arc::unwrap_or_clone(snapshot).rebuilt()).await;
+
+    let mut trees = state.store.trees.lock().unwrap();
+    let cache = trees.entry(tree_name.clone()).or_default();
+    cache.operation = None;
+    if let Some(response) = job_cancelled(&job) {
+        return response;
+    }
+    let tree = match rebuilt {
+        Ok(Ok(tree)) => tree,
+        Ok(Err(e)) => return HttpResponse::InternalServerError().body(format!("Failed to rebuild KD-Tree: {}", e)),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Failed to rebuild KD-Tree: {}", e)),
+    };
+
+    if let Some(settings) = state.bloom {
+        let filter = BloomFilter::from_tree(&tree, settings);
+        if let Err(e) = state.store.save_bloom(&tree_name, &filter) {
+            println!("Failed to save duplicate filter for tree {}: {}", tree_name, e);
+        }
+        cache.bloom = Some(filter);
+    }
+    if let Some(query_cache) = &state.query_cache {
+        query_cache.invalidate(&tree_name);
+    }
+    cache.set_tree(tree);
+    if let Err(e) = cache.save_now(state.store.disk(), &tree_name) {
+        // Same points either way, so keep serving the rebuilt tree and let the flush retry
+        cache.dirty = true;
+        return HttpResponse::InternalServerError().body(format!("Failed to save KD-Tree: {}", e));
+    }
+    let num_records = cache.num_records;
+
+    state.store.manage_memory(&mut trees);
+    HttpResponse::Ok().json(RebuildResponse { tree_name, num_records })
+}
+
+// Structural operation: bulk-loads a Parquet file from IMPORT_DIRECTORY into a new or
+// existing tree and rebuilds it balanced. Searches keep using the current tree while the
+// file is read off the lock; inserts get a 409.
+async fn import_parquet(query: Valid<ImportParquetParams>, state: web::Data<APPState>) -> impl Responder {
+    let tree_name = query.tree_name.clone();
+    let run_async = query.run_async;
+    run_job(&state, JobKind::Import, Some(&tree_name), run_async, |job| import(query, state.clone(), job)).await
+}
+
+async fn import(query: Valid<ImportParquetParams>, state: web::Data<APPState>, job: Arc<Job>) -> HttpResponse {
+    let started = Instant::now();
+    let tree_name = query.tree_name.clone();
+    let path = match resolve_import_path(&state, &query.path) {
+        Ok(path) => path,
+        Err((status, body)) => return HttpResponse::build(status).body(body),
+    };
+    if let Err(response) = ensure_hot(&state, &tree_name).await {
+        return response;
+    }
+    if let Some(response) = degraded_rejection(&state, &tree_name) {
+        return response;
+    }
+    // The rewritten tree holds the imported rows on top of what it has, and they take at
+    // least as much space as in the Parquet file
+    let imported = fs::metadata(&path).map_or(0, |metadata| metadata.len());
+    if let Some(response) = disk_rejection(&state, state.store.file_size(&tree_name) + imported) {
+        return response;
+    }
+    let Some(_permit) = state.heavy.acquire().await else {
+        return heavy_rejection();
+    };
+
+    // A new tree gets a placeholder entry holding the operation, so no insert can create
+    // it while the file is read
+    let existing = {
+        let mut trees = state.store.trees.lock().unwrap();
+        if let Some(operation) = trees.get(&tree_name).and_then(|cache| cache.operation.as_ref()) {
+            return HttpResponse::Conflict().json(operation.conflict(&tree_name));
+        }
+        let exists = trees.get(&tree_name).is_some_and(|cache| cache.tree.is_some() || cache.dimensions.is_some())
+            || state.store.has_file(&tree_name);
+        if !exists && state.settings.strict_create {
+            return HttpResponse::NotFound().body(format!(
+                "Tree {} not found; STRICT_CREATE is on, so create it with /create_tree first",
+                tree_name
+            ));
+        }
+        if exists {
+            if let Err((status, body)) = load_into_cache(&state, &mut trees, &tree_name) {
+                return HttpResponse::build(status).body(body);
+            }
+        }
+        let cache = trees.entry(tree_name.clone()).or_default();
+        cache.operation = Some(TreeOperation::start(OperationKind::Importing));
+        // Shared, not copied: the copy is made off the lock and doubles the tree's
+        // footprint until the swap below
+        cache.shared_tree()
+    };
+
+    let importing = state.clone();
+    let (name, embedding_column, data_column) = (tree_name.clone(), query.embedding_column().to_string(), query.data_column.clone());
+    let echo_column = query.echo.clone();
+    let importing_job = job.clone();
+    let imported = web::block(move || {
+        let required = existing.as_deref().and_then(KDTree::schema).map(Schema::required_fields).unwrap_or_default();
+        if !required.is_empty() {
+            return Err((StatusCode::BAD_REQUEST, format!(
+                "Tree {} requires metadata field(s) {}, which Parquet imports cannot set",
+                name, required.join(", ")
+            )));
+        }
+        let columns = ParquetColumns {
+            embedding: &embedding_column,
+            data: data_column.as_deref(),
+            echo: echo_column.as_deref(),
+            dimensions: existing.as_deref().map(KDTree::input_dimensions),
+            max_dimensions: importing.settings.max_dimensions,
+            max_data_bytes: importing.settings.max_data_bytes,
+        };
+        let import = read_parquet(&path, &columns, |report, total| {
+            importing_job.total.store(total as u64, Ordering::Relaxed);
+            importing_job.processed.store(report.row_group as u64 + 1, Ordering::Relaxed);
+            println!(
+                "Importing {:?} into tree {}: row group {}/{}, {} rows, {} imported, {} rejected",
+                path, name, report.row_group + 1, total, report.rows, report.imported, report.rejected
+            )
+        }).map_err(|e| (StatusCode::BAD_REQUEST, format!("Cannot import {:?}: {}", path, e)))?;
+
+        let tree = match (existing, import.dimensions) {
+            (Some(tree), _) => Arc::unwrap_or_clone(tree),
+            (None, Some(dimensions)) => KDTree::new(dimensions)
+                .map_err(|e| (StatusCode::BAD_REQUEST, format!("Failed to create KD-Tree: {}", e)))?,
+            // Nothing usable in the file, so there is no tree to create
+            (None, None) => return Ok((None, import)),
+        };
+        let points = import.points.iter().map(|point| tree.reduce(Cow::Borrowed(point)).into_owned()).collect();
+        let tree = tree.extended(points).map_err(|e| match e {
+            KdTreeError::DimensionMismatch { .. } => (StatusCode::BAD_REQUEST, format!("Cannot import {:?}: {}", path, e)),
+            e => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to build KD-Tree: {}", e)),
+        })?;
+        check_memory_budget(&importing, &name, estimate_memory_usage(&tree))?;
+        Ok((Some(tree), import))
+    }).await;
+
+    let mut trees = state.store.trees.lock().unwrap();
+    let cache = trees.entry(tree_name.clone()).or_default();
+    cache.operation = None;
+    if let Some(response) = job_cancelled(&job) {
+        forget_placeholder(&mut trees, &tree_name);
+        return response;
+    }
+    let (tree, import) = match imported {
+        Ok(Ok(imported)) => imported,
+        Ok(Err((status, body))) => {
+            forget_placeholder(&mut trees, &tree_name);
+            return HttpResponse::build(status).body(body);
+        }
+        Err(e) => {
+            forget_placeholder(&mut trees, &tree_name);
+            return HttpResponse::InternalServerError().body(format!("Import failed: {}", e));
+        }
+    };
+    let imported = import.points.len();
+    let content: usize = import.points.iter().map(Point::content_size).sum();
+    let Some(tree) = tree else {
+        forget_placeholder(&mut trees, &tree_name);
+        return HttpResponse::Ok().json(import_response(tree_name, 0, import, started));
+    };
+
+    let created = cache.dimensions.is_none();
+    if let Some(settings) = state.bloom {
+        let filter = BloomFilter::from_tree(&tree, settings);
+        if let Err(e) = state.store.save_bloom(&tree_name, &filter) {
+            println!("Failed to save duplicate filter for tree {}: {}", tree_name, e);
+        }
+        cache.bloom = Some(filter);
+    }
+    if let Some(query_cache) = &state.query_cache {
+        query_cache.invalidate(&tree_name);
+    }
+    cache.set_tree(tree);
+    if imported > 0 {
+        state.store.usage.tree(&tree_name).record_inserts(imported, content);
+    }
+    if let Err(e) = cache.save_now(state.store.disk(), &tree_name) {
+        cache.dirty = true;
+        return HttpResponse::InternalServerError().body(format!("Failed to save KD-Tree: {}", e));
+    }
+    let num_records = cache.num_records;
+    if created {
+        if let Err(e) = state.store.save_manifest(&trees) {
+            println!("Failed to save manifest: {}", e);
+        }
+    }
+    println!("Imported {} points into tree {} ({} rows rejected)", imported, tree_name, import.rejected);
+
+    state.store.manage_memory(&mut trees);
+    HttpResponse::Ok().json(import_response(tree_name, num_records, import, started))
+}
+
+fn import_response(tree_name: String, num_records: usize, import: ParquetImport, started: Instant) -> ImportResponse {
+    let imported = import.points.len();
+    ImportResponse {
+        tree_name,
+        imported,
+        rejected: import.rejected,
+        num_records,
+        row_groups: import.row_groups,
+        rejected_rows: import.rejected_rows,
+        summary: BatchSummary::finished(started, imported, import.rejected, 0),
+    }
+}
+
+// Structural operation: imports a JSONL file of points from IMPORT_DIRECTORY into a new or
+// existing tree, a chunk of lines at a time, and rebuilds it balanced at the end. As with
+// a Parquet import, searches keep using the current tree and inserts get a 409 until it
+// is swapped in. The copy being built is checkpointed every IMPORT_CHECKPOINT_INTERVAL
+// and when the import stops early, so running it again with the same file resumes.
+async fn import_jsonl(query: Valid<ImportJsonlParams>, state: web::Data<APPState>) -> impl Responder {
+    let tree_name = query.tree_name.clone();
+    let run_async = query.run_async;
+    run_job(&state, JobKind::Import, Some(&tree_name), run_async, |job| import_lines(query, state.clone(), job)).await
+}
+
+async fn import_lines(query: Valid<ImportJsonlParams>, state: web::Data<APPState>, job: Arc<Job>) -> HttpResponse {
+    let started = Instant::now();
+    let tree_name = query.tree_name.clone();
+    let path = match resolve_import_path(&state, &query.path) {
+        Ok(path) => path,
+        Err((status, body)) => return HttpResponse::build(status).body(body),
+    };
+    if let Err(response) = ensure_hot(&state, &tree_name).await {
+        return response;
+    }
+    if let Some(response) = degraded_rejection(&state, &tree_name) {
+        return response;
+    }
+    // The checkpointed copy and the rewritten tree both hold the imported lines on top of
+    // what the tree has
+    let imported = fs::metadata(&path).map_or(0, |metadata| metadata.len());
+    if let Some(response) = disk_rejection(&state, 2 * (state.store.file_size(&tree_name) + imported)) {
+        return response;
+    }
+    let Some(_permit) = state.heavy.acquire().await else {
+        return heavy_rejection();
+    };
+
+    let existing = {
+        let mut trees = state.store.trees.lock().unwrap();
+        if let Some(operation) = trees.get(&tree_name).and_then(|cache| cache.operation.as_ref()) {
+            return HttpResponse::Conflict().json(operation.conflict(&tree_name));
+        }
+        let exists = trees.get(&tree_name).is_some_and(|cache| cache.tree.is_some() || cache.dimensions.is_some())
+            || state.store.has_file(&tree_name);
+        if !exists && state.settings.strict_create {
+            return HttpResponse::NotFound().body(format!(
+                "Tree {} not found; STRICT_CREATE is on, so create it with /create_tree first",
+                tree_name
+            ));
+        }
+        if exists {
+            if let Err((status, body)) = load_into_cache(&state, &mut trees, &tree_name) {
+                return HttpResponse::build(status).body(body);
+            }
+        }
+        let cache = trees.entry(tree_name.clone()).or_default();
+        cache.operation = Some(TreeOperation::start(OperationKind::Importing));
+        cache.shared_tree()
+    };
+
+    let (importing, importing_job, name, restart) = (state.clone(), job.clone(), tree_name.clone(), query.restart == Some(true));
+    let imported = web::block(move || read_lines(&importing, &importing_job, &name, &path, existing, restart)).await;
+
+    let mut trees = state.store.trees.lock().unwrap();
+    let cache = trees.entry(tree_name.clone()).or_default();
+    cache.operation = None;
+    if let Some(response) = job_cancelled(&job) {
+        forget_placeholder(&mut trees, &tree_name);
+        return response;
+    }
+    let (tree, import) = match imported {
+        Ok(Ok(imported)) => imported,
+        Ok(Err((status, body))) => {
+            forget_placeholder(&mut trees, &tree_name);
+            return HttpResponse::build(status).body(body);
+        }
+        Err(e) => {
+            forget_placeholder(&mut trees, &tree_name);
+            return HttpResponse::InternalServerError().body(format!("Import failed: {}", e));
+        }
+    };
+    let Some(tree) = tree else {
+        forget_placeholder(&mut trees, &tree_name);
+        return HttpResponse::Ok().json(jsonl_import_response(tree_name, 0, import, started));
+    };
+
+    let created = cache.dimensions.is_none();
+    if let Some(settings) = state.bloom {
+        let filter = BloomFilter::from_tree(&tree, settings);
+        if let Err(e) = state.store.save_bloom(&tree_name, &filter) {
+            println!("Failed to save duplicate filter for tree {}: {}", tree_name, e);
+        }
+        cache.bloom = Some(filter);
+    }
+    if let Some(query_cache) = &state.query_cache {
+        query_cache.invalidate(&tree_name);
+    }
+    cache.set_tree(tree);
+    if import.imported > 0 {
+        state.store.usage.tree(&tree_name).record_inserts(import.imported, import.content_bytes);
+    }
+    if let Err(e) = cache.save_now(state.store.disk(), &tree_name) {
+        cache.dirty = true;
+        return HttpResponse::InternalServerError().body(format!("Failed to save KD-Tree: {}", e));
+    }
+    // Only once the tree file holds everything, so a crash before leaves the checkpoint
+    if let Some(bin_directory) = state.store.disk() {
+        if let Err(e) = ImportCheckpoint::remove(bin_directory, &tree_name) {
+            println!("Failed to remove the import checkpoint of tree {}: {}", tree_name, e);
+        }
+    }
+    let num_records = cache.num_records;
+    if created {
+        if let Err(e) = state.store.save_manifest(&trees) {
+            println!("Failed to save manifest: {}", e);
+        }
+    }
+    println!("Imported {} points into tree {} ({} lines rejected)", import.imported, tree_name, import.rejected);
+
+    state.store.manage_memory(&mut trees);
+    HttpResponse::Ok().json(jsonl_import_response(tree_name, num_records, import, started))
+}
+
+// Reads the file chunk by chunk into a copy of the tree, or into the copy an earlier run
+// checkpointed, inserting each chunk as it is validated. Returns the tree rebuilt
+// balanced, or None when neither the tree nor the file had a usable point. When the
+// import stops early the copy is checkpointed first.
+fn read_lines(
+    state: &APPState,
+    job: &Job,
+    tree_name: &str,
+    path: &Path,
+    existing: Option<Arc<KDTree>>,
+    restart: bool,
+) -> Result<(Option<KDTree>, JsonlImport), (StatusCode, String)> {
+    let file_size = fs::metadata(path).map_err(|e| (StatusCode::BAD_REQUEST, format!("Cannot import {:?}: {}", path, e)))?.len();
+    let base_next_seq = existing.as_deref().map_or(0, KDTree::next_seq);
+    let mut checkpoint = ImportCheckpoint {
+        path: path.to_path_buf(),
+        file_size,
+        base_next_seq,
+        staged_next_seq: 0,
+        position: Position::default(),
+    };
+    let resumed = state.store.disk().and_then(|bin_directory| resume_import(bin_directory, tree_name, &checkpoint, restart));
+    let mut import = JsonlImport::default();
+    let mut tree = match resumed {
+        Some((tree, position)) => {
+            checkpoint.position = position;
+            import.resumed_from_line = Some(position.lines + 1);
+            Some(tree)
+        }
+        None => existing.map(Arc::unwrap_or_clone),
+    };
+
+    let schema = tree.as_ref().and_then(KDTree::schema).cloned();
+    let mut rules = LineRules {
+        dimensions: tree.as_ref().map(KDTree::input_dimensions),
+        max_dimensions: state.settings.max_dimensions,
+        max_data_bytes: state.settings.max_data_bytes,
+        max_line_bytes: state.settings.max_payload_mb * 1024 * 1024,
+        schema: schema.as_ref(),
+    };
+    let mut reader = JsonlReader::open(path, checkpoint.position)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Cannot import {:?}: {}", path, e)))?;
+    job.total.store(file_size, Ordering::Relaxed);
+    job.processed.store(checkpoint.position.offset, Ordering::Relaxed);
+
+    let mut footprint = tree.as_ref().map_or(0, estimate_memory_usage);
+    let mut last_checkpoint = Instant::now();
+    let stopped = loop {
+        if job.stop_if_cancelled() {
+            break (StatusCode::CONFLICT, format!("Job {} was cancelled", job.id));
+        }
+        let chunk = match reader.next_chunk(&mut rules, &mut import.rejected_lines) {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => {
+                let Some(tree) = tree else { return Ok((None, import)) };
+                let tree = tree.rebuilt().map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to build KD-Tree: {}", e)))?;
+                return Ok((Some(tree), import));
+            }
+            Err(e) => break (StatusCode::BAD_REQUEST, format!(
+                "Cannot read {:?} at line {}: {}",
+                path, reader.position().lines + 1, e
+            )),
+        };
+        // A new tree takes the dimension of the first accepted line
+        if let (None, Some(dimensions)) = (&tree, rules.dimensions) {
+            match KDTree::new(dimensions) {
+                Ok(created) => tree = Some(created),
+                Err(e) => break (StatusCode::BAD_REQUEST, format!("Failed to create KD-Tree: {}", e)),
+            }
+        }
+        footprint += chunk.points.iter().map(estimate_point_size).sum::<usize>();
+        if let Err(rejection) = check_memory_budget(state, tree_name, footprint) {
+            break rejection;
+        }
+        import.content_bytes += chunk.points.iter().map(Point::content_size).sum::<usize>();
+        if let Some(building) = &mut tree {
+            for point in chunk.points {
+                let point = building.reduce(Cow::Owned(point)).into_owned();
+                match state.store.options.max_depth_factor {
+                    Some(factor) => if building.insert_bounded(point, factor) {
+                        Metrics::incr(&state.metrics.partial_rebuilds);
+                    },
+                    None => building.insert(point),
+                }
+            }
+        }
+
+        import.imported += chunk.report.imported;
+        import.rejected += chunk.report.rejected;
+        println!(
+            "Importing {:?} into tree {}: chunk {} from line {}, {} lines, {} imported, {} rejected",
+            path, tree_name, chunk.report.chunk, chunk.report.first_line, chunk.report.lines, chunk.report.imported, chunk.report.rejected
+        );
+        import.chunks.push(chunk.report);
+        checkpoint.position = reader.position();
+        job.processed.store(checkpoint.position.offset, Ordering::Relaxed);
+        if let (Some(building), true) = (&tree, last_checkpoint.elapsed() >= IMPORT_CHECKPOINT_INTERVAL) {
+            if let Err(e) = save_import_checkpoint(state, tree_name, building, &mut checkpoint) {
+                break (StatusCode::INTERNAL_SERVER_ERROR, e);
+            }
+            last_checkpoint = Instant::now();
+        }
+    };
+
+    let (status, mut body) = stopped;
+    if let Some(tree) = &tree {
+        match save_import_checkpoint(state, tree_name, tree, &mut checkpoint) {
+            Ok(true) => body.push_str(&format!(
+                "; {} lines are checkpointed, and importing the same file again resumes at line {}",
+                checkpoint.position.lines, checkpoint.position.lines + 1
+            )),
+            Ok(false) => {}
+            Err(e) => println!("{}", e),
+        }
+    }
+    Err((status, body))
+}
+
+// The copy and position an earlier import of the same file into the tree checkpointed.
+// A checkpoint of another file, of a file or tree changed since, or that `restart`
+// discards is removed and the import starts over.
+fn resume_import(bin_directory: &Path, tree_name: &str, current: &ImportCheckpoint, restart: bool) -> Option<(KDTree, Position)> {
+    let checkpoint = match ImportCheckpoint::load(bin_directory, tree_name) {
+        Ok(Some(checkpoint)) => checkpoint,
+        Ok(None) => return None,
+        Err(e) => {
+            println!("Ignoring the unreadable import checkpoint of tree {}: {}", tree_name, e);
+            return None;
+        }
+    };
+    let stale = if restart {
+        Some("a restart was requested".to_string())
+    } else if checkpoint.path != current.path {
+        Some(format!("it is of {:?}", checkpoint.path))
+    } else if checkpoint.file_size != current.file_size {
+        Some("the file has changed since".to_string())
+    } else if checkpoint.base_next_seq != current.base_next_seq {
+        Some("the tree has changed since".to_string())
+    } else {
+        None
+    };
+    let staged = ImportCheckpoint::staged_path(bin_directory, tree_name);
+    let resumed = match stale {
+        Some(reason) => Err(reason),
+        None => KDTree::load_from_file(&staged.to_string_lossy()).map_err(|e| format!("the checkpointed tree is unreadable: {}", e)),
+    };
+    let tree = match resumed {
+        Ok(tree) => tree,
+        Err(reason) => {
+            println!("Discarding the import checkpoint of tree {}: {}", tree_name, reason);
+            if let Err(e) = ImportCheckpoint::remove(bin_directory, tree_name) {
+                println!("Failed to remove the import checkpoint of tree {}: {}", tree_name, e);
+            }
+            return None;
+        }
+    };
+    // Points past the checkpoint are from a chunk saved just before a crash, and are
+    // about to be read again
+    let tree = match tree.next_seq() > checkpoint.staged_next_seq {
+        true => match tree.without(|point| point.seq >= checkpoint.staged_next_seq) {
+            Ok((tree, removed)) => {
+                println!("Dropped {} points of tree {} saved after its last import checkpoint", removed, tree_name);
+                tree
+            }
+            Err(e) => {
+                println!("Discarding the import checkpoint of tree {}: {}", tree_name, e);
+                return None;
+            }
+        },
+        false => tree,
+    };
+    println!("Resuming the import of {:?} into tree {} at line {}", checkpoint.path, tree_name, checkpoint.position.lines + 1);
+    Some((tree, checkpoint.position))
+}
+
+// Saves the copy being built, then records how far the import got. A crash in between
+// leaves points past the checkpoint in the copy, which a resumed import drops first.
+// Returns whether there was anywhere to save it.
+fn save_import_checkpoint(state: &APPState, tree_name: &str, tree: &KDTree, checkpoint: &mut ImportCheckpoint) -> Result<bool, String> {
+    let Some(bin_directory) = state.store.disk() else { return Ok(false) };
+    let failed = |e: String| format!("Failed to save the import checkpoint of tree {}: {}", tree_name, e);
+    tree.save_to_file(&ImportCheckpoint::staged_path(bin_directory, tree_name).to_string_lossy())
+        .map_err(|e| failed(e.to_string()))?;
+    checkpoint.staged_next_seq = tree.next_seq();
+    checkpoint.save(bin_directory, tree_name).map_err(|e| failed(e.to_string()))?;
+    Ok(true)
+}
+
+fn jsonl_import_response(tree_name: String, num_records: usize, import: JsonlImport, started: Instant) -> JsonlImportResponse {
+    JsonlImportResponse {
+        tree_name,
+        imported: import.imported,
+        rejected: import.rejected,
+        num_records,
+        resumed_from_line: import.resumed_from_line,
+        chunks: import.chunks,
+        rejected_lines: import.rejected_lines,
+        summary: BatchSummary::finished(started, import.imported, import.rejected, 0),
+    }
+}
+
+// Drops the entry an import of a new tree registered, unless it became a real tree
+fn forget_placeholder(trees: &mut HashMap<String, KDTreeCache>, tree_name: &str) {
+    if trees.get(tree_name).is_some_and(|cache| cache.tree.is_none() && cache.dimensions.is_none()) {
+        trees.remove(tree_name);
+    }
+}
+
+// Resolves `path` below IMPORT_DIRECTORY. Anything that ends up outside it, through `..`,
+// an absolute path or a symlink, is refused.
+fn resolve_import_path(state: &APPState, path: &str) -> Result<PathBuf, (StatusCode, String)> {
+    let Some(directory) = &state.settings.import_directory else {
+        return Err((StatusCode::FORBIDDEN, "Importing files is disabled; set IMPORT_DIRECTORY to allow it".to_string()));
+    };
+    let root = fs::canonicalize(directory).map_err(|e| {
+        (StatusCode::INTERNAL_SERVER_ERROR, format!("Cannot read IMPORT_DIRECTORY {:?}: {}", directory, e))
+    })?;
+    let resolved = fs::canonicalize(root.join(path)).map_err(|e| (StatusCode::NOT_FOUND, format!("Cannot open {}: {}", path, e)))?;
+    if !resolved.starts_with(&root) || !resolved.is_file() {
+        return Err((StatusCode::FORBIDDEN, format!("{} is not a file inside IMPORT_DIRECTORY", path)));
+    }
+    Ok(resolved)
+}
+
+// Re-embeds the data of every point of `tree_name` into the new tree `target` with the
+// EMBEDDING_URL provider, and answers 202 with a job to poll at /jobs/{id}. The target
+// takes the dimension of the provider's output and the source's partition field, schema
+// and metadata. The source stays fully usable; the target holds the operation until the
+// job ends, so nothing else writes to it. Starting the job again after a crash or a
+// failure resumes from the target's checkpoint, and picks up points inserted since.
+async fn reembed(query: Valid<ReembedParams>, state: web::Data<APPState>) -> impl Responder {
+    let (source, target) = (query.tree_name.clone(), query.target.clone());
+    if state.embedder.is_none() {
+        return HttpResponse::Forbidden().body("Re-embedding is disabled; set EMBEDDING_URL to allow it");
+    }
+    for tree_name in [&source, &target] {
+        if let Err(response) = ensure_hot(&state, tree_name).await {
+            return response;
+        }
+    }
+    // The target ends up about the size of the source, give or take the new dimension
+    if let Some(response) = disk_rejection(&state, state.store.file_size(&source)) {
+        return response;
+    }
+
+    let (snapshot, checkpoint) = {
+        let mut trees = state.store.trees.lock().unwrap();
+        if let Some(operation) = trees.get(&target).and_then(|cache| cache.operation.as_ref()) {
+            return HttpResponse::Conflict().json(operation.conflict(&target));
+        }
+        let checkpoint = match state.store.disk().map(|bin_directory| Checkpoint::load(bin_directory, &target)) {
+            Some(Ok(checkpoint)) => checkpoint,
+            None => None,
+            Some(Err(e)) => return HttpResponse::InternalServerError().body(format!(
+                "Cannot read the re-embedding checkpoint of tree {}: {}",
+                target, e
+            )),
+        };
+        let target_exists = trees.get(&target).is_some_and(|cache| cache.dimensions.is_some() || cache.tier != Tier::Hot)
+            || state.store.has_file(&target);
+        match &checkpoint {
+            Some(checkpoint) if checkpoint.source != source => return HttpResponse::Conflict().body(format!(
+                "Tree {} is being re-embedded from tree {}",
+                target, checkpoint.source
+            )),
+            None if target_exists => return HttpResponse::Conflict().body(format!("Tree {} already exists", target)),
+            _ => {}
+        }
+        if let Err((status, body)) = load_into_cache(&state, &mut trees, &source) {
+            return HttpResponse::build(status).body(body);
+        }
+        // Shared, not copied: the points are read off the lock while inserts go on
+        let snapshot = trees[&source].shared_tree().unwrap();
+        trees.entry(target.clone()).or_default().operation = Some(TreeOperation::start(OperationKind::Reembedding));
+        (snapshot, checkpoint)
+    };
+
+    let job = state.jobs.start(JobKind::Reembed, Some(&source), Some(&target));
+    let checkpoint = checkpoint.unwrap_or(Checkpoint { source: source.clone(), last_seq: 0, target_next_seq: 1 });
+    job.resumed_after_seq.store(checkpoint.last_seq, Ordering::Relaxed);
+    println!(
+        "Re-embedding tree {} into tree {} as job {}, after sequence number {}",
+        source, target, job.id, checkpoint.last_seq
+    );
+    let (running, running_job) = (state.clone(), job.clone());
+    actix_web::rt::task::spawn_blocking(move || {
+        let result = run_reembed(&running, &running_job, &snapshot, &target, checkpoint);
+        finish_reembed(&running, &target, &result);
+        match &result {
+            Ok(()) => println!("Re-embedding job {} into tree {} finished", running_job.id, target),
+            Err(e) => println!("Re-embedding job {} into tree {} stopped: {}", running_job.id, target, e),
+        }
+        running.jobs.finish(&running_job, None, result.map(|()| None));
+    });
+    HttpResponse::Accepted().json(job.status())
+}
+
+// Embeds the source points after the checkpoint in waves of EMBEDDING_CONCURRENCY
+// batches, inserting each wave into the target under the trees lock. The target is saved
+// and checkpointed every REEMBED_CHECKPOINT_INTERVAL and once more when the job stops,
+// whether it failed or not, so a resumed job only redoes what came after.
+fn run_reembed(state: &APPState, job: &Job, source: &KDTree, target: &str, mut checkpoint: Checkpoint) -> Result<(), String> {
+    let embedder = state.embedder.as_ref().unwrap();
+    roll_back_to_checkpoint(state, target, &checkpoint)?;
+
+    let points = source.select(None, |point| point.seq > checkpoint.last_seq, &job.cancel)
+        .map_err(|_| format!("Job {} was cancelled", job.id))?;
+    job.total.store(points.len() as u64, Ordering::Relaxed);
+
+    let batch_size = state.settings.embedding_batch_size;
+    let mut last_checkpoint = Instant::now();
+    let mut result = Ok(());
+    for wave in points.chunks(batch_size * state.settings.embedding_concurrency) {
+        if job.stop_if_cancelled() {
+            result = Err(format!("Job {} was cancelled", job.id));
+            break;
+        }
+        let embedded = match embed_wave(embedder, job, wave, batch_size) {
+            Ok(embedded) => embedded,
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
+        };
+        let mut trees = state.store.trees.lock().unwrap();
+        let created = match insert_reembedded(state, &mut trees, source, target, embedded) {
+            Ok(created) => created,
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
+        };
+        checkpoint.last_seq = wave.last().map_or(checkpoint.last_seq, |point| point.seq);
+        job.processed.fetch_add(wave.len() as u64, Ordering::Relaxed);
+        // A new target is checkpointed right away, so its file never goes long without one
+        if created || last_checkpoint.elapsed() >= REEMBED_CHECKPOINT_INTERVAL {
+            if let Err(e) = save_reembedded(state, &mut trees, target, &mut checkpoint) {
+                result = Err(e);
+                break;
+            }
+            last_checkpoint = Instant::now();
+        }
+        state.store.manage_memory(&mut trees);
+    }
+
+    let mut trees = state.store.trees.lock().unwrap();
+    result.and(save_reembedded(state, &mut trees, target, &mut checkpoint))
+}
+
+// Drops target points saved after the checkpoint by a flush or an eviction, since their
+// source points are about to be embedded again
+fn roll_back_to_checkpoint(state: &APPState, target: &str, checkpoint: &Checkpoint) -> Result<(), String> {
+    let mut trees = state.store.trees.lock().unwrap();
+    let cache = reembed_target(state, &mut trees, target)?;
+    if cache.tree.as_ref().is_none_or(|tree| tree.next_seq() <= checkpoint.target_next_seq) {
+        return Ok(());
+    }
+    let tree = Arc::unwrap_or_clone(cache.take_tree().unwrap());
+    let (tree, removed) = tree.without(|point| point.seq >= checkpoint.target_next_seq)
+        .map_err(|e| format!("Failed to roll tree {} back to its checkpoint: {}", target, e))?;
+    cache.set_tree(tree);
+    cache.save_now(state.store.disk(), target).map_err(|e| format!("Failed to save KD-Tree: {}", e))?;
+    println!("Dropped {} points of tree {} saved after its last re-embedding checkpoint", removed, target);
+    Ok(())
+}
+
+// The target's entry with its tree resident once it has one. Only an eviction without
+// persistence makes the entry, and with it the operation, go away.
+fn reembed_target<'a>(state: &APPState, trees: &'a mut HashMap<String, KDTreeCache>, target: &str) -> Result<&'a mut KDTreeCache, String> {
+    if trees.get(target).is_none_or(|cache| cache.operation.is_none()) {
+        return Err(format!("Tree {} was evicted while being re-embedded", target));
+    }
+    if trees[target].tree.is_none() && state.store.has_file(target) {
+        load_into_cache(state, trees, target).map_err(|(_, body)| body)?;
+    }
+    Ok(trees.get_mut(target).unwrap())
+}
+
+// Embeds the data of a wave of points, one provider request per batch and all batches
+// at once. Points without data can't be re-embedded and count as failures.
+fn embed_wave(embedder: &Embedder, job: &Job, wave: &[&Point], batch_size: usize) -> Result<Vec<Point>, String> {
+    let texts: Vec<(&Point, Cow<str>)> = wave.iter()
+        .filter_map(|point| match point.data() {
+            Some(data) => Some((*point, data)),
+            None => {
+                job.failures.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        })
+        .collect();
+    let batches: Vec<_> = std::thread::scope(|scope| {
+        let requests: Vec<_> = texts.chunks(batch_size).map(|batch| scope.spawn(move || {
+            let inputs: Vec<&str> = batch.iter().map(|(_, data)| data.as_ref()).collect();
+            embedder.embed(&inputs, |e| {
+                job.retries.fetch_add(1, Ordering::Relaxed);
+                println!("Retrying an embedding request of re-embedding job {}: {}", job.id, e);
+            })
+        })).collect();
+        requests.into_iter().map(|request| request.join().unwrap()).collect()
+    });
+
+    let mut embedded = Vec::with_capacity(texts.len());
+    for (batch, embeddings) in texts.chunks(batch_size).zip(batches) {
+        let embeddings = embeddings.map_err(|e| e.to_string())?;
+        embedded.extend(batch.iter().zip(embeddings).map(|((point, data), embedding)| Point {
+            embedding,
+            data: Some(data.to_string()),
+            metadata: point.metadata.clone(),
+            ..Point::default()
+        }));
+    }
+    Ok(embedded)
+}
+
+// Inserts a wave into the target, creating it with the dimension of the first embedding.
+// Returns whether it was created.
+fn insert_reembedded(
+    state: &APPState,
+    trees: &mut HashMap<String, KDTreeCache>,
+    source: &KDTree,
+    target: &str,
+    points: Vec<Point>,
+) -> Result<bool, String> {
+    let Some(dimensions) = points.first().map(Point::len) else { return Ok(false) };
+    let cache = reembed_target(state, trees, target)?;
+    let created = cache.tree.is_none();
+    if created {
+        if dimensions > state.settings.max_dimensions {
+            return Err(format!(
+                "The embedding provider returned {} dimensions but MAX_DIMENSIONS is {}",
+                dimensions, state.settings.max_dimensions
+            ));
+        }
+        let created = match source.partition_field() {
+            Some(field) => KDTree::with_partition_field(dimensions, field),
+            None => KDTree::new(dimensions),
+        };
+        let mut tree = created.map_err(|e| format!("Failed to create KD-Tree: {}", e))?;
+        tree.set_schema(source.schema().cloned());
+        tree.set_model(state.settings.embedding_model.clone());
+        println!("Creating KD-Tree {} with {} dimensions taken from the embedding provider", target, dimensions);
+        cache.set_tree(tree);
+    }
+
+    let tree = cache.tree.as_deref().unwrap();
+    if let Some(point) = points.iter().find(|point| point.len() != tree.input_dimensions()) {
+        return Err(format!(
+            "The embedding provider returned {} dimensions but tree {} has {}",
+            point.len(), target, tree.input_dimensions()
+        ));
+    }
+    let added: usize = points.iter().map(estimate_point_size).sum();
+    check_memory_budget(state, target, estimate_memory_usage(tree) + added).map_err(|(_, body)| body)?;
+
+    let inserted = points.len();
+    let content = points.iter().map(Point::content_size).sum();
+    for point in points {
+        if cache.insert(point, state.store.options.max_depth_factor) {
+            Metrics::incr(&state.metrics.partial_rebuilds);
+        }
+    }
+    cache.dirty = true;
+    state.store.usage.tree(target).record_inserts(inserted, content);
+    if let Some(query_cache) = &state.query_cache {
+        query_cache.invalidate(target);
+    }
+    Ok(created)
+}
+
+// Saves the target, then records how far the job got. A crash in between leaves points
+// past the checkpoint in the file, which a resumed job drops first.
+fn save_reembedded(state: &APPState, trees: &mut HashMap<String, KDTreeCache>, target: &str, checkpoint: &mut Checkpoint) -> Result<(), String> {
+    let cache = reembed_target(state, trees, target)?;
+    let Some(tree) = cache.shared_tree() else { return Ok(()) };
+    cache.save_now(state.store.disk(), target).map_err(|e| format!("Failed to save KD-Tree: {}", e))?;
+    checkpoint.target_next_seq = tree.next_seq();
+    if let Some(bin_directory) = state.store.disk() {
+        checkpoint.save(bin_directory, target)
+            .map_err(|e| format!("Failed to save the re-embedding checkpoint of tree {}: {}", target, e))?;
+    }
+    Ok(())
+}
+
+// Releases the target. A finished job's checkpoint goes; a failed one keeps it to resume
+// from, unless the target was never created.
+fn finish_reembed(state: &APPState, target: &str, result: &Result<(), String>) {
+    let mut trees = state.store.trees.lock().unwrap();
+    let created = trees.get(target).is_some_and(|cache| cache.dimensions.is_some());
+    if let Some(cache) = trees.get_mut(target) {
+        cache.operation = None;
+    }
+    forget_placeholder(&mut trees, target);
+    if let (Some(bin_directory), true) = (state.store.disk(), result.is_ok() || !created) {
+        if let Err(e) = Checkpoint::remove(bin_directory, target) {
+            println!("Failed to remove the re-embedding checkpoint of tree {}: {}", target, e);
+        }
+    }
+    if created {
+        if let Err(e) = state.store.save_manifest(&trees) {
+            println!("Failed to save manifest: {}", e);
+        }
+    }
+    state.store.manage_memory(&mut trees);
+}
+
+// Runs the body of a long endpoint the way the request asked: in place by default, or
+// with `async=true` as a background job, answering 202 with the job right away. The job
+// keeps what the request would have been answered with: the status, and the body as
+// `result` on success or as `error` otherwise.
+async fn run_job<F: Future<Output = HttpResponse> + 'static>(
+    state: &web::Data<APPState>,
+    kind: JobKind,
+    tree_name: Option<&str>,
+    run_async: Option<bool>,
+    work: impl FnOnce(Arc<Job>) -> F,
+) -> HttpResponse {
+    if run_async != Some(true) {
+        return work(Arc::new(Job::detached(kind, tree_name))).await;
+    }
+    let job = state.jobs.start(kind, tree_name, None);
+    let running = work(job.clone());
+    let (state, finished) = (state.clone(), job.clone());
+    let context = LockContext::new("job", tree_name.unwrap_or_default());
+    actix_web::rt::spawn(context.scope(async move {
+        let response = running.await;
+        let status = response.status();
+        let body = actix_web::body::to_bytes(response.into_body()).await.unwrap_or_default();
+        let outcome = match (status.is_success(), serde_json::from_slice(&body)) {
+            (true, Ok(result)) => Ok(Some(result)),
+            (true, Err(_)) => Ok(Some(serde_json::Value::String(String::from_utf8_lossy(&body).into_owned()))),
+            (false, _) => Err(String::from_utf8_lossy(&body).into_owned()),
+        };
+        state.jobs.finish(&finished, Some(status.as_u16()), outcome);
+    }));
+    HttpResponse::Accepted().json(job.status())
+}
+
+// What a job's work answers when it noticed a cancellation at one of its safe points,
+// before changing anything
+fn job_cancelled(job: &Job) -> Option<HttpResponse> {
+    job.stop_if_cancelled().then(|| HttpResponse::Conflict().body(format!("Job {} was cancelled", job.id)))
+}
+
+async fn list_jobs(state: web::Data<APPState>) -> impl Responder {
+    HttpResponse::Ok().json(JobsResponse { jobs: state.jobs.list() })
+}
+
+async fn get_job(id: web::Path<u64>, state: web::Data<APPState>) -> impl Responder {
+    match state.jobs.get(*id) {
+        Some(job) => HttpResponse::Ok().json(job.status()),
+        None => HttpResponse::NotFound().body(format!("Job {} not found", id)),
+    }
+}
+
+// Asks a running job to stop. The work stops at its next safe point, before it changes
+// any tree, so a job past its last one finishes normally.
+async fn cancel_job(id: web::Path<u64>, state: web::Data<APPState>) -> impl Responder {
+    let Some(job) = state.jobs.get(*id) else {
+        return HttpResponse::NotFound().body(format!("Job {} not found", id));
+    };
+    if job.is_finished() {
+        return HttpResponse::Conflict().body(format!("Job {} has already finished", id));
+    }
+    job.cancel.cancel();
+    println!("Cancelling job {}", id);
+    HttpResponse::Accepted().json(job.status())
+}
+
+// Structural operation: removes every point matching the filter and rebuilds the rest
+// balanced. Points are deleted outright, so nothing of them is left in the tree file.
+// A dry run only counts the matches.
+async fn delete_by_filter(query: Valid<DeleteByFilterParams>, state: web::Data<APPState>) -> impl Responder {
+    let tree_name = query.tree_name.clone();
+    let run_async = query.run_async;
+    run_job(&state, JobKind::DeleteByFilter, Some(&tree_name), run_async, |job| delete_matching(query, state.clone(), job)).await
+}
+
+async fn delete_matching(query: Valid<DeleteByFilterParams>, state: web::Data<APPState>, job: Arc<Job>) -> HttpResponse {
+    let tree_name = query.tree_name.clone();
+    if let Err(response) = ensure_hot(&state, &tree_name).await {
+        return response;
+    }
+    if let Some(response) = degraded_rejection(&state, &tree_name) {
+        return response;
+    }
+    let Some(_permit) = state.heavy.acquire().await else {
+        return heavy_rejection();
+    };
+    let filter = query.filter();
+
+    if query.dry_run == Some(true) {
+        let counting = state.clone();
+        let counted = web::block(move || {
+            let tree = shared_tree(&counting, &tree_name)?;
+            check_filter_schema(tree.schema(), &tree_name, Some(&filter))?;
+            let mut matched = 0;
+            tree.for_each_point(|point| if filter.matches(&point.metadata) { matched += 1 });
+            Ok(DeleteByFilterResponse { tree_name: tree_name.clone(), dry_run: true, matched, removed: 0, num_records: tree.len() })
+        }).await;
+        return match counted {
+            Ok(Ok(response)) => HttpResponse::Ok().json(response),
+            Ok(Err((status, body))) => HttpResponse::build(status).body(body),
+            Err(e) => HttpResponse::InternalServerError().body(format!("Dry run failed: {}", e)),
+        };
+    }
+
+    let snapshot = {
+        let mut trees = state.store.trees.lock().unwrap();
+        if let Some(operation) = trees.get(&tree_name).and_then(|cache| cache.operation.as_ref()) {
+            return HttpResponse::Conflict().json(operation.conflict(&tree_name));
+        }
+        if let Err((status, body)) = load_into_cache(&state, &mut trees, &tree_name) {
+            return HttpResponse::build(status).body(body);
+        }
+        let cache = trees.get_mut(&tree_name).unwrap();
+        if let Err((status, body)) = check_filter_schema(cache.tree.as_ref().unwrap().schema(), &tree_name, Some(&filter)) {
+            return HttpResponse::build(status).body(body);
+        }
+        cache.operation = Some(TreeOperation::start(OperationKind::Deleting));
+        // Shared, not copied: the copy is made off the lock and doubles the tree's
+        // footprint until the swap below
+        cache.shared_tree().unwrap()
+    };
+
+    let remaining = web::block(move || Arc::unwrap_or_clone(snapshot).without(|point| filter.matches(&point.metadata))).await;
+
+    let mut trees = state.store.trees.lock().unwrap();
+    let cache = trees.entry(tree_name.clone()).or_default();
+    cache.operation = None;
+    if let Some(response) = job_cancelled(&job) {
+        return response;
+    }
+    let (tree, removed) = match remaining {
+        Ok(Ok(remaining)) => remaining,
+        Ok(Err(e)) => return HttpResponse::InternalServerError().body(format!("Failed to delete points: {}", e)),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Failed to delete points: {}", e)),
+    };
+    if removed == 0 {
+        let num_records = cache.num_records;
+        return HttpResponse::Ok().json(DeleteByFilterResponse { tree_name, dry_run: false, matched: 0, removed, num_records });
+    }
+
+    // A Bloom filter cannot forget members, so it is built again from what is left
+    if let Some(settings) = state.bloom {
+        let filter = BloomFilter::from_tree(&tree, settings);
+        if let Err(e) = state.store.save_bloom(&tree_name, &filter) {
+            println!("Failed to save duplicate filter for tree {}: {}", tree_name, e);
+        }
+        cache.bloom = Some(filter);
+    }
+    if let Some(query_cache) = &state.query_cache {
+        query_cache.invalidate(&tree_name);
+    }
+    cache.set_tree(tree);
+    if let Err(e) = cache.save_now(state.store.disk(), &tree_name) {
+        // The deleted points are still in the file, so the flush has to retry
+        cache.dirty = true;
+        return HttpResponse::InternalServerError().body(format!("Failed to save KD-Tree: {}", e));
+    }
+    let num_records = cache.num_records;
+    state.store.usage.tree(&tree_name).record_change();
+    emit_audit_event(&state, json!({ "event": "points_deleted", "tree_name": tree_name, "removed": removed, "num_records": num_records }));
+
+    state.store.manage_memory(&mut trees);
+    HttpResponse::Ok().json(DeleteByFilterResponse { tree_name, dry_run: false, matched: removed, removed, num_records })
+}
+
+// Empties a tree but keeps its dimensions, partition field and projection, unlike
+// deleting and re-creating it. The empty tree is saved before the response.
+async fn truncate_tree(query: Valid<TruncateParams>, state: web::Data<APPState>) -> impl Responder {
+    let tree_name = query.tree_name.clone();
+    if let Err(response) = ensure_hot(&state, &tree_name).await {
+        return response;
+    }
+    if let Some(response) = degraded_rejection(&state, &tree_name) {
+        return response;
+    }
+
+    let mut trees = state.store.trees.lock().unwrap();
+    if let Some(operation) = trees.get(&tree_name).and_then(|cache| cache.operation.as_ref()) {
+        return HttpResponse::Conflict().json(operation.conflict(&tree_name));
+    }
+    if let Err((status, body)) = load_into_cache(&state, &mut trees, &tree_name) {
+        return HttpResponse::build(status).body(body);
+    }
+    let cache = trees.get_mut(&tree_name).unwrap();
+    let emptied = match cache.tree.as_ref().unwrap().emptied() {
+        Ok(tree) => tree,
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Failed to truncate KD-Tree: {}", e)),
+    };
+    let removed = cache.num_records;
+    let dimensions = emptied.input_dimensions();
+
+    if let Some(settings) = state.bloom {
+        let filter = BloomFilter::from_tree(&emptied, settings);
+        if let Err(e) = state.store.save_bloom(&tree_name, &filter) {
+            println!("Failed to save duplicate filter for tree {}: {}", tree_name, e);
+        }
+        cache.bloom = Some(filter);
+    }
+    if let Some(query_cache) = &state.query_cache {
+        query_cache.invalidate(&tree_name);
+    }
+    let old = cache.tree.take();
+    cache.set_tree(emptied);
+    if let Err(e) = cache.save_now(state.store.disk(), &tree_name) {
+        cache.dirty = true;
+        return HttpResponse::InternalServerError().body(format!("Failed to save KD-Tree: {}", e));
+    }
+    drop(trees);
+    // Freeing a large tree takes a while, so it happens off the lock
+    drop(old);
+
+    state.store.usage.tree(&tree_name).record_change();
+    emit_audit_event(&state, json!({ "event": "tree_truncated", "tree_name": tree_name, "removed": removed }));
+    HttpResponse::Ok().json(TruncateResponse { tree_name, removed, dimensions })
+}
+
+// The metadata schema of a tree, null when it takes any metadata
+async fn get_schema(query: Valid<TreeParams>, state: web::Data<APPState>) -> impl Responder {
+    let tree_name = &query.tree_name;
+    if let Err(response) = ensure_hot(&state, tree_name).await {
+        return response;
+    }
+    let mut trees = state.store.trees.lock().unwrap();
+    if let Err((status, body)) = load_into_cache(&state, &mut trees, tree_name) {
+        return HttpResponse::build(status).body(body);
+    }
+    let schema = trees[tree_name].tree.as_ref().unwrap().schema().cloned();
+
+    state.store.manage_memory(&mut trees);
+    HttpResponse::Ok().json(SchemaResponse { tree_name: tree_name.clone(), schema, nonconforming: None })
+}
+
+// Declares the metadata schema of a tree, replacing any previous one. Stored points are
+// checked against it first: a schema some of them don't match is refused with a 409
+// unless `force=true`, and forced, those points are kept as they are. The tree is saved
+// before the response.
+async fn put_schema(query: Valid<SchemaParams>, body: Bytes, state: web::Data<APPState>) -> impl Responder {
+    let schema = match std::str::from_utf8(&body).map_err(|e| e.to_string()).and_then(Schema::parse) {
+        Ok(schema) => schema,
+        Err(e) => return HttpResponse::BadRequest().body(format!("Invalid schema body: {}", e)),
+    };
+    let tree_name = query.tree_name.clone();
+    if let Err(response) = ensure_hot(&state, &tree_name).await {
+        return response;
+    }
+    let Some(permit) = state.heavy.acquire().await else {
+        return heavy_rejection();
+    };
+
+    let force = query.force.unwrap_or(false);
+    let changing = state.clone();
+    let changed = web::block(move || {
+        let mut trees = changing.store.trees.lock().unwrap();
+        if let Some(operation) = trees.get(&tree_name).and_then(|cache| cache.operation.as_ref()) {
+            return Err((StatusCode::CONFLICT, operation.conflict(&tree_name).to_string()));
+        }
+        load_into_cache(&changing, &mut trees, &tree_name)?;
+        let cache = trees.get_mut(&tree_name).unwrap();
+        let tree = cache.tree.as_ref().unwrap();
+        if let Some(field) = tree.partition_field().filter(|field| !schema.fields.contains_key(*field)) {
+            return Err((StatusCode::BAD_REQUEST, format!("Schema must declare the partition field `{}`", field)));
+        }
+
+        let (mut nonconforming, mut first) = (0, None);
+        tree.for_each_point(|point| {
+            if let Err(problem) = schema.check(&point.metadata) {
+                nonconforming += 1;
+                first.get_or_insert((point.seq, problem));
+            }
+        });
+        if let (false, Some((seq, problem))) = (force, first) {
+            return Err((StatusCode::CONFLICT, format!(
+                "{} of {} stored points in tree {} do not match the schema, e.g. seq {}: {}; pass force=true to apply it anyway",
+                nonconforming, tree.len(), tree_name, seq, problem
+            )));
+        }
+        cache.tree_mut().unwrap().set_schema(Some(schema.clone()));
+        if let Err(e) = cache.save_now(changing.store.disk(), &tree_name) {
+            cache.dirty = true;
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to save KD-Tree: {}", e)));
+        }
+        // Cached results may have come from filters the schema now refuses
+        if let Some(query_cache) = &changing.query_cache {
+            query_cache.invalidate(&tree_name);
+        }
+        changing.store.manage_memory(&mut trees);
+        Ok(SchemaResponse { tree_name, schema: Some(schema), nonconforming: Some(nonconforming) })
+    }).await;
+    drop(permit);
+
+    match changed {
+        Ok(Ok(response)) => {
+            emit_audit_event(&state, json!({
+                "event": "schema_changed",
+                "tree_name": response.tree_name,
+                "forced": force,
+                "nonconforming": response.nonconforming,
+            }));
+            HttpResponse::Ok().json(response)
+        }
+        Ok(Err((status, body))) => HttpResponse::build(status).body(body),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Failed to set schema: {}", e)),
+    }
+}
+
+// Drops the metadata schema of a tree, so it takes any metadata again
+async fn delete_schema(query: Valid<TreeParams>, state: web::Data<APPState>) -> impl Responder {
+    let tree_name = &query.tree_name;
+    if let Err(response) = ensure_hot(&state, tree_name).await {
+        return response;
+    }
+    let mut trees = state.store.trees.lock().unwrap();
+    if let Some(operation) = trees.get(tree_name).and_then(|cache| cache.operation.as_ref()) {
+        return HttpResponse::Conflict().json(operation.conflict(tree_name));
+    }
+    if let Err((status, body)) = load_into_cache(&state, &mut trees, tree_name) {
+        return HttpResponse::build(status).body(body);
+    }
+    let cache = trees.get_mut(tree_name).unwrap();
+    cache.tree_mut().unwrap().set_schema(None);
+    if let Err(e) = cache.save_now(state.store.disk(), tree_name) {
+        cache.dirty = true;
+        return HttpResponse::InternalServerError().body(format!("Failed to save KD-Tree: {}", e));
+    }
+
+    state.store.manage_memory(&mut trees);
+    drop(trees);
+    emit_audit_event(&state, json!({ "event": "schema_changed", "tree_name": tree_name, "removed": true }));
+    HttpResponse::Ok().json(SchemaResponse { tree_name: tree_name.clone(), schema: None, nonconforming: None })
+}
+
+// Logs a destructive change and posts it to AUDIT_WEBHOOK_URL when one is set. The post
+// runs on a blocking thread and its failure is only logged.
+fn emit_audit_event(state: &APPState, event: serde_json::Value) {
+    println!("AUDIT: {}", event);
+    if let Some(url) = state.settings.audit_webhook_url.clone() {
+        actix_web::rt::task::spawn_blocking(move || {
+            if let Err(e) = ureq::post(&url).set("Content-Type", "application/json").send_string(&event.to_string()) {
+                println!("Failed to send audit webhook: {}", e);
+            }
+        });
+    }
+}
+
+// Creates an empty tree up front, which is the only way to declare a partition field
+async fn create_tree(query: Valid<CreateTreeParams>, state: web::Data<APPState>) -> impl Responder {
+    let tree_name = &query.tree_name;
+    if let Some(response) = disk_rejection(&state, 0) {
+        return response;
+    }
+    let mut trees = state.store.trees.lock().unwrap();
+    if trees.get(tree_name).is_some_and(|cache| cache.dimensions.is_some() || cache.tier != Tier::Hot)
+        || state.store.has_file(tree_name)
+        || get_archive_file_path(&state.archive.directory, tree_name).exists()
+    {
+        return HttpResponse::Conflict().body(format!("Tree {} already exists", tree_name));
+    }
+
+    let dimensions = query.dimensions.unwrap_or_default();
+    if dimensions > state.settings.max_dimensions {
+        return HttpResponse::BadRequest().body(format!(
+            "Tree {} would have {} dimensions but MAX_DIMENSIONS is {}",
+            tree_name, dimensions, state.settings.max_dimensions
+        ));
+    }
+    let seed = query.project_to.map(|_| query.seed.unwrap_or(DEFAULT_SEED));
+    let stored_dimensions = query.project_to.unwrap_or(dimensions);
+    let created = match &query.partition_field {
+        Some(field) => KDTree::with_partition_field(stored_dimensions, field),
+        None => KDTree::new(stored_dimensions),
+    };
+    let created = match (created, seed) {
+        (Ok(tree), Some(seed)) => tree.with_reduction(RandomProjection::new(seed, dimensions, stored_dimensions)),
+        (created, _) => created,
+    };
+    let mut tree = match created {
+        Ok(tree) => tree,
+        Err(e) => return HttpResponse::BadRequest().body(format!("Failed to create KD-Tree: {}", e)),
+    };
+    tree.set_schema(query.schema());
+    tree.set_model(query.model.clone());
+    if let Some(Err(e)) = state.store.disk().map(|bin_directory| offload_tree(bin_directory, tree_name, &tree)) {
+        return HttpResponse::InternalServerError().body(format!("Failed to save KD-Tree: {}", e));
+    }
+    let cache = trees.entry(tree_name.clone()).or_default();
+    cache.set_tree(tree);
+    cache.offloaded_strategy = query.offloaded_strategy;
+
+    state.store.manage_memory(&mut trees);
+    HttpResponse::Ok().json(CreateTreeResponse {
+        tree_name: tree_name.clone(),
+        dimensions,
+        partition_field: query.partition_field.clone(),
+        project_to: query.project_to,
+        seed,
+        schema: query.schema(),
+        model: query.model.clone(),
+        offloaded_strategy: query.offloaded_strategy,
+    })
+}
+
+// Administrative endpoint: per-partition record counts. Unlike /status this needs the
+// tree itself, so an offloaded tree is loaded, but LRU recency is left alone.
+async fn get_stats(query: Valid<StatsParams>, state: web::Data<APPState>) -> impl Responder {
+    let tree_name = &query.tree_name;
+    if let Err(response) = ensure_hot(&state, tree_name).await {
+        return response;
+    }
+    let mut trees = state.store.trees.lock().unwrap();
+    if let Err((status, body)) = load_into_cache(&state, &mut trees, tree_name) {
+        return HttpResponse::build(status).body(body);
+    }
+
+    let tree = trees[tree_name].tree.as_ref().unwrap();
+    let partitions = tree.partition_counts();
+    let num_records = tree.len();
+    let validated = query.validate.unwrap_or(false);
+    let violation = if validated { tree.validate().err() } else { None };
+    let response = StatsResponse {
+        tree_name: tree_name.clone(),
+        num_records,
+        dimensions: tree.input_dimensions(),
+        stored_dimensions: tree.dimensions(),
+        projection_seed: tree.reduction().map(RandomProjection::seed),
+        depth: tree.depth(),
+        partition_field: tree.partition_field().map(str::to_string),
+        schema: tree.schema().cloned(),
+        model: tree.model().map(str::to_string),
+        unpartitioned: num_records - partitions.values().sum::<usize>(),
+        partitions,
+        distribution: tree.stats().summary(),
+        validated,
+        violation,
+    };
+
+    state.store.manage_memory(&mut trees);
+    HttpResponse::Ok().json(response)
+}
+
+// Shape of a tree for visualization, as JSON or Graphviz DOT. Walked off the lock on the
+// current version of the tree, and bounded in depth and node count.
+async fn tree_structure(query: Valid<TreeStructureParams>, state: web::Data<APPState>) -> impl Responder {
+    let tree_name = query.tree_name.clone();
+    if let Err(response) = ensure_hot(&state, &tree_name).await {
+        return response;
+    }
+    let max_depth = query.max_depth.unwrap_or(DEFAULT_STRUCTURE_DEPTH);
+    let walking = state.clone();
+    let described = web::block(move || {
+        let tree = shared_tree(&walking, &tree_name)?;
+        Ok(structure::describe(&tree, &tree_name, max_depth))
+    }).await;
+    let structure = match described {
+        Ok(Ok(structure)) => structure,
+        Ok(Err((status, body))) => return HttpResponse::build(status).body(body),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Failed to walk tree: {}", e)),
+    };
+    match query.format.unwrap_or_default() {
+        StructureFormat::Json => HttpResponse::Ok().json(structure),
+        StructureFormat::Dot => HttpResponse::Ok().content_type("text/vnd.graphviz").body(structure::to_dot(&structure)),
+    }
+}
+
+// Compares the embedding distribution of one tree against another from the statistics
+// both maintain on insert, so neither tree is scanned
+async fn get_drift(query: Valid<DriftParams>, state: web::Data<APPState>) -> impl Responder {
+    let (tree_name, against) = (&query.tree_name, &query.against);
+    for name in [tree_name, against] {
+        if let Err(response) = ensure_hot(&state, name).await {
+            return response;
+        }
+    }
+    let mut trees = state.store.trees.lock().unwrap();
+    for name in [tree_name, against] {
+        if let Err((status, body)) = load_into_cache(&state, &mut trees, name) {
+            return HttpResponse::build(status).body(body);
+        }
+    }
+
+    let (stats, other) = (trees[tree_name].tree.as_ref().unwrap().stats(), trees[against].tree.as_ref().unwrap().stats());
+    if stats.dimensions() != other.dimensions() {
+        return HttpResponse::BadRequest().body(format!(
+            "Tree {} has {} dimensions but {} has {}",
+            tree_name, stats.dimensions(), against, other.dimensions()
+        ));
+    }
+    if let Some(empty) = [(tree_name, stats), (against, other)].iter().find(|(_, stats)| stats.count() == 0) {
+        return HttpResponse::BadRequest().body(format!("Tree {} is empty", empty.0));
+    }
+    let drift = stats.drift(other);
+    let response = DriftResponse {
+        tree_name: tree_name.clone(),
+        against: against.clone(),
+        score: drift.score,
+        max_abs_smd: drift.max_abs_smd,
+        max_dimension: drift.max_dimension,
+        shifted_dimensions: drift.shifted_dimensions,
+        per_dimension: drift.per_dimension,
+    };
+
+    state.store.manage_memory(&mut trees);
+    HttpResponse::Ok().json(response)
+}
+
+// Makes sure a tree is in memory for a read that needs the whole tree, loading it from
+// disk if it was offloaded
+fn load_into_cache(state: &APPState, trees: &mut HashMap<String, KDTreeCache>, tree_name: &str) -> Result<(), (StatusCode, String)> {
+    state.store.collection(tree_name).load(trees).map_err(|e| collection_error(state, tree_name, e))
+}
+
+// Notes for /status how reading a known tree's file outside the cache went
+fn record_load<T>(state: &APPState, tree_name: &str, read: &Result<T, KdTreeError>) {
+    if let Some(cache) = state.store.trees.lock().unwrap().get_mut(tree_name) {
+        cache.record_load(read);
+    }
+}
+
+// The error to answer with when loading a tree failed. A file found corrupt is moved
+// aside to `<tree>.bin.corrupt`, so later requests fail fast instead of reading it again
+// and nothing gets written over it before someone has looked.
+fn tree_error(state: &APPState, tree_name: &str, error: KdTreeError) -> ApiError {
+    if let KdTreeError::Corrupt { .. } = &error {
+        if get_bin_file_path(&state.store.bin_directory, tree_name).exists() {
+            match quarantine_tree(&state.store.bin_directory, tree_name) {
+                Ok(path) => emit_audit_event(state, json!({
+                    "event": "tree_quarantined", "tree_name": tree_name, "path": path, "error": error.to_string(),
+                })),
+                Err(e) => println!("Failed to quarantine corrupt tree {}: {}", tree_name, e),
+            }
+        }
+    }
+    ApiError::tree(tree_name, error)
+}
+
+// The store's budget check, counted and answered with 507
+fn check_memory_budget(state: &APPState, tree_name: &str, required: usize) -> Result<(), (StatusCode, String)> {
+    state.store.check_memory_budget(tree_name, required).map_err(|e| collection_error(state, tree_name, CollectionError::OverBudget(e)))
+}
+
+// Streams the points matching the filters as NDJSON in sequence order. Every line
+// carries the point's `seq`, so an interrupted export resumes with `since_seq`.
+// The selection walks a shared version of the tree without holding the trees lock, so
+// inserts go on and the export sees the points as of its start. Only matching points are
+// copied out; the rest are just visited. A client that disconnects stops the selection,
+// or the stream, where it got to.
+async fn export_tree(query: Valid<ExportParams>, state: web::Data<APPState>) -> impl Responder {
+    let tree_name = query.tree_name.clone();
+    if let Err(response) = ensure_hot(&state, &tree_name).await {
+        return response;
+    }
+    let Some(permit) = state.heavy.acquire().await else {
+        return heavy_rejection();
+    };
+
+    let filter = query.filter();
+    let partition = query.partition.clone();
+    let selecting = state.clone();
+    let cancel = CancellationToken::new();
+    let guard = cancel.guard();
+    let selected = web::block(move || {
+        let started = Instant::now();
+        let points = select_points(&selecting, &tree_name, partition.as_deref(), &filter, &cancel)?;
+        if points.is_none() {
+            println!("Export of tree {} cancelled by client while selecting, after {}ms", tree_name, started.elapsed().as_millis());
+            Metrics::incr(&selecting.metrics.exports_cancelled);
+        }
+        Ok(points)
+    }).await;
+    guard.disarm();
+    drop(permit);
+    let points = match selected {
+        Ok(Ok(Some(points))) => points,
+        Ok(Ok(None)) => return HttpResponse::InternalServerError().body("Export cancelled"),
+        Ok(Err((status, body))) => return HttpResponse::build(status).body(body),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Export failed: {}", e)),
+    };
+
+    // Known now that selecting loaded the tree
+    let model = state.store.trees.lock().unwrap().get(&query.tree_name).and_then(|cache| cache.model.clone());
+    let projection = query.projection(state.settings.float_precision);
+    let mut progress = ExportProgress { state: state.clone(), tree_name: query.tree_name.clone(), sent: 0, total: points.len() };
+    let lines = futures_util::stream::iter(points.into_iter().map(move |point| {
+        progress.advance();
+        let mut line = projection.project(&point, None);
+        line["seq"] = json!(point.seq);
+        line["inserted_at"] = json!(point.inserted_at);
+        let mut bytes = line.to_string().into_bytes();
+        bytes.push(b'\n');
+        Ok::<_, Infallible>(Bytes::from(bytes))
+    }));
+    let mut response = HttpResponse::Ok();
+    response.content_type("application/x-ndjson");
+    if let Some(model) = model.and_then(|model| HeaderValue::from_str(&model).ok()) {
+        response.insert_header((HeaderName::from_static("x-embedding-model"), model));
+    }
+    response.streaming(lines)
+}
+
+// Lines an export stream has produced. The stream owns it, so it is dropped with the
+// stream, which happens early when the client disconnects.
+struct ExportProgress {
+    state: web::Data<APPState>,
+    tree_name: String,
+    sent: usize,
+    total: usize,
+}
+
+impl ExportProgress {
+    fn advance(&mut self) {
+        self.sent += 1;
+    }
+}
+
+impl Drop for ExportProgress {
+    fn drop(&mut self) {
+        if self.sent < self.total {
+            println!("Export of tree {} cancelled by client after {} of {} points", self.tree_name, self.sent, self.total);
+            Metrics::incr(&self.state.metrics.exports_cancelled);
+        }
+    }
+}
+
+// Serves the tree file, or a byte range of it (`offset`/`length` or a `Range` header) so a
+// download can resume after a dropped connection. The ETag is the file's SHA-256: HEAD
+// always sends it, GET once it is known. A GET whose `If-Match` names an older hash gets
+// a 412, as the file was rewritten in between and the pieces would not fit together.
+async fn get_snapshot(request: HttpRequest, query: Valid<SnapshotParams>, state: web::Data<APPState>) -> impl Responder {
+    let tree_name = query.tree_name.clone();
+    if let Some(response) = persistence_rejection(&state, "/snapshot") {
+        return response;
+    }
+    if let Err(response) = ensure_hot(&state, &tree_name).await {
+        return response;
+    }
+    let head = request.method() == Method::HEAD;
+    if head {
+        // A download starts with a HEAD, so it includes every insert acknowledged before
+        let mut trees = state.store.trees.lock().unwrap();
+        if let Some(cache) = trees.get_mut(&tree_name).filter(|cache| cache.dirty) {
+            if let Err(e) = cache.save_now(state.store.disk(), &tree_name) {
+                cache.dirty = true;
+                return HttpResponse::InternalServerError().body(format!("Failed to save KD-Tree: {}", e));
+            }
+        }
+    }
+
+    let path = get_bin_file_path(&state.store.bin_directory, &tree_name);
+    let if_match = request.headers().get(header::IF_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().trim_matches('"').to_ascii_lowercase());
+    let hashed = if head || if_match.is_some() {
+        match snapshot_hash(&state, &path, &tree_name).await {
+            Ok(hashed) => Some(hashed),
+            Err(response) => return response,
+        }
+    } else {
+        None
+    };
+
+    let mut file = match fs::File::open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return HttpResponse::NotFound().body(format!("Tree {} not found", tree_name));
+        }
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Cannot read tree {}: {}", tree_name, e)),
+    };
+    let version = match FileVersion::of(&file) {
+        Ok(version) => version,
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Cannot read tree {}: {}", tree_name, e)),
+    };
+    let hash = match hashed {
+        Some((hashed_version, hash)) if hashed_version == version => Some(hash),
+        Some(_) => None,
+        None => state.snapshot_hashes.get(&path, version),
+    };
+    if if_match.is_some() && if_match != hash {
+        return HttpResponse::PreconditionFailed().body(format!(
+            "Tree {} has changed since that hash; download it again from the start",
+            tree_name
+        ));
+    }
+
+    let len = version.len;
+    let range = match (query.offset, query.length) {
+        (None, None) => request.headers().get(header::RANGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| parse_range(value, len)),
+        (offset, length) => {
+            let start = offset.unwrap_or(0);
+            Some(if start >= len {
+                Err(())
+            } else {
+                Ok((start, length.map_or(len, |length| start.saturating_add(length).min(len))))
+            })
+        }
+    };
+    let (mut response, start, end) = match range {
+        None => (HttpResponse::Ok(), 0, len),
+        Some(Ok((start, end))) => {
+            let mut response = HttpResponse::PartialContent();
+            response.insert_header((header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end - 1, len)));
+            (response, start, end)
+        }
+        Some(Err(())) => {
+            return HttpResponse::RangeNotSatisfiable()
+                .insert_header((header::CONTENT_RANGE, format!("bytes */{}", len)))
+                .finish();
+        }
+    };
+    response.content_type("application/octet-stream").insert_header((header::ACCEPT_RANGES, "bytes"));
+    if let Some(hash) = hash {
+        response.insert_header((header::ETAG, format!("\"{}\"", hash)));
+    }
+    if let Err(e) = file.seek(SeekFrom::Start(start)) {
+        return HttpResponse::InternalServerError().body(format!("Cannot read tree {}: {}", tree_name, e));
+    }
+    response.no_chunking(end - start).streaming(file_stream(file, start, end))
+}
+
+// The SHA-256 of a tree file, read off the async threads unless the current version is
+// already hashed
+async fn snapshot_hash(state: &web::Data<APPState>, path: &Path, tree_name: &str) -> Result<(FileVersion, String), HttpResponse> {
+    let cached = fs::File::open(path)
+        .and_then(|file| FileVersion::of(&file))
+        .ok()
+        .and_then(|version| Some((version, state.snapshot_hashes.get(path, version)?)));
+    if let Some(hashed) = cached {
+        return Ok(hashed);
+    }
+    let Some(_permit) = state.heavy.acquire().await else {
+        return Err(heavy_rejection());
+    };
+    let (hashing, path) = (state.clone(), path.to_path_buf());
+    match web::block(move || hashing.snapshot_hashes.hash(&path)).await {
+        Ok(Ok(hashed)) => Ok(hashed),
+        Ok(Err(e)) if e.kind() == io::ErrorKind::NotFound => {
+            Err(HttpResponse::NotFound().body(format!("Tree {} not found", tree_name)))
+        }
+        Ok(Err(e)) => Err(HttpResponse::InternalServerError().body(format!("Cannot hash tree {}: {}", tree_name, e))),
+        Err(e) => Err(HttpResponse::InternalServerError().body(format!("Cannot hash tree {}: {}", tree_name, e))),
+    }
+}
+
+// Reads `start..end` of an open file in blocks on the blocking pool. A file that shrinks
+// underneath ends the stream with an error, which aborts the response.
+fn file_stream(file: fs::File, start: u64, end: u64) -> impl futures_util::Stream<Item = Result<Bytes, io::Error>> {
+    futures_util::stream::try_unfold((file, start), move |(mut file, position)| async move {
+        if position >= end {
+            return Ok(None);
+        }
+        let size = (end - position).min(SNAPSHOT_BLOCK_BYTES) as usize;
+        let (file, bytes) = web::block(move || {
+            let mut bytes = vec![0; size];
+            file.read_exact(&mut bytes)?;
+            Ok::<_, io::Error>((file, bytes))
+        }).await.map_err(io::Error::other)??;
+        Ok(Some((Bytes::from(bytes), (file, position + size as u64))))
+    })
+}
+
+// Stages one chunk of a tree file for `/restore/commit`
+async fn upload_chunk(query: Valid<RestoreChunkParams>, body: Bytes, state: web::Data<APPState>) -> impl Responder {
+    if let Some(response) = persistence_rejection(&state, "/restore") {
+        return response;
+    }
+    if let Some(response) = disk_rejection(&state, body.len() as u64) {
+        return response;
+    }
+    let tree_name = query.tree_name.clone();
+    let path = get_upload_file_path(&state.store.bin_directory, &tree_name);
+    let offset = query.offset;
+    match web::block(move || write_chunk(&path, offset, &body)).await {
+        Ok(Ok(staged)) => HttpResponse::Ok().json(UploadResponse { tree_name, staged }),
+        Ok(Err(e)) if e.kind() == io::ErrorKind::InvalidInput => HttpResponse::BadRequest().body(e.to_string()),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("Failed to stage upload: {}", e)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Failed to stage upload: {}", e)),
+    }
+}
+
+// How much of an upload has arrived, for resuming it
+async fn upload_status(query: Valid<TreeParams>, state: web::Data<APPState>) -> impl Responder {
+    if let Some(response) = persistence_rejection(&state, "/restore") {
+        return response;
+    }
+    let staged = staged_len(&get_upload_file_path(&state.store.bin_directory, &query.tree_name));
+    HttpResponse::Ok().json(UploadResponse { tree_name: query.tree_name.clone(), staged })
+}
+
+// Structural operation: replaces the tree with the staged upload once its SHA-256 matches
+// and it loads as a tree. A mismatch leaves both the tree and the upload alone.
+async fn commit_restore(query: Valid<RestoreCommitParams>, state: web::Data<APPState>) -> impl Responder {
+    let tree_name = query.tree_name.clone();
+    let run_async = query.run_async;
+    run_job(&state, JobKind::Restore, Some(&tree_name), run_async, |job| restore_upload(query, state.clone(), job)).await
+}
+
+async fn restore_upload(query: Valid<RestoreCommitParams>, state: web::Data<APPState>, job: Arc<Job>) -> HttpResponse {
+    let tree_name = query.tree_name.clone();
+    if let Some(response) = persistence_rejection(&state, "/restore/commit") {
+        return response;
+    }
+    if let Err(response) = ensure_hot(&state, &tree_name).await {
+        return response;
+    }
+    let upload = get_upload_file_path(&state.store.bin_directory, &tree_name);
+    if !upload.exists() {
+        return HttpResponse::NotFound().body(format!("No upload staged for tree {}", tree_name));
+    }
+    let Some(permit) = state.heavy.acquire().await else {
+        return heavy_rejection();
+    };
+
+    let expected = query.sha256.to_ascii_lowercase();
+    let (loading, name, path) = (state.clone(), tree_name.clone(), upload.clone());
+    let loaded = web::block(move || {
+        let hash = fs::File::open(&path)
+            .and_then(|mut file| sha256_of(&mut file))
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Cannot read upload for tree {}: {}", name, e)))?;
+        if hash != expected {
+            return Err((StatusCode::BAD_REQUEST, format!(
+                "Upload for tree {} has SHA-256 {} but {} was expected",
+                name, hash, expected
+            )));
+        }
+        let (tree, _) = KDTree::load_versioned(path.to_str().unwrap())
+            .map_err(|e| (StatusCode::BAD_REQUEST, format!("Upload for tree {} is not a tree file: {}", name, e)))?;
+        check_memory_budget(&loading, &name, estimate_memory_usage(&tree))?;
+        Ok((tree, hash))
+    }).await;
+    drop(permit);
+    let (tree, hash) = match loaded {
+        Ok(Ok(loaded)) => loaded,
+        Ok(Err((status, body))) => return HttpResponse::build(status).body(body),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Restore failed: {}", e)),
+    };
+    if let Some(response) = job_cancelled(&job) {
+        return response;
+    }
+
+    let mut trees = state.store.trees.lock().unwrap();
+    if let Some(operation) = trees.get(&tree_name).and_then(|cache| cache.operation.as_ref()) {
+        return HttpResponse::Conflict().json(operation.conflict(&tree_name));
+    }
+    let cache = trees.entry(tree_name.clone()).or_default();
+    let created = cache.dimensions.is_none();
+    // Outdates every snapshot taken before, so no pending write lands on the restored file
+    cache.write_seq += 1;
+    {
+        let mut persisted = cache.persisted.lock().unwrap();
+        if let Err(e) = fs::rename(&upload, get_bin_file_path(&state.store.bin_directory, &tree_name)) {
+            return HttpResponse::InternalServerError().body(format!("Failed to replace tree {}: {}", tree_name, e));
+        }
+        *persisted = cache.write_seq;
+    }
+
+    if let Some(settings) = state.bloom {
+        let filter = BloomFilter::from_tree(&tree, settings);
+        if let Err(e) = state.store.save_bloom(&tree_name, &filter) {
+            println!("Failed to save duplicate filter for tree {}: {}", tree_name, e);
+        }
+        cache.bloom = Some(filter);
+    }
+    if let Some(query_cache) = &state.query_cache {
+        query_cache.invalidate(&tree_name);
+    }
+    let dimensions = tree.input_dimensions();
+    let old = cache.tree.take();
+    cache.set_tree(tree);
+    cache.dirty = false;
+    let num_records = cache.num_records;
+    if created {
+        if let Err(e) = state.store.save_manifest(&trees) {
+            println!("Failed to save manifest: {}", e);
+        }
+    }
+    state.store.manage_memory(&mut trees);
+    drop(trees);
+    drop(old);
+
+    emit_audit_event(&state, json!({ "event": "tree_restored", "tree_name": tree_name, "num_records": num_records }));
+    HttpResponse::Ok().json(RestoreResponse { tree_name, num_records, dimensions, sha256: hash })
+}
+
+// Administrative endpoint: a uniform random sample of the points matching the filters,
+// drawn in a single traversal that only keeps the sample. Loads an offloaded tree but
+// leaves LRU recency alone. The traversal stops if the client disconnects.
+async fn sample_tree(query: Valid<SampleParams>, state: web::Data<APPState>) -> impl Responder {
+    let tree_name = query.tree_name.clone();
+    if let Err(response) = ensure_hot(&state, &tree_name).await {
+        return response;
+    }
+    let Some(permit) = state.heavy.acquire().await else {
+        return heavy_rejection();
+    };
+
+    let seed = query.seed.unwrap_or_else(|| {
+        SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64)
+    });
+    let count = query.count.unwrap_or(DEFAULT_SAMPLE_COUNT);
+    let (filter, partition, projection) = (query.filter(), query.partition.clone(), query.projection(state.settings.float_precision));
+    let sampling = state.clone();
+    let cancel = CancellationToken::new();
+    let guard = cancel.guard();
+    let sampled = web::block(move || {
+        let started = Instant::now();
+        let tree = shared_tree(&sampling, &tree_name)?;
+        if partition.is_some() && tree.partition_field().is_none() {
+            return Err((StatusCode::BAD_REQUEST, format!("Tree {} is not partitioned", tree_name)));
+        }
+        check_filter_schema(tree.schema(), &tree_name, filter.metadata.as_ref())?;
+        let mut rng = SplitMix64::new(seed);
+        let sampled = tree.sample(partition.as_deref(), count, &mut rng, |point| filter.matches(point), &cancel);
+        if sampled.is_err() {
+            println!("Sample of tree {} cancelled by client after {}ms", tree_name, started.elapsed().as_millis());
+        }
+        let points = sampled.ok().map(|(points, matched)| {
+            let points: Vec<_> = points.into_iter().map(|point| {
+                let mut hit = projection.project(point, None);
+                hit["seq"] = json!(point.seq);
+                hit
+            }).collect();
+            (points, matched)
+        });
+        Ok(points)
+    }).await;
+    guard.disarm();
+    drop(permit);
+
+    match sampled {
+        Ok(Ok(None)) => HttpResponse::InternalServerError().body("Sampling cancelled"),
+        Ok(Ok(Some((points, matched)))) => HttpResponse::Ok().json(json!({
+            "tree_name": query.tree_name,
+            "seed": seed,
+            "matched": matched,
+            "points": points,
+        })),
+        Ok(Err((status, body))) => HttpResponse::build(status).body(body),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Sampling failed: {}", e)),
+    }
+}
+
+// Administrative endpoint: runs queries through the tree search and through a scan of
+// every point, and compares the distances each returns, to catch pruning bugs in
+// production. Queries are the body's, or stored points sampled uniformly. Every query
+// runs against one shared version of the tree without the trees lock, so requests keep
+// being served while the audit runs, and the audit stops between queries if the client
+// disconnects.
+async fn audit_search(query: Valid<AuditSearchParams>, body: Bytes, state: web::Data<APPState>) -> impl Responder {
+    let tree_name = query.tree_name.clone();
+    let run_async = query.run_async;
+    run_job(&state, JobKind::AuditSearch, Some(&tree_name), run_async, |job| audit(query, body, state.clone(), job)).await
+}
+
+async fn audit(query: Valid<AuditSearchParams>, body: Bytes, state: web::Data<APPState>, job: Arc<Job>) -> HttpResponse {
+    let queries = match body.is_empty() {
+        true => None,
+        false => match serde_json::from_slice::<AuditSearchBody>(&body) {
+            Ok(body) if (1..=MAX_AUDIT_SAMPLES).contains(&body.queries.len()) => Some(body.queries),
+            Ok(_) => return HttpResponse::BadRequest().body(format!("queries: must hold between 1 and {} queries", MAX_AUDIT_SAMPLES)),
+            Err(e) => return HttpResponse::BadRequest().body(format!("Invalid audit body: {}", e)),
+        },
+    };
+    let tree_name = query.tree_name.clone();
+    if let Err(response) = ensure_hot(&state, &tree_name).await {
+        return response;
+    }
+    let Some(permit) = state.heavy.acquire().await else {
+        return heavy_rejection();
+    };
+
+    let n = query.n.unwrap_or(DEFAULT_AUDIT_N);
+    let budget = Duration::from_millis(query.budget_ms.unwrap_or(DEFAULT_AUDIT_BUDGET_MS));
+    let seed = queries.is_none().then(|| query.seed.unwrap_or_else(|| {
+        SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64)
+    }));
+    let samples = query.samples.unwrap_or(DEFAULT_AUDIT_SAMPLES);
+    let auditing = state.clone();
+    let cancel = job.cancel.clone();
+    let guard = cancel.guard();
+    let progress = job.clone();
+    let audited = web::block(move || {
+        let started = Instant::now();
+        let tree = shared_tree(&auditing, &tree_name)?;
+        // Each query as stored in the tree, with the point it was sampled from
+        let targets: Vec<(Point, Option<u64>)> = match (&queries, seed) {
+            (Some(queries), _) => queries.iter().enumerate().map(|(index, embedding)| {
+                if embedding.len() != tree.input_dimensions() {
+                    return Err((StatusCode::BAD_REQUEST, format!(
+                        "Query {} has {} dimensions but tree {} has {}",
+                        index, embedding.len(), tree_name, tree.input_dimensions()
+                    )));
+                }
+                let point = Point { embedding: embedding.clone(), ..Default::default() };
+                Ok((tree.reduce(Cow::Owned(point)).into_owned(), None))
+            }).collect::<Result<_, _>>()?,
+            (None, seed) => {
+                let mut rng = SplitMix64::new(seed.unwrap_or_default());
+                let Ok((points, _)) = tree.sample(None, samples, &mut rng, |_| true, &cancel) else {
+                    return Ok(None);
+                };
+                points.into_iter()
+                    .map(|point| (Point { embedding: point.embedding.clone(), ..Default::default() }, Some(point.seq)))
+                    .collect()
+            }
+        };
+        progress.total.store(targets.len() as u64, Ordering::Relaxed);
+
+        let mut response = AuditSearchResponse {
+            tree_name: tree_name.clone(),
+            n,
+            queries: 0,
+            requested: targets.len(),
+            recall: 1.0,
+            mismatched: 0,
+            elapsed_ms: 0,
+            seed,
+            divergences: Vec::new(),
+        };
+        let mut recall_sum = 0.0;
+        for (index, (target, seq)) in targets.iter().enumerate() {
+            if started.elapsed() >= budget {
+                break;
+            }
+            if progress.stop_if_cancelled() {
+                println!(
+                    "Search audit of tree {} cancelled after {} of {} queries in {}ms",
+                    tree_name, response.queries, response.requested, started.elapsed().as_millis()
+                );
+                return Ok(None);
+            }
+            let found: Vec<f64> = tree.nearest_neighbors_topn_scored(target, n).iter().map(|(distance, _)| *distance).collect();
+            let expected: Vec<f64> = tree.brute_force_topn(target, n).iter().map(|(distance, _)| *distance).collect();
+            // Points tied with the n-th closest are interchangeable, so results are
+            // compared by distance rather than by identity
+            let recall = match expected.last() {
+                Some(bound) => found.iter().filter(|distance| *distance <= bound).count().min(expected.len()) as f64 / expected.len() as f64,
+                None => 1.0,
+            };
+            recall_sum += recall;
+            response.queries += 1;
+            progress.processed.fetch_add(1, Ordering::Relaxed);
+            if found != expected {
+                response.mismatched += 1;
+                if response.divergences.len() < MAX_AUDIT_DIVERGENCES {
+                    response.divergences.push(AuditDivergence { query: index, seq: *seq, recall, tree: found, brute_force: expected });
+                }
+            }
+        }
+        if response.queries > 0 {
+            response.recall = recall_sum / response.queries as f64;
+        }
+        response.elapsed_ms = started.elapsed().as_millis() as u64;
+        Ok(Some(response))
+    }).await;
+    guard.disarm();
+    drop(permit);
+
+    match audited {
+        Ok(Ok(None)) => HttpResponse::InternalServerError().body("Search audit cancelled"),
+        Ok(Ok(Some(response))) => {
+            if response.mismatched > 0 {
+                println!(
+                    "WARNING: search audit of tree {}: {} of {} queries differ from a brute-force scan, recall@{} {:.4}",
+                    response.tree_name, response.mismatched, response.queries, response.n, response.recall
+                );
+            }
+            HttpResponse::Ok().json(response)
+        }
+        Ok(Err((status, body))) => HttpResponse::build(status).body(body),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Search audit failed: {}", e)),
+    }
+}
+
+// Administrative endpoint: checks the KD-tree invariant of every hot tree. Resident trees
+// are checked without holding the trees lock; offloaded ones are read from disk and
+// dropped again, so the cache is left as it was. Trees that fail are marked suspect, like a failed self-test.
+async fn verify_all(query: Valid<VerifyAllParams>, state: web::Data<APPState>) -> impl Responder {
+    run_job(&state, JobKind::VerifyAll, None, query.run_async, |job| verify(state.clone(), job)).await
+}
+
+async fn verify(state: web::Data<APPState>, job: Arc<Job>) -> HttpResponse {
+    let Some(permit) = state.heavy.acquire().await else {
+        return heavy_rejection();
+    };
+    let verifying = state.clone();
+    let verified = web::block(move || {
+        let (mut hot, mut skipped) = (Vec::new(), 0);
+        for (tree_name, cache) in verifying.store.trees.lock().unwrap().iter() {
+            if cache.tier == Tier::Hot { hot.push(tree_name.clone()) } else { skipped += 1 }
+        }
+        hot.sort();
+        job.total.store(hot.len() as u64, Ordering::Relaxed);
+
+        let mut trees = Vec::new();
+        for tree_name in hot {
+            if job.stop_if_cancelled() {
+                return None;
+            }
+            // Resident trees are checked on a shared version, off the lock
+            let resident = verifying.store.trees.lock().unwrap().get(&tree_name).and_then(KDTreeCache::shared_tree);
+            // An explicit check reads the file even while failed loads are backing off
+            let checked = match resident {
+                Some(tree) => Ok(tree.validate()),
+                None => {
+                    let read = verifying.store.read_tree(&tree_name, false);
+                    record_load(&verifying, &tree_name, &read);
+                    read.map(|tree| tree.validate())
+                }
+            };
+            let (violation, error) = match checked {
+                Ok(result) => (result.err(), None),
+                Err(e) => (None, Some(format!("Error loading tree: {}", e))),
+            };
+            if let Some(violation) = &violation {
+                if let Some(cache) = verifying.store.trees.lock().unwrap().get_mut(&tree_name) {
+                    cache.suspect = true;
+                }
+                println!("Tree {} failed verification: {}", tree_name, violation);
+            }
+            trees.push(TreeVerification { tree_name, valid: violation.is_none() && error.is_none(), violation, error });
+            job.processed.fetch_add(1, Ordering::Relaxed);
+        }
+        Some(VerifyAllResponse {
+            checked: trees.len(),
+            invalid: trees.iter().filter(|tree| !tree.valid).count(),
+            skipped,
+            trees,
+        })
+    }).await;
+    drop(permit);
+
+    match verified {
+        Ok(Some(response)) => HttpResponse::Ok().json(response),
+        Ok(None) => HttpResponse::Conflict().body("Verification was cancelled"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Verification failed: {}", e)),
+    }
+}
+
+// The tree for a read that walks all of it, loading it if needed. The trees lock is
+// released before returning: the read keeps this version however long it takes, and
+// inserts meanwhile go to a copy.
+fn shared_tree(state: &APPState, tree_name: &str) -> Result<Arc<KDTree>, (StatusCode, String)> {
+    let mut trees = state.store.trees.lock().unwrap();
+    load_into_cache(state, &mut trees, tree_name)?;
+    let tree = trees[tree_name].shared_tree().unwrap();
+    state.store.manage_memory(&mut trees);
+    Ok(tree)
+}
+
+// Copies out the points of `tree_name` matching `filter`, loading the tree if needed,
+// or None if `cancel` was cancelled first. Blocking: runs off the async workers.
+fn select_points(
+    state: &APPState,
+    tree_name: &str,
+    partition: Option<&str>,
+    filter: &Filter,
+    cancel: &CancellationToken,
+) -> Result<Option<Vec<Point>>, (StatusCode, String)> {
+    let tree = shared_tree(state, tree_name)?;
+    if partition.is_some() && tree.partition_field().is_none() {
+        return Err((StatusCode::BAD_REQUEST, format!("Tree {} is not partitioned", tree_name)));
+    }
+    check_filter_schema(tree.schema(), tree_name, filter.metadata.as_ref())?;
+    Ok(tree.select(partition, |point| filter.matches(point), cancel).ok().map(|points| points.into_iter().cloned().collect()))
+}
+
+// Refuses a filter that names metadata fields the tree's schema doesn't declare
+fn check_filter_schema(schema: Option<&Schema>, tree_name: &str, condition: Option<&Condition>) -> Result<(), (StatusCode, String)> {
+    match (schema, condition) {
+        (Some(schema), Some(condition)) => schema.check_condition(condition).map_err(|problem| {
+            (StatusCode::BAD_REQUEST, format!("Invalid filter for tree {}: {}", tree_name, problem))
+        }),
+        _ => Ok(()),
+    }
+}
+
+// Returned when the heavy request queue is full
+// Endpoints that work on tree files directly have nothing to work on without them
+fn persistence_rejection(state: &APPState, endpoint: &str) -> Option<HttpResponse> {
+    state.store.disk().is_none().then(|| {
+        HttpResponse::NotImplemented().body(format!("{} works on tree files, but persistence is disabled", endpoint))
+    })
+}
+
+// The 507 for a write of `needed` bytes that would leave less than MIN_FREE_DISK_MB free.
+// Only writes check: reads keep working on a full disk.
+fn disk_rejection(state: &APPState, needed: u64) -> Option<HttpResponse> {
+    let InsufficientSpace { available, required } = state.disk_space.check(needed).err()?;
+    Some(HttpResponse::InsufficientStorage().body(format!(
+        "Not enough disk space: {} bytes available, {} bytes required including the MIN_FREE_DISK_MB reserve",
+        available, required
+    )))
+}
+
+// The 503 for a write to a tree whose saves have failed for longer than
+// PERSISTENCE_DEADLINE_SECS, so changes that can't reach disk stop piling up in memory.
+// Writes are taken again once a save of the tree succeeds.
+fn degraded_rejection(state: &APPState, tree_name: &str) -> Option<HttpResponse> {
+    let health = Arc::clone(&state.store.trees.lock().unwrap().get(tree_name)?.save_health);
+    let failure = health.failure().filter(|_| state.persistence_degraded(&health))?;
+    Metrics::incr(&state.metrics.degraded_rejections);
+    Some(HttpResponse::ServiceUnavailable()
+        .insert_header(("Retry-After", DEGRADED_RETRY_AFTER_SECS.to_string()))
+        .body(format!(
+            "Tree {} is not accepting writes: saving it has failed for {}s ({} times in a row), last with: {}",
+            tree_name, failure.since.elapsed().as_secs(), failure.consecutive, failure.error
+        )))
+}
+
+// Content encodings request bodies may use; the extractors decode them
+const REQUEST_ENCODINGS: [&str; 6] = ["identity", "gzip", "x-gzip", "zstd", "br", "deflate"];
+
+fn unsupported_encoding(headers: &header::HeaderMap) -> Option<HttpResponse> {
+    let encoding = headers.get(header::CONTENT_ENCODING)?;
+    let supported = encoding.to_str().is_ok_and(|encoding| REQUEST_ENCODINGS.contains(&encoding.trim().to_ascii_lowercase().as_str()));
+    (!supported).then(|| HttpResponse::UnsupportedMediaType().body(format!(
+        "Unsupported Content-Encoding {:?}, send the body as gzip, zstd, br, deflate or uncompressed",
+        String::from_utf8_lossy(encoding.as_bytes())
+    )))
+}
+
+// A JSON body over MAX_PAYLOAD_MB once decompressed; other body errors keep their usual answer
+fn payload_too_large(error: JsonPayloadError, limit: usize) -> actix_web::Error {
+    match error {
+        JsonPayloadError::Overflow { .. } | JsonPayloadError::OverflowKnownLength { .. } => {
+            let response = HttpResponse::PayloadTooLarge().body(format!(
+                "Request body exceeds the MAX_PAYLOAD_MB limit of {} MB once decompressed; split it into smaller requests",
+                limit / (1024 * 1024)
+            ));
+            InternalError::from_response(error, response).into()
+        }
+        error => error.into(),
+    }
+}
+
+fn heavy_rejection() -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .insert_header(("Retry-After", HEAVY_RETRY_AFTER_SECS.to_string()))
+        .body("Too many heavy requests in progress, try again later")
+}
+
+// Who a request's budget is charged to: its X-API-Key, or its address when it sends none.
+// Keys are kept and reported only as a prefix of their SHA-256.
+fn rate_limit_client(request: &HttpRequest) -> String {
+    match request.headers().get("X-API-Key") {
+        Some(key) => {
+            let hash = sha256_of(&mut key.as_bytes()).unwrap_or_default();
+            format!("key:{}", &hash[..12])
+        }
+        None => format!("ip:{}", request.peer_addr().map(|address| address.ip().to_string()).unwrap_or_default()),
+    }
+}
+
+// Charges `cost` to the request's client, or why it is turned away. None when
+// RATE_LIMIT_BUDGET is 0.
+fn charge_budget(state: &APPState, request: &HttpRequest, cost: u64) -> Result<Option<Charge>, Exhausted> {
+    let Some(limiter) = &state.cost_limiter else {
+        return Ok(None);
+    };
+    limiter.charge(&rate_limit_client(request), cost).map(Some)
+}
+
+fn budget_exhausted(exhausted: Exhausted) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .insert_header(("Retry-After", exhausted.retry_after_secs.to_string()))
+        .insert_header(("X-RateLimit-Limit", exhausted.budget.to_string()))
+        .insert_header(("X-RateLimit-Remaining", exhausted.remaining.to_string()))
+        .insert_header(("X-RateLimit-Reset", exhausted.reset_secs.to_string()))
+        .insert_header(("X-RateLimit-Cost", exhausted.cost.to_string()))
+        .body(format!(
+            "Rate limit budget exhausted: this request costs {} and {} of {} per minute is left",
+            exhausted.cost, exhausted.remaining, exhausted.budget
+        ))
+}
+
+// Results a search collects while traversing, which its cost is reckoned per
+fn search_results(query: &SearchParams) -> usize {
+    let k = query.n.unwrap_or(1).saturating_add(query.offset.unwrap_or(0));
+    if query.group_by.is_some() {
+        k.saturating_mul(query.group_size.unwrap_or(1))
+    } else if query.diversity.is_some() {
+        k.saturating_mul(mmr::OVERFETCH).min(MAX_N)
+    } else {
+        k
+    }
+}
+
+fn filter_terms(query: &SearchParams) -> usize {
+    query.filter().map_or(0, |filter| filter.fields().len())
+}
+
+// Charges a search its estimated cost before anything else is done for it
+fn charge_search(state: &APPState, request: &HttpRequest, query: &SearchParams) -> Result<Option<Charge>, Exhausted> {
+    let Some(limiter) = &state.cost_limiter else {
+        return Ok(None);
+    };
+    charge_budget(state, request, limiter.search_cost(&query.tree_name, search_results(query), filter_terms(query)))
+}
+
+// Settles a search's charge with the nodes it visited, 0 when it was answered from the cache
+fn settle_search(state: &APPState, charge: Option<&Charge>, query: &SearchParams, visited: usize) {
+    if let (Some(limiter), Some(charge)) = (&state.cost_limiter, charge) {
+        let cost = limiter.measured(&query.tree_name, search_results(query), filter_terms(query), visited);
+        limiter.settle(charge, cost);
+    }
+}
+
+// Projects the footprint of a workload with the same accounting eviction uses, and
+// whether it would fit next to the trees resident right now
+async fn estimate_workload(workload: web::Json<Workload>, state: web::Data<APPState>) -> Result<HttpResponse, ApiError> {
+    workload.validate().map_err(ApiError::Validation)?;
+    let estimate = workload.estimate();
+    let resident_bytes = resident_memory_usage(&state.store.trees.lock().unwrap());
+    Ok(HttpResponse::Ok().json(json!({
+        "in_memory_bytes": estimate.in_memory_bytes,
+        "on_disk_bytes": estimate.on_disk_bytes,
+        "on_disk_compressed_bytes": estimate.on_disk_compressed_bytes,
+        "stored_precision": "f64",
+        "resident_bytes": resident_bytes,
+        "max_memory_bytes": state.store.options.max_memory_bytes,
+        "fits": resident_bytes + estimate.in_memory_bytes <= state.store.options.max_memory_bytes,
+    })))
+}
+
+async fn get_metrics(state: web::Data<APPState>) -> impl Responder {
+    state.metrics.heavy_in_flight.store(state.heavy.in_flight() as u64, Ordering::Relaxed);
+    state.metrics.heavy_queued.store(state.heavy.queued() as u64, Ordering::Relaxed);
+    let (trees, totals) = state.snapshot(None);
+    let latencies: Vec<_> = trees
+        .iter()
+        .filter_map(|tree| Some((tree.tree_name.clone(), state.store.usage.get(&tree.tree_name)?.latency.snapshot())))
+        .collect();
+    let locks = [(state.store.trees.name(), state.store.trees.waits())];
+    let clients = state.cost_limiter.as_ref().map(CostLimiter::clients).unwrap_or_default();
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(state.metrics.render(&trees, &totals, &locks, &latencies, &clients, state.settings.metrics_tree_labels))
+}
+
+// Administrative endpoint: every registered route, described from the tables the
+// server was built from
+async fn list_routes() -> impl Responder {
+    let routes = routes::describe(DATA_ROUTES, false).chain(routes::describe(ADMIN_ROUTES, true)).collect();
+    HttpResponse::Ok().json(RoutesResponse { version_prefix: V1_PREFIX.to_string(), routes })
+}
+
+// Administrative endpoint: the merged configuration the server is running with
+async fn get_config(state: web::Data<APPState>) -> impl Responder {
+    HttpResponse::Ok().json(state.settings.redacted())
+}
+
+// Administrative endpoint: reports cached facts only, never loads trees or touches LRU recency
+async fn get_status(request: HttpRequest, query: Valid<StatusParams>, state: web::Data<APPState>) -> impl Responder {
+    let (mut trees, totals) = state.snapshot(query.tree_name.as_deref());
+    let legacy = !request.path().starts_with(V1_PREFIX);
+    if !legacy {
+        trees.iter_mut().for_each(|tree| tree.last_accessed = None);
+    }
+    respond_with_etag(&request, &StatusResponse {
+        persistence: state.store.options.persistence,
+        disk: state.disk_space.status(),
+        active_trees: trees.len(),
+        unhealthy_trees: totals.unhealthy,
+        trees,
+        totals,
+        maintenance: state.maintenance.status(unix_seconds(SystemTime::now())),
+        deprecations: legacy.then(|| LAST_ACCESSED_DEPRECATION.to_string()).into_iter().collect(),
+    })
+}
+
+// Administrative endpoint: the cache entry of one tree, without loading it or touching
+// LRU recency
+async fn get_cache_entry(request: HttpRequest, query: Valid<TreeParams>, state: web::Data<APPState>) -> impl Responder {
+    let legacy = !request.path().starts_with(V1_PREFIX);
+    let trees = state.store.trees.lock().unwrap();
+    let Some(cache) = trees.get(&query.tree_name) else {
+        return HttpResponse::NotFound().body(format!("Tree {} not found", query.tree_name));
+    };
+    let tree_bytes = cache.tree.as_deref().map_or(0, estimate_memory_usage);
+    let bloom_bytes = cache.bloom.as_ref().map_or(0, BloomFilter::size_in_bytes);
+    HttpResponse::Ok().json(CacheEntry {
+        tree_name: query.tree_name.clone(),
+        tier: cache.tier,
+        in_memory: cache.tree.is_some(),
+        estimated_bytes: tree_bytes + bloom_bytes,
+        dirty: cache.dirty,
+        last_accessed: legacy.then(|| cache.last_accessed.elapsed().as_secs()),
+        last_accessed_at: accessed_at(cache).map(rfc3339),
+        seconds_since_access: accessed_at(cache).map(seconds_since),
+        access_count: cache.access_count,
+        operation: cache.operation.as_ref().map(TreeOperation::describe),
+        suspect: cache.suspect,
+        deprecations: legacy.then(|| LAST_ACCESSED_DEPRECATION.to_string()).into_iter().collect(),
+    })
+}
+
+// Administrative endpoint: drops the in-memory copy of a tree without saving it, so the
+// next request reloads it from disk. Unsaved changes are only thrown away when the
+// caller says so with `discard_unsaved=true`.
+async fn drop_cache_entry(query: Valid<DropCacheParams>, state: web::Data<APPState>) -> impl Responder {
+    let tree_name = &query.tree_name;
+    let mut trees = state.store.trees.lock().unwrap();
+    let Some(cache) = trees.get_mut(tree_name) else {
+        return HttpResponse::NotFound().body(format!("Tree {} not found", tree_name));
+    };
+    if let Some(operation) = &cache.operation {
+        return HttpResponse::Conflict().json(operation.conflict(tree_name));
+    }
+    let discard_unsaved = cache.tree.is_some() && cache.dirty;
+    if discard_unsaved && query.discard_unsaved != Some(true) {
+        return HttpResponse::Conflict().body(format!(
+            "Tree {} has unsaved changes; pass discard_unsaved=true to drop them",
+            tree_name
+        ));
+    }
+
+    let dropped = cache.tree.take().is_some();
+    cache.bloom = None;
+    cache.dirty = false;
+    if discard_unsaved {
+        // The saved filter may already hold the discarded points; rebuild it from the tree
+        if let Some(bin_directory) = state.store.disk() {
+            let _ = fs::remove_file(get_bloom_file_path(bin_directory, tree_name));
+        }
+        println!("Dropped tree {} from memory, discarding unsaved changes", tree_name);
+    }
+    if let Some(query_cache) = &state.query_cache {
+        query_cache.invalidate(tree_name);
+    }
+    HttpResponse::Ok().json(DropCacheResponse { tree_name: tree_name.clone(), dropped, discarded_unsaved: discard_unsaved })
+}
+
+// Administrative endpoint: every known tree and the tier its file is stored in
+async fn list_trees(request: HttpRequest, state: web::Data<APPState>) -> impl Responder {
+    let (trees, totals) = state.snapshot(None);
+    let summaries = trees.into_iter().map(|tree| TreeSummary {
+        tree_name: tree.tree_name,
+        tier: tree.tier,
+        num_records: tree.num_records,
+        dimensions: tree.dimensions,
+        model: tree.model,
+        last_accessed_at: tree.last_accessed_at,
+        seconds_since_access: tree.seconds_since_access,
+    }).collect();
+    respond_with_etag(&request, &TreesResponse { trees: summaries, totals })
+}
+
+// Administrative endpoint: how each tree was written over the last hour, with advice for
+// trees whose saves write far more than their inserts add
+async fn get_advisor(state: web::Data<APPState>) -> impl Responder {
+    state.count_writes();
+    let mut tree_names: Vec<String> = state.store.trees.lock().unwrap().keys().cloned().collect();
+    tree_names.sort();
+    let now = unix_seconds(SystemTime::now());
+    let mut window_secs = 0;
+    let trees: Vec<_> = tree_names.iter()
+        .filter_map(|tree_name| {
+            let recent = state.store.usage.get(tree_name)?.recent_writes(now);
+            window_secs = recent.window_secs;
+            (recent.inserts > 0 || recent.saves > 0).then(|| advisor::traffic(tree_name, &recent))
+        })
+        .collect();
+    let mut advised: Vec<_> = trees.iter()
+        .filter_map(|traffic| Some((traffic.write_amplification.unwrap_or_default(), advisor::advise(traffic)?)))
+        .collect();
+    advised.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+    HttpResponse::Ok().json(AdvisorResponse {
+        window_secs,
+        trees,
+        recommendations: advised.into_iter().map(|(_, recommendation)| recommendation).collect(),
+    })
+}
+
+// Administrative endpoint: hourly usage of every tree summed over a window, for
+// chargeback. Deleted trees are reported until their hours expire. Sent as CSV when
+// `Accept` asks for text/csv.
+async fn get_usage(request: HttpRequest, query: Valid<UsageParams>, state: web::Data<APPState>) -> impl Responder {
+    let retention_hours = state.store.usage.retention_hours();
+    if retention_hours == 0 {
+        return HttpResponse::Forbidden().body("Usage history is disabled; set USAGE_RETENTION_DAYS to keep it");
+    }
+    let now = unix_seconds(SystemTime::now());
+    let to = query.to.unwrap_or(now);
+    let from = query.from.unwrap_or(now.saturating_sub(retention_hours * HOUR_SECS));
+    let from = from - from % HOUR_SECS;
+    let trees: Vec<_> = state.store.usage.all().into_iter()
+        .filter(|(tree_name, _)| query.tree_name.as_ref().is_none_or(|wanted| wanted == tree_name))
+        .map(|(tree_name, usage)| usage::report(&tree_name, &usage.hours(from, to)))
+        .filter(|report| report.hours > 0)
+        .collect();
+    let response = UsageResponse {
+        from: rfc3339(UNIX_EPOCH + Duration::from_secs(from)),
+        to: rfc3339(UNIX_EPOCH + Duration::from_secs(to)),
+        retention_days: retention_hours / 24,
+        totals: usage::totals(&trees),
+        trees,
+    };
+    let wants_csv = request.headers().get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/csv"));
+    if wants_csv {
+        return HttpResponse::Ok().content_type("text/csv").body(usage::to_csv(&response));
+    }
+    HttpResponse::Ok().json(response)
+}
+
+// Fields that change with the clock alone. They are left out of ETags, or a polled status
+// would never be unchanged; a 304 can leave them a little stale.
+const CLOCK_FIELDS: [&str; 5] = ["last_accessed", "seconds_since_access", "elapsed_secs", "search_qps_1m", "search_qps_1h"];
+
+// Answers with the body and its ETag, or with an empty 304 when `If-None-Match` already
+// names that tag. The tag is a hash of the body without `CLOCK_FIELDS`.
+fn respond_with_etag(request: &HttpRequest, response: &impl Serialize) -> HttpResponse {
+    let body = match serde_json::to_value(response) {
+        Ok(body) => body,
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error serializing response: {}", e)),
+    };
+    let mut tagged = body.clone();
+    strip_fields(&mut tagged, &CLOCK_FIELDS);
+    let mut hasher = DefaultHasher::new();
+    tagged.to_string().hash(&mut hasher);
+    let etag = format!("\"{:016x}\"", hasher.finish());
+
+    let unchanged = request.headers().get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|tags| tags.split(',').map(str::trim).any(|tag| tag == etag || tag == "*"));
+    if unchanged {
+        return HttpResponse::NotModified().insert_header((header::ETAG, etag)).finish();
+    }
+    HttpResponse::Ok().insert_header((header::ETAG, etag)).json(body)
+}
+
+fn strip_fields(value: &mut serde_json::Value, fields: &[&str]) {
+    match value {
+        serde_json::Value::Object(object) => {
+            object.retain(|key, _| !fields.contains(&key.as_str()));
+            object.values_mut().for_each(|value| strip_fields(value, fields));
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(|value| strip_fields(value, fields)),
+        _ => {}
+    }
+}
+
+// Writes every tree left dirty by `durability=none` inserts, snapshotting under the
+// lock and writing after it is released. A failed write is retried as
+// SAVE_RETRY_ATTEMPTS allows, then the tree stays dirty and later flushes pass it over
+// for a backoff that grows with every failed flush, unless `all` (at shutdown).
+fn flush_dirty_trees(state: &APPState, all: bool) {
+    // Keeps the low disk space warning current while nothing else writes. Flushes still
+    // go ahead below the reserve: they save changes that were already acknowledged.
+    state.disk_space.available();
+    let pending: Vec<(String, io::Result<Option<PendingWrite>>)> = {
+        let mut trees = state.store.trees.lock().unwrap();
+        trees.iter_mut()
+            .filter(|(_, cache)| cache.dirty && (all || !cache.save_health.backing_off()))
+            .map(|(tree_name, cache)| (tree_name.clone(), cache.snapshot(state.store.disk(), tree_name)))
+            .collect()
+    };
+    let policy = state.save_retry_policy();
+    for (tree_name, snapshot) in pending {
+        let written = snapshot.and_then(|pending| pending.map_or(Ok(Durability::None), |pending| pending.write_with(false, &policy)));
+        if let Err(e) = written {
+            Metrics::incr(&state.metrics.flush_failures);
+            println!("Failed to flush tree {}: {}", tree_name, e);
+            if let Some(cache) = state.store.trees.lock().unwrap().get_mut(&tree_name) {
+                cache.dirty = true;
+            }
+        }
+    }
+}
+
+// Makes sure `tree_name` is not archived before a request touches it. An archived tree
+// starts restoring on its first request; the caller then waits up to `restore_wait`
+// and otherwise gets a 503 with Retry-After while the restore carries on.
+async fn ensure_hot(state: &web::Data<APPState>, tree_name: &str) -> Result<(), HttpResponse> {
+    let deadline = Instant::now() + state.archive.restore_wait;
+    loop {
+        let start_restore = {
+            let mut trees = state.store.trees.lock().unwrap();
+            let Some(cache) = trees.get_mut(tree_name) else { return Ok(()) };
+            match cache.tier {
+                Tier::Hot => return Ok(()),
+                Tier::Archived => {
+                    cache.tier = Tier::Restoring;
+                    true
+                }
+                Tier::Archiving | Tier::Restoring => false,
+            }
+        };
+        if start_restore {
+            let context = LockContext::new("restore", tree_name);
+            actix_web::rt::spawn(context.scope(restore_tree(state.clone(), tree_name.to_string())));
+        }
+        if Instant::now() >= deadline {
+            return Err(HttpResponse::ServiceUnavailable()
+                .insert_header(("Retry-After", RESTORE_RETRY_AFTER_SECS.to_string()))
+                .body(format!("Tree {} is archived and being restored", tree_name)));
+        }
+        actix_web::rt::time::sleep(Duration::from_millis(100).min(deadline - Instant::now())).await;
+    }
+}
+
+async fn restore_tree(state: web::Data<APPState>, tree_name: String) {
+    let from = get_archive_file_path(&state.archive.directory, &tree_name);
+    let to = get_bin_file_path(&state.store.bin_directory, &tree_name);
+    let restored = web::block(move || decompress_file(&from, &to)).await;
+
+    let mut trees = state.store.trees.lock().unwrap();
+    let Some(cache) = trees.get_mut(&tree_name) else { return };
+    match restored {
+        Ok(Ok(bytes)) => {
+            cache.tier = Tier::Hot;
+            Metrics::incr(&state.metrics.trees_restored);
+            println!("Restored tree {} from the archive ({} bytes)", tree_name, bytes);
+        }
+        Ok(Err(e)) => {
+            cache.tier = Tier::Archived;
+            println!("Failed to restore tree {}: {}", tree_name, e);
+        }
+        Err(e) => {
+            cache.tier = Tier::Archived;
+            println!("Failed to restore tree {}: {}", tree_name, e);
+        }
+    }
+}
+
+// Moves trees idle for longer than `after` into the archive directory. A tree counts as
+// idle when neither a request nor a write has touched it, and only trees with nothing
+// in flight are moved. Returns how many trees were archived.
+fn archive_idle_trees(state: &APPState, after: Duration) -> usize {
+    let Some(cutoff) = SystemTime::now().checked_sub(after) else { return 0 };
+    let idle = |cache: &KDTreeCache| {
+        cache.tier == Tier::Hot
+            && cache.operation.is_none()
+            && !cache.dirty
+            && *cache.persisted.lock().unwrap() == cache.write_seq
+            && cache.last_accessed_at < cutoff
+    };
+    let candidates: Vec<String> = {
+        let trees = state.store.trees.lock().unwrap();
+        trees.iter().filter(|(_, cache)| idle(cache)).map(|(tree_name, _)| tree_name.clone()).collect()
+    };
+
+    let mut archived = 0;
+    for tree_name in candidates {
+        let bin_path = get_bin_file_path(&state.store.bin_directory, &tree_name);
+        let modified = fs::metadata(&bin_path).and_then(|metadata| metadata.modified());
+        if modified.is_ok_and(|modified| modified >= cutoff) {
+            continue;
+        }
+
+        // Re-checked under the lock since a request may have come in meanwhile
+        {
+            let mut trees = state.store.trees.lock().unwrap();
+            let Some(cache) = trees.get_mut(&tree_name) else { continue };
+            if !idle(cache) {
+                continue;
+            }
+            cache.tier = Tier::Archiving;
+            cache.tree = None;
+            cache.bloom = None;
+        }
+
+        let archive_path = get_archive_file_path(&state.archive.directory, &tree_name);
+        let outcome = compress_file(&bin_path, &archive_path);
+        let mut trees = state.store.trees.lock().unwrap();
+        let Some(cache) = trees.get_mut(&tree_name) else { continue };
+        match outcome {
+            Ok(bytes) => {
+                cache.tier = Tier::Archived;
+                archived += 1;
+                Metrics::incr(&state.metrics.trees_archived);
+                // The filter is rebuilt from the tree after a restore
+                let _ = fs::remove_file(get_bloom_file_path(&state.store.bin_directory, &tree_name));
+                println!("Archived tree {} to {:?} ({} bytes)", tree_name, archive_path, bytes);
+            }
+            Err(e) => {
+                cache.tier = Tier::Hot;
+                println!("Failed to archive tree {}: {}", tree_name, e);
+            }
+        }
+    }
+    archived
+}
+
+// Removes what `gc::scan` finds in the bin directory. Each file is re-checked under the
+// trees lock, so files of a tree with a running operation, archive move or restore are
+// skipped, and a filter whose tree was created meanwhile is kept.
+fn collect_garbage(state: &APPState) -> io::Result<GcResponse> {
+    let candidates = gc::scan(
+        &state.store.bin_directory,
+        Duration::from_secs(state.settings.gc_temp_max_age_secs),
+        state.settings.gc_backup_retention,
+    )?;
+    let mut response = GcResponse { files_removed: 0, bytes_reclaimed: 0, skipped: 0, removed: Vec::new() };
+    let trees = state.store.trees.lock().unwrap();
+    for garbage in candidates {
+        let cache = trees.get(&garbage.tree_name);
+        if cache.is_some_and(|cache| cache.operation.is_some() || matches!(cache.tier, Tier::Archiving | Tier::Restoring)) {
+            response.skipped += 1;
+            continue;
+        }
+        if garbage.kind == GarbageKind::OrphanedFilter && cache.is_some() {
+            continue;
+        }
+        if let Err(e) = fs::remove_file(&garbage.path) {
+            println!("Failed to remove {:?}: {}", garbage.path, e);
+            continue;
+        }
+        println!("Removed {:?} ({} bytes)", garbage.path, garbage.bytes);
+        response.files_removed += 1;
+        response.bytes_reclaimed += garbage.bytes;
+        response.removed.push(RemovedFile { file_name: garbage.file_name, kind: garbage.kind, bytes: garbage.bytes });
+    }
+    drop(trees);
+
+    state.metrics.gc_files_removed.fetch_add(response.files_removed as u64, Ordering::Relaxed);
+    state.metrics.gc_bytes_reclaimed.fetch_add(response.bytes_reclaimed, Ordering::Relaxed);
+    if response.files_removed > 0 || response.skipped > 0 {
+        println!(
+            "Garbage collection removed {} files ({} bytes) from {:?}, skipped {} of busy trees",
+            response.files_removed, response.bytes_reclaimed, state.store.bin_directory, response.skipped
+        );
+    }
+    Ok(response)
+}
+
+async fn run_gc(state: web::Data<APPState>) -> impl Responder {
+    if let Some(response) = persistence_rejection(&state, "/gc") {
+        return response;
+    }
+    let collecting = state.clone();
+    match web::block(move || collect_garbage(&collecting)).await {
+        Ok(Ok(response)) => HttpResponse::Ok().json(response),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("Error scanning {:?}: {}", state.store.bin_directory, e)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Garbage collection failed: {}", e)),
+    }
+}
+
+fn next_random(seed: &mut u64) -> u64 {
+    *seed ^= *seed << 13;
+    *seed ^= *seed >> 7;
+    *seed ^= *seed << 17;
+    *seed
+}
+
+// A cheap random walk down a random subtree. Not uniform, but it reaches every depth
+// without visiting the whole tree.
+fn sample_point<'a>(tree: &'a KDTree, seed: &mut u64) -> Option<&'a Point> {
+    let roots: Vec<&Node> = tree.roots().collect();
+    if roots.is_empty() {
+        return None;
+    }
+    let mut node = roots[next_random(seed) as usize % roots.len()];
+    loop {
+        let child = match next_random(seed) % 3 {
+            0 => None,
+            1 => node.left.as_deref(),
+            _ => node.right.as_deref(),
+        };
+        match child {
+            Some(child) => node = child,
+            None => return Some(node.point()),
+        }
+    }
+}
+
+// Runs one self-test cycle: each resident tree must return sampled points of its own at
+// distance 0. Only reads trees, takes the lock per tree and stops once the budget is
+// spent; `cursor` rotates where the next cycle starts so every tree gets its turn.
+fn self_test_cycle(state: &APPState, settings: &SelfTestSettings, cursor: &mut usize, seed: &mut u64) {
+    let started = Instant::now();
+    let mut tree_names: Vec<String> = {
+        let trees = state.store.trees.lock().unwrap();
+        trees.iter().filter(|(_, cache)| cache.tree.is_some()).map(|(tree_name, _)| tree_name.clone()).collect()
+    };
+    if tree_names.is_empty() {
+        return;
+    }
+    tree_names.sort();
+    let start = *cursor % tree_names.len();
+    tree_names.rotate_left(start);
+
+    let mut suspects = Vec::new();
+    for tree_name in tree_names {
+        if started.elapsed() >= settings.budget {
+            break;
+        }
+        *cursor += 1;
+        let mut trees = state.store.trees.lock().unwrap();
+        let Some(cache) = trees.get_mut(&tree_name) else { continue };
+        let Some(tree) = &cache.tree else { continue };
+        let failed = (0..settings.samples).filter_map(|_| sample_point(tree, seed)).find(|point| {
+            tree.nearest_neighbors_topn_scored(point, 1)
+                .first()
+                .map(|(distance, _)| *distance)
+                .is_none_or(|distance| distance != 0.0)
+        });
+        cache.suspect = failed.is_some();
+        if let Some(point) = failed {
+            Metrics::incr(&state.metrics.self_test_failures);
+            suspects.push((tree_name, point.embedding.len(), cache.dirty));
+        }
+    }
+
+    for (tree_name, dimensions, dirty) in suspects {
+        eprintln!("ERROR: self-test failed for tree {}: a stored {}-dimensional point was not found at distance 0", tree_name, dimensions);
+        if let Some(url) = &settings.webhook_url {
+            let body = json!({ "event": "self_test_failed", "tree_name": tree_name }).to_string();
+            if let Err(e) = ureq::post(url).set("Content-Type", "application/json").send_string(&body) {
+                println!("Failed to send self-test webhook for tree {}: {}", tree_name, e);
+            }
+        }
+        // Unflushed inserts only exist in memory, so reloading would lose them
+        if settings.reload && !dirty {
+            match state.store.load_tree(&tree_name) {
+                Ok(tree) => {
+                    let mut trees = state.store.trees.lock().unwrap();
+                    if let Some(cache) = trees.get_mut(&tree_name) {
+                        if cache.tree.is_some() && !cache.dirty && cache.operation.is_none() {
+                            cache.set_tree(tree);
+                            println!("Reloaded suspect tree {} from disk", tree_name);
+                        }
+                    }
+                }
+                Err(e) => println!("Failed to reload suspect tree {}: {}", tree_name, e),
+            }
+        }
+    }
+}
+
+// Runs a background maintenance task on a blocking thread if the scheduler lets it run
+// now. Not awaited, so a run that outlasts its interval makes the next tick skip.
+fn spawn_maintenance(state: &web::Data<APPState>, task: &'static str, run: impl FnOnce(&APPState) + Send + 'static) {
+    if !state.maintenance.begin(task, unix_seconds(SystemTime::now())) {
+        return;
+    }
+    let state = state.clone();
+    actix_web::rt::spawn(async move {
+        let running = state.clone();
+        let _ = actix_web::rt::task::spawn_blocking(move || run(&running)).await;
+        state.maintenance.finish(task, unix_seconds(SystemTime::now()));
+    });
+}
+
+fn save_manifest(state: &APPState) -> io::Result<()> {
+    state.store.save_manifest(&state.store.trees.lock().unwrap())?;
+    match state.store.disk() {
+        Some(bin_directory) => state.store.usage.save_history(bin_directory),
+        None => Ok(()),
+    }
+}
+
+// Counts recent tree file writes, records every tree's size into its usage history and
+// drops expired hours. Sizes are read under the trees lock, file sizes after it is released.
+fn sample_usage(state: &APPState) {
+    state.count_writes();
+    if state.store.usage.retention_hours() == 0 {
+        return;
+    }
+    let sizes: Vec<(String, usize)> = state.store.trees.lock().unwrap().iter()
+        .map(|(tree_name, cache)| (tree_name.clone(), cache.num_records))
+        .collect();
+    let now = unix_seconds(SystemTime::now());
+    for (tree_name, num_records) in sizes {
+        let bytes = state.store.file_size(&tree_name);
+        state.store.usage.tree(&tree_name).record_stored(num_records as u64, bytes, now);
+    }
+    state.store.usage.prune(now);
+}
+
+// Trees whose files are in the bin directory, by persisted access count and then last
+// access, hottest first
+fn hottest_first(trees: &HashMap<String, KDTreeCache>) -> Vec<String> {
+    let mut ranked: Vec<(&String, &KDTreeCache)> = trees.iter().filter(|(_, cache)| cache.tier == Tier::Hot).collect();
+    ranked.sort_by(|(_, a), (_, b)| {
+        b.access_count.cmp(&a.access_count).then(b.last_accessed_at.cmp(&a.last_accessed_at))
+    });
+    ranked.into_iter().map(|(tree_name, _)| tree_name.clone()).collect()
+}
+
+// Loads trees hottest-first with `concurrency` blocking loads in flight, stopping once
+// the memory budget is reached. Runs alongside the server, so a tree a request has
+// already loaded is left alone.
+async fn preload_trees(state: web::Data<APPState>, concurrency: usize) {
+    let queue: VecDeque<String> = hottest_first(&state.store.trees.lock().unwrap()).into();
+    let total = queue.len();
+    println!("Preloading up to {} trees with concurrency {}", total, concurrency);
+
+    let queue = Arc::new(Mutex::new(queue));
+    let workers: Vec<_> = (0..concurrency.max(1)).map(|_| {
+        let queue = Arc::clone(&queue);
+        let state = state.clone();
+        actix_web::rt::spawn(async move {
+            loop {
+                let next = queue.lock().unwrap().pop_front();
+                let Some(tree_name) = next else { break };
+                if resident_memory_usage(&state.store.trees.lock().unwrap()) >= state.store.options.max_memory_bytes {
+                    queue.lock().unwrap().clear();
+                    break;
+                }
+
+                let (loading, name) = (state.clone(), tree_name.clone());
+                let loaded = actix_web::rt::task::spawn_blocking(move || loading.store.load_tree(&name)).await;
+
+                let mut trees = state.store.trees.lock().unwrap();
+                match loaded {
+                    Ok(Ok(tree)) => {
+                        let cache = trees.entry(tree_name.clone()).or_default();
+                        if cache.tree.is_none() {
+                            cache.set_tree(tree);
+                        }
+                    }
+                    Ok(Err(e)) => println!("Failed to preload tree {}: {}", tree_name, e),
+                    Err(e) => println!("Failed to preload tree {}: {}", tree_name, e),
+                }
+                let loaded_count = trees.values().filter(|cache| cache.tree.is_some()).count();
+                println!(
+                    "Preload: loaded {}/{} trees, {:.1}MB/{:.1}MB",
+                    loaded_count, total,
+                    resident_memory_usage(&trees) as f64 / (1024.0 * 1024.0),
+                    state.store.options.max_memory_bytes as f64 / (1024.0 * 1024.0),
+                );
+            }
+        })
+    }).collect();
+
+    for worker in workers {
+        let _ = worker.await;
+    }
+    println!("Preload finished");
+}
+
+// Reads the tree files WARM_FILES selects into the OS page cache, hottest first, one at
+// a time and at most WARM_FILES_MB_PER_SEC, so it leaves disk bandwidth to preload and
+// to requests. Whether a tree is resident makes no difference; its file is only read.
+async fn warm_tree_files(state: web::Data<APPState>, files: WarmFiles) {
+    let selected: Vec<String> = {
+        let trees = state.store.trees.lock().unwrap();
+        if let WarmFiles::Trees(names) = &files {
+            for tree_name in names.iter().filter(|tree_name| !trees.contains_key(*tree_name)) {
+                println!("WARM_FILES names tree {}, which does not exist", tree_name);
+            }
+        }
+        hottest_first(&trees).into_iter().filter(|tree_name| files.selects(tree_name)).collect()
+    };
+    let max_bytes_per_sec = (state.settings.warm_files_mb_per_sec > 0).then(|| state.settings.warm_files_mb_per_sec * 1024 * 1024);
+    println!("Warming {} tree files into the page cache", selected.len());
+
+    let started = Instant::now();
+    let mut total = 0;
+    for tree_name in selected {
+        let path = get_bin_file_path(&state.store.bin_directory, &tree_name);
+        let file_started = Instant::now();
+        match actix_web::rt::task::spawn_blocking(move || warm_file(&path, max_bytes_per_sec)).await {
+            Ok(Ok(bytes)) => {
+                total += bytes;
+                if let Some(cache) = state.store.trees.lock().unwrap().get_mut(&tree_name) {
+                    cache.warmed_at = Some(SystemTime::now());
+                }
+                println!("Warmed tree {}: {} bytes in {} ms", tree_name, bytes, file_started.elapsed().as_millis());
+            }
+            Ok(Err(e)) => println!("Failed to warm tree {}: {}", tree_name, e),
+            Err(e) => println!("Failed to warm tree {}: {}", tree_name, e),
+        }
+    }
+    println!("Warming finished: {} bytes in {} ms", total, started.elapsed().as_millis());
+}
+
+// Routes applications use to read and write trees
+const DATA_ROUTES: &[Route] = &[
+    Route::post("/insert", |route| route.to(insert_point)).mutating().requiring(&["tree_name"]),
+    Route::post("/insert_multi", |route| route.to(insert_multi)).mutating(),
+    Route::post("/nearesttop", |route| route.to(nearest_neighbor_top_n)).requiring(&["tree_name", "n"]),
+    Route::post("/exists_within", |route| route.to(exists_within)).requiring(&["tree_name", "distance"]),
+    Route::post("/get_by_embedding", |route| route.to(get_by_embedding)).requiring(&["tree_name"]),
+    Route::post("/rebuild", |route| route.to(rebuild_tree)).mutating().requiring(&["tree_name"]),
+    Route::post("/import_parquet", |route| route.to(import_parquet)).mutating().requiring(&["tree_name", "path"]),
+    Route::post("/import_jsonl", |route| route.to(import_jsonl)).mutating().requiring(&["tree_name", "path"]),
+    Route::post("/reembed", |route| route.to(reembed)).mutating().requiring(&["tree_name", "target"]),
+    Route::post("/delete_by_filter", |route| route.to(delete_by_filter)).mutating().requiring(&["tree_name"]),
+    Route::post("/truncate", |route| route.to(truncate_tree)).mutating().requiring(&["tree_name"]),
+    Route::post("/create_tree", |route| route.to(create_tree)).mutating().requiring(&["tree_name", "dimensions"]),
+    Route::get("/stats", |route| route.to(get_stats)).requiring(&["tree_name"]),
+    Route::get("/tree_structure", |route| route.to(tree_structure)).requiring(&["tree_name"]),
+    Route::get("/schema", |route| route.to(get_schema)).requiring(&["tree_name"]),
+    Route::new(Method::PUT, "/schema", |route| route.to(put_schema)).mutating().requiring(&["tree_name"]),
+    Route::new(Method::DELETE, "/schema", |route| route.to(delete_schema)).mutating().requiring(&["tree_name"]),
+    Route::get("/drift", |route| route.to(get_drift)).requiring(&["tree_name", "against"]),
+    Route::get("/export", |route| route.to(export_tree)).requiring(&["tree_name"]),
+    Route::get("/snapshot", |route| route.to(get_snapshot)).requiring(&["tree_name"]),
+    Route::new(Method::HEAD, "/snapshot", |route| route.to(get_snapshot)).requiring(&["tree_name"]),
+    Route::new(Method::PUT, "/restore", |route| route.to(upload_chunk))
+        .mutating()
+        .requiring(&["tree_name", "offset"])
+        .with_payload_limit(MAX_UPLOAD_CHUNK_BYTES),
+    Route::get("/restore", |route| route.to(upload_status)).requiring(&["tree_name"]),
+    Route::post("/restore/commit", |route| route.to(commit_restore)).mutating().requiring(&["tree_name", "sha256"]),
+    Route::get("/sample", |route| route.to(sample_tree)).requiring(&["tree_name"]),
+];
+
+// Operational endpoints, served on their own listener when ADMIN_PORT is set
+const ADMIN_ROUTES: &[Route] = &[
+    Route::get("/jobs", |route| route.to(list_jobs)),
+    Route::get("/jobs/{id}", |route| route.to(get_job)),
+    Route::new(Method::DELETE, "/jobs/{id}", |route| route.to(cancel_job)).mutating(),
+    Route::get("/verify_all", |route| route.to(verify_all)),
+    Route::post("/audit_search", |route| route.to(audit_search)).requiring(&["tree_name"]),
+    Route::post("/gc", |route| route.to(run_gc)).mutating(),
+    Route::post("/estimate", |route| route.to(estimate_workload)),
+    Route::get("/status", |route| route.to(get_status)),
+    Route::get("/trees", |route| route.to(list_trees)),
+    Route::get("/usage", |route| route.to(get_usage)),
+    Route::get("/advisor", |route| route.to(get_advisor)),
+    Route::get("/routes", |route| route.to(list_routes)),
+    Route::get("/v1/status", |route| route.to(get_status)),
+    Route::get("/v1/trees", |route| route.to(list_trees)),
+    Route::get("/v1/cache", |route| route.to(get_cache_entry)).requiring(&["tree_name"]),
+    Route::get("/metrics", |route| route.to(get_metrics)),
+    Route::get("/config", |route| route.to(get_config)),
+    Route::get("/cache", |route| route.to(get_cache_entry)).requiring(&["tree_name"]),
+    Route::new(Method::DELETE, "/cache", |route| route.to(drop_cache_entry)).mutating().requiring(&["tree_name"]),
+];
+
+pub fn data_routes(config: &mut web::ServiceConfig) {
+    routes::register(config, DATA_ROUTES);
+}
+
+pub fn admin_routes(config: &mut web::ServiceConfig) {
+    routes::register(config, ADMIN_ROUTES);
+}
+
+pub fn all_routes(config: &mut web::ServiceConfig) {
+    data_routes(config);
+    admin_routes(config);
+}
+
+// Trees load and rebalance with this many threads unless PRELOAD_CONCURRENCY says otherwise
+fn preload_concurrency(settings: &Settings) -> usize {
+    settings.preload_concurrency.unwrap_or_else(|| std::thread::available_parallelism().map_or(4, |n| n.get()))
+}
+
+// The state every handler shares, built from the effective configuration. With
+// persistence enabled the trees are registered from the bin directory, after tree files
+// left in the working directory are moved there when `adopt_orphans` is set; without it
+// every tree starts empty. Background tasks are left to the caller.
+pub fn build_state(settings: Settings, adopt_orphans: bool) -> io::Result<web::Data<APPState>> {
+    let max_memory_mb = settings.max_memory_mb;
+    let bloom = settings.dedup_bloom_capacity.map(|capacity| BloomSettings {
+        capacity,
+        fp_rate: settings.dedup_bloom_fp_rate,
+    });
+    let query_cache = NonZeroUsize::new(settings.query_cache_entries)
+        .map(|entries| QueryCache::new(entries, Duration::from_secs(settings.query_cache_ttl_secs)));
+
+    // Below 1 even a perfectly balanced tree would exceed the bound, so treat it as off
+    let max_depth_factor = Some(settings.max_depth_factor).filter(|factor| *factor >= 1.0);
+
+    let archive = ArchiveSettings {
+        directory: settings.archive_directory.clone(),
+        after: (settings.archive_after_days > 0)
+            .then(|| Duration::from_secs(settings.archive_after_days * 24 * 60 * 60)),
+        restore_wait: Duration::from_millis(settings.archive_restore_wait_ms),
+    };
+
+    let auto_migrate = settings.auto_migrate;
+    let bin_path = settings.bin_directory.clone();
+    let persistence = settings.persistence;
+
+    // Without persistence the bin directory is never touched and every tree starts empty
+    let (trees, usage) = match persistence {
+        Persistence::Disabled => (HashMap::new(), UsageRegistry::default()),
+        Persistence::Enabled => {
+            // Create bin directory if it doesn't exist
+            ensure_bin_directory(&bin_path)?;
+
+            if archive.after.is_some() {
+                fs::create_dir_all(&archive.directory)?;
+            }
+
+            let mut manifest = Manifest::load(&bin_path);
+            if adopt_orphans {
+                let adopted = adopt_orphaned_trees(Path::new("."), &bin_path, &mut manifest);
+                if adopted > 0 {
+                    println!("Moved {} tree files from the working directory into {:?}", adopted, bin_path);
+                    manifest.save(&bin_path)?;
+                }
+            }
+            let mut trees = register_trees(&bin_path, Some(&archive.directory), &manifest)?;
+            println!("Registered {} trees from {:?}", trees.len(), bin_path);
+            // Before the listener exists, so no request ever sees a tree being rebuilt
+            rebalance_trees(&bin_path, &mut trees, settings.rebalance_on_startup, max_depth_factor, preload_concurrency(&settings), auto_migrate);
+            (trees, UsageRegistry::from_manifest(&manifest))
+        }
+    };
+    let usage = usage.with_history(
+        settings.usage_retention_days,
+        (persistence == Persistence::Enabled).then_some(bin_path.as_path()),
+    );
+    let disk_space = DiskSpace::new(
+        (persistence == Persistence::Enabled).then_some(bin_path.as_path()),
+        settings.min_free_disk_mb * 1024 * 1024,
+    );
+    if let Some(available) = disk_space.available() {
+        println!("{} MB free on the filesystem of {:?}", available / (1024 * 1024), bin_path);
+        if available < settings.min_free_disk_mb * 1024 * 1024 {
+            println!(
+                "WARNING: Less than MIN_FREE_DISK_MB ({} MB) is free, so writes will be refused with 507 until space is freed",
+                settings.min_free_disk_mb
+            );
+        }
+    }
+    let jobs = Jobs::open((persistence == Persistence::Enabled).then_some(bin_path.as_path()));
+    let options = StoreOptions {
+        persistence,
+        max_memory_bytes: max_memory_mb * 1024 * 1024, // Convert MB to bytes
+        auto_migrate,
+        max_depth_factor,
+        lock_wait_warn: (settings.lock_wait_warn_ms > 0).then(|| Duration::from_millis(settings.lock_wait_warn_ms)),
+        soft_memory_bytes: (settings.memory_soft_limit_percent > 0)
+            .then(|| max_memory_mb * 1024 * 1024 / 100 * settings.memory_soft_limit_percent),
+        eviction_idle: Duration::from_secs(settings.eviction_idle_secs),
+        strict_create: settings.strict_create,
+        max_dimensions: settings.max_dimensions,
+    };
+    Ok(web::Data::new(APPState {
+        store: Store::new(bin_path, options, trees, usage),
+        metrics: Metrics::default(),
+        bloom,
+        query_cache,
+        default_durability: settings.insert_durability,
+        writers: Mutex::new(HashMap::new()),
+        write_queue_capacity: settings.write_queue_capacity,
+        archive,
+        heavy: HeavyLimiter::new(settings.max_heavy_concurrency, settings.max_heavy_queue),
+        cost_limiter: (settings.rate_limit_budget > 0).then(|| CostLimiter::new(settings.rate_limit_budget)),
+        snapshot_hashes: HashCache::default(),
+        maintenance: Scheduler::new(settings.maintenance_window),
+        ephemeral_loads: Mutex::new(HashMap::new()),
+        disk_space,
+        embedder: Embedder::from_settings(&settings),
+        jobs,
+        settings,
+    }))
+}
+
+// Locks taken while serving a request are attributed to its route and tree. A client
+// can make up paths and tree names freely, so requests that match no route wait as
+// `unmatched`, and names of trees the server has no usage for as `other`; either way
+// the waits recorded stay bounded by the routes and trees there are.
+fn lock_context(state: &APPState, request: &ServiceRequest) -> LockContext {
+    let endpoint = request.match_pattern().unwrap_or_else(|| "unmatched".to_string());
+    let tree_name = web::Query::<HashMap<String, String>>::from_query(request.query_string())
+        .ok()
+        .and_then(|query| query.into_inner().remove("tree_name"));
+    let tree = match tree_name {
+        None => String::new(),
+        Some(tree_name) if is_valid_tree_name(&tree_name) && state.store.usage.get(&tree_name).is_some() => tree_name,
+        Some(_) => OTHER_TREES.to_string(),
+    };
+    LockContext::new(endpoint, tree)
+}
+
+// The application serving `routes` over `state`, with the body limits and the request
+// wrappers every listener shares
+pub fn app(
+    state: web::Data<APPState>,
+    routes: fn(&mut web::ServiceConfig),
+) -> App<impl ServiceFactory<ServiceRequest, Config = (), Response = ServiceResponse<impl MessageBody>, Error = actix_web::Error, InitError = ()>> {
+    let payload_limit = state.settings.max_payload_mb * 1024 * 1024;
+    let known_trees = state.clone();
+    App::new()
+        .app_data(state)
+        // Compressed bodies are decoded by the extractors, and the limits apply to the
+        // decoded size, so a small compressed body can't expand past them
+        .app_data(web::JsonConfig::default().limit(payload_limit).error_handler(move |error, _| {
+            payload_too_large(error, payload_limit)
+        }))
+        .app_data(web::PayloadConfig::new(payload_limit))
+        .wrap_fn(move |request, service| {
+            let context = lock_context(&known_trees, &request);
+            let response = context.scope(service.call(request));
+            async move {
+                // Data-path handlers report where their time went, failures included
+                let (response, timing) = server_timing::collect(response).await;
+                let mut response = response?;
+                if let Some(timing) = timing.and_then(|timing| HeaderValue::from_str(&timing).ok()) {
+                    response.headers_mut().insert(HeaderName::from_static("server-timing"), timing);
+                }
+                Ok(response)
+            }
+        })
+        .wrap_fn(|request, service| {
+            // A body in an encoding the extractors can't decode would reach the
+            // handlers as garbage
+            match unsupported_encoding(request.headers()) {
+                Some(rejection) => Either::Left(ready(Ok(request.into_response(rejection)))),
+                None => Either::Right(service.call(request)),
+            }
+        })
+        .configure(routes)
+}
+
+// The server binary: parses the command line and the configuration, then serves until
+// stopped and saves what is left
+pub async fn run() -> io::Result<()> {
+    let cli = Cli::parse();
+
+    // Load environment variables from .env file
+    dotenv().ok();
+
+    // Config file first, environment variables on top, defaults for everything else
+    let settings = match Settings::load() {
+        Ok((settings, warnings)) => {
+            for warning in warnings {
+                println!("WARNING: {}", warning);
+            }
+            settings
+        }
+        Err(problems) => {
+            eprintln!("Invalid configuration:");
+            for problem in problems {
+                eprintln!("  {}", problem);
+            }
+            std::process::exit(1);
+        }
+    };
+    match serde_json::to_string(&settings.redacted()) {
+        Ok(effective) => println!("Effective configuration: {}", effective),
+        Err(e) => println!("Failed to log the effective configuration: {}", e),
+    }
+
+    let host = settings.host.clone();
+    let port = settings.port;
+    let admin_address = settings.admin_port
+        .map(|admin_port| format!("{}:{}", settings.admin_host.as_ref().unwrap_or(&host), admin_port));
+    let bin_directory = settings.bin_directory.clone();
+
+    match cli.command {
+        Some(Command::Migrate { directory }) => {
+            return migrate_directory(&directory.unwrap_or_else(|| bin_directory.clone()));
+        }
+        Some(Command::Verify { directory }) => {
+            return verify_directory(&directory.unwrap_or_else(|| bin_directory.clone()));
+        }
+        None => {}
+    }
+
+    let state = build_state(settings, !cli.no_migrate)?;
+    let settings = &state.settings;
+    let persistence = settings.persistence;
+    let max_memory_mb = settings.max_memory_mb;
+    let preload_concurrency = preload_concurrency(settings);
+    let manifest_flush_secs = settings.manifest_flush_secs;
+    let dirty_flush_secs = settings.dirty_flush_secs;
+    let gc_interval = (settings.gc_interval_minutes > 0)
+        .then(|| Duration::from_secs(settings.gc_interval_minutes * 60));
+    let self_test = (settings.self_test_interval_minutes > 0).then(|| SelfTestSettings {
+        interval: Duration::from_secs(settings.self_test_interval_minutes * 60),
+        samples: settings.self_test_samples,
+        budget: Duration::from_millis(settings.self_test_budget_ms),
+        webhook_url: settings.self_test_webhook_url.clone(),
+        reload: settings.self_test_reload,
+    });
+
+    let address = format!("{}:{}", host, port);
+    // Without ADMIN_PORT every endpoint stays on PORT
+    let routes: fn(&mut web::ServiceConfig) = if admin_address.is_some() { data_routes } else { all_routes };
+    let server = HttpServer::new({
+        let state = state.clone();
+        move || app(state.clone(), routes)
+    })
+    // A client that closes its end has given up on the response, so its handler is
+    // dropped, which cancels the work it started
+    .h1_allow_half_closed(false)
+    .bind(&address)?;
+    let admin_server = match &admin_address {
+        Some(admin_address) => Some(HttpServer::new({
+            let state = state.clone();
+            move || app(state.clone(), admin_routes)
+        })
+            .h1_allow_half_closed(false)
+            .bind(admin_address)?),
+        None => None,
+    };
+
+    println!("Server running on {}", address);
+    if let Some(admin_address) = &admin_address {
+        println!(
+            "Data endpoints on {}; operational endpoints only on {}: {}",
+            address, admin_address, routes::prefixes(ADMIN_ROUTES).join(", ")
+        );
+    }
+    match persistence {
+        Persistence::Enabled => println!("Binary files directory: {:?}", bin_directory),
+        Persistence::Disabled => println!(
+            "WARNING: persistence is disabled: trees live in memory only, nothing is written to disk, \
+             and evicted trees and everything left at shutdown are lost"
+        ),
+    }
+    println!("Maximum memory usage: {} MB", max_memory_mb);
+    if settings.query_cache_entries > 0 {
+        println!("Query cache: {} entries, {}s TTL", settings.query_cache_entries, settings.query_cache_ttl_secs);
+    }
+    if let Some(bloom) = state.bloom {
+        println!("Insert deduplication: capacity {} at {} false-positive rate", bloom.capacity, bloom.fp_rate);
+    }
+
+    if settings.preload {
+        actix_web::rt::spawn(preload_trees(state.clone(), preload_concurrency));
+    }
+    if let Some(files) = state.settings.warm_files.clone().filter(|_| persistence == Persistence::Enabled) {
+        actix_web::rt::spawn(warm_tree_files(state.clone(), files));
+    }
+
+    println!("Maintenance window: {}", state.settings.maintenance_window);
+    let now = unix_seconds(SystemTime::now());
+    if state.archive.after.is_some() {
+        state.maintenance.register(ARCHIVAL_TASK, Duration::from_secs(manifest_flush_secs.max(1)), now);
+    }
+    if let Some(gc_interval) = gc_interval {
+        state.maintenance.register(GC_TASK, gc_interval, now);
+    }
+    for task in state.maintenance.status(now).tasks.iter().filter(|task| task.next_run.is_none()) {
+        println!("WARNING: {} never runs: no tick of its interval falls inside the maintenance window", task.task);
+    }
+
+    // Archive idle trees within the maintenance window, then persist access statistics
+    // and tiers so restarts keep the hottest-first order and know where every tree lives
+    let flush_state = state.clone();
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(Duration::from_secs(manifest_flush_secs.max(1)));
+        loop {
+            interval.tick().await;
+            if let Some(after) = flush_state.archive.after {
+                spawn_maintenance(&flush_state, ARCHIVAL_TASK, move |state| {
+                    archive_idle_trees(state, after);
+                });
+            }
+            sample_usage(&flush_state);
+            if let Err(e) = save_manifest(&flush_state) {
+                println!("Failed to save manifest: {}", e);
+            }
+        }
+    });
+
+    // Inserts acknowledged with `durability=none` reach disk here
+    let dirty_state = state.clone();
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(Duration::from_secs(dirty_flush_secs.max(1)));
+        loop {
+            interval.tick().await;
+            let state = dirty_state.clone();
+            let _ = actix_web::rt::task::spawn_blocking(move || flush_dirty_trees(&state, false)).await;
+        }
+    });
+
+    // Offloads trees above the soft limit so request handlers rarely pay for an eviction;
+    // they still evict themselves once the hard limit is exceeded
+    if let Some(soft_limit) = state.store.options.soft_memory_bytes {
+        println!("Background eviction above {:.1} MB", soft_limit as f64 / (1024.0 * 1024.0));
+        let eviction_state = state.clone();
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(EVICTION_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                loop {
+                    let state = eviction_state.clone();
+                    match actix_web::rt::task::spawn_blocking(move || state.store.evict_above_soft_limit()).await {
+                        Ok(Ok(Some((_, freed)))) => {
+                            Metrics::incr(&eviction_state.metrics.background_evictions);
+                            eviction_state.metrics.background_evicted_bytes.fetch_add(freed as u64, Ordering::Relaxed);
+                        }
+                        Ok(Ok(None)) | Err(_) => break,
+                        Ok(Err(e)) => {
+                            println!("Background eviction failed: {}", e);
+                            break;
+                        }
+                    }
+                    // Lets requests waiting for the trees lock in before the next eviction
+                    tokio::task::yield_now().await;
+                }
+            }
+        });
+    }
+
+    if let Some(gc_interval) = gc_interval {
+        println!("Garbage collection of {:?} every {}s", bin_directory, gc_interval.as_secs());
+        let gc_state = state.clone();
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(gc_interval);
+            loop {
+                interval.tick().await;
+                spawn_maintenance(&gc_state, GC_TASK, |state| {
+                    if let Err(e) = collect_garbage(state) {
+                        println!("Garbage collection failed: {}", e);
+                    }
+                });
+            }
+        });
+    }
+
+    if let Some(settings) = self_test {
+        println!("Self-test every {}s, {} samples per tree", settings.interval.as_secs(), settings.samples);
+        let self_test_state = state.clone();
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(settings.interval);
+            interval.tick().await; // The first tick fires immediately; let startup settle
+            let mut cursor = 0;
+            let mut seed = unix_seconds(SystemTime::now()) | 1;
+            loop {
+                interval.tick().await;
+                let state = self_test_state.clone();
+                let settings = settings.clone();
+                if let Ok((next_cursor, next_seed)) = actix_web::rt::task::spawn_blocking(move || {
+                    self_test_cycle(&state, &settings, &mut cursor, &mut seed);
+                    (cursor, seed)
+                }).await {
+                    cursor = next_cursor;
+                    seed = next_seed;
+                }
+            }
+        });
+    }
+
+    // Both listeners stop on the same signal
+    let result = match admin_server {
+        Some(admin_server) => try_join(server.run(), admin_server.run()).await.map(|_| ()),
+        None => server.run().await,
+    };
+
+    // Closing the queues lets every writer drain what is left and exit
+    let writers: Vec<_> = state.writers.lock().unwrap().drain().map(|(_, (_, writer))| writer).collect();
+    for writer in writers {
+        let _ = writer.await;
+    }
+    flush_dirty_trees(&state, true);
+    if let Err(e) = save_manifest(&state) {
+        println!("Failed to save manifest: {}", e);
+    }
+    result
+}
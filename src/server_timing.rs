@@ -0,0 +1,64 @@
+// Phase durations of the request being served, sent back in its `Server-Timing` header.
+// The server collects them per request in a task-local: data-path handlers, and the
+// search instrumentation they already share, record phases as they go, and the
+// middleware writes the header on whatever response comes out, errors included.
+// Recording outside a request does nothing.
+use std::cell::RefCell;
+use std::fmt::Write;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+tokio::task_local! {
+    static PHASES: RefCell<Option<Vec<(&'static str, Duration)>>>; // None until the handler reports
+}
+
+// Runs the request `future`, returning its output and the header value if the handler
+// reported any timing. The header ends with the `total` time spent in `future`.
+pub async fn collect<F: Future>(future: F) -> (F::Output, Option<String>) {
+    let started = Instant::now();
+    PHASES.scope(RefCell::new(None), async move {
+        let output = future.await;
+        let phases = PHASES.with(|phases| phases.borrow_mut().take());
+        (output, phases.map(|phases| render(&phases, started.elapsed())))
+    }).await
+}
+
+// Marks the current request as one that answers with a header, even if it fails before
+// timing any phase
+pub fn enable() {
+    let _ = PHASES.try_with(|phases| {
+        phases.borrow_mut().get_or_insert_with(Vec::new);
+    });
+}
+
+// Adds `elapsed` to the phase, so a phase timed in several steps is reported once
+pub fn record(name: &'static str, elapsed: Duration) {
+    let _ = PHASES.try_with(|phases| {
+        let mut phases = phases.borrow_mut();
+        let phases = phases.get_or_insert_with(Vec::new);
+        match phases.iter_mut().find(|(phase, _)| *phase == name) {
+            Some((_, total)) => *total += elapsed,
+            None => phases.push((name, elapsed)),
+        }
+    });
+}
+
+// Runs `work`, recording it as `name`
+pub fn time<T>(name: &'static str, work: impl FnOnce() -> T) -> T {
+    let started = Instant::now();
+    let result = work();
+    record(name, started.elapsed());
+    result
+}
+
+// Durations in milliseconds, in the order the phases were first recorded
+fn render(phases: &[(&'static str, Duration)], total: Duration) -> String {
+    let mut header = String::new();
+    for (name, elapsed) in phases.iter().chain([&("total", total)]) {
+        if !header.is_empty() {
+            header.push_str(", ");
+        }
+        let _ = write!(header, "{};dur={:.3}", name, elapsed.as_secs_f64() * 1000.0);
+    }
+    header
+}
@@ -0,0 +1,130 @@
+// Copying tree files in pieces: byte ranges of a tree file for downloads that can resume
+// after a dropped connection, and chunked uploads staged next to the tree until a commit
+// checks their SHA-256 and swaps them in.
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+// Largest chunk a single `PUT /restore` may carry
+pub const MAX_UPLOAD_CHUNK_BYTES: usize = 64 * 1024 * 1024;
+
+// Identifies one version of a file without reading it. Every write replaces a tree file, so
+// any write changes the modification time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileVersion {
+    pub len: u64,
+    pub modified: SystemTime,
+}
+
+impl FileVersion {
+    pub fn of(file: &File) -> io::Result<FileVersion> {
+        let metadata = file.metadata()?;
+        Ok(FileVersion { len: metadata.len(), modified: metadata.modified()? })
+    }
+}
+
+// SHA-256 of tree files per file version, so only the first request after a write pays
+// for reading the whole file
+#[derive(Default)]
+pub struct HashCache {
+    hashes: Mutex<HashMap<PathBuf, (FileVersion, String)>>,
+}
+
+impl HashCache {
+    pub fn get(&self, path: &Path, version: FileVersion) -> Option<String> {
+        let hashes = self.hashes.lock().unwrap();
+        hashes.get(path).filter(|(cached, _)| *cached == version).map(|(_, hash)| hash.clone())
+    }
+
+    // Hashes the file unless its current version is cached. Fails if the file is
+    // written while it is being read, as the hash would match neither version.
+    pub fn hash(&self, path: &Path) -> io::Result<(FileVersion, String)> {
+        let mut file = File::open(path)?;
+        let version = FileVersion::of(&file)?;
+        if let Some(hash) = self.get(path, version) {
+            return Ok((version, hash));
+        }
+        let hash = sha256_of(&mut file)?;
+        if FileVersion::of(&file)? != version {
+            return Err(io::Error::other(format!("{:?} changed while it was hashed", path)));
+        }
+        self.hashes.lock().unwrap().insert(path.to_path_buf(), (version, hash.clone()));
+        Ok((version, hash))
+    }
+}
+
+// Lower-case hex, as sent in `ETag` and expected by `/restore/commit`
+pub fn sha256_of(reader: &mut impl Read) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1024 * 1024];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+// A single `Range: bytes=...` header as a half-open range of a file of `len` bytes.
+// None means the header is to be ignored and the whole file served, as HTTP requires
+// for malformed headers and allows for multiple ranges; an error means the range lies
+// beyond the end of the file.
+pub fn parse_range(header: &str, len: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+    if start.is_empty() {
+        // The last `end` bytes
+        let suffix: u64 = end.parse().ok()?;
+        if suffix == 0 {
+            return Some(Err(()));
+        }
+        return Some(Ok((len.saturating_sub(suffix), len)));
+    }
+    let start: u64 = start.parse().ok()?;
+    let end = match end {
+        "" => len,
+        end => end.parse::<u64>().ok()?.checked_add(1)?.min(len), // Inclusive in the header
+    };
+    if end <= start {
+        return if start >= len { Some(Err(())) } else { None };
+    }
+    Some(Ok((start, end)))
+}
+
+pub fn get_upload_file_path(bin_directory: &Path, tree_name: &str) -> PathBuf {
+    // The `.tmp` suffix lets garbage collection remove abandoned uploads
+    bin_directory.join(format!("{}.bin.upload.tmp", tree_name))
+}
+
+// Bytes staged so far, 0 when no upload has started
+pub fn staged_len(path: &Path) -> u64 {
+    fs::metadata(path).map_or(0, |metadata| metadata.len())
+}
+
+// Writes a chunk at `offset` and drops anything staged after it, so a client whose
+// response got lost can resend the chunk, and offset 0 starts over. Returns the number
+// of bytes now staged.
+pub fn write_chunk(path: &Path, offset: u64, bytes: &[u8]) -> io::Result<u64> {
+    let staged = staged_len(path);
+    if offset > staged {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("offset {} is past the {} bytes staged so far", offset, staged),
+        ));
+    }
+    let mut file = OpenOptions::new().create(true).truncate(false).write(true).open(path)?;
+    file.set_len(offset)?;
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(bytes)?;
+    Ok(offset + bytes.len() as u64)
+}
@@ -0,0 +1,129 @@
+use serde::{Deserialize, Serialize};
+
+use crate::api::{DistributionStats, NormBucket};
+
+// Upper bounds of the norm histogram buckets: powers of two from 1/16 to 4096, plus a
+// final bucket for everything larger
+const NORM_BUCKET_MIN_EXPONENT: i32 = -4;
+const NORM_BUCKET_MAX_EXPONENT: i32 = 12;
+const NORM_BUCKETS: usize = (NORM_BUCKET_MAX_EXPONENT - NORM_BUCKET_MIN_EXPONENT) as usize + 2;
+
+// A standardized mean difference above this counts a dimension as shifted
+pub const SHIFT_THRESHOLD: f64 = 0.2;
+
+// Distribution of a tree's embeddings, kept up to date on every insert so it never
+// needs a scan: per-dimension mean and variance (Welford's algorithm) and a
+// histogram of vector norms
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TreeStats {
+    count: u64,
+    mean: Vec<f64>,
+    m2: Vec<f64>,             // Sum of squared deviations from the mean, per dimension
+    norm_histogram: Vec<u64>, // Counts per bucket, see NORM_BUCKET_*
+}
+
+impl TreeStats {
+    pub fn new(k: usize) -> Self {
+        TreeStats {
+            count: 0,
+            mean: vec![0.0; k],
+            m2: vec![0.0; k],
+            norm_histogram: vec![0; NORM_BUCKETS],
+        }
+    }
+
+    pub fn from_embeddings<'a>(k: usize, embeddings: impl IntoIterator<Item = &'a [f64]>) -> Self {
+        let mut stats = TreeStats::new(k);
+        for embedding in embeddings {
+            stats.add(embedding);
+        }
+        stats
+    }
+
+    pub fn add(&mut self, embedding: &[f64]) {
+        self.count += 1;
+        let count = self.count as f64;
+        for ((mean, m2), value) in self.mean.iter_mut().zip(&mut self.m2).zip(embedding) {
+            let delta = value - *mean;
+            *mean += delta / count;
+            *m2 += delta * (value - *mean);
+        }
+        let norm = embedding.iter().map(|value| value * value).sum::<f64>().sqrt();
+        self.norm_histogram[norm_bucket(norm)] += 1;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn dimensions(&self) -> usize {
+        self.mean.len()
+    }
+
+    // Population variance per dimension; zero until there are two points
+    pub fn variance(&self) -> Vec<f64> {
+        if self.count < 2 {
+            return vec![0.0; self.m2.len()];
+        }
+        self.m2.iter().map(|m2| m2 / self.count as f64).collect()
+    }
+
+    pub fn summary(&self) -> DistributionStats {
+        DistributionStats {
+            count: self.count,
+            mean: self.mean.clone(),
+            variance: self.variance(),
+            norm_histogram: self.norm_histogram.iter().enumerate().map(|(bucket, count)| NormBucket {
+                le: (bucket < NORM_BUCKETS - 1).then(|| 2f64.powi(NORM_BUCKET_MIN_EXPONENT + bucket as i32)),
+                count: *count,
+            }).collect(),
+        }
+    }
+
+    // Compares this distribution against `other` by the standardized mean difference of
+    // every dimension: the difference of the means over the pooled standard deviation.
+    // A dimension that is constant in both trees has no defined difference unless the
+    // constants agree.
+    pub fn drift(&self, other: &TreeStats) -> Drift {
+        let (variance, other_variance) = (self.variance(), other.variance());
+        let per_dimension: Vec<Option<f64>> = (0..self.mean.len()).map(|dimension| {
+            let difference = self.mean[dimension] - other.mean[dimension];
+            let pooled = ((variance[dimension] + other_variance[dimension]) / 2.0).sqrt();
+            if pooled > 0.0 {
+                Some(difference / pooled)
+            } else if difference == 0.0 {
+                Some(0.0)
+            } else {
+                None
+            }
+        }).collect();
+
+        let defined: Vec<(usize, f64)> = per_dimension.iter().enumerate()
+            .filter_map(|(dimension, smd)| smd.map(|smd| (dimension, smd.abs())))
+            .collect();
+        let max = defined.iter().copied().max_by(|a, b| a.1.total_cmp(&b.1));
+        Drift {
+            score: if defined.is_empty() { 0.0 } else { defined.iter().map(|(_, smd)| smd).sum::<f64>() / defined.len() as f64 },
+            max_abs_smd: max.map_or(0.0, |(_, smd)| smd),
+            max_dimension: max.map(|(dimension, _)| dimension),
+            shifted_dimensions: defined.iter().filter(|(_, smd)| *smd > SHIFT_THRESHOLD).count()
+                + per_dimension.iter().filter(|smd| smd.is_none()).count(),
+            per_dimension,
+        }
+    }
+}
+
+// Outcome of comparing two distributions
+pub struct Drift {
+    pub score: f64,                     // Mean absolute standardized mean difference
+    pub max_abs_smd: f64,
+    pub max_dimension: Option<usize>,
+    pub shifted_dimensions: usize,      // Above SHIFT_THRESHOLD, or constant at different values
+    pub per_dimension: Vec<Option<f64>>, // Signed standardized mean difference, None when undefined
+}
+
+fn norm_bucket(norm: f64) -> usize {
+    (NORM_BUCKET_MIN_EXPONENT..=NORM_BUCKET_MAX_EXPONENT)
+        .position(|exponent| norm <= 2f64.powi(exponent))
+        .unwrap_or(NORM_BUCKETS - 1)
+}
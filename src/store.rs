@@ -0,0 +1,1056 @@
+// The tree cache behind both the server and embedded use: trees loaded on demand from
+// their files in the bin directory, evicted least-recently-used first once the memory
+// budget is exceeded, and saved before they are dropped. The HTTP handlers add
+// archival, duplicate filtering, write queues and the query cache on top.
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::archive::Tier;
+use crate::bloom::{BloomFilter, BloomSettings};
+use crate::durability::{PendingWrite, SaveHealth, WriteTally};
+use crate::kdtree::{KDTree, KdTreeError, Point, FORMAT_VERSION};
+use crate::lock::TrackedMutex;
+use crate::manifest::{unix_seconds, Manifest, TreeEntry};
+use crate::memory::{estimate_load_size, estimate_memory_usage, estimate_point_size};
+use crate::operation::TreeOperation;
+use crate::scan::OffloadedStrategy;
+use crate::usage::UsageRegistry;
+
+// Wait before a tree whose file failed to load is read again, doubled for every further
+// failure in a row up to the maximum, so a corrupt file isn't parsed on every request
+const LOAD_RETRY_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_LOAD_RETRY_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+// The latest of one or more failed loads in a row
+#[derive(Debug, Clone)]
+pub struct LoadFailure {
+    pub error: String,
+    pub attempted_at: SystemTime,
+    pub consecutive: u32,
+    retry_at: Instant, // Loads before then fail with the recorded error without reading the file
+}
+
+impl LoadFailure {
+    pub fn retry_in(&self) -> Duration {
+        self.retry_at.saturating_duration_since(Instant::now())
+    }
+}
+
+// The resident tree is shared: readers that walk all of it clone the `Arc` under the
+// trees lock and release the lock, so they see one consistent version while inserts go
+// on. A writer that finds its version still shared changes a copy instead.
+#[derive(Debug)]
+pub struct KDTreeCache {
+    pub tree: Option<Arc<KDTree>>,
+    retired: Vec<(Weak<KDTree>, usize)>, // Replaced versions readers may still hold, with their size
+    pub last_accessed: Instant, // Only advanced by data-path requests
+    pub last_accessed_at: SystemTime, // Wall-clock twin of last_accessed, persisted in the manifest
+    pub created_at: Option<SystemTime>, // None for trees from before creation times were recorded
+    pub access_count: u64,
+    pub num_records: usize,     // Last known size, so admin endpoints never need to load the tree
+    pub dimensions: Option<usize>, // Known once the tree has been loaded or created
+    pub model: Option<String>,  // Embedding model tag of the tree, known like `dimensions`
+    pub offloaded_strategy: Option<OffloadedStrategy>, // Set at creation; only lives in the manifest
+    pub depth: Option<usize>,   // As of the last write, kept in the manifest for startup rebalancing
+    pub bloom: Option<BloomFilter>,
+    pub operation: Option<TreeOperation>, // Structural operation currently owning the tree
+    pub dirty: bool,            // Changes not yet written, left for the background flush
+    pub write_seq: u64,         // Bumped for every snapshot taken for persistence
+    pub persisted: Arc<Mutex<u64>>, // Newest snapshot on disk, shared with writers outside the trees lock
+    pub written: Arc<WriteTally>, // File writes not yet counted into the tree's usage
+    pub save_health: Arc<SaveHealth>, // Failed saves, shared with writers outside the trees lock
+    pub suspect: bool,          // The last self-test found a stored point the tree could not find
+    pub tier: Tier,
+    pub load_failure: Option<LoadFailure>, // Set while the tree's file fails to load, cleared by a successful load
+    pub warmed_at: Option<SystemTime>, // When startup warming last read the tree's file into the OS page cache
+}
+
+impl KDTreeCache {
+    pub fn new() -> Self {
+        KDTreeCache {
+            tree: None,
+            retired: Vec::new(),
+            last_accessed: Instant::now(),
+            last_accessed_at: UNIX_EPOCH,
+            created_at: Some(SystemTime::now()),
+            access_count: 0,
+            num_records: 0,
+            dimensions: None,
+            model: None,
+            offloaded_strategy: None,
+            depth: None,
+            bloom: None,
+            operation: None,
+            dirty: false,
+            write_seq: 0,
+            persisted: Arc::new(Mutex::new(0)),
+            written: Arc::default(),
+            save_health: Arc::default(),
+            suspect: false,
+            tier: Tier::Hot,
+            load_failure: None,
+            warmed_at: None,
+        }
+    }
+
+    // Registers a tree known from disk; nothing is loaded until it is needed
+    pub fn from_entry(entry: &TreeEntry) -> Self {
+        KDTreeCache {
+            access_count: entry.access_count,
+            num_records: entry.num_records,
+            dimensions: entry.dimensions,
+            model: entry.model.clone(),
+            offloaded_strategy: entry.offloaded_strategy,
+            depth: entry.depth,
+            last_accessed_at: UNIX_EPOCH + Duration::from_secs(entry.last_accessed_at),
+            created_at: (entry.created_at > 0).then(|| UNIX_EPOCH + Duration::from_secs(entry.created_at)),
+            tier: entry.tier,
+            ..KDTreeCache::new()
+        }
+    }
+
+    pub fn to_entry(&self) -> TreeEntry {
+        TreeEntry {
+            dimensions: self.dimensions,
+            model: self.model.clone(),
+            offloaded_strategy: self.offloaded_strategy,
+            depth: self.depth,
+            num_records: self.num_records,
+            access_count: self.access_count,
+            last_accessed_at: unix_seconds(self.last_accessed_at),
+            created_at: self.created_at.map_or(0, unix_seconds),
+            tier: self.tier.persisted(),
+            ..TreeEntry::default() // Usage totals are filled in from the usage registry
+        }
+    }
+
+    pub fn set_tree(&mut self, tree: KDTree) {
+        self.num_records = tree.len();
+        self.dimensions = Some(tree.input_dimensions());
+        self.model = tree.model().map(str::to_string);
+        self.load_failure = None;
+        self.take_tree();
+        self.tree = Some(Arc::new(tree));
+    }
+
+    // The error a load fails with right away while the last failure backs off
+    pub fn load_backoff(&self) -> Option<KdTreeError> {
+        let failure = self.load_failure.as_ref().filter(|failure| failure.retry_at > Instant::now())?;
+        Some(KdTreeError::LoadBackoff {
+            error: failure.error.clone(),
+            failures: failure.consecutive,
+            retry_in_secs: failure.retry_in().as_secs().max(1),
+        })
+    }
+
+    // Records how reading the tree's file went. A tree that has no file has nothing to
+    // report, and a failure backs off the next load further.
+    pub fn record_load<T>(&mut self, result: &Result<T, KdTreeError>) {
+        match result {
+            Ok(_) => self.load_failure = None,
+            Err(e) if e.is_not_found() => {}
+            Err(e) => {
+                let consecutive = self.load_failure.as_ref().map_or(1, |failure| failure.consecutive + 1);
+                let backoff = LOAD_RETRY_BACKOFF.saturating_mul(2u32.saturating_pow(consecutive - 1)).min(MAX_LOAD_RETRY_BACKOFF);
+                // With the whole chain, so `/status` says what was wrong with the file
+                let mut error = e.to_string();
+                let mut source = std::error::Error::source(e);
+                while let Some(cause) = source {
+                    error.push_str(&format!(": {}", cause));
+                    source = cause.source();
+                }
+                self.load_failure = Some(LoadFailure {
+                    error,
+                    attempted_at: SystemTime::now(),
+                    consecutive,
+                    retry_at: Instant::now() + backoff,
+                });
+            }
+        }
+    }
+
+    // Removes the resident tree, e.g. to evict it. Returned so a caller can drop a large
+    // tree after releasing the trees lock.
+    pub fn take_tree(&mut self) -> Option<Arc<KDTree>> {
+        let tree = self.tree.take()?;
+        self.retire(&tree);
+        Some(tree)
+    }
+
+    // The resident tree for a reader to keep after the trees lock is released
+    pub fn shared_tree(&self) -> Option<Arc<KDTree>> {
+        self.tree.clone()
+    }
+
+    // The resident tree for changing. A version readers still hold is copied first and
+    // left to them.
+    pub fn tree_mut(&mut self) -> Option<&mut KDTree> {
+        if let Some(tree) = self.tree.take() {
+            self.retire(&tree);
+            self.tree = Some(tree);
+        }
+        self.tree.as_mut().map(Arc::make_mut)
+    }
+
+    // Keeps counting a version that leaves the cache while readers hold it, since its
+    // memory is only freed once the last of them lets go
+    fn retire(&mut self, tree: &Arc<KDTree>) {
+        self.retired.retain(|(version, _)| version.strong_count() > 0);
+        if Arc::strong_count(tree) > 1 {
+            self.retired.push((Arc::downgrade(tree), estimate_memory_usage(tree)));
+        }
+    }
+
+    // Serializes the tree so it can be written after the trees lock is released. Without
+    // a bin directory (persistence disabled) there is nothing to write and the tree
+    // simply counts as saved.
+    pub fn snapshot(&mut self, bin_directory: Option<&Path>, tree_name: &str) -> io::Result<Option<PendingWrite>> {
+        let Some(tree) = &self.tree else { return Ok(None) };
+        self.depth = Some(tree.depth());
+        self.dirty = false;
+        let Some(bin_directory) = bin_directory else { return Ok(None) };
+        let image = tree.to_image()?;
+        self.write_seq += 1;
+        Ok(Some(PendingWrite {
+            path: get_bin_file_path(bin_directory, tree_name),
+            image,
+            seq: self.write_seq,
+            persisted: Arc::clone(&self.persisted),
+            written: Arc::clone(&self.written),
+            health: Arc::clone(&self.save_health),
+        }))
+    }
+
+    // Writes the tree while the caller still holds the trees lock, streaming it
+    // straight to the file instead of buffering a snapshot. Like `snapshot`, only marks
+    // the tree saved when there is no bin directory.
+    pub fn save_now(&mut self, bin_directory: Option<&Path>, tree_name: &str) -> io::Result<()> {
+        let Some(tree) = &self.tree else { return Ok(()) };
+        if let Some(bin_directory) = bin_directory {
+            self.write_seq += 1;
+            let mut persisted = self.persisted.lock().unwrap();
+            let saved = offload_tree(bin_directory, tree_name, tree);
+            self.save_health.record(&saved);
+            saved?;
+            *persisted = self.write_seq;
+            self.written.record(&get_bin_file_path(bin_directory, tree_name));
+        }
+        self.depth = Some(tree.depth());
+        self.dirty = false;
+        Ok(())
+    }
+
+    // Adds a point already reduced to the stored dimensions to the resident tree and its
+    // duplicate filter. Returns whether the depth bound forced a partial rebuild.
+    pub fn insert(&mut self, point: Point, max_depth_factor: Option<f64>) -> bool {
+        if self.tree.is_none() {
+            return false;
+        }
+        if let Some(filter) = &mut self.bloom {
+            filter.insert(&point.embedding);
+        }
+        let tree = self.tree_mut().unwrap();
+        let rebuilt = match max_depth_factor {
+            Some(factor) => tree.insert_bounded(point, factor),
+            None => {
+                tree.insert(point);
+                false
+            }
+        };
+        self.num_records += 1;
+        rebuilt
+    }
+
+    // Nothing to save: no unsaved changes, and no snapshot still waiting to be written
+    pub fn is_clean(&self) -> bool {
+        !self.dirty && self.persisted.try_lock().is_ok_and(|persisted| *persisted >= self.write_seq)
+    }
+
+    // Changes are waiting to be saved but saving them has been failing and is backing
+    // off, so evicting the tree would only fail again
+    pub fn save_backing_off(&self) -> bool {
+        !self.is_clean() && self.save_health.backing_off()
+    }
+
+    // Estimated memory held by the resident tree, its duplicate filter and any replaced
+    // versions readers still hold
+    pub fn resident_bytes(&self) -> usize {
+        let retired: usize = self.retired.iter()
+            .filter(|(version, _)| version.strong_count() > 0)
+            .map(|(_, bytes)| bytes)
+            .sum();
+        self.tree.as_deref().map_or(0, estimate_memory_usage) + self.bloom.as_ref().map_or(0, BloomFilter::size_in_bytes) + retired
+    }
+
+    // Record a user access for LRU purposes; administrative endpoints must not call this
+    pub fn touch(&mut self) {
+        self.last_accessed = Instant::now();
+        self.last_accessed_at = SystemTime::now();
+        self.access_count += 1;
+    }
+}
+
+impl Default for KDTreeCache {
+    fn default() -> Self {
+        KDTreeCache::new()
+    }
+}
+
+pub fn ensure_bin_directory(path: &Path) -> io::Result<()> {
+    if !path.exists() {
+        println!("Creating bin directory at: {:?}", path);
+        fs::create_dir_all(path)?;
+    }
+    Ok(())
+}
+
+pub fn get_bin_file_path(bin_directory: &Path, tree_name: &str) -> PathBuf {
+    bin_directory.join(format!("{}.bin", tree_name))
+}
+
+// Where a tree file that failed to load as corrupt is moved to
+pub fn get_quarantine_file_path(bin_directory: &Path, tree_name: &str) -> PathBuf {
+    bin_directory.join(format!("{}.bin.corrupt", tree_name))
+}
+
+// Fails with `NotFound` only when the tree has no file at all: a quarantined tree stays
+// corrupt until its file is restored, so it is never silently recreated empty
+pub fn load_tree(bin_directory: &Path, tree_name: &str, auto_migrate: bool) -> Result<KDTree, KdTreeError> {
+    let file_path = get_bin_file_path(bin_directory, tree_name);
+    if !file_path.exists() {
+        let quarantined = get_quarantine_file_path(bin_directory, tree_name);
+        if quarantined.exists() {
+            return Err(KdTreeError::Corrupt {
+                detail: format!("{:?} was quarantined after failing to load", quarantined),
+                source: None,
+            });
+        }
+        return Err(KdTreeError::Io(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("File not found: {:?}", file_path)
+        )));
+    }
+    let (tree, version) = KDTree::load_versioned(file_path.to_str().unwrap())?;
+    if auto_migrate && version < FORMAT_VERSION {
+        match tree.rewrite_legacy_file(file_path.to_str().unwrap(), version) {
+            Ok(()) => {
+                println!("Migrated tree {} from format v{} to v{}", tree_name, version, FORMAT_VERSION);
+                // Loaded again so the data stays in the new file's payload section
+                return KDTree::load_from_file(file_path.to_str().unwrap());
+            }
+            Err(e) => println!("Failed to migrate tree {} from format v{}: {}", tree_name, version, e),
+        }
+    }
+    Ok(tree)
+}
+
+// Moves a tree file that failed to load as corrupt out of the way, keeping it for
+// inspection. Later loads fail as corrupt until a restore writes a new file.
+pub fn quarantine_tree(bin_directory: &Path, tree_name: &str) -> io::Result<PathBuf> {
+    let quarantined = get_quarantine_file_path(bin_directory, tree_name);
+    fs::rename(get_bin_file_path(bin_directory, tree_name), &quarantined)?;
+    Ok(quarantined)
+}
+
+pub fn offload_tree(bin_directory: &Path, tree_name: &str, tree: &KDTree) -> Result<(), KdTreeError> {
+    let file_path = get_bin_file_path(bin_directory, tree_name);
+    tree.save_to_file(file_path.to_str().unwrap())
+}
+
+pub fn get_bloom_file_path(bin_directory: &Path, tree_name: &str) -> PathBuf {
+    bin_directory.join(format!("{}.bloom", tree_name))
+}
+
+// Loads the duplicate filter persisted next to the tree, rebuilding it when it is
+// missing or no longer matches the tree (e.g. the bin file was replaced without it)
+pub fn load_bloom(bin_directory: &Path, tree_name: &str, tree: &KDTree, settings: BloomSettings) -> BloomFilter {
+    let file_path = get_bloom_file_path(bin_directory, tree_name);
+    match BloomFilter::load_from_file(file_path.to_str().unwrap()) {
+        Ok(filter) if filter.items == tree.len() => filter,
+        _ => {
+            println!("Rebuilding duplicate filter for tree {}", tree_name);
+            BloomFilter::from_tree(tree, settings)
+        }
+    }
+}
+
+pub fn offload_bloom(bin_directory: &Path, tree_name: &str, filter: &BloomFilter) -> io::Result<()> {
+    let file_path = get_bloom_file_path(bin_directory, tree_name);
+    filter.save_to_file(file_path.to_str().unwrap())
+}
+
+pub fn resident_memory_usage(trees: &HashMap<String, KDTreeCache>) -> usize {
+    trees.values().map(KDTreeCache::resident_bytes).sum()
+}
+
+pub fn manage_memory(
+    trees: &mut HashMap<String, KDTreeCache>,
+    max_memory_usage: usize,
+    idle_after: Duration,
+    bin_directory: Option<&Path>
+) {
+    let mut total_memory_usage = resident_memory_usage(trees);
+
+    while total_memory_usage > max_memory_usage {
+        let candidates = trees.iter().filter(|(_, cache)| cache.tree.is_some() && !cache.save_backing_off());
+        let Some((tree_name, reason)) = choose_victim(candidates, idle_after) else { break };
+        match evict(trees, bin_directory, &tree_name, "over the memory limit", reason) {
+            Ok(freed) => total_memory_usage -= freed,
+            // Dropping the tree unsaved would lose its changes, so it stays over the limit
+            // until a save succeeds, e.g. once disk space is freed
+            Err(e) => {
+                println!("Failed to save tree {} for eviction, keeping it in memory: {}", tree_name, e);
+                break;
+            }
+        }
+    }
+}
+
+// Picks the resident tree to evict next, and says why. Among trees idle for at least
+// `idle_after`, the largest clean one goes first: dropping it frees the most and costs no
+// save. Otherwise it is the least recently used tree, dirty or not.
+fn choose_victim<'a>(
+    candidates: impl Iterator<Item = (&'a String, &'a KDTreeCache)>,
+    idle_after: Duration,
+) -> Option<(String, String)> {
+    let candidates: Vec<_> = candidates.collect();
+    let idle_clean = candidates
+        .iter()
+        .filter(|(_, cache)| cache.last_accessed.elapsed() >= idle_after && cache.is_clean())
+        .map(|(tree_name, cache)| (cache.resident_bytes(), *tree_name))
+        .max();
+    if let Some((_, tree_name)) = idle_clean {
+        return Some((tree_name.clone(), "largest clean idle tree".to_string()));
+    }
+    let (tree_name, cache) = candidates.into_iter().min_by_key(|(_, cache)| cache.last_accessed)?;
+    let reason = match cache.last_accessed.elapsed() >= idle_after {
+        true => "least recently used, no idle tree is clean".to_string(),
+        false => format!("least recently used, no tree idle for {}s", idle_after.as_secs()),
+    };
+    Some((tree_name.clone(), reason))
+}
+
+// Drops a resident tree and its filter from memory, saving the tree first unless it is
+// clean. Logs the eviction with why the tree was picked. Returns the bytes freed.
+// Without a bin directory the tree could never be loaded again, so it is forgotten.
+fn evict(
+    trees: &mut HashMap<String, KDTreeCache>,
+    bin_directory: Option<&Path>,
+    tree_name: &str,
+    trigger: &str,
+    reason: String,
+) -> io::Result<usize> {
+    let Some(cache) = trees.get_mut(tree_name) else { return Ok(0) };
+    let clean = cache.is_clean();
+    if !clean {
+        cache.save_now(bin_directory, tree_name)?;
+    }
+    let held = cache.resident_bytes();
+    cache.take_tree();
+    cache.bloom = None;
+    // A version a reader still holds stays counted until it is freed
+    let freed = held - cache.resident_bytes();
+    let idle = cache.last_accessed.elapsed().as_secs();
+    let state = match (bin_directory, clean) {
+        (None, _) => {
+            trees.remove(tree_name);
+            "dropped, persistence is disabled"
+        }
+        (Some(_), true) => "clean",
+        (Some(_), false) => "dirty, saved first",
+    };
+    println!(
+        "Evicted tree {} {}: {:.1} MB, idle {}s, {} ({})",
+        tree_name, trigger, freed as f64 / (1024.0 * 1024.0), idle, state, reason,
+    );
+    Ok(freed)
+}
+
+// Registers every tree file in the bin directory, then every archived tree that has no
+// file there, so status reporting and preloading know about trees before their first
+// request. The files decide the tier; a tree found in both places is hot. Manifest
+// entries without any file are trees created but never saved, kept so their dimension
+// still holds. Without an archive directory to look in, such entries keep the tier the
+// manifest gives them.
+pub fn register_trees(bin_directory: &Path, archive_directory: Option<&Path>, manifest: &Manifest) -> io::Result<HashMap<String, KDTreeCache>> {
+    let mut trees = HashMap::new();
+    for entry in fs::read_dir(bin_directory)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|extension| extension != "bin") {
+            continue;
+        }
+        if let Some(tree_name) = path.file_stem().and_then(|stem| stem.to_str()) {
+            let mut cache = manifest.trees.get(tree_name)
+                .map_or_else(KDTreeCache::new, KDTreeCache::from_entry);
+            cache.tier = Tier::Hot;
+            trees.insert(tree_name.to_string(), cache);
+        }
+    }
+    if let Some(Ok(entries)) = archive_directory.map(fs::read_dir) {
+        for entry in entries {
+            let path = entry?.path();
+            let Some(tree_name) = path.file_name().and_then(|name| name.to_str()).and_then(|name| name.strip_suffix(".bin.gz")) else {
+                continue;
+            };
+            if !trees.contains_key(tree_name) {
+                let mut cache = manifest.trees.get(tree_name)
+                    .map_or_else(KDTreeCache::new, KDTreeCache::from_entry);
+                cache.tier = Tier::Archived;
+                trees.insert(tree_name.to_string(), cache);
+            }
+        }
+    }
+    for (tree_name, entry) in &manifest.trees {
+        if !trees.contains_key(tree_name) {
+            let mut cache = KDTreeCache::from_entry(entry);
+            if archive_directory.is_some() {
+                cache.tier = Tier::Hot;
+            }
+            trees.insert(tree_name.clone(), cache);
+        }
+    }
+    Ok(trees)
+}
+
+pub fn manifest_of(trees: &HashMap<String, KDTreeCache>, usage: &UsageRegistry) -> Manifest {
+    Manifest {
+        trees: trees.iter()
+            .filter(|(_, cache)| cache.dimensions.is_some() || cache.tier != Tier::Hot)
+            .map(|(tree_name, cache)| {
+                let mut entry = cache.to_entry();
+                if let Some(usage) = usage.get(tree_name) {
+                    usage.fill_entry(&mut entry);
+                }
+                (tree_name.clone(), entry)
+            })
+            .collect(),
+    }
+}
+
+// Whether trees are kept in files at all. Disabled keeps everything in memory only: no
+// bin directory, nothing written, and a tree that is evicted or left at shutdown is lost.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Persistence {
+    #[default]
+    Enabled,
+    Disabled,
+}
+
+impl FromStr for Persistence {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, String> {
+        match value {
+            "enabled" => Ok(Persistence::Enabled),
+            "disabled" => Ok(Persistence::Disabled),
+            _ => Err(format!("unknown persistence mode: {}", value)),
+        }
+    }
+}
+
+pub struct StoreOptions {
+    pub persistence: Persistence,
+    pub max_memory_bytes: usize,        // Resident trees beyond this are evicted, least recently used first
+    pub auto_migrate: bool,             // Rewrite legacy tree files in the current format on load
+    pub max_depth_factor: Option<f64>,  // Depth bound as a multiple of log2(n), disabled when None
+    pub lock_wait_warn: Option<Duration>, // Waits for the trees lock at least this long are logged
+    pub soft_memory_bytes: Option<usize>, // `evict_above_soft_limit` works down to this; no background eviction when None
+    pub eviction_idle: Duration,        // Trees unused this long are evicted clean and largest first
+    pub strict_create: bool,            // Only trees created explicitly take inserts
+    pub max_dimensions: usize,          // Largest dimension a tree an insert creates may have
+}
+
+impl Default for StoreOptions {
+    fn default() -> Self {
+        StoreOptions {
+            persistence: Persistence::Enabled,
+            max_memory_bytes: 1024 * 1024 * 1024,
+            auto_migrate: false,
+            max_depth_factor: Some(2.0),
+            lock_wait_warn: None,
+            soft_memory_bytes: None,
+            eviction_idle: Duration::from_secs(60),
+            strict_create: false,
+            max_dimensions: 4096,
+        }
+    }
+}
+
+// A directory of trees. The server keeps one in its state; tests and small tools can
+// open one directly and work through `collection` handles without any HTTP.
+pub struct Store {
+    pub trees: TrackedMutex<HashMap<String, KDTreeCache>>,
+    pub bin_directory: PathBuf,
+    pub options: StoreOptions,
+    pub usage: UsageRegistry, // Per-tree read/write counters, recorded without the trees lock
+}
+
+impl Store {
+    pub fn new(bin_directory: PathBuf, options: StoreOptions, trees: HashMap<String, KDTreeCache>, usage: UsageRegistry) -> Self {
+        let trees = TrackedMutex::new(trees, "trees", options.lock_wait_warn);
+        Store { trees, bin_directory, options, usage }
+    }
+
+    // Opens `directory`, creating it if needed, and registers the trees already in it.
+    // Nothing is loaded until a collection is used. With persistence disabled the
+    // directory is left alone and the store starts empty.
+    pub fn open(directory: &Path, options: StoreOptions) -> io::Result<Store> {
+        if options.persistence == Persistence::Disabled {
+            return Ok(Store::new(directory.to_path_buf(), options, HashMap::new(), UsageRegistry::default()));
+        }
+        ensure_bin_directory(directory)?;
+        let manifest = Manifest::load(directory);
+        let trees = register_trees(directory, None, &manifest)?;
+        Ok(Store::new(directory.to_path_buf(), options, trees, UsageRegistry::from_manifest(&manifest)))
+    }
+
+    // The directory trees are saved to, None when persistence is disabled. Everything
+    // that writes goes through this, so both modes share their code paths.
+    pub fn disk(&self) -> Option<&Path> {
+        match self.options.persistence {
+            Persistence::Enabled => Some(&self.bin_directory),
+            Persistence::Disabled => None,
+        }
+    }
+
+    // Size of the tree's file, 0 when it has none. Rewriting a file writes the new one next
+    // to it, so this much space is needed again until the rename.
+    pub fn file_size(&self, tree_name: &str) -> u64 {
+        self.disk()
+            .and_then(|bin_directory| fs::metadata(get_bin_file_path(bin_directory, tree_name)).ok())
+            .map_or(0, |metadata| metadata.len())
+    }
+
+    // Whether the tree has a file, which never counts with persistence disabled
+    pub fn has_file(&self, tree_name: &str) -> bool {
+        self.disk().is_some_and(|bin_directory| get_bin_file_path(bin_directory, tree_name).exists())
+    }
+
+    pub fn collection(&self, name: &str) -> Collection<'_> {
+        Collection { store: self, name: name.to_string() }
+    }
+
+    pub fn load_tree(&self, tree_name: &str) -> Result<KDTree, KdTreeError> {
+        self.read_tree(tree_name, self.options.auto_migrate)
+    }
+
+    // Loads a tree from its file, always `NotFound` with persistence disabled
+    pub fn read_tree(&self, tree_name: &str, auto_migrate: bool) -> Result<KDTree, KdTreeError> {
+        match self.disk() {
+            Some(bin_directory) => load_tree(bin_directory, tree_name, auto_migrate),
+            None => Err(KdTreeError::Io(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Tree {} has no file: persistence is disabled", tree_name)
+            ))),
+        }
+    }
+
+    // The tree's duplicate filter, rebuilt from the tree when there is no file to read
+    pub fn load_bloom(&self, tree_name: &str, tree: &KDTree, settings: BloomSettings) -> BloomFilter {
+        match self.disk() {
+            Some(bin_directory) => load_bloom(bin_directory, tree_name, tree, settings),
+            None => BloomFilter::from_tree(tree, settings),
+        }
+    }
+
+    pub fn save_bloom(&self, tree_name: &str, filter: &BloomFilter) -> io::Result<()> {
+        match self.disk() {
+            Some(bin_directory) => offload_bloom(bin_directory, tree_name, filter),
+            None => Ok(()),
+        }
+    }
+
+    pub fn save_manifest(&self, trees: &HashMap<String, KDTreeCache>) -> io::Result<()> {
+        match self.disk() {
+            Some(bin_directory) => manifest_of(trees, &self.usage).save(bin_directory),
+            None => Ok(()),
+        }
+    }
+
+    // Makes sure a tree is resident, loading it from its file if it was offloaded.
+    // Fails with `NotFound` when the tree has no file.
+    pub fn load_into(&self, trees: &mut HashMap<String, KDTreeCache>, tree_name: &str) -> Result<(), KdTreeError> {
+        if trees.get(tree_name).is_none_or(|cache| cache.tree.is_none()) {
+            if let Some(error) = trees.get(tree_name).and_then(KDTreeCache::load_backoff) {
+                return Err(error);
+            }
+            match self.load_tree(tree_name) {
+                Ok(tree) => trees.entry(tree_name.to_string()).or_default().set_tree(tree),
+                Err(e) if e.is_not_found() => return Err(e),
+                loaded @ Err(_) => {
+                    trees.entry(tree_name.to_string()).or_default().record_load(&loaded);
+                    return loaded.map(drop);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Refuses to bring a tree of an estimated `required` bytes into memory when it would not
+    // fit the budget even with every other tree evicted; evicting them all would only
+    // thrash the cache
+    pub fn check_memory_budget(&self, tree_name: &str, required: usize) -> Result<(), String> {
+        if required <= self.options.max_memory_bytes {
+            return Ok(());
+        }
+        Err(format!(
+            "Tree {} needs an estimated {} bytes in memory but the memory budget is {} bytes",
+            tree_name, required, self.options.max_memory_bytes
+        ))
+    }
+
+    // Budget check for loading an offloaded tree, estimated from its file size
+    pub fn check_load_budget(&self, tree_name: &str, num_records: usize) -> Result<(), String> {
+        match self.disk().map(|bin_directory| fs::metadata(get_bin_file_path(bin_directory, tree_name))) {
+            Some(Ok(metadata)) => self.check_memory_budget(tree_name, estimate_load_size(metadata.len(), num_records)),
+            // Missing files are reported by the load itself
+            _ => Ok(()),
+        }
+    }
+
+    pub fn manage_memory(&self, trees: &mut HashMap<String, KDTreeCache>) {
+        manage_memory(trees, self.options.max_memory_bytes, self.options.eviction_idle, self.disk());
+    }
+
+    // Evicts a tree while resident memory is above the soft limit, picked like
+    // `manage_memory` does, one tree per call so the trees lock is released in between.
+    // Trees a structural operation owns are left alone. Returns the evicted tree and the
+    // bytes freed, or None when under the limit or nothing can be evicted.
+    pub fn evict_above_soft_limit(&self) -> io::Result<Option<(String, usize)>> {
+        let Some(limit) = self.options.soft_memory_bytes else { return Ok(None) };
+        let mut trees = self.trees.lock().unwrap();
+        if resident_memory_usage(&trees) <= limit {
+            return Ok(None);
+        }
+        let candidates = trees.iter()
+            .filter(|(_, cache)| cache.tree.is_some() && cache.operation.is_none() && !cache.save_backing_off());
+        let Some((tree_name, reason)) = choose_victim(candidates, self.options.eviction_idle) else { return Ok(None) };
+        let freed = evict(&mut trees, self.disk(), &tree_name, "above the soft limit", reason)?;
+        Ok(Some((tree_name, freed)))
+    }
+
+    // Writes every tree with unsaved changes, then the manifest
+    pub fn flush(&self) -> io::Result<()> {
+        let mut trees = self.trees.lock().unwrap();
+        for (tree_name, cache) in trees.iter_mut().filter(|(_, cache)| cache.dirty) {
+            cache.save_now(self.disk(), tree_name)?;
+        }
+        self.save_manifest(&trees)
+    }
+}
+
+// Set when a request names another embedding model than the one its tree was created
+// for. Two models often share a dimension, so nothing else would catch it.
+pub fn model_mismatch(tree_name: &str, tree_model: Option<&str>, requested: Option<&str>) -> Option<String> {
+    match (tree_model, requested) {
+        (Some(tree_model), Some(requested)) if tree_model != requested => Some(format!(
+            "Tree {} was created for embedding model {} but the request is for {}",
+            tree_name, tree_model, requested
+        )),
+        _ => None,
+    }
+}
+
+// Why a collection refused an insert or a search. The server answers each with its own
+// status code.
+#[derive(Debug)]
+pub enum CollectionError {
+    Invalid(String),       // The point or query doesn't fit the tree
+    NotFound(String),      // The tree doesn't exist and STRICT_CREATE keeps inserts from creating it
+    Archived(String),      // The tree was archived, so there is nothing in memory to add to
+    Busy(Value),           // A structural operation that doesn't allow writes owns the tree
+    ModelMismatch(String), // The point comes from another embedding model than the tree
+    OverBudget(String),    // The tree would not fit the memory budget even alone
+    Load(KdTreeError),     // The tree's file could not be read
+}
+
+impl fmt::Display for CollectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CollectionError::Invalid(message)
+            | CollectionError::NotFound(message)
+            | CollectionError::Archived(message)
+            | CollectionError::ModelMismatch(message)
+            | CollectionError::OverBudget(message) => f.write_str(message),
+            CollectionError::Busy(conflict) => write!(f, "{}", conflict),
+            CollectionError::Load(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for CollectionError {}
+
+// What an insert is checked against besides its tree's dimensions and schema
+#[derive(Clone, Copy, Default)]
+pub struct InsertChecks<'a> {
+    pub force: bool,            // Skip the memory budget check
+    pub model: Option<&'a str>, // Model the point comes from
+    pub strict_model: bool,     // Refuse the point if its tree was created for another model
+}
+
+// An insert that passed every check and only has to be applied
+pub struct PreparedInsert {
+    pub point: Point,  // Reduced to the stored dimensions
+    pub size: usize,   // Estimated memory the point adds
+    pub created: bool, // The insert created the tree implicitly
+}
+
+// A handle to one tree of a `Store`. The tree is created by the first insert, loaded on
+// use and evicted under memory pressure like any other. The server's handlers go through
+// the same checks, so embedded and HTTP use can't drift apart.
+pub struct Collection<'a> {
+    store: &'a Store,
+    name: String,
+}
+
+impl Collection<'_> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Makes sure the tree is resident, refusing to load one that can't fit the memory budget
+    pub fn load(&self, trees: &mut HashMap<String, KDTreeCache>) -> Result<(), CollectionError> {
+        if trees.get(&self.name).is_none_or(|cache| cache.tree.is_none()) {
+            let num_records = trees.get(&self.name).map_or(0, |cache| cache.num_records);
+            self.store.check_load_budget(&self.name, num_records).map_err(CollectionError::OverBudget)?;
+        }
+        self.store.load_into(trees, &self.name).map_err(CollectionError::Load)
+    }
+
+    // Checks an insert and brings the tree into `cache`, creating it if it has no file
+    // yet, but inserts nothing. The file is authoritative: only a tree that has no file
+    // yet may be created, otherwise a file that failed to load would be overwritten by a
+    // new tree on the next save. `footprint` carries the tree's estimated size from one
+    // insert of a batch to the next.
+    pub fn prepare_insert(
+        &self,
+        cache: &mut KDTreeCache,
+        point: Point,
+        InsertChecks { force, model, strict_model }: InsertChecks,
+        footprint: &mut Option<usize>,
+    ) -> Result<PreparedInsert, CollectionError> {
+        let tree_name = &self.name;
+        if point.embedding.is_empty() {
+            return Err(CollectionError::Invalid("Embedding must not be empty".to_string()));
+        }
+        if let Some(index) = point.embedding.iter().position(|component| !component.is_finite()) {
+            return Err(CollectionError::Invalid(format!("Embedding component {} is not a finite number", index)));
+        }
+        // Archived after the caller checked, so there is no file to load and add to
+        if cache.tier != Tier::Hot {
+            return Err(CollectionError::Archived(format!("Tree {} is archived", tree_name)));
+        }
+        if let Some(operation) = cache.operation.as_ref().filter(|operation| !operation.kind.allows_writes()) {
+            return Err(CollectionError::Busy(operation.conflict(tree_name)));
+        }
+
+        let mut created = false;
+        if cache.tree.is_none() {
+            if !force {
+                self.store.check_load_budget(tree_name, cache.num_records).map_err(CollectionError::OverBudget)?;
+            }
+            match self.store.load_tree(tree_name) {
+                Ok(loaded_tree) => cache.set_tree(loaded_tree),
+                Err(e) if e.is_not_found() => {
+                    if let Some(dimensions) = cache.dimensions.filter(|dimensions| *dimensions != point.len()) {
+                        return Err(CollectionError::Invalid(format!(
+                            "Point has {} dimensions but tree {} has {}",
+                            point.len(), tree_name, dimensions
+                        )));
+                    }
+                    // A known dimension means the tree was created explicitly and is only
+                    // waiting for its first save
+                    created = cache.dimensions.is_none();
+                    if created && self.store.options.strict_create {
+                        return Err(CollectionError::NotFound(format!(
+                            "Tree {} not found; STRICT_CREATE is on, so create it with /create_tree first",
+                            tree_name
+                        )));
+                    }
+                    if point.len() > self.store.options.max_dimensions {
+                        return Err(CollectionError::Invalid(format!(
+                            "Point has {} dimensions but MAX_DIMENSIONS is {}",
+                            point.len(), self.store.options.max_dimensions
+                        )));
+                    }
+                    if created {
+                        println!(
+                            "WARNING: Implicitly creating KD-Tree {} with {} dimensions inferred from its first point; \
+                             use /create_tree or STRICT_CREATE=true to fix dimensions up front",
+                            tree_name, point.len()
+                        );
+                    } else {
+                        println!("Creating KD-Tree {} with {} dimensions", tree_name, point.len());
+                    }
+                    let mut tree = KDTree::new(point.len())
+                        .map_err(|e| CollectionError::Invalid(format!("Failed to create KD-Tree: {}", e)))?;
+                    // An implicitly created tree is tagged with the model of its first point
+                    tree.set_model(if created { model.map(str::to_string) } else { cache.model.clone() });
+                    cache.set_tree(tree);
+                }
+                Err(e) => return Err(CollectionError::Load(e)),
+            }
+        }
+
+        let tree = cache.tree.as_ref().unwrap();
+        if let (true, Some(mismatch)) = (strict_model, model_mismatch(tree_name, tree.model(), model)) {
+            return Err(CollectionError::ModelMismatch(mismatch));
+        }
+        if point.len() != tree.input_dimensions() {
+            return Err(CollectionError::Invalid(format!(
+                "Point has {} dimensions but tree {} has {}",
+                point.len(), tree_name, tree.input_dimensions()
+            )));
+        }
+        let point = tree.reduce(Cow::Owned(point)).into_owned();
+        if let Some(Err(problem)) = tree.schema().map(|schema| schema.check(&point.metadata)) {
+            return Err(CollectionError::Invalid(format!(
+                "Point metadata does not match the schema of tree {}: {}",
+                tree_name, problem
+            )));
+        }
+
+        // With every other tree evicted this one alone must still fit
+        let size = estimate_point_size(&point);
+        if !force {
+            let current = *footprint.get_or_insert_with(|| {
+                estimate_memory_usage(tree) + cache.bloom.as_ref().map_or(0, BloomFilter::size_in_bytes)
+            });
+            self.store.check_memory_budget(tree_name, current + size).map_err(CollectionError::OverBudget)?;
+        }
+
+        cache.touch();
+        Ok(PreparedInsert { point, size, created })
+    }
+
+    // Inserts a prepared point into the tree `prepare_insert` left resident. True when the
+    // insert had to rebuild part of the tree to keep its depth bounded.
+    pub fn apply_insert(&self, cache: &mut KDTreeCache, prepared: PreparedInsert, footprint: &mut Option<usize>) -> bool {
+        if let Some(footprint) = footprint {
+            *footprint += prepared.size;
+        }
+        cache.insert(prepared.point, self.store.options.max_depth_factor)
+    }
+
+    // The query as the tree stores its points, reduced if the tree reduces them
+    pub fn query<'p>(&self, tree: &KDTree, query: &'p Point) -> Result<Cow<'p, Point>, CollectionError> {
+        if query.len() != tree.input_dimensions() {
+            return Err(CollectionError::Invalid(format!(
+                "Query has {} dimensions but tree {} has {}",
+                query.len(), self.name, tree.input_dimensions()
+            )));
+        }
+        Ok(tree.reduce(Cow::Borrowed(query)))
+    }
+
+    // Adds a point, creating the tree with the point's dimensions if it has no file yet.
+    // The change stays in memory until `flush` or an eviction writes it. A refused insert
+    // into a tree that didn't exist leaves none behind.
+    pub fn insert(&self, point: Point) -> Result<(), CollectionError> {
+        let mut trees = self.store.trees.lock().unwrap();
+        let registered = trees.contains_key(&self.name);
+        let cache = trees.entry(self.name.clone()).or_default();
+        let prepared = match self.prepare_insert(cache, point, InsertChecks::default(), &mut None) {
+            Ok(prepared) => prepared,
+            Err(e) => {
+                if !registered {
+                    trees.remove(&self.name);
+                }
+                return Err(e);
+            }
+        };
+        let size = prepared.point.content_size();
+        self.apply_insert(cache, prepared, &mut None);
+        cache.dirty = true;
+        self.store.usage.tree(&self.name).record_inserts(1, size);
+
+        self.store.manage_memory(&mut trees);
+        Ok(())
+    }
+
+    // The `n` points nearest to `embedding` with their distances, closest first
+    pub fn search(&self, embedding: &[f64], n: usize) -> Result<Vec<(f64, Point)>, CollectionError> {
+        let mut trees = self.store.trees.lock().unwrap();
+        self.load(&mut trees)?;
+        let cache = trees.get_mut(&self.name).unwrap();
+        cache.touch();
+        let tree = cache.tree.as_ref().unwrap();
+        let query = Point { embedding: embedding.to_vec(), ..Default::default() };
+        let hits = tree.nearest_neighbors_topn_owned(self.query(tree, &query)?.as_ref(), n);
+        self.store.usage.tree(&self.name).record_search(0);
+
+        self.store.manage_memory(&mut trees);
+        Ok(hits)
+    }
+
+    // Number of points, known without loading the tree
+    pub fn len(&self) -> usize {
+        self.store.trees.lock().unwrap().get(&self.name).map_or(0, |cache| cache.num_records)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Writes the tree if it has unsaved changes, then the manifest, which a reopened
+    // store reads the tree's size from
+    pub fn flush(&self) -> io::Result<()> {
+        let mut trees = self.store.trees.lock().unwrap();
+        match trees.get_mut(&self.name) {
+            Some(cache) if cache.dirty => cache.save_now(self.store.disk(), &self.name)?,
+            _ => return Ok(()),
+        }
+        self.store.save_manifest(&trees)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(x: f64) -> Point {
+        Point { embedding: vec![x, 0.0], ..Default::default() }
+    }
+
+    #[test]
+    fn readers_keep_their_version_while_writers_change_a_copy() {
+        let mut cache = KDTreeCache::new();
+        cache.set_tree(KDTree::build(2, (0..100).map(|i| point(i as f64)).collect()).unwrap());
+
+        // Without readers, inserts change the resident version in place
+        let resident = Arc::as_ptr(cache.tree.as_ref().unwrap());
+        cache.insert(point(-1.0), None);
+        assert_eq!(Arc::as_ptr(cache.tree.as_ref().unwrap()), resident);
+        let alone = cache.resident_bytes();
+
+        // A reader, such as an export, keeps the version it started with while inserts go on
+        let reader = cache.shared_tree().unwrap();
+        for i in 2..=10 {
+            cache.insert(point(-(i as f64)), None);
+        }
+        assert_eq!(reader.len(), 101);
+        assert_eq!(cache.tree.as_ref().unwrap().len(), 110);
+        // Both versions are counted until the reader lets go
+        let shared = cache.resident_bytes();
+        assert!(shared >= alone + estimate_memory_usage(&reader), "{} bytes for both versions", shared);
+        drop(reader);
+        assert!(cache.resident_bytes() < shared);
+
+        // An evicted version stays counted while it is read
+        let reader = cache.shared_tree().unwrap();
+        cache.take_tree();
+        assert_eq!(cache.resident_bytes(), estimate_memory_usage(&reader));
+        drop(reader);
+        assert_eq!(cache.resident_bytes(), 0);
+    }
+}
@@ -0,0 +1,143 @@
+// Shape of a tree for /tree_structure, to draw it for teaching and debugging. The walk
+// is read-only and bounded: nodes are described down to `max_depth` and at most
+// MAX_STRUCTURE_NODES of them in all, deeper subtrees are summarized by their size, and
+// no embeddings are included, so it can't turn into a dump of a large tree.
+use serde::Deserialize;
+use std::fmt::Write;
+
+use crate::api::{StructureNode, StructureRoot, TreeStructure};
+use crate::bloom::hash_pair;
+use crate::kdtree::{KDTree, Node};
+
+pub const DEFAULT_STRUCTURE_DEPTH: usize = 8;
+pub const MAX_STRUCTURE_DEPTH: usize = 12;
+
+// Enough for a single tree at MAX_STRUCTURE_DEPTH; partitioned trees share it, and
+// subtrees left over once it is spent are summarized
+const MAX_STRUCTURE_NODES: usize = (1 << (MAX_STRUCTURE_DEPTH + 1)) - 1;
+
+// Characters of a point's data shown on its node
+const PREVIEW_CHARS: usize = 40;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum StructureFormat {
+    #[default]
+    Json,
+    Dot, // Graphviz
+}
+
+struct Walk {
+    max_depth: usize,
+    budget: usize, // Nodes that can still be described
+    truncated: bool,
+}
+
+// Describes the shared tree and every partition subtree, in that order. Subtree sizes
+// are counted as the walk goes, which visits every point once but keeps none of them.
+pub fn describe(tree: &KDTree, tree_name: &str, max_depth: usize) -> TreeStructure {
+    let mut walk = Walk { max_depth, budget: MAX_STRUCTURE_NODES, truncated: false };
+    let roots = tree.labelled_roots()
+        .map(|(partition, root)| StructureRoot { partition: partition.map(str::to_string), root: walk.node(root, 0).0 })
+        .collect();
+    TreeStructure { tree_name: tree_name.to_string(), num_records: tree.len(), max_depth, truncated: walk.truncated, roots }
+}
+
+impl Walk {
+    // The description of `node` and the size of its subtree
+    fn node(&mut self, node: &Node, depth: usize) -> (StructureNode, usize) {
+        if depth > self.max_depth || self.budget == 0 {
+            self.truncated |= depth <= self.max_depth;
+            let size = 1 + KDTree::count_nodes(&node.left) + KDTree::count_nodes(&node.right);
+            return (StructureNode::Subtree { depth, size }, size);
+        }
+        self.budget -= 1;
+        let (left, left_size) = self.child(&node.left, depth + 1);
+        let (right, right_size) = self.child(&node.right, depth + 1);
+        let size = 1 + left_size + right_size;
+        let point = node.point();
+        let described = StructureNode::Node {
+            node: format!("{:016x}", hash_pair(&point.embedding).0),
+            depth,
+            axis: node.axis(),
+            split: node.split_value(node.axis()),
+            size,
+            preview: point.data().map(|data| preview(&data)),
+            left,
+            right,
+        };
+        (described, size)
+    }
+
+    fn child(&mut self, child: &Option<Box<Node>>, depth: usize) -> (Option<Box<StructureNode>>, usize) {
+        match child {
+            Some(node) => {
+                let (described, size) = self.node(node, depth);
+                (Some(Box::new(described)), size)
+            }
+            None => (None, 0),
+        }
+    }
+}
+
+// The first PREVIEW_CHARS characters on one line, marked when cut
+fn preview(data: &str) -> String {
+    let mut chars = data.chars();
+    let mut preview: String = chars.by_ref()
+        .take(PREVIEW_CHARS)
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect();
+    if chars.next().is_some() {
+        preview.push('…');
+    }
+    preview
+}
+
+// Graphviz DOT: a box per described node with its split, size and preview, a dashed
+// ellipse per summarized subtree, and a cluster per root. Left edges hold the points
+// below the split.
+pub fn to_dot(structure: &TreeStructure) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "digraph \"{}\" {{", escape(&structure.tree_name));
+    let _ = writeln!(out, "  node [shape=box, fontname=\"Helvetica\"];");
+    let mut next_id = 0;
+    for (index, root) in structure.roots.iter().enumerate() {
+        let label = root.partition.as_ref().map_or("shared".to_string(), |partition| format!("partition {}", partition));
+        let _ = writeln!(out, "  subgraph cluster_{} {{", index);
+        let _ = writeln!(out, "    label=\"{}\";", escape(&label));
+        write_dot_node(&mut out, &root.root, &mut next_id);
+        let _ = writeln!(out, "  }}");
+    }
+    out.push_str("}\n");
+    out
+}
+
+// Writes `node`, its descendants and the edges to them, returning the node's id
+fn write_dot_node(out: &mut String, node: &StructureNode, next_id: &mut usize) -> usize {
+    let id = *next_id;
+    *next_id += 1;
+    match node {
+        StructureNode::Subtree { size, .. } => {
+            let _ = writeln!(out, "    n{} [label=\"{} points\", shape=ellipse, style=dashed];", id, size);
+        }
+        StructureNode::Node { depth, axis, split, size, preview, left, right, .. } => {
+            let mut label = format!("axis {} split {}\\ndepth {}, {} points", axis, split, depth, size);
+            if let Some(preview) = preview {
+                let _ = write!(label, "\\n{}", escape(preview));
+            }
+            let _ = writeln!(out, "    n{} [label=\"{}\"];", id, label);
+            for (child, edge) in [(left, "<"), (right, ">=")] {
+                if let Some(child) = child {
+                    let child_id = write_dot_node(out, child, next_id);
+                    let _ = writeln!(out, "    n{} -> n{} [label=\"{}\"];", id, child_id, edge);
+                }
+            }
+        }
+    }
+    id
+}
+
+// Escapes a string for a quoted DOT id or label
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
@@ -0,0 +1,68 @@
+use serde::Serialize;
+
+use crate::bloom::hash_pair;
+
+// Longest trace returned by explain mode
+pub const MAX_TRACE_ENTRIES: usize = 500;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Branch {
+    Left,
+    Right,
+}
+
+// One node visited by an explained search
+#[derive(Serialize, Debug, Clone)]
+pub struct TraceEntry {
+    pub node: String,        // Hex hash of the node's embedding, stable across restarts
+    pub depth: usize,
+    pub axis: usize,
+    pub split: f64,          // The node's value on `axis`
+    pub distance: f64,       // Distance from the node to the target
+    pub branch: Branch,      // Side descended into first
+    pub plane_distance: f64, // Distance from the target to the splitting plane
+    pub bound: f64,          // Pruning bound once the first side had been searched
+    pub pruned: bool,        // Whether the other side was skipped because plane_distance >= bound
+}
+
+// Bounded record of a traversal, in visiting order. Entries past the cap are
+// dropped but still counted in `visited`.
+#[derive(Serialize, Debug, Default)]
+pub struct Trace {
+    pub visited: usize,
+    pub truncated: bool,
+    pub entries: Vec<TraceEntry>,
+}
+
+impl Trace {
+    // Records a visit and returns its index while there is room for it
+    pub fn visit(&mut self, embedding: &[f64], depth: usize, axis: usize, distance: f64, branch: Branch) -> Option<usize> {
+        self.visited += 1;
+        if self.entries.len() >= MAX_TRACE_ENTRIES {
+            self.truncated = true;
+            return None;
+        }
+        self.entries.push(TraceEntry {
+            node: format!("{:016x}", hash_pair(embedding).0),
+            depth,
+            axis,
+            split: embedding[axis],
+            distance,
+            branch,
+            plane_distance: 0.0,
+            bound: f64::INFINITY,
+            pruned: false,
+        });
+        Some(self.entries.len() - 1)
+    }
+
+    // Fills in the pruning decision made after the first side was searched
+    pub fn decide(&mut self, index: Option<usize>, plane_distance: f64, bound: f64, pruned: bool) {
+        if let Some(entry) = index.and_then(|index| self.entries.get_mut(index)) {
+            entry.plane_distance = plane_distance;
+            entry.bound = bound;
+            entry.pruned = pruned;
+        }
+    }
+}
@@ -0,0 +1,416 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+use crate::api::{TreeUsageReport, TreeUsageStatus, UsageResponse, UsageTotals};
+use crate::latency::SearchLatency;
+use crate::manifest::{unix_seconds, Manifest, TreeEntry};
+
+pub const USAGE_FILE: &str = "usage.json";
+
+const BUCKETS: usize = 60;
+
+pub const HOUR_SECS: u64 = 60 * 60;
+
+// Events counted in `BUCKETS` round-robin buckets of `width` seconds. The first recorder
+// to find a bucket stamped with an old period resets it, so an event racing with the
+// reset may be lost; that is fine for a rate.
+struct RateWindow {
+    width: u64,
+    stamps: [AtomicU64; BUCKETS], // Period (unix seconds / width) each bucket counts
+    counts: [AtomicU64; BUCKETS],
+}
+
+impl RateWindow {
+    fn new(width: u64) -> Self {
+        RateWindow {
+            width,
+            stamps: std::array::from_fn(|_| AtomicU64::new(0)),
+            counts: std::array::from_fn(|_| AtomicU64::new(0)),
+        }
+    }
+
+    fn record(&self, now: u64, count: u64) {
+        let period = now / self.width;
+        let slot = (period % BUCKETS as u64) as usize;
+        let stamp = self.stamps[slot].load(Ordering::Relaxed);
+        if stamp != period
+            && self.stamps[slot].compare_exchange(stamp, period, Ordering::Relaxed, Ordering::Relaxed).is_ok()
+        {
+            self.counts[slot].store(0, Ordering::Relaxed);
+        }
+        self.counts[slot].fetch_add(count, Ordering::Relaxed);
+    }
+
+    // Events over the whole window, the current bucket included
+    fn total(&self, now: u64) -> u64 {
+        let period = now / self.width;
+        (0..BUCKETS)
+            .filter(|&slot| period.saturating_sub(self.stamps[slot].load(Ordering::Relaxed)) < BUCKETS as u64)
+            .map(|slot| self.counts[slot].load(Ordering::Relaxed))
+            .sum()
+    }
+
+    // Events per second averaged over the whole window
+    fn per_second(&self, now: u64) -> f64 {
+        self.total(now) as f64 / self.span() as f64
+    }
+
+    fn span(&self) -> u64 {
+        BUCKETS as u64 * self.width
+    }
+}
+
+// One hour of a tree's usage, kept for billing over any window. Stored points and bytes
+// are sampled: `points` is the hour's last sample and `peak_points` its highest, and an
+// hour starts out with the previous hour's last sample.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct UsageHour {
+    pub hour: u64, // Unix seconds the hour starts at
+    pub inserts: u64,
+    pub searches: u64,
+    pub bytes_served: u64,
+    pub points: u64,
+    pub peak_points: u64,
+    pub bytes_stored: u64, // Size of the tree file
+    pub peak_bytes_stored: u64,
+}
+
+// Insert traffic of one tree over the last hour, for the write advisor
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RecentWrites {
+    pub window_secs: u64,
+    pub inserts: u64,
+    pub bytes_inserted: u64, // Content of the inserted points
+    pub saves: u64,          // Writes of the tree file
+    pub bytes_written: u64,
+}
+
+// Read and write counters of one tree, for telling used trees from abandoned ones.
+// Totals are persisted in the manifest and the hourly history in `usage.json`; the
+// rolling rates start over on restart.
+pub struct TreeUsage {
+    searches: AtomicU64,
+    inserts: AtomicU64,
+    bytes_served: AtomicU64,   // Response bytes of searches
+    bytes_inserted: AtomicU64, // Content of inserted points, see `Point::content_size`
+    bytes_written: AtomicU64,  // Tree file bytes written to disk
+    saves: AtomicU64,          // Tree file writes
+    last_write_at: AtomicU64,  // Unix seconds of the last applied insert, delete or truncation, 0 if never
+    last_minute: RateWindow,   // Searches, one bucket per second
+    last_hour: RateWindow,     // Searches, one bucket per minute
+    inserts_last_hour: RateWindow, // Points, one bucket per minute like the rest
+    inserted_last_hour: RateWindow, // Bytes of content
+    saves_last_hour: RateWindow,
+    written_last_hour: RateWindow, // Bytes
+    hours: Option<Mutex<VecDeque<UsageHour>>>, // Oldest first; None when no history is kept
+    pub latency: SearchLatency, // Not persisted
+}
+
+impl TreeUsage {
+    fn new(history: bool) -> Self {
+        TreeUsage {
+            searches: AtomicU64::new(0),
+            inserts: AtomicU64::new(0),
+            bytes_served: AtomicU64::new(0),
+            bytes_inserted: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            saves: AtomicU64::new(0),
+            last_write_at: AtomicU64::new(0),
+            last_minute: RateWindow::new(1),
+            last_hour: RateWindow::new(60),
+            inserts_last_hour: RateWindow::new(60),
+            inserted_last_hour: RateWindow::new(60),
+            saves_last_hour: RateWindow::new(60),
+            written_last_hour: RateWindow::new(60),
+            hours: history.then(Mutex::default),
+            latency: SearchLatency::default(),
+        }
+    }
+
+    fn from_entry(entry: &TreeEntry, history: bool) -> Self {
+        let usage = TreeUsage::new(history);
+        usage.searches.store(entry.searches, Ordering::Relaxed);
+        usage.inserts.store(entry.inserts, Ordering::Relaxed);
+        usage.bytes_served.store(entry.bytes_served, Ordering::Relaxed);
+        usage.bytes_inserted.store(entry.bytes_inserted, Ordering::Relaxed);
+        usage.bytes_written.store(entry.bytes_written, Ordering::Relaxed);
+        usage.saves.store(entry.saves, Ordering::Relaxed);
+        usage.last_write_at.store(entry.last_write_at, Ordering::Relaxed);
+        usage
+    }
+
+    pub fn record_search(&self, bytes: usize) {
+        let now = unix_seconds(SystemTime::now());
+        self.searches.fetch_add(1, Ordering::Relaxed);
+        self.bytes_served.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.last_minute.record(now, 1);
+        self.last_hour.record(now, 1);
+        self.in_hour(now, |hour| {
+            hour.searches += 1;
+            hour.bytes_served += bytes as u64;
+        });
+    }
+
+    // `bytes` is the content of the inserted points
+    pub fn record_inserts(&self, count: usize, bytes: usize) {
+        let now = unix_seconds(SystemTime::now());
+        self.inserts.fetch_add(count as u64, Ordering::Relaxed);
+        self.bytes_inserted.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.inserts_last_hour.record(now, count as u64);
+        self.inserted_last_hour.record(now, bytes as u64);
+        self.record_change();
+        self.in_hour(now, |hour| hour.inserts += count as u64);
+    }
+
+    // Writes of the tree file, drained from its `WriteTally`
+    pub fn record_saves(&self, saves: u64, bytes: u64, now: u64) {
+        self.saves.fetch_add(saves, Ordering::Relaxed);
+        self.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+        self.saves_last_hour.record(now, saves);
+        self.written_last_hour.record(now, bytes);
+    }
+
+    pub fn recent_writes(&self, now: u64) -> RecentWrites {
+        RecentWrites {
+            window_secs: self.inserts_last_hour.span(),
+            inserts: self.inserts_last_hour.total(now),
+            bytes_inserted: self.inserted_last_hour.total(now),
+            saves: self.saves_last_hour.total(now),
+            bytes_written: self.written_last_hour.total(now),
+        }
+    }
+
+    // Records how much the tree stores right now
+    pub fn record_stored(&self, points: u64, bytes: u64, now: u64) {
+        self.in_hour(now, |hour| {
+            hour.points = points;
+            hour.peak_points = hour.peak_points.max(points);
+            hour.bytes_stored = bytes;
+            hour.peak_bytes_stored = hour.peak_bytes_stored.max(bytes);
+        });
+    }
+
+    // Updates the hour `now` falls in, starting it if it is new
+    fn in_hour(&self, now: u64, update: impl FnOnce(&mut UsageHour)) {
+        let Some(hours) = &self.hours else { return };
+        let mut hours = hours.lock().unwrap();
+        let start = now - now % HOUR_SECS;
+        if hours.back().is_none_or(|hour| hour.hour < start) {
+            let (points, bytes_stored) = hours.back().map_or((0, 0), |hour| (hour.points, hour.bytes_stored));
+            hours.push_back(UsageHour {
+                hour: start,
+                points,
+                peak_points: points,
+                bytes_stored,
+                peak_bytes_stored: bytes_stored,
+                ..Default::default()
+            });
+        }
+        // A clock stepping back counts into the latest hour
+        update(hours.back_mut().unwrap());
+    }
+
+    // The recorded hours starting in [from, to), oldest first
+    pub fn hours(&self, from: u64, to: u64) -> Vec<UsageHour> {
+        let Some(hours) = &self.hours else { return Vec::new() };
+        hours.lock().unwrap().iter().filter(|hour| hour.hour >= from && hour.hour < to).cloned().collect()
+    }
+
+    // A write that removed points, which moves `last_write_at` but counts no inserts
+    pub fn record_change(&self) {
+        self.last_write_at.store(unix_seconds(SystemTime::now()), Ordering::Relaxed);
+    }
+
+    pub fn last_write_at(&self) -> u64 {
+        self.last_write_at.load(Ordering::Relaxed)
+    }
+
+    pub fn status(&self) -> TreeUsageStatus {
+        let now = unix_seconds(SystemTime::now());
+        TreeUsageStatus {
+            searches: self.searches.load(Ordering::Relaxed),
+            inserts: self.inserts.load(Ordering::Relaxed),
+            bytes_served: self.bytes_served.load(Ordering::Relaxed),
+            bytes_inserted: self.bytes_inserted.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            saves: self.saves.load(Ordering::Relaxed),
+            write_amplification: write_amplification(
+                self.bytes_written.load(Ordering::Relaxed),
+                self.bytes_inserted.load(Ordering::Relaxed),
+            ),
+            last_write_at: self.last_write_at.load(Ordering::Relaxed),
+            search_qps_1m: self.last_minute.per_second(now),
+            search_qps_1h: self.last_hour.per_second(now),
+        }
+    }
+
+    // Copies the persisted totals into a manifest entry
+    pub fn fill_entry(&self, entry: &mut TreeEntry) {
+        entry.searches = self.searches.load(Ordering::Relaxed);
+        entry.inserts = self.inserts.load(Ordering::Relaxed);
+        entry.bytes_served = self.bytes_served.load(Ordering::Relaxed);
+        entry.bytes_inserted = self.bytes_inserted.load(Ordering::Relaxed);
+        entry.bytes_written = self.bytes_written.load(Ordering::Relaxed);
+        entry.saves = self.saves.load(Ordering::Relaxed);
+        entry.last_write_at = self.last_write_at.load(Ordering::Relaxed);
+    }
+}
+
+// Usage of every tree, kept out of the trees map so request paths record with a shared
+// read lock and atomics instead of the trees lock
+#[derive(Default)]
+pub struct UsageRegistry {
+    trees: RwLock<HashMap<String, Arc<TreeUsage>>>,
+    retention_hours: u64, // Hours of history kept per tree; 0 keeps none
+}
+
+impl UsageRegistry {
+    pub fn from_manifest(manifest: &Manifest) -> Self {
+        let trees = manifest.trees.iter()
+            .map(|(tree_name, entry)| (tree_name.clone(), Arc::new(TreeUsage::from_entry(entry, false))))
+            .collect();
+        UsageRegistry { trees: RwLock::new(trees), retention_hours: 0 }
+    }
+
+    // Keeps `retention_days` of hourly history per tree, starting from what `usage.json`
+    // in `bin_directory` holds. Trees deleted since keep their history until it expires.
+    // An unreadable file is reported and ignored.
+    pub fn with_history(self, retention_days: u64, bin_directory: Option<&Path>) -> Self {
+        let retention_hours = retention_days * 24;
+        let mut saved = BTreeMap::new();
+        if let (true, Some(path)) = (retention_hours > 0, bin_directory.map(Self::history_path)) {
+            match fs::read(&path).map(|bytes| serde_json::from_slice::<BTreeMap<String, Vec<UsageHour>>>(&bytes)) {
+                Ok(Ok(history)) => saved = history,
+                Ok(Err(e)) => println!("Ignoring unreadable usage history {:?}: {}", path, e),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => println!("Ignoring unreadable usage history {:?}: {}", path, e),
+            }
+        }
+        let mut trees = self.trees.into_inner().unwrap();
+        for usage in trees.values_mut() {
+            let entry = Arc::get_mut(usage).unwrap();
+            entry.hours = (retention_hours > 0).then(Mutex::default);
+        }
+        for (tree_name, hours) in saved {
+            let usage = trees.entry(tree_name).or_insert_with(|| Arc::new(TreeUsage::new(true)));
+            Arc::get_mut(usage).unwrap().hours = Some(Mutex::new(hours.into()));
+        }
+        UsageRegistry { trees: RwLock::new(trees), retention_hours }
+    }
+
+    pub fn history_path(bin_directory: &Path) -> PathBuf {
+        bin_directory.join(USAGE_FILE)
+    }
+
+    pub fn retention_hours(&self) -> u64 {
+        self.retention_hours
+    }
+
+    // Drops hours older than the retention
+    pub fn prune(&self, now: u64) {
+        let cutoff = now.saturating_sub(self.retention_hours * HOUR_SECS);
+        for usage in self.trees.read().unwrap().values() {
+            if let Some(hours) = &usage.hours {
+                let mut hours = hours.lock().unwrap();
+                while hours.front().is_some_and(|hour| hour.hour + HOUR_SECS <= cutoff) {
+                    hours.pop_front();
+                }
+            }
+        }
+    }
+
+    // Every tree with recorded hours, by name
+    pub fn all(&self) -> BTreeMap<String, Arc<TreeUsage>> {
+        self.trees.read().unwrap().iter()
+            .filter(|(_, usage)| usage.hours.as_ref().is_some_and(|hours| !hours.lock().unwrap().is_empty()))
+            .map(|(tree_name, usage)| (tree_name.clone(), Arc::clone(usage)))
+            .collect()
+    }
+
+    // Written to a temporary file and renamed so a crash never leaves a torn file
+    pub fn save_history(&self, bin_directory: &Path) -> io::Result<()> {
+        if self.retention_hours == 0 {
+            return Ok(());
+        }
+        let history: BTreeMap<String, Vec<UsageHour>> = self.all().into_iter()
+            .map(|(tree_name, usage)| (tree_name, usage.hours(0, u64::MAX)))
+            .collect();
+        let path = Self::history_path(bin_directory);
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_vec(&history).map_err(io::Error::other)?)?;
+        fs::rename(tmp_path, path)
+    }
+
+    // The counters of `tree_name`, created on first use
+    pub fn tree(&self, tree_name: &str) -> Arc<TreeUsage> {
+        if let Some(usage) = self.get(tree_name) {
+            return usage;
+        }
+        let mut trees = self.trees.write().unwrap();
+        let history = self.retention_hours > 0;
+        Arc::clone(trees.entry(tree_name.to_string()).or_insert_with(|| Arc::new(TreeUsage::new(history))))
+    }
+
+    // For reports, which must not create counters for trees nobody used
+    pub fn get(&self, tree_name: &str) -> Option<Arc<TreeUsage>> {
+        self.trees.read().unwrap().get(tree_name).cloned()
+    }
+}
+
+// Bytes written to disk per byte of content inserted, None before anything was inserted
+pub fn write_amplification(bytes_written: u64, bytes_inserted: u64) -> Option<f64> {
+    (bytes_inserted > 0).then(|| bytes_written as f64 / bytes_inserted as f64)
+}
+
+// Sums a tree's recorded hours into its report
+pub fn report(tree_name: &str, hours: &[UsageHour]) -> TreeUsageReport {
+    let mut report = TreeUsageReport { tree_name: tree_name.to_string(), hours: hours.len(), ..Default::default() };
+    for hour in hours {
+        report.inserts += hour.inserts;
+        report.searches += hour.searches;
+        report.bytes_served += hour.bytes_served;
+        report.points = hour.points;
+        report.peak_points = report.peak_points.max(hour.peak_points);
+        report.bytes_stored = hour.bytes_stored;
+        report.peak_bytes_stored = report.peak_bytes_stored.max(hour.peak_bytes_stored);
+        report.point_hours += hour.points;
+        report.byte_hours += hour.bytes_stored;
+    }
+    report
+}
+
+pub fn totals(trees: &[TreeUsageReport]) -> UsageTotals {
+    let mut totals = UsageTotals { trees: trees.len(), ..Default::default() };
+    for tree in trees {
+        totals.inserts += tree.inserts;
+        totals.searches += tree.searches;
+        totals.bytes_served += tree.bytes_served;
+        totals.points += tree.points;
+        totals.bytes_stored += tree.bytes_stored;
+        totals.point_hours += tree.point_hours;
+        totals.byte_hours += tree.byte_hours;
+    }
+    totals
+}
+
+// One row per tree under a header, for spreadsheets and billing imports. Tree names
+// never need quoting.
+pub fn to_csv(usage: &UsageResponse) -> String {
+    let mut out = String::from(
+        "tree_name,from,to,hours,inserts,searches,bytes_served,points,peak_points,bytes_stored,peak_bytes_stored,point_hours,byte_hours\n",
+    );
+    for tree in &usage.trees {
+        out.push_str(&format!(
+            "{},{},{},{},{},{},{},{},{},{},{},{},{}\n",
+            tree.tree_name, usage.from, usage.to, tree.hours, tree.inserts, tree.searches, tree.bytes_served,
+            tree.points, tree.peak_points, tree.bytes_stored, tree.peak_bytes_stored, tree.point_hours, tree.byte_hours,
+        ));
+    }
+    out
+}
@@ -0,0 +1,109 @@
+// Startup warming of the OS page cache. After a host reboot even trees that get loaded
+// read their files from a cold disk, and trees left on disk answer their first
+// `cache=false` searches and scans just as slowly. Warming reads the chosen tree files
+// through once, sequentially and at a capped rate, and keeps nothing of them: the point
+// is only that the kernel has the pages when the tree is first read for real.
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Bytes read at a time, and between checks of the rate cap
+const WARM_CHUNK_BYTES: usize = 1024 * 1024;
+
+// Which tree files to warm, e.g. `tree_a,tree_b` or `all`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum WarmFiles {
+    All,
+    Trees(Vec<String>),
+}
+
+impl WarmFiles {
+    pub fn selects(&self, tree_name: &str) -> bool {
+        match self {
+            WarmFiles::All => true,
+            WarmFiles::Trees(trees) => trees.iter().any(|selected| selected == tree_name),
+        }
+    }
+}
+
+impl FromStr for WarmFiles {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, String> {
+        if value.trim().eq_ignore_ascii_case("all") {
+            return Ok(WarmFiles::All);
+        }
+        let mut trees: Vec<String> = Vec::new();
+        for tree_name in value.split(',').map(str::trim).filter(|tree_name| !tree_name.is_empty()) {
+            if !trees.iter().any(|selected| selected == tree_name) {
+                trees.push(tree_name.to_string());
+            }
+        }
+        match trees.is_empty() {
+            true => Err("expected `all` or a comma-separated list of tree names".to_string()),
+            false => Ok(WarmFiles::Trees(trees)),
+        }
+    }
+}
+
+impl TryFrom<String> for WarmFiles {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, String> {
+        value.parse()
+    }
+}
+
+impl From<WarmFiles> for String {
+    fn from(files: WarmFiles) -> String {
+        files.to_string()
+    }
+}
+
+impl fmt::Display for WarmFiles {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WarmFiles::All => write!(f, "all"),
+            WarmFiles::Trees(trees) => write!(f, "{}", trees.join(",")),
+        }
+    }
+}
+
+// Reads the file at `path` to its end, at most `max_bytes_per_sec` when set, and returns
+// the bytes read. A file that ends before its recorded size fails, so a truncated tree
+// file shows up in the log before anything tries to load it.
+pub fn warm_file(path: &Path, max_bytes_per_sec: Option<u64>) -> io::Result<u64> {
+    let mut file = File::open(path)?;
+    let expected = file.metadata()?.len();
+    let mut buffer = vec![0; WARM_CHUNK_BYTES];
+    let started = Instant::now();
+    let mut read = 0u64;
+    loop {
+        match file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(bytes) => read += bytes as u64,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+        // Sleeps off any lead over the rate the cap allows
+        if let Some(rate) = max_bytes_per_sec {
+            let due = Duration::from_secs_f64(read as f64 / rate as f64);
+            if let Some(ahead) = due.checked_sub(started.elapsed()) {
+                thread::sleep(ahead);
+            }
+        }
+    }
+    if read < expected {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("read {} of the {} bytes the file should have", read, expected),
+        ));
+    }
+    Ok(read)
+}
@@ -0,0 +1,154 @@
+// Snapshot of the JSON the API speaks: the shape of every response, and the fields each
+// request body accepts as named by the 400 an unknown one gets. A change to either shows
+// up as a diff of tests/snapshots/api_schema.json; run with UPDATE_SNAPSHOTS=1 to accept it.
+mod common;
+
+use actix_web::test::TestRequest;
+use common::{insert, send};
+use serde_json::{json, Map, Value};
+use std::fs;
+use std::path::Path;
+
+const SNAPSHOT: &str = "tests/snapshots/api_schema.json";
+
+// The value with every leaf replaced by its JSON type, and arrays by the merged shape of
+// their elements
+fn shape(value: &Value) -> Value {
+    match value {
+        Value::Null => json!("null"),
+        Value::Bool(_) => json!("boolean"),
+        Value::Number(_) => json!("number"),
+        Value::String(_) => json!("string"),
+        Value::Array(elements) => match elements.iter().map(shape).reduce(merge) {
+            Some(element) => json!([element]),
+            None => json!([]),
+        },
+        Value::Object(fields) => Value::Object(fields.iter().map(|(key, value)| (key.clone(), shape(value))).collect()),
+    }
+}
+
+fn merge(a: Value, b: Value) -> Value {
+    match (a, b) {
+        (Value::Object(mut a), Value::Object(b)) => {
+            for (key, value) in b {
+                let merged = match a.remove(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => value,
+                };
+                a.insert(key, merged);
+            }
+            Value::Object(a)
+        }
+        (Value::Array(a), Value::Array(b)) => match a.into_iter().chain(b).reduce(merge) {
+            Some(element) => json!([element]),
+            None => json!([]),
+        },
+        // A field that is sometimes null, say, is recorded with both types
+        (Value::String(a), Value::String(b)) => {
+            let mut types: Vec<&str> = a.split(" | ").chain(b.split(" | ")).collect();
+            types.sort();
+            types.dedup();
+            json!(types.join(" | "))
+        }
+        (a, b) => json!(format!("{} | {}", a, b)),
+    }
+}
+
+fn keys_outside_snake_case(value: &Value, path: &str, found: &mut Vec<String>) {
+    match value {
+        Value::Object(fields) => {
+            for (key, value) in fields {
+                let snake = key.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+                if !snake {
+                    found.push(format!("{}.{}", path, key));
+                }
+                keys_outside_snake_case(value, &format!("{}.{}", path, key), found);
+            }
+        }
+        Value::Array(elements) => elements.iter().for_each(|element| keys_outside_snake_case(element, path, found)),
+        _ => {}
+    }
+}
+
+fn post(uri: &str, body: Value) -> TestRequest {
+    TestRequest::post().uri(uri).set_json(body)
+}
+
+#[actix_web::test]
+async fn api_json_matches_the_snapshot() {
+    let store = common::state();
+    let service = store.service().await;
+    for (i, lang) in ["en", "de", "fr", "en"].iter().enumerate() {
+        let point = json!({ "embedding": [i as f64, 1.0], "data": format!("doc {}", i), "metadata": { "lang": lang, "doc": i % 2 } });
+        send(&service, insert("docs", point)).await;
+    }
+
+    let query = json!({ "embedding": [1.0, 1.0] });
+    let responses = vec![
+        ("POST /insert", insert("docs", json!({ "embedding": [9.0, 9.0], "metadata": { "lang": "ja", "doc": 7 } }))),
+        ("POST /insert_multi", post("/insert_multi", json!([{ "tree_name": "other", "point": { "embedding": [1.0] } }]))),
+        ("POST /create_tree", TestRequest::post().uri("/create_tree?tree_name=made&dimensions=3")),
+        ("POST /nearesttop", post("/nearesttop?tree_name=docs&n=2", query.clone())),
+        ("POST /nearesttop fields", post("/nearesttop?tree_name=docs&n=2&fields=data,distance", query.clone())),
+        ("POST /nearesttop explain", post("/nearesttop?tree_name=docs&n=2&explain=true", query.clone())),
+        ("POST /nearesttop histogram", post("/nearesttop?tree_name=docs&n=2&histogram=true", query.clone())),
+        ("POST /nearesttop group_by", post("/nearesttop?tree_name=docs&n=2&group_by=doc", query.clone())),
+        ("POST /nearesttop b64", post("/nearesttop?tree_name=docs&n=2&encoding=b64", query.clone())),
+        ("POST /exists_within", post("/exists_within?tree_name=docs&distance=1", query.clone())),
+        ("POST /get_by_embedding", post("/get_by_embedding?tree_name=docs", query.clone())),
+        ("POST /estimate", post("/estimate", json!({ "dimensions": 384, "points": 1000 }))),
+        ("GET /stats", TestRequest::get().uri("/stats?tree_name=docs")),
+        ("GET /schema", TestRequest::get().uri("/schema?tree_name=docs")),
+        ("GET /sample", TestRequest::get().uri("/sample?tree_name=docs")),
+        ("GET /status", TestRequest::get().uri("/status")),
+        ("GET /v1/status", TestRequest::get().uri("/v1/status")),
+        ("GET /trees", TestRequest::get().uri("/trees")),
+        ("GET /v1/trees", TestRequest::get().uri("/v1/trees")),
+        ("GET /v1/cache", TestRequest::get().uri("/v1/cache?tree_name=docs")),
+        ("GET /usage", TestRequest::get().uri("/usage")),
+        ("GET /advisor", TestRequest::get().uri("/advisor")),
+        ("GET /jobs", TestRequest::get().uri("/jobs")),
+        ("GET /routes", TestRequest::get().uri("/routes")),
+        ("GET /config", TestRequest::get().uri("/config")),
+        ("POST /nearesttop invalid", post("/nearesttop?tree_name=docs&n=0", query.clone())),
+        // Last, since searching the empty tree counts as an access in /status
+        ("POST /nearesttop empty", post("/nearesttop?tree_name=made&n=2&fields=data", json!({ "embedding": [1.0, 1.0, 1.0] }))),
+    ];
+    let mut snapshot = Map::new();
+    let mut outside_snake_case = Vec::new();
+    for (name, request) in responses {
+        let (status, body) = send(&service, request).await;
+        keys_outside_snake_case(&body, name, &mut outside_snake_case);
+        snapshot.insert(name.to_string(), json!({ "status": status.as_u16(), "body": shape(&body) }));
+    }
+
+    // Each request body names the fields it takes when given one it doesn't
+    let typo = json!({ "embeding": [1.0, 1.0] });
+    let bodies = vec![
+        ("POST /insert body", post("/insert?tree_name=docs", typo.clone())),
+        ("POST /insert_multi body", post("/insert_multi", json!([{ "tree_name": "docs", "point": { "embedding": [1.0, 1.0] }, "durability": "fsync" }]))),
+        ("POST /insert_multi point", post("/insert_multi", json!([{ "tree_name": "docs", "point": typo.clone() }]))),
+        ("POST /nearesttop body", post("/nearesttop?tree_name=docs&n=1", typo.clone())),
+        ("POST /estimate body", post("/estimate", json!({ "dimensions": 384, "point": 1000 }))),
+    ];
+    for (name, request) in bodies {
+        let (status, body) = send(&service, request).await;
+        assert_eq!(status.as_u16(), 400, "{}: {}", name, body);
+        snapshot.insert(name.to_string(), json!({ "status": status.as_u16(), "error": body }));
+    }
+    assert!(outside_snake_case.is_empty(), "keys outside snake_case: {:?}", outside_snake_case);
+
+    let snapshot = serde_json::to_string_pretty(&Value::Object(snapshot)).unwrap() + "\n";
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(SNAPSHOT);
+    if std::env::var_os("UPDATE_SNAPSHOTS").is_some() {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, &snapshot).unwrap();
+        return;
+    }
+    let expected = fs::read_to_string(&path).unwrap_or_default();
+    assert!(
+        snapshot == expected,
+        "the API's JSON changed; check the difference and run with UPDATE_SNAPSHOTS=1 to accept it:\n{}",
+        snapshot
+    );
+}
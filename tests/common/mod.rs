@@ -0,0 +1,65 @@
+// What the HTTP tests share: a server state over a fresh bin directory, and shorthands
+// for the requests they send through `actix_web::test`
+#![allow(dead_code)]
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::{test, web};
+use serde_json::Value;
+use tempfile::TempDir;
+use vodb::config::Settings;
+use vodb::server::{all_routes, app, build_state, APPState};
+
+pub struct TestState {
+    pub state: web::Data<APPState>,
+    pub bin_directory: TempDir,
+}
+
+pub fn state() -> TestState {
+    state_with(|_| {})
+}
+
+pub fn state_with(configure: impl FnOnce(&mut Settings)) -> TestState {
+    let bin_directory = tempfile::tempdir().unwrap();
+    let mut settings = Settings { bin_directory: bin_directory.path().to_path_buf(), ..Default::default() };
+    configure(&mut settings);
+    TestState { state: build_state(settings, false).unwrap(), bin_directory }
+}
+
+impl TestState {
+    // A new server over the same bin directory, as after a restart
+    pub fn restarted(self) -> TestState {
+        let settings = Settings { bin_directory: self.bin_directory.path().to_path_buf(), ..Default::default() };
+        TestState { state: build_state(settings, false).unwrap(), bin_directory: self.bin_directory }
+    }
+
+    pub async fn service(
+        &self,
+    ) -> impl Service<actix_http::Request, Response = ServiceResponse<impl MessageBody>, Error = actix_web::Error> {
+        test::init_service(app(self.state.clone(), all_routes)).await
+    }
+}
+
+// Sends `request` and returns the status with the body, parsed as JSON when it is JSON
+pub async fn send<S, B>(service: &S, request: test::TestRequest) -> (StatusCode, Value)
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let response = test::call_service(service, request.to_request()).await;
+    let status = response.status();
+    let body = test::read_body(response).await;
+    let body = serde_json::from_slice(&body).unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&body).into_owned()));
+    (status, body)
+}
+
+pub fn insert(tree_name: &str, point: Value) -> test::TestRequest {
+    test::TestRequest::post().uri(&format!("/insert?tree_name={}&durability=fsync", tree_name)).set_json(point)
+}
+
+pub fn search(tree_name: &str, n: usize, params: &str, embedding: &[f64]) -> test::TestRequest {
+    test::TestRequest::post()
+        .uri(&format!("/nearesttop?tree_name={}&n={}{}", tree_name, n, params))
+        .set_json(serde_json::json!({ "embedding": embedding }))
+}
@@ -0,0 +1,84 @@
+mod common;
+
+use actix_web::http::{header, StatusCode};
+use actix_web::test;
+use common::{search, send};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde_json::{json, Value};
+use std::io::Write;
+
+fn gzip(body: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(body).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn compressed(uri: &str, encoding: &str, body: Vec<u8>) -> test::TestRequest {
+    test::TestRequest::post()
+        .uri(uri)
+        .insert_header((header::CONTENT_TYPE, "application/json"))
+        .insert_header((header::CONTENT_ENCODING, encoding))
+        .set_payload(body)
+}
+
+#[actix_web::test]
+async fn compressed_batches_are_inserted() {
+    let store = common::state();
+    let service = store.service().await;
+    let batch: Vec<Value> = (0..8)
+        .map(|i| json!({ "tree_name": format!("tree-{}", i), "point": { "embedding": [i as f64, 1.0], "data": "x".repeat(1000) } }))
+        .collect();
+    let body = serde_json::to_vec(&batch).unwrap();
+    let wire = gzip(&body);
+    assert!(wire.len() * 10 < body.len());
+    let (status, response) = send(&service, compressed("/insert_multi", "gzip", wire)).await;
+    assert_eq!(status, StatusCode::OK, "{}", response);
+
+    let point = serde_json::to_vec(&json!({ "embedding": [5.0, 5.0], "data": "zstd" })).unwrap();
+    let wire = zstd::encode_all(point.as_slice(), 3).unwrap();
+    let (status, response) = send(&service, compressed("/insert?tree_name=tree-0", "zstd", wire)).await;
+    assert_eq!(status, StatusCode::OK, "{}", response);
+
+    for i in 0..8 {
+        let (_, body) = send(&service, search(&format!("tree-{}", i), 10, "", &[i as f64, 1.0])).await;
+        let results = body["results"].as_array().unwrap();
+        assert_eq!(results[0]["data"].as_str().unwrap().len(), 1000, "{}", i);
+        assert_eq!(results.len(), if i == 0 { 2 } else { 1 });
+    }
+}
+
+#[actix_web::test]
+async fn bodies_are_limited_by_their_decompressed_size() {
+    let store = common::state_with(|settings| settings.max_payload_mb = 1);
+    let service = store.service().await;
+    // A few kilobytes on the wire that expand to 16 MB
+    let bomb = serde_json::to_vec(&json!({ "embedding": [1.0, 2.0], "data": "x".repeat(16 * 1024 * 1024) })).unwrap();
+    let wire = gzip(&bomb);
+    assert!(wire.len() < 64 * 1024);
+    let (status, body) = send(&service, compressed("/insert?tree_name=docs", "gzip", wire)).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert!(body.as_str().unwrap().contains("once decompressed"), "{}", body);
+
+    let batch = serde_json::to_vec(&vec![json!({ "tree_name": "docs", "point": { "embedding": [1.0], "data": "y".repeat(2 * 1024 * 1024) } })]).unwrap();
+    let (status, _) = send(&service, compressed("/insert_multi", "zstd", zstd::encode_all(batch.as_slice(), 3).unwrap())).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+    // Nothing was inserted
+    let (status, _) = send(&service, search("docs", 1, "", &[1.0, 2.0])).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // Just under the limit decompressed still goes through
+    let point = serde_json::to_vec(&json!({ "embedding": [1.0, 2.0], "data": "x".repeat(512 * 1024) })).unwrap();
+    let (status, body) = send(&service, compressed("/insert?tree_name=docs", "gzip", gzip(&point))).await;
+    assert!(status.is_success(), "{} {}", status, body);
+}
+
+#[actix_web::test]
+async fn unknown_encodings_are_unsupported_media() {
+    let store = common::state();
+    let service = store.service().await;
+    let (status, body) = send(&service, compressed("/insert?tree_name=docs", "lzma", b"{}".to_vec())).await;
+    assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    assert!(body.as_str().unwrap().contains("lzma"), "{}", body);
+}
@@ -0,0 +1,79 @@
+// A tree's dimension at the API boundary: empty embeddings are refused before any tree is
+// built from them, and mismatches name both sides
+mod common;
+
+use actix_web::test::TestRequest;
+use common::{insert, search, send};
+use serde_json::json;
+
+#[actix_web::test]
+async fn an_empty_embedding_is_refused_and_the_server_carries_on() {
+    let store = common::state();
+    let service = store.service().await;
+
+    let (status, body) = send(&service, insert("docs", json!({ "embedding": [], "data": "x" }))).await;
+    assert_eq!(status.as_u16(), 400, "{}", body);
+    assert_eq!(body, "Embedding must not be empty");
+    let multi = TestRequest::post().uri("/insert_multi").set_json(json!([{ "tree_name": "docs", "point": { "embedding": [] } }]));
+    let (status, body) = send(&service, multi).await;
+    assert_eq!(status.as_u16(), 400, "{}", body);
+    assert_eq!(body["fields"][0]["field"], "[0].point.embedding", "{}", body);
+    let (status, body) = send(&service, TestRequest::post().uri("/create_tree?tree_name=docs&dimensions=0")).await;
+    assert_eq!(status.as_u16(), 400, "{}", body);
+
+    // No zero-dimension tree was left behind to trip up the next request
+    let (_, trees) = send(&service, TestRequest::get().uri("/v1/trees")).await;
+    assert_eq!(trees["trees"], json!([]), "{}", trees);
+
+    let (status, body) = send(&service, insert("docs", json!({ "embedding": [1.0, 2.0], "data": "x" }))).await;
+    assert_eq!(status.as_u16(), 200, "{}", body);
+    let (status, body) = send(&service, search("docs", 1, "", &[])).await;
+    assert_eq!(status.as_u16(), 400, "{}", body);
+    assert_eq!(body, "Query embedding must not be empty");
+    let (status, body) = send(&service, search("docs", 1, "", &[1.0, 2.0])).await;
+    assert_eq!(status.as_u16(), 200, "{}", body);
+    assert_eq!(body["results"][0]["data"], "x", "{}", body);
+}
+
+#[actix_web::test]
+async fn dimension_mismatches_name_both_sides() {
+    let store = common::state();
+    let service = store.service().await;
+    send(&service, insert("docs", json!({ "embedding": [1.0, 2.0] }))).await;
+
+    let (status, body) = send(&service, search("docs", 1, "", &[1.0, 2.0, 3.0])).await;
+    assert_eq!(status.as_u16(), 400);
+    assert_eq!(body, "Query has 3 dimensions but tree docs has 2");
+    let (status, body) = send(&service, insert("docs", json!({ "embedding": [1.0] }))).await;
+    assert_eq!(status.as_u16(), 400);
+    assert_eq!(body, "Point has 1 dimensions but tree docs has 2");
+
+    for uri in ["/v1/trees", "/v1/status"] {
+        let (_, body) = send(&service, TestRequest::get().uri(uri)).await;
+        assert_eq!(body["trees"][0]["dimensions"], 2, "{}: {}", uri, body);
+    }
+}
+
+#[actix_web::test]
+async fn insert_multi_echoes_refs_and_sums_up_a_refused_request() {
+    let store = common::state();
+    let service = store.service().await;
+    send(&service, insert("narrow", json!({ "embedding": [1.0, 2.0] }))).await;
+
+    let entries = json!([
+        { "tree_name": "wide", "point": { "embedding": [1.0, 2.0, 3.0] }, "ref": { "doc": 7 } },
+        { "tree_name": "narrow", "point": { "embedding": [1.0, 2.0, 3.0] }, "ref": "doc-7" },
+    ]);
+    let (status, body) = send(&service, TestRequest::post().uri("/insert_multi").set_json(entries)).await;
+    assert_eq!(status.as_u16(), 400, "{}", body);
+    assert_eq!(body["entries"][0]["ref"], json!({ "doc": 7 }));
+    assert_eq!(body["entries"][1]["ref"], "doc-7");
+    assert_eq!(body["entries"][1]["status"], "failed");
+    let summary = &body["summary"];
+    assert_eq!((summary["succeeded"].clone(), summary["failed"].clone(), summary["skipped"].clone()), (json!(0), json!(1), json!(1)), "{}", body);
+
+    // The ref is not part of the stored point
+    let (_, body) = send(&service, search("narrow", 1, "", &[1.0, 2.0])).await;
+    assert_eq!(body["results"].as_array().unwrap().len(), 1);
+    assert!(body["results"][0].get("ref").is_none(), "{}", body);
+}
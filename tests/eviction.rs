@@ -0,0 +1,48 @@
+// Which tree goes when memory runs short: the least recently used one, where only data
+// requests count as use. Monitoring that polls a cold tree must not keep it resident.
+mod common;
+
+use actix_web::http::StatusCode;
+use actix_web::test::TestRequest;
+use common::{insert, search, send};
+use serde_json::{json, Value};
+
+async fn tree_status<S, B>(service: &S, tree_name: &str) -> Value
+where
+    S: actix_web::dev::Service<actix_http::Request, Response = actix_web::dev::ServiceResponse<B>, Error = actix_web::Error>,
+    B: actix_web::body::MessageBody,
+{
+    let (_, body) = send(service, TestRequest::get().uri(&format!("/v1/status?tree_name={}", tree_name))).await;
+    body["trees"][0].clone()
+}
+
+#[actix_web::test]
+async fn a_status_poller_does_not_keep_a_cold_tree_resident() {
+    let store = common::state_with(|settings| settings.max_memory_mb = 1);
+    let service = store.service().await;
+    let chunk = "x".repeat(400 * 1024);
+    for tree_name in ["cold", "hot"] {
+        let (status, _) = send(&service, insert(tree_name, json!({ "embedding": [1.0, 2.0], "data": chunk }))).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+    let (status, _) = send(&service, search("hot", 1, "", &[1.0, 2.0])).await;
+    assert_eq!(status, StatusCode::OK);
+    let accesses = tree_status(&service, "cold").await["access_count"].clone();
+
+    // Monitoring reads the cold tree over and over after the hot one was last searched
+    for _ in 0..10 {
+        for uri in ["/status", "/v1/status?tree_name=cold", "/stats?tree_name=cold", "/trees", "/metrics", "/usage", "/advisor"] {
+            let (status, body) = send(&service, TestRequest::get().uri(uri)).await;
+            assert_eq!(status, StatusCode::OK, "{}: {}", uri, body);
+        }
+    }
+    assert_eq!(tree_status(&service, "cold").await["access_count"], accesses);
+
+    // A third tree pushes memory over the budget, and the tree nobody searched goes
+    let (status, _) = send(&service, insert("new", json!({ "embedding": [1.0, 2.0], "data": chunk }))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(tree_status(&service, "hot").await["in_memory"], json!(true));
+    assert_eq!(tree_status(&service, "cold").await["in_memory"], json!(false));
+    let (status, _) = send(&service, search("hot", 1, "&if_in_memory=true", &[1.0, 2.0])).await;
+    assert_eq!(status, StatusCode::OK);
+}
@@ -0,0 +1,119 @@
+// Exports over a real connection: one whose client disconnects part way through the
+// stream is dropped on the server, and a slow one sees the tree as it was when it
+// started while inserts go on
+use actix_web::dev::ServerHandle;
+use actix_web::HttpServer;
+use serde_json::json;
+use std::time::{Duration, Instant};
+use vodb::server::{all_routes, app};
+
+mod common;
+
+const POINTS: usize = 20_000;
+
+// A server on a real port over a tree `docs` of `points` points with 1KB of data each,
+// loaded with one import
+async fn serve_docs(points: usize) -> (common::TestState, String, ServerHandle) {
+    let store = common::state_with(|settings| settings.import_directory = Some(settings.bin_directory.join("import")));
+    let import = store.bin_directory.path().join("import");
+    std::fs::create_dir(&import).unwrap();
+    let data = "x".repeat(1000);
+    let lines: Vec<String> = (0..points).map(|i| json!({ "embedding": [i as f64, 1.0], "data": data }).to_string()).collect();
+    std::fs::write(import.join("docs.jsonl"), lines.join("\n")).unwrap();
+
+    let state = store.state.clone();
+    let server = HttpServer::new(move || app(state.clone(), all_routes)).workers(2).bind(("127.0.0.1", 0)).unwrap();
+    let url = format!("http://{}", server.addrs()[0]);
+    let server = server.run();
+    let handle = server.handle();
+    actix_web::rt::spawn(server);
+    let path = import.join("docs.jsonl");
+    let response = reqwest::Client::new()
+        .post(format!("{}/import_jsonl?tree_name=docs&path={}", url, path.to_str().unwrap()))
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success(), "{}", response.text().await.unwrap());
+    (store, url, handle)
+}
+
+// The value of an unlabelled counter in the /metrics text
+async fn counter(http: &reqwest::Client, url: &str, name: &str) -> u64 {
+    let metrics = http.get(format!("{}/metrics", url)).send().await.unwrap().text().await.unwrap();
+    metrics
+        .lines()
+        .find_map(|line| line.strip_prefix(name).and_then(|value| value.trim().parse().ok()))
+        .unwrap_or_else(|| panic!("no {} in\n{}", name, metrics))
+}
+
+#[actix_web::test]
+async fn an_export_stops_when_its_client_disconnects() {
+    let (_store, url, handle) = serve_docs(POINTS).await;
+    let http = reqwest::Client::new();
+
+    let export = reqwest::Client::new();
+    let mut response = export.get(format!("{}/export?tree_name=docs", url)).send().await.unwrap();
+    assert!(response.status().is_success());
+    let first = response.chunk().await.unwrap().unwrap();
+    assert!(first.starts_with(b"{"));
+    drop(response);
+    drop(export);
+
+    // The stream is dropped soon after the connection closes, with most lines unsent
+    let dropped = Instant::now();
+    while counter(&http, &url, "vodb_exports_cancelled_total").await == 0 {
+        assert!(dropped.elapsed() < Duration::from_secs(5), "the export kept running after its client left");
+        actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    // The server carries on, and an export read to the end isn't counted
+    let lines = http.get(format!("{}/export?tree_name=docs", url)).send().await.unwrap().text().await.unwrap();
+    assert_eq!(lines.lines().count(), POINTS);
+    assert_eq!(counter(&http, &url, "vodb_exports_cancelled_total").await, 1);
+
+    handle.stop(true).await;
+}
+
+#[actix_web::test]
+async fn a_slow_export_sees_the_tree_as_of_its_start_while_inserts_go_on() {
+    const START: usize = 2_000;
+    const INSERTS: usize = 20;
+    let (_store, url, handle) = serve_docs(START).await;
+    let http = reqwest::Client::new();
+
+    // The client reads the export slowly, and inserts land while it does
+    let mut response = http.get(format!("{}/export?tree_name=docs&fields=data", url)).send().await.unwrap();
+    assert!(response.status().is_success());
+    let export = async {
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await.unwrap() {
+            body.extend_from_slice(&chunk);
+            // Lines arrive about one per chunk
+            actix_web::rt::time::sleep(Duration::from_millis(1)).await;
+        }
+        (body, Instant::now())
+    };
+    let inserts = async {
+        for i in 0..INSERTS {
+            let point = json!({ "embedding": [-(i as f64), 2.0], "data": "new" });
+            let started = Instant::now();
+            let response = http.post(format!("{}/insert?tree_name=docs", url)).json(&point).send().await.unwrap();
+            assert!(response.status().is_success(), "{}", response.text().await.unwrap());
+            assert!(started.elapsed() < Duration::from_secs(2), "an insert waited for the export");
+        }
+        Instant::now()
+    };
+    let ((body, exported), inserted) = futures_util::join!(export, inserts);
+    assert!(inserted < exported, "the export finished before the inserts, so they never overlapped");
+
+    // Exactly the points there were when it started, each once and in sequence order
+    let lines: Vec<serde_json::Value> = String::from_utf8(body).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    assert_eq!(lines.len(), START);
+    let seqs: Vec<u64> = lines.iter().map(|line| line["seq"].as_u64().unwrap()).collect();
+    assert!(seqs.windows(2).all(|pair| pair[0] < pair[1]));
+    assert!(lines.iter().all(|line| line["data"] != "new"));
+
+    let trees: serde_json::Value = http.get(format!("{}/v1/trees", url)).send().await.unwrap().json().await.unwrap();
+    assert_eq!(trees["trees"][0]["num_records"], START + INSERTS);
+    handle.stop(true).await;
+}
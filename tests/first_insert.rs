@@ -0,0 +1,126 @@
+// Stress test for tree creation: many concurrent first inserts into a tree that doesn't
+// exist yet, on several workers, must all land in the one tree that gets created. A
+// refused first insert must not create anything.
+use actix_web::http::StatusCode;
+use actix_web::test::TestRequest;
+use actix_web::HttpServer;
+use futures_util::future::join_all;
+use common::{insert, send};
+use serde_json::{json, Value};
+use vodb::server::{all_routes, app};
+
+mod common;
+
+const DIMENSIONS: usize = 8;
+const INSERTS: usize = 50;
+const ROUNDS: usize = 5;
+
+#[actix_web::test]
+async fn concurrent_first_inserts_share_one_tree() {
+    let store = common::state();
+    let state = store.state.clone();
+    let server = HttpServer::new(move || app(state.clone(), all_routes)).workers(4).bind(("127.0.0.1", 0)).unwrap();
+    let url = format!("http://{}", server.addrs()[0]);
+    let server = server.run();
+    let handle = server.handle();
+    actix_web::rt::spawn(server);
+    let http = reqwest::Client::new();
+
+    // A few fresh names, so the race is run more than once
+    for round in 0..ROUNDS {
+        let tree_name = format!("fresh-{}", round);
+        let inserts = (0..INSERTS).map(|i| {
+            let embedding: Vec<f64> = (0..DIMENSIONS).map(|axis| (i * DIMENSIONS + axis) as f64).collect();
+            http.post(format!("{}/insert?tree_name={}&durability=fsync", url, tree_name))
+                .json(&json!({ "embedding": embedding, "data": i.to_string() }))
+                .send()
+        });
+        for response in join_all(inserts).await {
+            let response = response.unwrap();
+            assert!(response.status().is_success(), "{}", response.text().await.unwrap());
+        }
+    }
+
+    let trees: Value = http.get(format!("{}/v1/trees", url)).send().await.unwrap().json().await.unwrap();
+    let trees = trees["trees"].as_array().unwrap();
+    assert_eq!(trees.len(), ROUNDS, "{:?}", trees);
+    for tree in trees {
+        assert_eq!(tree["num_records"], INSERTS, "{}", tree);
+        assert_eq!(tree["dimensions"], DIMENSIONS, "{}", tree);
+    }
+
+    // What was saved holds every point too, not just the tree in memory
+    for round in 0..ROUNDS {
+        let tree_name = format!("fresh-{}", round);
+        let dropped = http.delete(format!("{}/cache?tree_name={}", url, tree_name)).send().await.unwrap();
+        assert!(dropped.status().is_success(), "{}", dropped.text().await.unwrap());
+        let response: Value = http
+            .post(format!("{}/nearesttop?tree_name={}&n={}", url, tree_name, INSERTS * 2))
+            .json(&json!({ "embedding": vec![0.0; DIMENSIONS] }))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(response["collection"]["num_records"], INSERTS);
+        let hits = response["results"].as_array().unwrap_or_else(|| panic!("{}", response));
+        assert_eq!(hits.len(), INSERTS);
+        let mut data: Vec<usize> = hits.iter().map(|hit| hit["data"].as_str().unwrap().parse().unwrap()).collect();
+        data.sort();
+        assert_eq!(data, (0..INSERTS).collect::<Vec<_>>());
+        assert!(hits.iter().all(|hit| hit["embedding"].as_array().unwrap().len() == DIMENSIONS));
+    }
+
+    handle.stop(true).await;
+}
+
+// The trees `/trees` and `/status` list, which must agree
+async fn listed_trees<S, B>(service: &S) -> Value
+where
+    S: actix_web::dev::Service<actix_http::Request, Response = actix_web::dev::ServiceResponse<B>, Error = actix_web::Error>,
+    B: actix_web::body::MessageBody,
+{
+    let (_, trees) = send(service, TestRequest::get().uri("/trees")).await;
+    let (_, status) = send(service, TestRequest::get().uri("/status")).await;
+    assert_eq!(trees["trees"].as_array().map(Vec::len), status["trees"].as_array().map(Vec::len), "{}\n{}", trees, status);
+    trees["trees"].clone()
+}
+
+#[actix_web::test]
+async fn a_strict_create_rejection_leaves_no_tree_behind() {
+    let store = common::state_with(|settings| settings.strict_create = true);
+    let service = store.service().await;
+    for _ in 0..2 {
+        let (status, body) = send(&service, insert("ghost", json!({ "embedding": [1.0, 2.0] }))).await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "{}", body);
+        assert_eq!(listed_trees(&service).await, json!([]));
+    }
+
+    // Created properly, the same name takes inserts
+    let (status, _) = send(&service, TestRequest::post().uri("/create_tree?tree_name=ghost&dimensions=2")).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, body) = send(&service, insert("ghost", json!({ "embedding": [1.0, 2.0] }))).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(listed_trees(&service).await[0]["num_records"], 1);
+}
+
+#[actix_web::test]
+async fn a_first_insert_over_max_dimensions_leaves_no_tree_behind() {
+    let store = common::state_with(|settings| settings.max_dimensions = 4);
+    let service = store.service().await;
+    let (status, body) = send(&service, insert("wide", json!({ "embedding": [1.0, 2.0, 3.0, 4.0, 5.0] }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+    assert_eq!(body, "Point has 5 dimensions but MAX_DIMENSIONS is 4");
+    assert_eq!(listed_trees(&service).await, json!([]));
+}
+
+#[actix_web::test]
+async fn a_first_insert_over_the_memory_budget_leaves_no_tree_behind() {
+    let store = common::state_with(|settings| settings.max_memory_mb = 1);
+    let service = store.service().await;
+    let data = "x".repeat(1024 * 1024);
+    let (status, body) = send(&service, insert("huge", json!({ "embedding": [1.0, 2.0], "data": data }))).await;
+    assert_eq!(status, StatusCode::INSUFFICIENT_STORAGE, "{}", body);
+    assert_eq!(listed_trees(&service).await, json!([]));
+}
@@ -0,0 +1,80 @@
+// Load test for the lock wait histogram: concurrent searches and inserts on several
+// workers contend for the trees lock, and the waits show up in /metrics under route
+// patterns and known trees only
+use actix_web::HttpServer;
+use futures_util::future::join_all;
+use serde_json::json;
+use vodb::server::{all_routes, app};
+
+mod common;
+
+const DIMENSIONS: usize = 32;
+const SEED_POINTS: usize = 3_000;
+const REQUESTS: usize = 400;
+
+fn embedding(seed: usize) -> Vec<f64> {
+    (0..DIMENSIONS).map(|i| ((seed * 31 + i * 17) % 97) as f64 / 97.0).collect()
+}
+
+#[actix_web::test]
+async fn lock_waits_are_recorded_under_contention() {
+    let store = common::state();
+    let state = store.state.clone();
+    let server = HttpServer::new(move || app(state.clone(), all_routes)).workers(4).bind(("127.0.0.1", 0)).unwrap();
+    let url = format!("http://{}", server.addrs()[0]);
+    let server = server.run();
+    let handle = server.handle();
+    actix_web::rt::spawn(server);
+    let http = reqwest::Client::new();
+
+    let seeds = (0..SEED_POINTS).map(|i| {
+        http.post(format!("{}/insert?tree_name=hot&durability=none", url)).json(&json!({ "embedding": embedding(i) })).send()
+    });
+    for response in join_all(seeds).await {
+        assert!(response.unwrap().status().is_success());
+    }
+
+    let requests = (0..REQUESTS).map(|i| {
+        let request = match i % 4 {
+            0 => http.post(format!("{}/insert?tree_name=hot&durability=none", url)).json(&json!({ "embedding": embedding(SEED_POINTS + i) })),
+            // Made-up and encoded names, which must not get labels of their own
+            1 => http.post(format!("{}/nearesttop?tree_name=ghost-{}&n=5", url, i)).json(&json!({ "embedding": embedding(i) })),
+            2 if i % 8 == 2 => http.get(format!("{}/jobs/{}", url, i)),
+            _ => http.post(format!("{}/nearesttop?tree_name=%68ot&n=100", url)).json(&json!({ "embedding": embedding(i) })),
+        };
+        request.send()
+    });
+    for response in join_all(requests).await {
+        response.unwrap();
+    }
+
+    let metrics = http.get(format!("{}/metrics", url)).send().await.unwrap().text().await.unwrap();
+    let counts: Vec<(String, String, u64)> = metrics
+        .lines()
+        .filter_map(|line| line.strip_prefix("vodb_lock_wait_seconds_count{"))
+        .map(|line| {
+            let (labels, count) = line.split_once("} ").unwrap();
+            let label = |name: &str| labels.split(',').find_map(|pair| pair.strip_prefix(&format!("{}=\"", name))).unwrap().trim_end_matches('"').to_string();
+            (label("endpoint"), label("tree"), count.parse().unwrap())
+        })
+        .collect();
+
+    for (endpoint, tree, _) in &counts {
+        assert!(["/insert", "/nearesttop", "/jobs/{id}", "/metrics", "writer", "background"].contains(&endpoint.as_str()), "{}", endpoint);
+        assert!(["hot", "other", ""].contains(&tree.as_str()), "{}", tree);
+    }
+    let searches: u64 = counts.iter().filter(|(endpoint, tree, _)| endpoint == "/nearesttop" && tree == "hot").map(|(_, _, count)| count).sum();
+    assert!(searches >= (REQUESTS / 4) as u64);
+    let ghosts = counts.iter().find(|(endpoint, tree, _)| endpoint == "/nearesttop" && tree == "other");
+    assert!(ghosts.is_some_and(|(_, _, count)| *count >= (REQUESTS / 4) as u64));
+
+    // Four workers searching a tree of this size under the one lock do wait for it
+    let waited: f64 = metrics
+        .lines()
+        .filter(|line| line.starts_with("vodb_lock_wait_seconds_sum{") && line.contains("endpoint=\"/nearesttop\""))
+        .map(|line| line.rsplit_once(' ').unwrap().1.parse::<f64>().unwrap())
+        .sum();
+    assert!(waited > 0.0, "no lock waits recorded:\n{}", metrics);
+
+    handle.stop(true).await;
+}
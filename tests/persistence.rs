@@ -0,0 +1,130 @@
+mod common;
+
+use actix_web::http::StatusCode;
+use actix_web::test;
+use common::{insert, search, send};
+use serde_json::json;
+use std::fs;
+use std::time::Duration;
+
+// Makes every save of `tree_name` fail: the temporary file it writes first can't be
+// created where a directory of that name is in the way
+fn block_saves(store: &common::TestState, tree_name: &str) {
+    fs::create_dir(store.bin_directory.path().join(format!("{}.bin.tmp", tree_name))).unwrap();
+}
+
+#[actix_web::test]
+async fn writes_are_refused_once_saves_fail_past_the_deadline() {
+    let store = common::state_with(|settings| settings.persistence_deadline_secs = 1);
+    let service = store.service().await;
+    let (status, _) = send(&service, insert("docs", json!({ "embedding": [1.0, 2.0] }))).await;
+    assert_eq!(status, StatusCode::OK);
+
+    block_saves(&store, "docs");
+    // The point is kept in memory and acknowledged as unsaved
+    let (status, body) = send(&service, insert("docs", json!({ "embedding": [3.0, 4.0] }))).await;
+    assert_eq!(status, StatusCode::ACCEPTED, "{}", body);
+    assert_eq!(body["durability"], "none");
+    assert!(body["save_error"].as_str().is_some(), "{}", body);
+    let (_, status_body) = send(&service, test::TestRequest::get().uri("/v1/status?tree_name=docs")).await;
+    let tree = &status_body["trees"][0];
+    assert_eq!(tree["save_failures"], 1, "{}", status_body);
+    assert_eq!(tree["dirty"], true);
+    assert_eq!(tree["save_failure"]["consecutive_failures"], 1);
+    assert_eq!(tree["save_failure"]["degraded"], false);
+
+    // Within the deadline writes are still taken
+    let (status, _) = send(&service, insert("docs", json!({ "embedding": [5.0, 6.0] }))).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+
+    actix_web::rt::time::sleep(Duration::from_millis(1100)).await;
+    let request = insert("docs", json!({ "embedding": [7.0, 8.0] })).to_request();
+    let response = test::call_service(&service, request).await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.headers().get("Retry-After").unwrap(), "60");
+    let (_, status_body) = send(&service, test::TestRequest::get().uri("/v1/status")).await;
+    assert_eq!(status_body["totals"]["persistence_degraded"], 1, "{}", status_body);
+    assert_eq!(status_body["trees"][0]["save_failure"]["degraded"], true);
+
+    // Reads go on, and other trees still take writes
+    let (status, _) = send(&service, search("docs", 10, "", &[1.0, 2.0])).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = send(&service, insert("other", json!({ "embedding": [1.0, 2.0] }))).await;
+    assert_eq!(status, StatusCode::OK);
+
+}
+
+#[actix_web::test]
+async fn a_failed_save_keeps_the_point_in_memory_and_the_tree_resident() {
+    let store = common::state_with(|settings| settings.max_memory_mb = 1);
+    let service = store.service().await;
+    let chunk = "x".repeat(600 * 1024);
+    let (status, _) = send(&service, insert("first", json!({ "embedding": [1.0, 2.0], "data": chunk }))).await;
+    assert_eq!(status, StatusCode::OK);
+
+    block_saves(&store, "first");
+    let (status, body) = send(&service, insert("first", json!({ "embedding": [3.0, 4.0], "data": "unsaved" }))).await;
+    assert_eq!(status, StatusCode::ACCEPTED, "{}", body);
+
+    // A second tree pushes memory over the budget. Evicting the first would mean saving
+    // it, which keeps failing, so it stays resident with its unsaved point.
+    let (status, _) = send(&service, insert("second", json!({ "embedding": [1.0, 2.0], "data": chunk }))).await;
+    assert_eq!(status, StatusCode::OK);
+    let (_, body) = send(&service, test::TestRequest::get().uri("/v1/status?tree_name=first")).await;
+    let tree = &body["trees"][0];
+    assert_eq!((tree["in_memory"].clone(), tree["dirty"].clone()), (json!(true), json!(true)), "{}", body);
+    assert_eq!(tree["num_records"], 2);
+
+    let (status, body) = send(&service, search("first", 10, "&if_in_memory=true", &[3.0, 4.0])).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["results"][0]["data"], "unsaved", "{}", body);
+    // Dropping it would lose the point, so that needs saying explicitly
+    let (status, _) = send(&service, test::TestRequest::delete().uri("/cache?tree_name=first")).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+}
+
+#[actix_web::test]
+async fn a_restart_keeps_the_dimension_of_the_tree_on_disk() {
+    let store = common::state();
+    let service = store.service().await;
+    let (status, _) = send(&service, insert("docs", json!({ "embedding": [1.0, 2.0, 3.0], "data": "saved" }))).await;
+    assert_eq!(status, StatusCode::OK);
+    drop(service);
+
+    // The first request after the restart used to create a fresh 2-dimension tree and
+    // save it over the 3-dimension file
+    let store = store.restarted();
+    let service = store.service().await;
+    let (status, body) = send(&service, insert("docs", json!({ "embedding": [1.0, 2.0], "data": "wrong" }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+    assert_eq!(body, "Point has 2 dimensions but tree docs has 3");
+    drop(service);
+
+    let store = store.restarted();
+    let service = store.service().await;
+    let (status, body) = send(&service, search("docs", 10, "", &[1.0, 2.0, 3.0])).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["collection"]["dimensions"], 3);
+    let results = body["results"].as_array().unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0]["data"], "saved");
+}
+
+#[actix_web::test]
+async fn a_new_tree_keeps_its_dimension_before_its_first_save() {
+    let store = common::state();
+    block_saves(&store, "docs");
+    let service = store.service().await;
+    let (status, body) = send(&service, insert("docs", json!({ "embedding": [1.0, 2.0, 3.0] }))).await;
+    assert_eq!(status, StatusCode::ACCEPTED, "{}", body);
+    let (status, body) = send(&service, insert("docs", json!({ "embedding": [1.0, 2.0] }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+    drop(service);
+
+    // Nothing was saved, but the manifest already has the dimension
+    let store = store.restarted();
+    let service = store.service().await;
+    let (status, body) = send(&service, insert("docs", json!({ "embedding": [1.0, 2.0] }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+    assert_eq!(body, "Point has 2 dimensions but tree docs has 3");
+}
@@ -0,0 +1,138 @@
+// The `fields` projection: exactly which keys each result carries for a given list of
+// fields, on every endpoint that takes one
+mod common;
+
+use actix_web::test::TestRequest;
+use common::{insert, search, send};
+use serde_json::{json, Value};
+
+fn keys(value: &Value) -> Vec<&str> {
+    let object = value.as_object().unwrap_or_else(|| panic!("not an object: {}", value));
+    let mut keys: Vec<&str> = object.keys().map(String::as_str).collect();
+    keys.sort();
+    keys
+}
+
+// The sorted keys of every result, which must all be the same
+fn result_keys(results: &Value) -> Vec<&str> {
+    let results = results.as_array().unwrap_or_else(|| panic!("not a list: {}", results));
+    assert!(!results.is_empty());
+    let first = keys(&results[0]);
+    assert!(results.iter().all(|result| keys(result) == first), "{}", Value::Array(results.clone()));
+    first
+}
+
+async fn store_with_docs() -> common::TestState {
+    let store = common::state();
+    let service = store.service().await;
+    for i in 0..3 {
+        let point = json!({ "embedding": [i as f64, 1.0], "data": format!("doc {}", i), "metadata": { "lang": "en" } });
+        send(&service, insert("docs", point)).await;
+    }
+    store
+}
+
+#[actix_web::test]
+async fn search_results_carry_exactly_the_requested_fields() {
+    let store = store_with_docs().await;
+    let service = store.service().await;
+
+    // The answer is always the same object, and `collection` is one more key of it
+    let cases: [(Option<&str>, &[&str], &[&str]); 9] = [
+        (Some("data,distance"), &["results"], &["data", "distance"]),
+        (Some("distance,data"), &["results"], &["data", "distance"]),
+        (Some("embedding"), &["results"], &["embedding"]),
+        (Some("data,metadata,embedding,distance"), &["results"], &["data", "distance", "embedding", "metadata"]),
+        (Some("data,data"), &["results"], &["data"]),
+        (Some(""), &["results"], &[]),
+        (None, &["collection", "results"], &["data", "embedding", "metadata"]),
+        (Some("metadata,collection"), &["collection", "results"], &["metadata"]),
+        (Some("collection"), &["collection", "results"], &[]),
+    ];
+    for (fields, envelope, expected) in cases {
+        let params = fields.map(|fields| format!("&fields={}", fields)).unwrap_or_default();
+        let (status, body) = send(&service, search("docs", 2, &params, &[0.0, 1.0])).await;
+        assert_eq!(status.as_u16(), 200, "{:?}: {}", fields, body);
+        assert_eq!(keys(&body), envelope, "{:?}", fields);
+        assert_eq!(result_keys(&body["results"]), expected, "{:?}", fields);
+        if envelope.contains(&"collection") {
+            assert_eq!(keys(&body["collection"]), ["dimensions", "last_write_at", "metric", "num_records"]);
+        }
+    }
+}
+
+#[actix_web::test]
+async fn unknown_field_names_are_rejected() {
+    let store = store_with_docs().await;
+    let service = store.service().await;
+    for fields in ["colour", "data,colour", "id"] {
+        let (status, body) = send(&service, search("docs", 2, &format!("&fields={}", fields), &[0.0, 1.0])).await;
+        assert_eq!(status.as_u16(), 400, "{}: {}", fields, body);
+        assert_eq!(body["fields"][0]["field"], "fields", "{}", body);
+    }
+    let (status, body) = send(&service, TestRequest::get().uri("/export?tree_name=docs&fields=colour")).await;
+    assert_eq!(status.as_u16(), 400, "{}", body);
+}
+
+#[actix_web::test]
+async fn point_endpoints_project_the_same_way() {
+    let store = store_with_docs().await;
+    let service = store.service().await;
+
+    let (_, body) = send(&service, TestRequest::get().uri("/sample?tree_name=docs&fields=data")).await;
+    assert_eq!(result_keys(&body["points"]), ["data", "seq"]);
+
+    let lookup = TestRequest::post().uri("/get_by_embedding?tree_name=docs&fields=metadata").set_json(json!({ "embedding": [1.0, 1.0] }));
+    let (_, body) = send(&service, lookup).await;
+    assert_eq!(keys(&body), ["inserted_at", "metadata", "seq"]);
+
+    // Exports keep the bookkeeping keys that resuming one relies on
+    let exports: [(&str, &[&str]); 2] = [
+        ("", &["data", "embedding", "inserted_at", "metadata", "seq"]),
+        ("&fields=data,metadata", &["data", "inserted_at", "metadata", "seq"]),
+    ];
+    for (params, expected) in exports {
+        let (status, body) = send(&service, TestRequest::get().uri(&format!("/export?tree_name=docs{}", params))).await;
+        assert_eq!(status.as_u16(), 200, "{}", body);
+        let lines: Vec<Value> = body.as_str().unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(result_keys(&Value::Array(lines)), expected, "{}", params);
+    }
+}
+
+// Significant digits of `value` as printed in JSON
+fn significant_digits(value: f64) -> usize {
+    let printed = value.abs().to_string();
+    let digits = printed.trim_start_matches(['0', '.']).replace('.', "");
+    digits.trim_end_matches('0').len()
+}
+
+#[actix_web::test]
+async fn rounded_embeddings_round_trip_into_inserts_and_lookups() {
+    let store = common::state();
+    let service = store.service().await;
+    let precise = [0.123456789012345, 1.0 / 3.0, -2.468013579246801, 12345.678901234];
+    send(&service, insert("docs", json!({ "embedding": precise, "data": "precise" }))).await;
+
+    let (status, body) = send(&service, search("docs", 1, "&float_precision=6&fields=embedding,distance", &[0.0; 4])).await;
+    assert_eq!(status.as_u16(), 200, "{}", body);
+    let rounded: Vec<f64> = body["results"][0]["embedding"].as_array().unwrap().iter().map(|value| value.as_f64().unwrap()).collect();
+    assert_eq!(rounded, [0.123457, 0.333333, -2.46801, 12345.7]);
+    assert!(rounded.iter().all(|value| significant_digits(*value) <= 6), "{:?}", rounded);
+    // Only the embedding is rounded
+    let distance = precise.iter().map(|value| value * value).sum::<f64>().sqrt();
+    assert_eq!(body["results"][0]["distance"], distance, "{}", body);
+
+    // Sent back as it came, the rounded embedding is a valid point and query
+    let (status, body) = send(&service, insert("docs", json!({ "embedding": rounded, "data": "rounded" }))).await;
+    assert_eq!(status.as_u16(), 200, "{}", body);
+    let lookup = TestRequest::post().uri("/get_by_embedding?tree_name=docs&fields=data").set_json(json!({ "embedding": rounded }));
+    let (status, body) = send(&service, lookup).await;
+    assert_eq!(status.as_u16(), 200, "{}", body);
+    assert_eq!(body["data"], "rounded");
+    let (status, body) = send(&service, search("docs", 2, "&fields=data,distance", &rounded)).await;
+    assert_eq!(status.as_u16(), 200, "{}", body);
+    let results = &body["results"];
+    assert_eq!((results[0]["data"].clone(), results[0]["distance"].clone()), (json!("rounded"), json!(0.0)));
+    assert_eq!(results[1]["data"], "precise");
+}
@@ -0,0 +1,128 @@
+mod common;
+
+use actix_web::http::StatusCode;
+use actix_web::test;
+use common::{insert, search, send};
+use serde_json::json;
+
+#[actix_web::test]
+async fn reject_only_conflicts_for_offloaded_trees() {
+    let store = common::state();
+    let service = store.service().await;
+    let (status, _) = send(&service, insert("docs", json!({ "embedding": [1.0, 2.0] }))).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = send(&service, test::TestRequest::delete().uri("/cache?tree_name=docs")).await;
+    assert_eq!(status, StatusCode::OK);
+
+    for params in ["&if_in_memory=true", "&offloaded_strategy=reject"] {
+        let (status, body) = send(&service, search("docs", 1, params, &[1.0, 2.0])).await;
+        assert_eq!(status, StatusCode::CONFLICT, "{}", params);
+        assert_eq!(body, json!("tree_offloaded"));
+
+        // A tree that was never created is missing, not offloaded
+        let (status, _) = send(&service, search("dcos", 1, params, &[1.0, 2.0])).await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "{}", params);
+    }
+}
+
+#[actix_web::test]
+async fn cached_answers_do_not_bypass_if_in_memory() {
+    let store = common::state_with(|settings| {
+        settings.max_memory_mb = 1;
+        settings.query_cache_entries = 16;
+    });
+    let service = store.service().await;
+    let chunk = "x".repeat(600 * 1024);
+    send(&service, insert("first", json!({ "embedding": [1.0, 2.0], "data": chunk }))).await;
+    let (status, _) = send(&service, search("first", 1, "&if_in_memory=true", &[1.0, 2.0])).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Filling the memory budget with a second tree evicts the first, which keeps its
+    // cached answer since its points did not change
+    let (status, _) = send(&service, insert("second", json!({ "embedding": [1.0, 2.0], "data": chunk }))).await;
+    assert_eq!(status, StatusCode::OK);
+    let (_, status_body) = send(&service, test::TestRequest::get().uri("/v1/status?tree_name=first")).await;
+    assert_eq!(status_body["trees"][0]["in_memory"], json!(false));
+
+    let (status, body) = send(&service, search("first", 1, "&if_in_memory=true", &[1.0, 2.0])).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(body, json!("tree_offloaded"));
+    let (status, _) = send(&service, search("first", 1, "", &[1.0, 2.0])).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+// Searches answered from the query cache so far, from /metrics
+async fn cache_hits<S, B>(service: &S) -> u64
+where
+    S: actix_web::dev::Service<actix_http::Request, Response = actix_web::dev::ServiceResponse<B>, Error = actix_web::Error>,
+    B: actix_web::body::MessageBody,
+{
+    let (_, metrics) = send(service, test::TestRequest::get().uri("/metrics")).await;
+    let metrics = metrics.as_str().unwrap().to_string();
+    metrics
+        .lines()
+        .find_map(|line| line.strip_prefix("vodb_query_cache_hits_total ").and_then(|value| value.parse().ok()))
+        .unwrap_or_else(|| panic!("no cache hits counter in\n{}", metrics))
+}
+
+#[actix_web::test]
+async fn cached_answers_count_as_accesses_and_searches() {
+    let store = common::state_with(|settings| settings.query_cache_entries = 16);
+    let service = store.service().await;
+    let (status, _) = send(&service, insert("docs", json!({ "embedding": [1.0, 2.0], "data": "a" }))).await;
+    assert_eq!(status, StatusCode::OK);
+
+    for searches in [1, 2, 3] {
+        let (status, body) = send(&service, search("docs", 1, "", &[1.0, 2.0])).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(cache_hits(&service).await, searches - 1);
+        let (_, status_body) = send(&service, test::TestRequest::get().uri("/v1/status?tree_name=docs")).await;
+        let tree = &status_body["trees"][0];
+        // The insert was the first access
+        assert_eq!(tree["access_count"], searches + 1, "{}", tree);
+        assert_eq!(tree["usage"]["searches"], searches, "{}", tree);
+    }
+}
+
+#[actix_web::test]
+async fn empty_trees_answer_empty_and_inserts_invalidate_cached_answers() {
+    let store = common::state_with(|settings| settings.query_cache_entries = 16);
+    let service = store.service().await;
+    let (status, _) = send(&service, test::TestRequest::post().uri("/create_tree?tree_name=docs&dimensions=2")).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // A created tree with nothing in it answers an empty list, and is cached like any other
+    for hits in [0, 1] {
+        let (status, body) = send(&service, search("docs", 3, "", &[1.0, 2.0])).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(body["results"], json!([]), "{}", body);
+        assert_eq!(body["tree_size"], 0, "{}", body);
+        assert_eq!(cache_hits(&service).await, hits);
+    }
+
+    // Each insert changes the answer, so the cached one is not served again
+    for (count, embedding) in [(1, [1.0, 2.0]), (2, [3.0, 4.0])] {
+        let (status, _) = send(&service, insert("docs", json!({ "embedding": embedding, "data": count.to_string() }))).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, body) = send(&service, search("docs", 3, "", &[1.0, 2.0])).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(body["results"].as_array().unwrap().len(), count, "{}", body);
+        assert_eq!(body["collection"]["num_records"], count);
+        assert_eq!(cache_hits(&service).await, 1);
+    }
+    let (_, body) = send(&service, search("docs", 3, "", &[1.0, 2.0])).await;
+    assert_eq!(body["results"][0]["data"], "1");
+    assert_eq!(cache_hits(&service).await, 2);
+
+    // Truncating empties it again
+    let (status, body) = send(&service, test::TestRequest::post().uri("/truncate?tree_name=docs&confirm=true")).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let (status, body) = send(&service, search("docs", 3, "", &[1.0, 2.0])).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["results"], json!([]), "{}", body);
+    assert_eq!(body["tree_size"], 0, "{}", body);
+
+    // Only a tree that doesn't exist is missing
+    let (status, _) = send(&service, search("nothing", 3, "", &[1.0, 2.0])).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
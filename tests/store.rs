@@ -0,0 +1,63 @@
+// The embedded store: collections used directly, without HTTP, keep their points across a
+// reopen and refuse the same inserts the server does
+use std::path::Path;
+use vodb::kdtree::Point;
+use vodb::store::{CollectionError, Store, StoreOptions};
+
+fn point(embedding: &[f64], data: &str) -> Point {
+    Point { embedding: embedding.to_vec(), data: Some(data.to_string()), ..Default::default() }
+}
+
+fn open(directory: &Path) -> Store {
+    Store::open(directory, StoreOptions::default()).unwrap()
+}
+
+#[test]
+fn flushed_points_are_found_after_a_reopen() {
+    let directory = tempfile::tempdir().unwrap();
+    let store = open(directory.path());
+    let docs = store.collection("docs");
+    for (i, data) in ["origin", "near", "far"].iter().enumerate() {
+        docs.insert(point(&[(i * i) as f64, 0.0], data)).unwrap();
+    }
+    docs.flush().unwrap();
+    drop(store);
+
+    let store = open(directory.path());
+    let docs = store.collection("docs");
+    // Known from the manifest before anything is loaded
+    assert_eq!(docs.len(), 3);
+    let hits = docs.search(&[1.2, 0.0], 2).unwrap();
+    // Payloads stay in the file until read
+    let data: Vec<_> = hits.iter().map(|(_, point)| point.data().unwrap().into_owned()).collect();
+    assert_eq!(data, ["near", "origin"]);
+    assert!(hits[0].0 < hits[1].0);
+
+    // The tree kept its dimension
+    assert!(matches!(docs.insert(point(&[1.0, 2.0, 3.0], "wide")), Err(CollectionError::Invalid(_))));
+    assert!(matches!(docs.search(&[1.0], 1), Err(CollectionError::Invalid(_))));
+}
+
+#[test]
+fn refused_first_inserts_create_no_collection() {
+    let directory = tempfile::tempdir().unwrap();
+    let options = StoreOptions { max_dimensions: 2, max_memory_bytes: 64 * 1024, ..StoreOptions::default() };
+    let store = Store::open(directory.path(), options).unwrap();
+    let refusals = [
+        (point(&[1.0, f64::NAN], "nan"), "not a finite number"),
+        (point(&[1.0, 2.0, 3.0], "wide"), "MAX_DIMENSIONS"),
+        (point(&[1.0, 2.0], &"x".repeat(64 * 1024)), "memory budget"),
+    ];
+    for (refused, reason) in refusals {
+        let error = store.collection("docs").insert(refused).unwrap_err();
+        assert!(error.to_string().contains(reason), "{}", error);
+        assert!(store.trees.lock().unwrap().is_empty());
+    }
+
+    let strict = Store::open(directory.path(), StoreOptions { strict_create: true, ..StoreOptions::default() }).unwrap();
+    let docs = strict.collection("docs");
+    assert!(matches!(docs.insert(point(&[1.0, 2.0], "a")), Err(CollectionError::NotFound(_))));
+    assert!(strict.trees.lock().unwrap().is_empty());
+    store.flush().unwrap();
+    assert!(!directory.path().join("docs.bin").exists());
+}